@@ -0,0 +1,4 @@
+-- Optional restrictions of API keys, a NULL column leaves the key unrestricted
+-- Without these columns the server grants every key all scopes and projects
+ALTER TABLE "api_key" ADD COLUMN IF NOT EXISTS "scopes" text[];
+ALTER TABLE "api_key" ADD COLUMN IF NOT EXISTS "allowedProjects" text[];
@@ -0,0 +1,9 @@
+-- Next console line number of each run, shared by all server instances
+-- The server creates the table on startup if it is missing
+CREATE TABLE IF NOT EXISTS "mlop_console_lines" (
+    "tenantId" text NOT NULL,
+    "projectName" text NOT NULL,
+    "runId" bigint NOT NULL,
+    "nextLineNumber" bigint NOT NULL,
+    PRIMARY KEY ("tenantId", "projectName", "runId")
+);
@@ -0,0 +1,17 @@
+CREATE TABLE mlop_runs (
+    tenantId LowCardinality(String) CODEC(ZSTD(1)),
+    projectName String CODEC(ZSTD(1)),
+    runId UInt64 CODEC(ZSTD(1)),
+    runName String CODEC(ZSTD(1)),
+    status LowCardinality(String) CODEC(ZSTD(1)), -- INIT, RUNNING, COMPLETED, FAILED, CRASHED
+    exitCode Nullable(Int32) CODEC(ZSTD(1)),
+    config String CODEC(ZSTD(1)), -- JSON
+    metadata String CODEC(ZSTD(1)), -- JSON
+    createdAt DateTime64(3) CODEC(DoubleDelta, LZ4),
+    endedAt Nullable(DateTime64(3)) CODEC(ZSTD(1)),
+    -- Last ingest call or heartbeat seen for the run, used to detect crashed runs
+    lastSeenAt DateTime64(3) CODEC(DoubleDelta, LZ4),
+    -- Version column, the row with the latest update wins
+    updatedAt DateTime64(3) CODEC(DoubleDelta, LZ4)
+) ENGINE = ReplacingMergeTree(updatedAt)
+ORDER BY (tenantId, projectName, runId);
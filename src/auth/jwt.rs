@@ -0,0 +1,269 @@
+use axum::http::HeaderMap;
+use chrono::DateTime;
+use futures::future::BoxFuture;
+use jsonwebtoken::{
+    errors::ErrorKind,
+    jwk::{AlgorithmParameters, JwkSet, KeyAlgorithm},
+    Algorithm, DecodingKey, Validation,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use super::{bearer_token, ApiKeyGrant, AuthProvider};
+use crate::config::Config;
+use crate::error::{AppError, ErrorCode};
+
+/// Claims of a signed token handed to a job instead of an API key
+///
+/// # Example
+/// ```json
+/// {
+///     "tenantId": "my-org",
+///     "projectName": "my-project",
+///     "runId": 123,
+///     "scopes": ["ingest:metrics", "ingest:logs", "files:upload", "runs:write"],
+///     "exp": 1767225600
+/// }
+/// ```
+/// `projectName` and `runId` bind the token to one project or run, `exp` is required
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Claims {
+    tenant_id: String,
+    sub: Option<String>, // Shown in logs
+    project_name: Option<String>,
+    run_id: Option<u64>,
+    scopes: Option<Vec<String>>, // Omit to grant every scope
+    exp: i64,                    // Seconds since the Unix epoch
+}
+
+// A key that may have signed a token
+struct VerificationKey {
+    key_id: Option<String>, // `kid` of JWKS keys, tokens naming another kid skip this key
+    algorithm: Algorithm,
+    key: DecodingKey,
+}
+
+// Accepts signed JWTs (HS256, RS256 or EdDSA) in the Authorization header
+// and hands every other bearer token to the wrapped provider
+pub struct JwtAuthProvider {
+    inner: Arc<dyn AuthProvider>,
+    keys: Vec<VerificationKey>,
+    issuer: Option<String>,
+}
+
+impl JwtAuthProvider {
+    // Loads the verification keys from JWT_HS256_SECRET, JWT_PUBLIC_KEY_FILE and JWT_JWKS_FILE
+    // Returns None if none of them is configured
+    pub fn from_config(
+        inner: Arc<dyn AuthProvider>,
+        config: &Config,
+    ) -> Result<Option<Self>, AppError> {
+        let mut keys = Vec::new();
+
+        if let Some(secret) = &config.jwt_hs256_secret {
+            keys.push(VerificationKey {
+                key_id: None,
+                algorithm: Algorithm::HS256,
+                key: DecodingKey::from_secret(secret.as_bytes()),
+            });
+        }
+
+        if let Some(path) = &config.jwt_public_key_file {
+            let pem = read_file(path)?;
+            // The file may hold either an RSA or an Ed25519 public key
+            let key = match DecodingKey::from_rsa_pem(&pem) {
+                Ok(key) => (Algorithm::RS256, key),
+                Err(_) => (
+                    Algorithm::EdDSA,
+                    DecodingKey::from_ed_pem(&pem).map_err(|e| {
+                        configuration_error(format!("Invalid public key in {}: {}", path, e))
+                    })?,
+                ),
+            };
+            keys.push(VerificationKey {
+                key_id: None,
+                algorithm: key.0,
+                key: key.1,
+            });
+        }
+
+        if let Some(path) = &config.jwt_jwks_file {
+            let jwks: JwkSet = serde_json::from_slice(&read_file(path)?)
+                .map_err(|e| configuration_error(format!("Invalid JWKS in {}: {}", path, e)))?;
+            for jwk in &jwks.keys {
+                // Infer the algorithm from the key type if the JWK does not name one
+                let algorithm = match (jwk.common.key_algorithm, &jwk.algorithm) {
+                    (Some(KeyAlgorithm::HS256), _) | (None, AlgorithmParameters::OctetKey(_)) => {
+                        Algorithm::HS256
+                    }
+                    (Some(KeyAlgorithm::RS256), _) | (None, AlgorithmParameters::RSA(_)) => {
+                        Algorithm::RS256
+                    }
+                    (Some(KeyAlgorithm::EdDSA), _)
+                    | (None, AlgorithmParameters::OctetKeyPair(_)) => Algorithm::EdDSA,
+                    _ => {
+                        warn!(kid = ?jwk.common.key_id, "Skipping JWKS key with unsupported algorithm");
+                        continue;
+                    }
+                };
+                keys.push(VerificationKey {
+                    key_id: jwk.common.key_id.clone(),
+                    algorithm,
+                    key: DecodingKey::from_jwk(jwk).map_err(|e| {
+                        configuration_error(format!("Invalid JWKS key in {}: {}", path, e))
+                    })?,
+                });
+            }
+        }
+
+        if keys.is_empty() {
+            return Ok(None);
+        }
+        info!(keys = keys.len(), "JWT authentication enabled");
+        Ok(Some(Self {
+            inner,
+            keys,
+            issuer: config.jwt_issuer.clone(),
+        }))
+    }
+
+    // Checks the signature, expiry and issuer of a token and returns what it grants
+    fn verify(&self, token: &str) -> Result<ApiKeyGrant, AppError> {
+        let header = jsonwebtoken::decode_header(token).map_err(|_| invalid_token())?;
+
+        let mut expired = false;
+        for key in self.keys.iter().filter(|key| {
+            key.algorithm == header.alg
+                && (key.key_id.is_none() || header.kid.is_none() || key.key_id == header.kid)
+        }) {
+            let mut validation = Validation::new(key.algorithm);
+            validation.validate_aud = false;
+            if let Some(issuer) = &self.issuer {
+                validation.set_issuer(&[issuer]);
+            }
+
+            match jsonwebtoken::decode::<Claims>(token, &key.key, &validation) {
+                Ok(data) => {
+                    let claims = data.claims;
+                    return Ok(ApiKeyGrant {
+                        key_id: claims.sub.unwrap_or_else(|| "jwt".to_string()),
+                        tenant_id: claims.tenant_id,
+                        expires_at: DateTime::from_timestamp(claims.exp, 0),
+                        scopes: claims.scopes,
+                        allowed_projects: None,
+                        project_name: claims.project_name,
+                        run_id: claims.run_id,
+                    });
+                }
+                Err(e) if *e.kind() == ErrorKind::ExpiredSignature => expired = true,
+                Err(_) => {}
+            }
+        }
+
+        if expired {
+            warn!("JWT has expired");
+            return Err(AppError::new(ErrorCode::TokenExpired, "Token has expired"));
+        }
+        warn!(alg = ?header.alg, kid = ?header.kid, "JWT could not be verified");
+        Err(invalid_token())
+    }
+}
+
+impl AuthProvider for JwtAuthProvider {
+    fn authenticate<'a>(
+        &'a self,
+        headers: &'a HeaderMap,
+    ) -> BoxFuture<'a, Result<ApiKeyGrant, AppError>> {
+        Box::pin(async move {
+            match bearer_token(headers) {
+                // JWTs consist of three dot-separated parts, anything else is treated as an API key
+                Ok(token) if token.split('.').count() == 3 => self.verify(token),
+                _ => self.inner.authenticate(headers).await,
+            }
+        })
+    }
+
+    fn ping(&self) -> BoxFuture<'_, Result<(), AppError>> {
+        self.inner.ping()
+    }
+
+    fn invalidate_api_key(&self, hashed_key: &str) -> bool {
+        self.inner.invalidate_api_key(hashed_key)
+    }
+
+    fn invalidate_all_api_keys(&self) -> usize {
+        self.inner.invalidate_all_api_keys()
+    }
+
+    fn key_invalidations(&self) -> Option<watch::Receiver<u64>> {
+        self.inner.key_invalidations()
+    }
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>, AppError> {
+    std::fs::read(path).map_err(|e| configuration_error(format!("Failed to read {}: {}", path, e)))
+}
+
+fn configuration_error(message: String) -> AppError {
+    AppError::new(ErrorCode::ConfigurationError, message)
+}
+
+fn invalid_token() -> AppError {
+    AppError::new(ErrorCode::InvalidToken, "Invalid token")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::single_tenant::SingleTenantProvider;
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+
+    fn provider(secret: &str) -> JwtAuthProvider {
+        JwtAuthProvider {
+            inner: Arc::new(SingleTenantProvider::new("fallback".to_string())),
+            keys: vec![VerificationKey {
+                key_id: None,
+                algorithm: Algorithm::HS256,
+                key: DecodingKey::from_secret(secret.as_bytes()),
+            }],
+            issuer: None,
+        }
+    }
+
+    fn token(secret: &str, exp: i64) -> String {
+        let claims = json!({ "tenantId": "org", "projectName": "p", "runId": 7, "exp": exp });
+        jsonwebtoken::encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(secret.as_bytes()),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_verify_hs256() {
+        let provider = provider("secret");
+        let exp = chrono::Utc::now().timestamp() + 600;
+
+        let grant = provider.verify(&token("secret", exp)).unwrap();
+        assert_eq!(grant.tenant_id, "org");
+        assert_eq!(grant.project_name.as_deref(), Some("p"));
+        assert_eq!(grant.run_id, Some(7));
+
+        assert!(matches!(
+            provider.verify(&token("other", exp)).unwrap_err().code,
+            ErrorCode::InvalidToken
+        ));
+        assert!(matches!(
+            provider
+                .verify(&token("secret", exp - 7200))
+                .unwrap_err()
+                .code,
+            ErrorCode::TokenExpired
+        ));
+    }
+}
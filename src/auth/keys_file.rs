@@ -0,0 +1,138 @@
+use axum::http::HeaderMap;
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use serde::Deserialize;
+use std::collections::HashMap;
+use tracing::{info, warn};
+
+use super::{bearer_token, hash_api_key, ApiKeyGrant, AuthProvider};
+use crate::error::{AppError, ErrorCode};
+
+/// Static API keys for deployments without Postgres, loaded once at startup
+///
+/// # Example
+/// ```toml
+/// [[keys]]
+/// hashedKey = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+/// tenantId = "my-org"
+/// scopes = ["ingest:metrics", "ingest:logs", "read"]
+/// ```
+/// The same structure is accepted as JSON (`{ "keys": [...] }`) for files without a `.toml` extension
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeysFile {
+    keys: Vec<KeyEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+struct KeyEntry {
+    hashed_key: String, // SHA-256 hex digest of the key, as in the `api_key` table
+    tenant_id: String,  // Organization the key belongs to
+    id: Option<String>, // Shown in logs, defaults to the tenant ID
+    expires_at: Option<DateTime<Utc>>, // RFC 3339 expiry time
+    scopes: Option<Vec<String>>, // Omit to grant every scope
+    allowed_projects: Option<Vec<String>>, // Omit to allow every project
+}
+
+// Authenticates requests against a fixed set of hashed keys
+pub struct KeysFileProvider {
+    keys: HashMap<String, ApiKeyGrant>, // Hashed key -> grant
+}
+
+impl KeysFileProvider {
+    // Reads and parses the keys file, TOML if the path ends in `.toml` and JSON otherwise
+    pub fn load(path: &str) -> Result<Self, AppError> {
+        let contents = std::fs::read_to_string(path).map_err(|e| {
+            AppError::new(
+                ErrorCode::ConfigurationError,
+                format!("Failed to read keys file {}: {}", path, e),
+            )
+        })?;
+        let provider = Self::parse(&contents, path.ends_with(".toml"))?;
+        info!(path, keys = provider.keys.len(), "Loaded API keys file");
+        Ok(provider)
+    }
+
+    fn parse(contents: &str, is_toml: bool) -> Result<Self, AppError> {
+        let file: KeysFile = if is_toml {
+            toml::from_str(contents).map_err(|e| e.to_string())
+        } else {
+            serde_json::from_str(contents).map_err(|e| e.to_string())
+        }
+        .map_err(|e| {
+            AppError::new(
+                ErrorCode::ConfigurationError,
+                format!("Invalid keys file: {}", e),
+            )
+        })?;
+
+        let mut keys = HashMap::with_capacity(file.keys.len());
+        for entry in file.keys {
+            let grant = ApiKeyGrant {
+                key_id: entry.id.unwrap_or_else(|| entry.tenant_id.clone()),
+                tenant_id: entry.tenant_id,
+                expires_at: entry.expires_at,
+                scopes: entry.scopes,
+                allowed_projects: entry.allowed_projects,
+                project_name: None,
+                run_id: None,
+            };
+            if keys.insert(entry.hashed_key, grant).is_some() {
+                return Err(AppError::new(
+                    ErrorCode::ConfigurationError,
+                    "Invalid keys file: duplicate hashedKey",
+                ));
+            }
+        }
+        Ok(Self { keys })
+    }
+}
+
+impl AuthProvider for KeysFileProvider {
+    fn authenticate<'a>(
+        &'a self,
+        headers: &'a HeaderMap,
+    ) -> BoxFuture<'a, Result<ApiKeyGrant, AppError>> {
+        Box::pin(async move {
+            let token = bearer_token(headers)?;
+            let grant = self.keys.get(&hash_api_key(token)).ok_or_else(|| {
+                warn!("API key not found in keys file");
+                AppError::new(ErrorCode::InvalidToken, "Invalid API key")
+            })?;
+            grant.check_expiry()?;
+            Ok(grant.clone())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_toml_and_json() {
+        let hashed_key = hash_api_key("secret");
+        let toml = format!(
+            "[[keys]]\nhashedKey = \"{}\"\ntenantId = \"org\"\nscopes = [\"read\"]\n",
+            hashed_key
+        );
+        let json = format!(
+            r#"{{"keys": [{{"hashedKey": "{}", "tenantId": "org", "scopes": ["read"]}}]}}"#,
+            hashed_key
+        );
+
+        for provider in [
+            KeysFileProvider::parse(&toml, true).unwrap(),
+            KeysFileProvider::parse(&json, false).unwrap(),
+        ] {
+            let grant = &provider.keys[&hashed_key];
+            assert_eq!(grant.tenant_id, "org");
+            assert_eq!(grant.key_id, "org");
+            assert_eq!(grant.scopes, Some(vec!["read".to_string()]));
+            assert_eq!(grant.allowed_projects, None);
+        }
+
+        assert!(KeysFileProvider::parse(r#"{"keys": [{"tenantId": "org"}]}"#, false).is_err());
+    }
+}
@@ -0,0 +1,354 @@
+use axum::http::HeaderMap;
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use sha2::{Digest, Sha256};
+use tokio::sync::watch;
+use tracing::{debug, instrument, warn};
+
+use crate::error::{invalid_auth_error, AppError, ErrorCode};
+
+pub mod jwt;
+pub mod keys_file;
+pub mod single_tenant;
+
+// Source of the tenant and permissions behind a request's credentials
+// Selected at startup by AUTH_PROVIDER (Postgres `api_key` table, static keys file, or no auth)
+pub trait AuthProvider: Send + Sync {
+    // Resolves the credentials in the request headers to what they grant access to
+    fn authenticate<'a>(
+        &'a self,
+        headers: &'a HeaderMap,
+    ) -> BoxFuture<'a, Result<ApiKeyGrant, AppError>>;
+
+    // Checks that the backing store is reachable, used by the readiness check
+    fn ping(&self) -> BoxFuture<'_, Result<(), AppError>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    // Drops a hashed key from any cache, returns whether it was cached
+    fn invalidate_api_key(&self, _hashed_key: &str) -> bool {
+        false
+    }
+
+    // Drops every key from any cache, returns how many were cached
+    fn invalidate_all_api_keys(&self) -> usize {
+        0
+    }
+
+    // Changes whenever cached keys are invalidated, so long-lived connections can
+    // re-authenticate right away instead of at their next periodic check
+    fn key_invalidations(&self) -> Option<watch::Receiver<u64>> {
+        None
+    }
+}
+
+// What a valid API key grants access to
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiKeyGrant {
+    pub key_id: String,
+    pub tenant_id: String,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub scopes: Option<Vec<String>>,
+    pub allowed_projects: Option<Vec<String>>,
+    pub project_name: Option<String>, // Project the credentials are bound to (signed tokens only)
+    pub run_id: Option<u64>,          // Run the credentials are bound to (signed tokens only)
+}
+
+impl ApiKeyGrant {
+    // Rejects keys past their expiry time
+    pub fn check_expiry(&self) -> Result<(), AppError> {
+        if let Some(expires_at) = self.expires_at {
+            if expires_at < Utc::now() {
+                warn!(key_id = %self.key_id, tenant_id = %self.tenant_id, expiry = %expires_at, "API key has expired");
+                return Err(AppError::new(
+                    ErrorCode::InvalidToken,
+                    "API key has expired",
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Hashes an API key using SHA256
+// Skips hashing if the key already starts with "mlpi_" (assumed pre-hashed or special format)
+// This prefix is used internally to identify keys that might have a different hashing mechanism or origin.
+pub fn hash_api_key(api_key: &str) -> String {
+    if api_key.starts_with("mlpi_") {
+        // Assume keys starting with "mlpi_" are already hashed or special
+        api_key.to_string()
+    } else {
+        // Hash other keys using SHA256
+        let mut hasher = Sha256::new();
+        hasher.update(api_key.as_bytes());
+        // Return the hex-encoded hash
+        format!("{:x}", hasher.finalize())
+    }
+}
+
+// Permissions an API key can be granted, each route requires one of them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    IngestMetrics, // POST /ingest/metrics
+    IngestLogs,    // POST /ingest/logs
+    IngestData,    // POST /ingest/data
+    UploadFiles,   // POST /files
+    RunsWrite,     // POST /status, POST /heartbeat
+    Read,          // /step, /query/*, GET /status
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::IngestMetrics => "ingest:metrics",
+            Scope::IngestLogs => "ingest:logs",
+            Scope::IngestData => "ingest:data",
+            Scope::UploadFiles => "files:upload",
+            Scope::RunsWrite => "runs:write",
+            Scope::Read => "read",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Auth {
+    pub tenant_id: String,
+    pub scopes: Option<Vec<String>>, // None grants every scope
+    pub allowed_projects: Option<Vec<String>>, // None allows every project
+    pub project_name: Option<String>, // Project the credentials are bound to
+    pub run_id: Option<u64>,         // Run the credentials are bound to
+    pub expires_at: Option<DateTime<Utc>>, // When the credentials stop being valid
+}
+
+impl Auth {
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        match &self.scopes {
+            Some(scopes) => scopes.iter().any(|s| s == scope.as_str()),
+            None => true,
+        }
+    }
+
+    pub fn allows_project(&self, project_name: &str) -> bool {
+        match &self.allowed_projects {
+            Some(projects) => projects.iter().any(|p| p == project_name),
+            None => true,
+        }
+    }
+
+    // Makes the project and run the credentials are bound to override the request headers
+    // Headers naming a different project or run are rejected
+    pub fn bind_headers(&self, headers: &mut HeaderMap) -> Result<(), AppError> {
+        let bindings = [
+            ("X-Project-Name", self.project_name.clone()),
+            ("X-Run-Id", self.run_id.map(|id| id.to_string())),
+        ];
+        for (name, bound) in bindings {
+            let Some(bound) = bound else {
+                continue;
+            };
+            match headers.get(name).map(|h| h.to_str()) {
+                Some(Ok(value)) if value == bound => {}
+                Some(_) => {
+                    warn!(tenant_id = %self.tenant_id, header = name, "Header does not match token claims");
+                    return Err(AppError::new(
+                        ErrorCode::InsufficientPermissions,
+                        format!("Token is not valid for this {}", name),
+                    ));
+                }
+                None => {
+                    // Claim values came from a verified token, so they are valid header values
+                    if let Ok(value) = bound.parse() {
+                        headers.insert(name, value);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Rejects keys that lack `scope` or may not access the project named in the headers
+    pub fn authorize(&self, scope: Scope, headers: &HeaderMap) -> Result<(), AppError> {
+        if !self.has_scope(scope) {
+            warn!(tenant_id = %self.tenant_id, scope = scope.as_str(), "API key lacks required scope");
+            return Err(AppError::new(
+                ErrorCode::InsufficientPermissions,
+                format!("API key lacks the '{}' scope", scope.as_str()),
+            ));
+        }
+
+        let project_name = headers.get("X-Project-Name").and_then(|h| h.to_str().ok());
+        if let Some(project_name) = project_name {
+            if !self.allows_project(project_name) {
+                warn!(tenant_id = %self.tenant_id, project_name, "API key may not access project");
+                return Err(AppError::new(
+                    ErrorCode::InsufficientPermissions,
+                    format!("API key may not access project '{}'", project_name),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Rejects a project and run named in the request body (instead of the headers)
+    // that the credentials are not bound to or may not access
+    pub fn authorize_target(&self, project_name: &str, run_id: u64) -> Result<(), AppError> {
+        let bound_elsewhere = matches!(&self.project_name, Some(bound) if bound != project_name)
+            || matches!(self.run_id, Some(bound) if bound != run_id);
+        if bound_elsewhere {
+            warn!(tenant_id = %self.tenant_id, project_name, run_id, "Request targets a run outside of the token claims");
+            return Err(AppError::new(
+                ErrorCode::InsufficientPermissions,
+                "Token is not valid for this project or run",
+            ));
+        }
+        if !self.allows_project(project_name) {
+            warn!(tenant_id = %self.tenant_id, project_name, "API key may not access project");
+            return Err(AppError::new(
+                ErrorCode::InsufficientPermissions,
+                format!("API key may not access project '{}'", project_name),
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Authenticates the request and checks that the key grants `scope`
+// Fills in the X-Project-Name/X-Run-Id headers from the claims of bound tokens
+#[instrument(skip(headers, provider), fields(token_prefix = tracing::field::Empty))]
+pub async fn auth(
+    headers: &mut HeaderMap,
+    provider: &dyn AuthProvider,
+    scope: Scope,
+) -> Result<Auth, AppError> {
+    let auth = authenticate(headers, provider).await?;
+    auth.authorize(scope, headers)?;
+    Ok(auth)
+}
+
+// Authenticates the request without checking a scope, for connections that carry
+// several kinds of data and call `Auth::authorize` for each of them
+pub async fn authenticate(
+    headers: &mut HeaderMap,
+    provider: &dyn AuthProvider,
+) -> Result<Auth, AppError> {
+    debug!("Attempting authentication");
+    let grant = provider.authenticate(headers).await?;
+    let auth = Auth {
+        tenant_id: grant.tenant_id,
+        scopes: grant.scopes,
+        allowed_projects: grant.allowed_projects,
+        project_name: grant.project_name,
+        run_id: grant.run_id,
+        expires_at: grant.expires_at,
+    };
+    auth.bind_headers(headers)?;
+    debug!(tenant_id = %auth.tenant_id, "Authentication successful");
+
+    Ok(auth)
+}
+
+// Extracts the API key from the `Authorization: Bearer <key>` header
+pub fn bearer_token(headers: &HeaderMap) -> Result<&str, AppError> {
+    let auth_header = headers.get("Authorization").ok_or_else(|| {
+        warn!("Missing Authorization header");
+        AppError::new(ErrorCode::MissingToken, "Missing Authorization header")
+    })?;
+
+    let auth_str = auth_header.to_str().map_err(|_| {
+        warn!("Invalid characters in Authorization header");
+        AppError::new(
+            ErrorCode::InvalidTokenFormat,
+            "Authorization header contains invalid characters",
+        )
+    })?;
+
+    if !auth_str.starts_with("Bearer ") {
+        warn!("Authorization header does not start with 'Bearer '");
+        return Err(AppError::new(
+            ErrorCode::InvalidBearerFormat,
+            "Authorization header must start with 'Bearer '",
+        ));
+    }
+
+    let token = auth_str[7..].trim();
+    // Record prefix for easier debugging without logging full token
+    tracing::Span::current().record("token_prefix", token.chars().take(8).collect::<String>());
+
+    if token.is_empty() {
+        warn!("Empty Bearer token provided");
+        return Err(invalid_auth_error("Bearer token cannot be empty"));
+    }
+
+    // Basic token character validation (can be enhanced)
+    if !token
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+    {
+        warn!(token_prefix = %token.chars().take(8).collect::<String>(), "Bearer token contains invalid characters");
+        return Err(AppError::new(
+            ErrorCode::InvalidTokenFormat,
+            "Bearer token contains invalid characters",
+        ));
+    }
+
+    debug!("Token extracted, looking up tenant ID");
+    Ok(token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_authorize_checks_scope_and_project() {
+        let auth = Auth {
+            tenant_id: "tenant".to_string(),
+            scopes: Some(vec!["ingest:metrics".to_string(), "read".to_string()]),
+            allowed_projects: Some(vec!["allowed".to_string()]),
+            project_name: None,
+            run_id: None,
+            expires_at: None,
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Project-Name", "allowed".parse().unwrap());
+
+        assert!(auth.authorize(Scope::IngestMetrics, &headers).is_ok());
+        assert!(matches!(
+            auth.authorize(Scope::IngestLogs, &headers)
+                .unwrap_err()
+                .code,
+            ErrorCode::InsufficientPermissions
+        ));
+
+        headers.insert("X-Project-Name", "other".parse().unwrap());
+        assert!(auth.authorize(Scope::Read, &headers).is_err());
+
+        // Keys without restrictions may do anything
+        let unrestricted = Auth {
+            scopes: None,
+            allowed_projects: None,
+            ..auth
+        };
+        assert!(unrestricted.authorize(Scope::UploadFiles, &headers).is_ok());
+    }
+
+    #[test]
+    fn test_bound_tokens_override_headers() {
+        let auth = Auth {
+            tenant_id: "tenant".to_string(),
+            scopes: None,
+            allowed_projects: None,
+            project_name: Some("bound".to_string()),
+            run_id: Some(42),
+            expires_at: None,
+        };
+
+        let mut headers = HeaderMap::new();
+        auth.bind_headers(&mut headers).unwrap();
+        assert_eq!(headers["X-Project-Name"], "bound");
+        assert_eq!(headers["X-Run-Id"], "42");
+
+        headers.insert("X-Run-Id", "43".parse().unwrap());
+        assert!(auth.bind_headers(&mut headers).is_err());
+    }
+}
@@ -0,0 +1,36 @@
+use axum::http::HeaderMap;
+use futures::future::BoxFuture;
+
+use super::{ApiKeyGrant, AuthProvider};
+use crate::error::AppError;
+
+// Accepts every request, with or without credentials, as the same tenant
+// Only meant for local development, anyone who can reach the server can read and write all data
+pub struct SingleTenantProvider {
+    tenant_id: String,
+}
+
+impl SingleTenantProvider {
+    pub fn new(tenant_id: String) -> Self {
+        Self { tenant_id }
+    }
+}
+
+impl AuthProvider for SingleTenantProvider {
+    fn authenticate<'a>(
+        &'a self,
+        _headers: &'a HeaderMap,
+    ) -> BoxFuture<'a, Result<ApiKeyGrant, AppError>> {
+        Box::pin(async move {
+            Ok(ApiKeyGrant {
+                key_id: "single-tenant".to_string(),
+                tenant_id: self.tenant_id.clone(),
+                expires_at: None,
+                scopes: None,           // Every scope
+                allowed_projects: None, // Every project
+                project_name: None,
+                run_id: None,
+            })
+        })
+    }
+}
@@ -1,5 +1,8 @@
 use std::time::Duration;
 
+use crate::processors::fair_queue::SendLimits;
+use crate::rate_limit::TenantLimits;
+
 // Holds application configuration loaded from environment variables
 #[derive(Debug, Clone)]
 pub struct Config {
@@ -12,8 +15,50 @@ pub struct Config {
     pub storage_secret_access_key: String,
     pub storage_bucket: String,
     pub storage_endpoint: String, // e.g., "https://<accountid>.r2.cloudflarestorage.com" or "s3.us-west-2.amazonaws.com"
-    // Primary database (PostgreSQL) connection URL
-    pub database_url: String,
+    // Where API keys are looked up (Postgres, a static keys file, or no auth at all)
+    pub auth_backend: AuthBackend,
+    // Maximum number of connections in the PostgreSQL pool
+    pub database_max_connections: u32,
+    // How long API key lookups are cached (found keys, unknown keys) and how many at most
+    pub api_key_cache_ttl: Duration,
+    pub api_key_negative_cache_ttl: Duration,
+    pub api_key_cache_max_entries: usize,
+    // How often the last used time of API keys is written (at most once per key per interval)
+    pub api_key_last_used_interval: Duration,
+    // Postgres channel notified with the hashed key when an API key is revoked or changed
+    pub api_key_notify_channel: Option<String>,
+    // Keys for verifying signed JWTs accepted alongside API keys, JWTs are rejected if none is set
+    pub jwt_hs256_secret: Option<String>,
+    pub jwt_public_key_file: Option<String>, // PEM encoded RSA (RS256) or Ed25519 (EdDSA) public key
+    pub jwt_jwks_file: Option<String>,       // Local JWKS file
+    // Required `iss` claim of JWTs, any issuer is accepted if unset
+    pub jwt_issuer: Option<String>,
+    // Bearer token for the /admin routes and /metrics, which are disabled if unset
+    pub admin_token: Option<String>,
+    // Label ingest metrics with tenant IDs, off by default as they identify customers
+    pub metrics_tenant_labels: bool,
+    // Directory for the on-disk write-ahead spool of background processor batches
+    pub spool_dir: String,
+    // Maximum time to wait for in-flight requests and final flushes on shutdown
+    pub shutdown_drain_timeout: Duration,
+    // How long idempotency keys are remembered, and how many at most
+    pub idempotency_window: Duration,
+    pub idempotency_max_keys: usize,
+    // Ingest limits of tenants without an entry in the tenant limits file
+    pub tenant_limits: TenantLimits,
+    // TOML/JSON file with per-tenant ingest limits
+    pub tenant_limits_file: Option<String>,
+    // How long ingest requests wait for space in a background processor channel,
+    // and how many queued records make them fail right away (503 ServiceOverloaded)
+    pub ingest_send_limits: SendLimits,
+    // Size limit of gzip, zstd and deflate request bodies after decompression
+    pub ingest_max_decompressed_bytes: u64,
+    // Columnar imports (/import) running at once, further imports are rejected until one ends
+    pub import_max_concurrent: usize,
+    // Identifies this server instance in allocated run IDs (0-31, unique per instance)
+    pub node_id: u64,
+    // Runs that send no data or heartbeat for this long are marked as CRASHED
+    pub run_crash_timeout: Duration,
 }
 
 impl Config {
@@ -36,8 +81,144 @@ impl Config {
             storage_bucket: std::env::var("STORAGE_BUCKET").expect("STORAGE_BUCKET is not set"),
             storage_endpoint: std::env::var("STORAGE_ENDPOINT")
                 .expect("STORAGE_ENDPOINT is not set"),
-            database_url: std::env::var("DATABASE_DIRECT_URL")
-                .expect("DATABASE_DIRECT_URL is not set"),
+            auth_backend: AuthBackend::from_env(),
+            database_max_connections: std::env::var("DATABASE_MAX_CONNECTIONS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(5),
+            api_key_cache_ttl: Duration::from_secs(
+                std::env::var("API_KEY_CACHE_TTL_SECS")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(60),
+            ),
+            api_key_negative_cache_ttl: Duration::from_secs(
+                std::env::var("API_KEY_NEGATIVE_CACHE_TTL_SECS")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(5),
+            ),
+            api_key_cache_max_entries: std::env::var("API_KEY_CACHE_MAX_ENTRIES")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(10_000),
+            api_key_last_used_interval: Duration::from_secs(
+                std::env::var("API_KEY_LAST_USED_INTERVAL_SECS")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(60),
+            ),
+            api_key_notify_channel: match std::env::var("API_KEY_NOTIFY_CHANNEL") {
+                Ok(channel) if channel.is_empty() => None, // Set to an empty string to disable
+                Ok(channel) => Some(channel),
+                Err(_) => Some("api_key_changes".to_string()),
+            },
+            jwt_hs256_secret: std::env::var("JWT_HS256_SECRET")
+                .ok()
+                .filter(|s| !s.is_empty()),
+            jwt_public_key_file: std::env::var("JWT_PUBLIC_KEY_FILE").ok(),
+            jwt_jwks_file: std::env::var("JWT_JWKS_FILE").ok(),
+            jwt_issuer: std::env::var("JWT_ISSUER").ok(),
+            admin_token: std::env::var("ADMIN_TOKEN").ok().filter(|t| !t.is_empty()),
+            metrics_tenant_labels: std::env::var("METRICS_TENANT_LABELS").unwrap_or_default()
+                == "true",
+            spool_dir: std::env::var("SPOOL_DIR").unwrap_or_else(|_| "spool".to_string()),
+            shutdown_drain_timeout: Duration::from_secs(
+                std::env::var("SHUTDOWN_DRAIN_TIMEOUT_SECS")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(60),
+            ),
+            idempotency_window: Duration::from_secs(
+                std::env::var("IDEMPOTENCY_WINDOW_SECS")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(3600),
+            ),
+            idempotency_max_keys: std::env::var("IDEMPOTENCY_MAX_KEYS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(100_000),
+            tenant_limits: TenantLimits {
+                rows_per_sec: std::env::var("TENANT_ROWS_PER_SEC")
+                    .ok()
+                    .and_then(|v| v.parse().ok()),
+                bytes_per_sec: std::env::var("TENANT_BYTES_PER_SEC")
+                    .ok()
+                    .and_then(|v| v.parse().ok()),
+                daily_rows: std::env::var("TENANT_DAILY_ROWS")
+                    .ok()
+                    .and_then(|v| v.parse().ok()),
+                daily_bytes: std::env::var("TENANT_DAILY_BYTES")
+                    .ok()
+                    .and_then(|v| v.parse().ok()),
+            },
+            tenant_limits_file: std::env::var("TENANT_LIMITS_FILE").ok(),
+            ingest_send_limits: SendLimits {
+                timeout: std::env::var("INGEST_SEND_TIMEOUT_MS")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .or(Some(DEFAULT_INGEST_SEND_TIMEOUT.as_millis() as u64))
+                    .filter(|&ms| ms > 0) // 0 waits indefinitely
+                    .map(Duration::from_millis),
+                high_water_mark: std::env::var("INGEST_HIGH_WATER_MARK")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .or(Some(DEFAULT_INGEST_HIGH_WATER_MARK))
+                    .filter(|&records| records > 0), // 0 disables the high-water mark
+            },
+            ingest_max_decompressed_bytes: std::env::var("INGEST_MAX_DECOMPRESSED_BYTES")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_MAX_DECOMPRESSED_BYTES),
+            import_max_concurrent: std::env::var("IMPORT_MAX_CONCURRENT")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_IMPORT_MAX_CONCURRENT),
+            node_id: std::env::var("NODE_ID")
+                .ok()
+                .map(|v| v.parse().expect("NODE_ID must be a number"))
+                .unwrap_or(0),
+            run_crash_timeout: Duration::from_secs(
+                std::env::var("RUN_CRASH_TIMEOUT_SECS")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(600),
+            ),
+        }
+    }
+}
+
+// Source of API keys, selected by AUTH_PROVIDER
+#[derive(Debug, Clone)]
+pub enum AuthBackend {
+    // The `api_key` table of the primary database (default)
+    Postgres { database_url: String },
+    // A TOML or JSON file mapping hashed keys to tenants
+    KeysFile { path: String },
+    // Every request belongs to a single tenant, without any credentials (development only)
+    SingleTenant { tenant_id: String },
+}
+
+impl AuthBackend {
+    // Panics if the selected backend is unknown or its required variables are not set
+    fn from_env() -> Self {
+        match std::env::var("AUTH_PROVIDER").as_deref() {
+            Ok("postgres") | Err(_) => AuthBackend::Postgres {
+                database_url: std::env::var("DATABASE_DIRECT_URL")
+                    .expect("DATABASE_DIRECT_URL is not set"),
+            },
+            Ok("file") => AuthBackend::KeysFile {
+                path: std::env::var("AUTH_KEYS_FILE")
+                    .expect("AUTH_KEYS_FILE is not set (required with AUTH_PROVIDER=file)"),
+            },
+            Ok("none") => AuthBackend::SingleTenant {
+                tenant_id: std::env::var("AUTH_TENANT_ID").unwrap_or_else(|_| "local".to_string()),
+            },
+            Ok(other) => panic!(
+                "Unknown AUTH_PROVIDER '{}', expected postgres, file or none",
+                other
+            ),
         }
     }
 }
@@ -47,6 +228,34 @@ pub const METRICS_TABLE_NAME: &str = "mlop_metrics";
 pub const LOGS_TABLE_NAME: &str = "mlop_logs";
 pub const DATA_TABLE_NAME: &str = "mlop_data";
 pub const FILES_TABLE_NAME: &str = "mlop_files";
+pub const RUNS_TABLE_NAME: &str = "mlop_runs";
+
+// Records each tenant can queue for a background processor before its requests wait
+pub const TENANT_QUEUE_CAPACITY: usize = 1_000;
+// Records a background processor takes from one tenant before serving the next one
+pub const TENANT_QUEUE_QUANTUM: usize = 100;
+
+// How long ingest requests wait for space in their tenant's queue, unless configured otherwise
+pub const DEFAULT_INGEST_SEND_TIMEOUT: Duration = Duration::from_secs(5);
+// Records queued over all tenants at which ingest requests are shed, unless configured otherwise
+// A single tenant is already bounded by its own queue and the send timeout, so the mark is the
+// full queues of ten tenants: it bounds the memory of the channel when many tenants back up at once
+pub const DEFAULT_INGEST_HIGH_WATER_MARK: usize = 10 * TENANT_QUEUE_CAPACITY;
+
+// Retry-After of requests shed because a channel is saturated, about one flush interval
+pub const OVERLOADED_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+// Size limit of compressed request bodies after decompression, unless configured otherwise
+// Lines are buffered until their newline, so this also bounds the memory of a single request
+pub const DEFAULT_MAX_DECOMPRESSED_BYTES: u64 = 256 * 1024 * 1024;
+
+// Imports hand rows to ClickHouse (or the background processors) in batches of this many rows
+// or this many bytes of source records, whichever is reached first
+pub const IMPORT_BATCH_ROWS: usize = 100_000;
+pub const IMPORT_BATCH_BYTES: u64 = 64 * 1024 * 1024;
+// Columnar imports running at once, unless configured otherwise
+// Each holds its whole file in memory, up to the decompressed size limit
+pub const DEFAULT_IMPORT_MAX_CONCURRENT: usize = 4;
 
 // Configuration for the background flush behavior
 pub struct FlushConfig {
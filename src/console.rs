@@ -0,0 +1,413 @@
+use clickhouse::sql::Identifier;
+use clickhouse::{Client, Row};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::mem;
+use std::sync::Mutex;
+use tracing::error;
+
+use crate::config::LOGS_TABLE_NAME;
+use crate::error::{AppError, ErrorCode};
+use crate::heartbeat::RunKey;
+use crate::runs::now_millis;
+
+// Longest console line kept in a single log row, longer lines are split
+pub const MAX_CONSOLE_LINE_BYTES: usize = 64 * 1024;
+// Runs that sent no console output for this long are forgotten, their numbering is reloaded
+// from ClickHouse if they send more (by then all of their lines have been flushed)
+const LINE_NUMBERS_IDLE_MILLIS: u64 = 60 * 60 * 1000;
+
+// Postgres table holding the next line number of each run, see `ConsoleLineNumbers::shared`
+const CREATE_LINE_NUMBERS_TABLE: &str =
+    include_str!("../docker-setup/sql/postgres/console_lines.sql");
+
+// SQL query to reserve `$5` line numbers of a run, returns the first of them
+// A run numbered for the first time starts at `$4`, the numbering already stored in ClickHouse
+const RESERVE_LINE_NUMBERS_QUERY: &str = r#"
+    INSERT INTO "mlop_console_lines" ("tenantId", "projectName", "runId", "nextLineNumber")
+    VALUES ($1, $2, $3, $4 + $5)
+    ON CONFLICT ("tenantId", "projectName", "runId")
+    DO UPDATE SET "nextLineNumber" = "mlop_console_lines"."nextLineNumber" + $5
+    RETURNING "nextLineNumber" - $5"#;
+
+// Splits raw console output into the lines a terminal would show
+// Carriage returns overwrite the line (progress bars such as tqdm keep only their final state),
+// ANSI escape sequences (colors, cursor movement) are removed
+#[derive(Debug, Default)]
+pub struct ConsoleDecoder {
+    line: Vec<u8>,    // Bytes of the current line since its last carriage return
+    pending_cr: bool, // The previous chunk ended with `\r`, which may start a `\r\n`
+}
+
+impl ConsoleDecoder {
+    // Feeds the next chunk of output, returns the lines it completed
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        let mut lines = Vec::new();
+        for &byte in chunk {
+            if mem::take(&mut self.pending_cr) {
+                if byte == b'\n' {
+                    lines.push(self.take_line());
+                    continue;
+                }
+                // A lone carriage return moves back to the start of the line
+                self.line.clear();
+            }
+            match byte {
+                b'\n' => lines.push(self.take_line()),
+                b'\r' => self.pending_cr = true,
+                _ => {
+                    self.line.push(byte);
+                    if self.line.len() >= MAX_CONSOLE_LINE_BYTES {
+                        lines.push(self.split_line());
+                    }
+                }
+            }
+        }
+        lines
+    }
+
+    // Ends the output, returns its last line if it did not end with a newline
+    // A trailing carriage return keeps the line, as it is still shown until overwritten
+    pub fn finish(mut self) -> Option<String> {
+        if self.line.is_empty() {
+            return None;
+        }
+        Some(self.take_line())
+    }
+
+    fn take_line(&mut self) -> String {
+        String::from_utf8_lossy(&strip_ansi(&mem::take(&mut self.line))).into_owned()
+    }
+
+    // Cuts an overlong line, a character split by the limit starts the next line
+    fn split_line(&mut self) -> String {
+        let start = self
+            .line
+            .iter()
+            .rposition(|&byte| byte & 0xc0 != 0x80) // Start of the last character
+            .unwrap_or(0);
+        let end = if start > 0 && start + utf8_width(self.line[start]) > self.line.len() {
+            start
+        } else {
+            self.line.len()
+        };
+        let rest = self.line.split_off(end);
+        let line = self.take_line();
+        self.line = rest;
+        line
+    }
+}
+
+// Length of a UTF-8 character from its first byte
+fn utf8_width(first: u8) -> usize {
+    match first {
+        0xf0.. => 4,
+        0xe0.. => 3,
+        0xc0.. => 2,
+        _ => 1,
+    }
+}
+
+// Removes ANSI escape sequences: CSI (`ESC [ ... final`), OSC (`ESC ] ... BEL` or
+// `ESC ] ... ESC \`) and two-byte sequences such as `ESC 7`
+fn strip_ansi(line: &[u8]) -> Vec<u8> {
+    let mut stripped = Vec::with_capacity(line.len());
+    let mut i = 0;
+    while i < line.len() {
+        if line[i] != 0x1b {
+            stripped.push(line[i]);
+            i += 1;
+            continue;
+        }
+        match line.get(i + 1) {
+            Some(b'[') => {
+                i += 2;
+                // Parameter and intermediate bytes up to the final byte
+                while i < line.len() && !(0x40..=0x7e).contains(&line[i]) {
+                    i += 1;
+                }
+                i += 1;
+            }
+            Some(b']') => {
+                i += 2;
+                while i < line.len() {
+                    if line[i] == 0x07 {
+                        i += 1;
+                        break;
+                    }
+                    if line[i] == 0x1b && line.get(i + 1) == Some(&b'\\') {
+                        i += 2;
+                        break;
+                    }
+                    i += 1;
+                }
+            }
+            Some(_) => i += 2,
+            None => i += 1,
+        }
+    }
+    stripped
+}
+
+// Next console line number of a run and when it was last used
+#[derive(Debug, Clone, Copy)]
+struct LineCounter {
+    next: u64,
+    last_used: u64, // Milliseconds since the Unix epoch
+}
+
+// Assigns log line numbers that increase monotonically within each run, shared by the
+// stdout and stderr streams of the run's console output and its OTLP log records
+// Numbering continues after the highest line number already stored, so it survives restarts
+// With a database, numbers are allocated in Postgres and every instance may number any run
+// Without one, numbers are allocated per instance: all console output and OTLP logs of a run
+// must then reach the same instance (see "Console Ingest" in the README)
+#[derive(Default)]
+pub struct ConsoleLineNumbers {
+    // Next number of each run, or only when it was last used if numbers come from the database
+    counters: Mutex<HashMap<RunKey, LineCounter>>,
+    database: Option<PgPool>,
+}
+
+impl ConsoleLineNumbers {
+    // Allocates line numbers from Postgres, creating the table of line numbers if it is missing
+    pub async fn shared(pool: PgPool) -> Result<Self, AppError> {
+        sqlx::query(CREATE_LINE_NUMBERS_TABLE)
+            .execute(&pool)
+            .await
+            .map_err(|e| {
+                error!(error = %e, "Failed to create the console line numbers table");
+                AppError::new(
+                    ErrorCode::DatabaseError,
+                    format!("Failed to create the console line numbers table: {}", e),
+                )
+            })?;
+        Ok(Self {
+            counters: Mutex::new(HashMap::new()),
+            database: Some(pool),
+        })
+    }
+
+    // Reserves `count` consecutive line numbers for the run, returns the first of them
+    pub async fn reserve(
+        &self,
+        client: &Client,
+        key: &RunKey,
+        count: u64,
+    ) -> Result<u64, AppError> {
+        if let Some(pool) = &self.database {
+            return self.reserve_shared(pool, client, key, count).await;
+        }
+        if let Some(first) = self.try_reserve(key, count) {
+            return Ok(first);
+        }
+
+        let next = stored_next_line_number(client, key).await?;
+        Ok(self.load(key, next, count))
+    }
+
+    // Reserves the numbers in Postgres, where every instance reserves them
+    // The stored numbering is loaded once per run, the database ignores it if the run is
+    // already numbered there
+    async fn reserve_shared(
+        &self,
+        pool: &PgPool,
+        client: &Client,
+        key: &RunKey,
+        count: u64,
+    ) -> Result<u64, AppError> {
+        let start = match self.try_reserve(key, 0) {
+            Some(_) => 0,
+            None => stored_next_line_number(client, key).await?,
+        };
+        let first: i64 = sqlx::query_scalar(RESERVE_LINE_NUMBERS_QUERY)
+            .bind(&key.tenant_id)
+            .bind(&key.project_name)
+            .bind(key.run_id as i64)
+            .bind(start as i64)
+            .bind(count as i64)
+            .fetch_one(pool)
+            .await
+            .map_err(|e| {
+                error!(error = %e, "Failed to reserve console line numbers");
+                AppError::new(
+                    ErrorCode::DatabaseError,
+                    "Failed to reserve console line numbers",
+                )
+            })?;
+        self.load(key, 0, 0);
+        Ok(first as u64)
+    }
+
+    // Adds the run to the counters starting at `next`, then reserves `count` numbers
+    // Another request for the run may have loaded it meanwhile, idle runs are forgotten
+    fn load(&self, key: &RunKey, next: u64, count: u64) -> u64 {
+        let now = now_millis();
+        let mut counters = self.counters.lock().unwrap();
+        counters
+            .retain(|_, counter| now.saturating_sub(counter.last_used) < LINE_NUMBERS_IDLE_MILLIS);
+        let counter = counters.entry(key.clone()).or_insert(LineCounter {
+            next,
+            last_used: now,
+        });
+        let first = counter.next;
+        counter.next += count;
+        counter.last_used = now;
+        first
+    }
+
+    fn try_reserve(&self, key: &RunKey, count: u64) -> Option<u64> {
+        let mut counters = self.counters.lock().unwrap();
+        let counter = counters.get_mut(key)?;
+        let first = counter.next;
+        counter.next += count;
+        counter.last_used = now_millis();
+        Some(first)
+    }
+}
+
+// Logs already stored for a run
+#[derive(Debug, Row, Deserialize, Serialize)]
+pub(crate) struct StoredLines {
+    pub lines: u64,
+    pub max_line_number: u64,
+}
+
+// Line number following the highest one stored for the run, 0 if it has no logs yet
+async fn stored_next_line_number(client: &Client, key: &RunKey) -> Result<u64, AppError> {
+    let stored = client
+        .query(
+            "SELECT count() AS lines, max(lineNumber) AS max_line_number FROM ? \
+             WHERE tenantId = ? AND projectName = ? AND runId = ?",
+        )
+        .bind(Identifier(LOGS_TABLE_NAME))
+        .bind(&key.tenant_id)
+        .bind(&key.project_name)
+        .bind(key.run_id)
+        .fetch_one::<StoredLines>()
+        .await?;
+    Ok(if stored.lines > 0 {
+        stored.max_line_number + 1
+    } else {
+        0
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clickhouse::test::{handlers, Mock};
+    use clickhouse::Compression;
+
+    fn run(run_id: u64) -> RunKey {
+        RunKey {
+            tenant_id: "tenant".to_string(),
+            project_name: "project".to_string(),
+            run_id,
+        }
+    }
+
+    fn decode(chunks: &[&[u8]]) -> Vec<String> {
+        let mut decoder = ConsoleDecoder::default();
+        let mut lines: Vec<String> = chunks.iter().flat_map(|c| decoder.push(c)).collect();
+        lines.extend(decoder.finish());
+        lines
+    }
+
+    #[test]
+    fn test_carriage_returns_keep_final_progress() {
+        let lines = decode(&[
+            b"epoch 1\n\r 10%|#  |\r 50%|##",
+            b"# |\r100%|####|\n",
+            b"done\r\nwindows\r",
+            b"\nlast",
+        ]);
+        assert_eq!(lines, ["epoch 1", "100%|####|", "done", "windows", "last"]);
+    }
+
+    #[test]
+    fn test_ansi_escapes_are_stripped() {
+        let lines = decode(&[
+            b"\x1b[31mred\x1b[0m \x1b[1;32mbold\x1b[0m\n",
+            b"\x1b]0;title\x07plain\x1b]8;;http://x\x1b\\link\x1b]8;;\x1b\\\n",
+            b"\x1b[2K\x1b[Aup\x1b7\n",
+        ]);
+        assert_eq!(lines, ["red bold", "plainlink", "up"]);
+    }
+
+    #[test]
+    fn test_long_lines_are_split_at_char_boundaries() {
+        let mut line = "a".repeat(MAX_CONSOLE_LINE_BYTES - 1);
+        line.push('é');
+        line.push_str("bc\n");
+        let lines = decode(&[line.as_bytes()]);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], "a".repeat(MAX_CONSOLE_LINE_BYTES - 1));
+        assert_eq!(lines[1], "ébc");
+    }
+
+    #[tokio::test]
+    async fn test_numbering_is_loaded_from_stored_logs() {
+        let mock = Mock::new();
+        let stored = |lines, max_line_number| {
+            handlers::provide(vec![StoredLines {
+                lines,
+                max_line_number,
+            }])
+        };
+        mock.add(stored(3, 41));
+        mock.add(stored(0, 0));
+        mock.add(stored(0, 0));
+        mock.add(stored(3, 44));
+        let client = Client::default()
+            .with_url(mock.url())
+            .with_compression(Compression::None);
+        let numbers = ConsoleLineNumbers::default();
+
+        assert_eq!(numbers.reserve(&client, &run(1), 2).await.unwrap(), 42);
+        // Loaded once, later reservations continue in memory
+        assert_eq!(numbers.reserve(&client, &run(1), 1).await.unwrap(), 44);
+        // Runs without logs start at 0
+        assert_eq!(numbers.reserve(&client, &run(2), 5).await.unwrap(), 0);
+
+        // Idle runs are forgotten when another run is loaded, and reloaded when they send more
+        numbers
+            .counters
+            .lock()
+            .unwrap()
+            .get_mut(&run(1))
+            .unwrap()
+            .last_used = 0;
+        assert_eq!(numbers.reserve(&client, &run(3), 1).await.unwrap(), 0);
+        assert_eq!(numbers.reserve(&client, &run(1), 1).await.unwrap(), 45);
+    }
+
+    // Needs a Postgres database, skipped unless TEST_DATABASE_URL is set
+    #[tokio::test]
+    async fn test_instances_share_the_numbering_of_a_run() {
+        let Ok(database_url) = std::env::var("TEST_DATABASE_URL") else {
+            return;
+        };
+        let pool = PgPool::connect(&database_url).await.unwrap();
+        let mock = Mock::new();
+        for _ in 0..2 {
+            mock.add(handlers::provide(vec![StoredLines {
+                lines: 3,
+                max_line_number: 41,
+            }]));
+        }
+        let client = Client::default()
+            .with_url(mock.url())
+            .with_compression(Compression::None);
+        let first = ConsoleLineNumbers::shared(pool.clone()).await.unwrap();
+        let second = ConsoleLineNumbers::shared(pool).await.unwrap();
+        let run = run(now_millis());
+
+        // Each instance loads the stored numbering once, the database keeps the first one
+        assert_eq!(first.reserve(&client, &run, 2).await.unwrap(), 42);
+        assert_eq!(second.reserve(&client, &run, 3).await.unwrap(), 44);
+        assert_eq!(first.reserve(&client, &run, 1).await.unwrap(), 47);
+        assert_eq!(second.reserve(&client, &run, 1).await.unwrap(), 48);
+    }
+}
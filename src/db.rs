@@ -1,14 +1,25 @@
+use axum::http::HeaderMap;
 use chrono::{DateTime, Utc};
-use sha2::{Digest, Sha256};
-use sqlx::postgres::{PgPool, PgPoolOptions};
+use futures::future::BoxFuture;
+use sqlx::postgres::{PgListener, PgPool, PgPoolOptions};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use tracing::{error, info, instrument, warn};
+use tokio::sync::watch;
+use tracing::{debug, error, info, instrument, warn};
 
+use crate::auth::{bearer_token, hash_api_key, ApiKeyGrant, AuthProvider};
+use crate::config::Config;
 use crate::error::{AppError, ErrorCode};
+use crate::key_cache::{ApiKeyCache, CachedKey};
+use crate::telemetry::AUTH_CACHE_REQUESTS;
 
 // Wrapper struct for the PostgreSQL connection pool
 pub struct Database {
-    pool: PgPool, // SQLx connection pool
+    pool: PgPool,                                             // SQLx connection pool
+    get_api_key_query: &'static str,                          // Matches the columns `api_key` has
+    key_cache: ApiKeyCache, // Recent API key lookups, to avoid a query per request
+    pending_last_used: Mutex<HashMap<String, DateTime<Utc>>>, // Key ID -> last successful auth, not yet written
 }
 
 // Represents an API key row fetched from the database
@@ -21,23 +32,50 @@ pub struct ApiKey {
     pub expires_at: Option<DateTime<Utc>>, // Optional expiration timestamp
     pub last_used: Option<DateTime<Utc>>, // Optional last used timestamp
     pub created_at: DateTime<Utc>, // Creation timestamp
+    pub scopes: Option<Vec<String>>, // Granted scopes, NULL grants all scopes
+    pub allowed_projects: Option<Vec<String>>, // Projects the key may access, NULL allows all
 }
 
 // SQL query to fetch an API key by its hashed value
 const GET_API_KEY_QUERY: &str = r#"
     SELECT id, "organizationId" as organization_id, "key" as key, 
            "expiresAt"::timestamptz as expires_at, "lastUsed"::timestamptz as last_used,
-           "createdAt"::timestamptz as created_at
+           "createdAt"::timestamptz as created_at,
+           "scopes" as scopes, "allowedProjects" as allowed_projects
     FROM "api_key" 
     WHERE "key" = $1"#; // Parameter $1 is the hashed key
 
+// Same query for an `api_key` table without the permission columns, every key is unrestricted
+// The columns are added by docker-setup/sql/postgres/api_key_permissions.sql
+const GET_UNRESTRICTED_API_KEY_QUERY: &str = r#"
+    SELECT id, "organizationId" as organization_id, "key" as key, 
+           "expiresAt"::timestamptz as expires_at, "lastUsed"::timestamptz as last_used,
+           "createdAt"::timestamptz as created_at,
+           NULL::text[] as scopes, NULL::text[] as allowed_projects
+    FROM "api_key" 
+    WHERE "key" = $1"#;
+
+// SQL query to count the permission columns of the `api_key` table visible on the search path
+const PERMISSION_COLUMNS_QUERY: &str = r#"
+    SELECT count(*) FROM information_schema.columns
+    WHERE table_schema = ANY(current_schemas(false)) AND table_name = 'api_key'
+      AND column_name IN ('scopes', 'allowedProjects')"#;
+
+// SQL query to set the last used timestamp of many API keys at once
+// Never moves "lastUsed" backwards, e.g. if another instance wrote a later time
+const UPDATE_LAST_USED_QUERY: &str = r#"
+    UPDATE "api_key" SET "lastUsed" = used."lastUsed"
+    FROM UNNEST($1::text[], $2::timestamptz[]) AS used(id, "lastUsed")
+    WHERE "api_key".id = used.id
+      AND ("api_key"."lastUsed" IS NULL OR "api_key"."lastUsed" < used."lastUsed")"#;
+
 impl Database {
     // Establishes a connection pool to the PostgreSQL database
-    #[instrument(skip(database_url))]
-    pub async fn connect(database_url: &str) -> Result<Self, AppError> {
+    #[instrument(skip(database_url, config))]
+    pub async fn connect(database_url: &str, config: &Config) -> Result<Self, AppError> {
         info!("Attempting to connect to the database");
         let pool = PgPoolOptions::new()
-            .max_connections(5) // Configure max number of connections
+            .max_connections(config.database_max_connections) // Configure max number of connections
             .acquire_timeout(Duration::from_secs(3)) // Configure connection acquire timeout
             .connect(database_url) // Connect using the provided URL
             .await
@@ -49,68 +87,397 @@ impl Database {
             })?;
 
         info!("Successfully connected to the database");
-        Ok(Self { pool })
-    }
 
-    // Hashes an API key using SHA256
-    // Skips hashing if the key already starts with "mlpi_" (assumed pre-hashed or special format)
-    // This prefix is used internally to identify keys that might have a different hashing mechanism or origin.
-    fn hash_api_key(api_key: &str) -> String {
-        if api_key.starts_with("mlpi_") {
-            // Assume keys starting with "mlpi_" are already hashed or special
-            api_key.to_string()
+        // Keys are unrestricted until the permission columns are added
+        let permission_columns: i64 = sqlx::query_scalar(PERMISSION_COLUMNS_QUERY)
+            .fetch_one(&pool)
+            .await
+            .map_err(|e| {
+                error!(error = %e, "Failed to read the columns of api_key");
+                AppError::new(
+                    ErrorCode::DatabaseError,
+                    "Failed to read the columns of api_key",
+                )
+            })?;
+        let get_api_key_query = if permission_columns == 2 {
+            GET_API_KEY_QUERY
         } else {
-            // Hash other keys using SHA256
-            let mut hasher = Sha256::new();
-            hasher.update(api_key.as_bytes());
-            // Return the hex-encoded hash
-            format!("{:x}", hasher.finalize())
+            warn!("api_key has no scopes and allowedProjects columns, API keys are unrestricted");
+            GET_UNRESTRICTED_API_KEY_QUERY
+        };
+
+        Ok(Self {
+            pool,
+            get_api_key_query,
+            key_cache: ApiKeyCache::new(
+                config.api_key_cache_ttl,
+                config.api_key_negative_cache_ttl,
+                config.api_key_cache_max_entries,
+            ),
+            pending_last_used: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Connection pool, also used to number console lines
+    pub fn pool(&self) -> &PgPool {
+        &self.pool
+    }
+
+    // Periodically writes the last used time of keys that authenticated since the previous write
+    // Coalesces all uses of a key within `interval` into a single update, off the request path
+    pub async fn write_last_used(self: Arc<Self>, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            self.flush_last_used().await;
+        }
+    }
+
+    // Writes the pending last used times in one update, and once more on shutdown
+    // Times that could not be written stay pending for the next flush
+    pub async fn flush_last_used(&self) {
+        let pending = std::mem::take(&mut *self.pending_last_used.lock().unwrap());
+        if pending.is_empty() {
+            return;
+        }
+        let (ids, times): (Vec<String>, Vec<DateTime<Utc>>) = pending.into_iter().unzip();
+
+        let result = sqlx::query(UPDATE_LAST_USED_QUERY)
+            .bind(&ids)
+            .bind(&times)
+            .execute(&self.pool)
+            .await;
+        match result {
+            Ok(done) => debug!(
+                keys = ids.len(),
+                updated = done.rows_affected(),
+                "API key last used times written"
+            ),
+            Err(e) => {
+                warn!(error = %e, keys = ids.len(), "Failed to write API key last used times, retrying later");
+                // Put the times back unless a newer use was recorded meanwhile
+                let mut pending_last_used = self.pending_last_used.lock().unwrap();
+                for (id, time) in ids.into_iter().zip(times) {
+                    pending_last_used.entry(id).or_insert(time);
+                }
+            }
         }
     }
 
-    // Retrieves the tenant ID associated with a given API key
-    // Hashes the key and queries the database
+    // Listens for Postgres notifications on `channel` and drops revoked keys from the cache
+    // The payload is the hashed key, an empty payload invalidates every cached key
+    pub async fn listen_for_key_changes(self: Arc<Self>, channel: String) {
+        loop {
+            let mut listener = match PgListener::connect_with(&self.pool).await {
+                Ok(listener) => listener,
+                Err(e) => {
+                    error!(error = %e, "Failed to connect API key change listener, retrying");
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    continue;
+                }
+            };
+            if let Err(e) = listener.listen(&channel).await {
+                error!(error = %e, channel = %channel, "Failed to listen for API key changes, retrying");
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                continue;
+            }
+            info!(channel = %channel, "Listening for API key changes");
+
+            loop {
+                match listener.try_recv().await {
+                    Ok(Some(notification)) => match notification.payload() {
+                        "" => {
+                            let count = self.invalidate_all_api_keys();
+                            info!(count, "All cached API keys invalidated by notification");
+                        }
+                        hashed_key => {
+                            self.invalidate_api_key(hashed_key);
+                            debug!("Cached API key invalidated by notification");
+                        }
+                    },
+                    // The connection was lost and notifications may have been missed
+                    Ok(None) => {
+                        warn!("API key change listener disconnected, invalidating all cached keys");
+                        self.invalidate_all_api_keys();
+                    }
+                    Err(e) => {
+                        error!(error = %e, "API key change listener failed, reconnecting");
+                        self.invalidate_all_api_keys();
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    // Retrieves the tenant and permissions associated with a given API key
+    // Hashes the key and looks it up in the cache, falling back to the database
     #[instrument(skip(self, api_key))]
-    pub async fn get_tenant_by_api_key(&self, api_key: &str) -> Result<String, AppError> {
+    pub async fn get_api_key_grant(&self, api_key: &str) -> Result<ApiKeyGrant, AppError> {
         // Hash the provided API key
-        let hashed_key = Self::hash_api_key(api_key);
+        let hashed_key = hash_api_key(api_key);
+
+        let cached = match self.key_cache.get(&hashed_key) {
+            Some(cached) => {
+                AUTH_CACHE_REQUESTS.with_label_values(&["hit"]).inc();
+                cached
+            }
+            None => {
+                AUTH_CACHE_REQUESTS.with_label_values(&["miss"]).inc();
+                let cached = self.fetch_api_key(&hashed_key).await?;
+                self.key_cache.insert(hashed_key, cached.clone());
+                cached
+            }
+        };
+
+        let grant = match cached {
+            CachedKey::Found(grant) => grant,
+            CachedKey::NotFound => {
+                warn!("API key not found in database");
+                return Err(AppError::new(ErrorCode::InvalidToken, "Invalid API key"));
+            }
+        };
+
+        // Check if the key has expired (also for cached keys)
+        grant.check_expiry()?;
+
+        // Record the use, it is written to the database in the background
+        self.pending_last_used
+            .lock()
+            .unwrap()
+            .insert(grant.key_id.clone(), Utc::now());
+
+        // Return the organization ID (tenant ID) and permissions associated with the valid key
+        Ok(grant)
+    }
 
+    // Queries the database for a hashed API key
+    async fn fetch_api_key(&self, hashed_key: &str) -> Result<CachedKey, AppError> {
         // Execute the prepared query to find the key
-        let api_key_result = sqlx::query_as::<_, ApiKey>(GET_API_KEY_QUERY)
+        let api_key_result = sqlx::query_as::<_, ApiKey>(self.get_api_key_query)
             .persistent(true) // Keep the prepared statement cached
             .bind(hashed_key) // Bind the hashed key to the query parameter
             .fetch_optional(&self.pool) // Expect zero or one result
             .await;
 
-        let api_key = match api_key_result {
-            Ok(Some(key)) => key,
-            Ok(None) => {
-                warn!("API key not found in database");
-                return Err(AppError::new(ErrorCode::InvalidToken, "Invalid API key"));
-            }
+        match api_key_result {
+            Ok(Some(key)) => Ok(CachedKey::Found(ApiKeyGrant {
+                key_id: key.id,
+                tenant_id: key.organization_id,
+                expires_at: key.expires_at,
+                scopes: key.scopes,
+                allowed_projects: key.allowed_projects,
+                project_name: None,
+                run_id: None,
+            })),
+            Ok(None) => Ok(CachedKey::NotFound),
             Err(e) => {
                 // Log the original database error
                 error!(error = %e, "Database error while fetching API key");
                 // Return a generic error to the client
-                return Err(AppError::new(
+                Err(AppError::new(
                     ErrorCode::DatabaseError,
                     "Failed to validate API key",
-                ));
+                ))
             }
+        }
+    }
+}
+
+// Authenticates requests against the `api_key` table
+impl AuthProvider for Database {
+    fn authenticate<'a>(
+        &'a self,
+        headers: &'a HeaderMap,
+    ) -> BoxFuture<'a, Result<ApiKeyGrant, AppError>> {
+        Box::pin(async move {
+            let token = bearer_token(headers)?;
+            self.get_api_key_grant(token).await
+        })
+    }
+
+    // Checks that a connection can be acquired and used
+    fn ping(&self) -> BoxFuture<'_, Result<(), AppError>> {
+        Box::pin(async move {
+            sqlx::query("SELECT 1")
+                .execute(&self.pool)
+                .await
+                .map_err(|e| {
+                    AppError::new(
+                        ErrorCode::DatabaseUnavailable,
+                        format!("Database ping failed: {}", e),
+                    )
+                })?;
+            Ok(())
+        })
+    }
+
+    fn invalidate_api_key(&self, hashed_key: &str) -> bool {
+        self.key_cache.invalidate(hashed_key)
+    }
+
+    fn invalidate_all_api_keys(&self) -> usize {
+        self.key_cache.invalidate_all()
+    }
+
+    fn key_invalidations(&self) -> Option<watch::Receiver<u64>> {
+        Some(self.key_cache.invalidations())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes::testing::test_config;
+    use sqlx::Executor;
+
+    // URL of an empty schema with an `api_key` table, None unless TEST_DATABASE_URL is set
+    // Each test uses its own schema, so tests can run concurrently
+    async fn test_database_url(schema: &str) -> Option<String> {
+        let database_url = std::env::var("TEST_DATABASE_URL").ok()?;
+        let pool = PgPool::connect(&database_url).await.unwrap();
+        pool.execute(
+            format!(
+                r#"DROP SCHEMA IF EXISTS "{schema}" CASCADE;
+                CREATE SCHEMA "{schema}";
+                CREATE TABLE "{schema}"."api_key" (
+                    id text PRIMARY KEY,
+                    "organizationId" text NOT NULL,
+                    "key" text NOT NULL,
+                    "expiresAt" timestamp(3),
+                    "lastUsed" timestamp(3),
+                    "createdAt" timestamp(3) NOT NULL DEFAULT now()
+                );"#
+            )
+            .as_str(),
+        )
+        .await
+        .unwrap();
+        let separator = if database_url.contains('?') { '&' } else { '?' };
+        Some(format!(
+            "{database_url}{separator}options=-c%20search_path%3D{schema}"
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_keys_are_unrestricted_without_permission_columns() {
+        let Some(database_url) = test_database_url("test_permission_columns").await else {
+            return;
         };
+        let pool = PgPool::connect(&database_url).await.unwrap();
+        sqlx::query(
+            r#"INSERT INTO "api_key" (id, "organizationId", "key") VALUES ('1', 'org', $1)"#,
+        )
+        .bind(hash_api_key("secret"))
+        .execute(&pool)
+        .await
+        .unwrap();
 
-        // Check if the key has expired
-        if let Some(expires_at) = api_key.expires_at {
-            if expires_at < Utc::now() {
-                warn!(key_id = %api_key.id, tenant_id = %api_key.organization_id, expiry = %expires_at, "API key has expired");
-                return Err(AppError::new(
-                    ErrorCode::InvalidToken,
-                    "API key has expired",
-                ));
-            }
+        let database = Database::connect(&database_url, &test_config())
+            .await
+            .unwrap();
+        let grant = database.get_api_key_grant("secret").await.unwrap();
+        assert_eq!(grant.tenant_id, "org");
+        assert_eq!(grant.scopes, None);
+
+        // Once the columns are added, a new connection reads them
+        pool.execute(include_str!(
+            "../docker-setup/sql/postgres/api_key_permissions.sql"
+        ))
+        .await
+        .unwrap();
+        sqlx::query(r#"UPDATE "api_key" SET "scopes" = ARRAY['ingest:logs']"#)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let database = Database::connect(&database_url, &test_config())
+            .await
+            .unwrap();
+        let grant = database.get_api_key_grant("secret").await.unwrap();
+        assert_eq!(grant.scopes, Some(vec!["ingest:logs".to_string()]));
+    }
+
+    // Last used times of the keys in the database, in milliseconds
+    async fn last_used(pool: &PgPool) -> Vec<(String, Option<i64>)> {
+        let rows: Vec<(String, Option<DateTime<Utc>>)> =
+            sqlx::query_as(r#"SELECT id, "lastUsed"::timestamptz FROM "api_key" ORDER BY id"#)
+                .fetch_all(pool)
+                .await
+                .unwrap();
+        rows.into_iter()
+            .map(|(id, time)| (id, time.map(|t| t.timestamp_millis())))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_uses_of_a_key_are_written_once() {
+        let Some(database_url) = test_database_url("test_last_used_coalesced").await else {
+            return;
+        };
+        let pool = PgPool::connect(&database_url).await.unwrap();
+        for (id, key) in [("1", "first"), ("2", "second"), ("3", "unused")] {
+            sqlx::query(
+                r#"INSERT INTO "api_key" (id, "organizationId", "key") VALUES ($1, 'org', $2)"#,
+            )
+            .bind(id)
+            .bind(hash_api_key(key))
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        let database = Database::connect(&database_url, &test_config())
+            .await
+            .unwrap();
+        for key in ["first", "second", "first", "first"] {
+            database.get_api_key_grant(key).await.unwrap();
+        }
+        // Only the last use of each key is pending
+        let pending = database.pending_last_used.lock().unwrap().clone();
+        assert_eq!(pending.len(), 2);
+
+        database.flush_last_used().await;
+        assert!(database.pending_last_used.lock().unwrap().is_empty());
+        let written = last_used(&pool).await;
+        for (id, time) in &written[..2] {
+            // Postgres rounds to milliseconds
+            let used = pending[id].timestamp_millis();
+            assert!(time.is_some_and(|t| t.abs_diff(used) <= 1), "{}", id);
         }
+        assert_eq!(written[2], ("3".to_string(), None));
+    }
 
-        // Return the organization ID (tenant ID) associated with the valid key
-        Ok(api_key.organization_id.to_string())
+    #[tokio::test]
+    async fn test_unwritten_uses_are_kept_for_the_shutdown_flush() {
+        let Some(database_url) = test_database_url("test_last_used_shutdown").await else {
+            return;
+        };
+        let pool = PgPool::connect(&database_url).await.unwrap();
+        sqlx::query(
+            r#"INSERT INTO "api_key" (id, "organizationId", "key") VALUES ('1', 'org', $1)"#,
+        )
+        .bind(hash_api_key("secret"))
+        .execute(&pool)
+        .await
+        .unwrap();
+        let database = Database::connect(&database_url, &test_config())
+            .await
+            .unwrap();
+        database.get_api_key_grant("secret").await.unwrap();
+
+        // A failed write keeps the use pending
+        pool.execute(r#"ALTER TABLE "api_key" RENAME TO "api_key_moved""#)
+            .await
+            .unwrap();
+        database.flush_last_used().await;
+        assert_eq!(database.pending_last_used.lock().unwrap().len(), 1);
+
+        // The flush on shutdown writes it
+        pool.execute(r#"ALTER TABLE "api_key_moved" RENAME TO "api_key""#)
+            .await
+            .unwrap();
+        database.flush_last_used().await;
+        assert!(database.pending_last_used.lock().unwrap().is_empty());
+        assert!(last_used(&pool).await[0].1.is_some());
     }
 }
@@ -1,10 +1,14 @@
 use axum::{
-    http::StatusCode,
+    http::{header, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
 use serde::{Deserialize, Serialize};
 use std::fmt;
+use std::time::Duration;
+
+use crate::config::OVERLOADED_RETRY_AFTER;
+use crate::processors::fair_queue::SendError;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ErrorResponse {
@@ -36,6 +40,9 @@ pub enum ErrorCode {
     InvalidLogFormat = 2006,
     InvalidTimestamp = 2007,
     InvalidStepValue = 2008,
+    DuplicateRequest = 2009,
+    InvalidStatusTransition = 2010,
+    RunNotFound = 2011,
 
     // Processing Errors (3xxx)
     ProcessingFailed = 3001,
@@ -76,7 +83,7 @@ impl ErrorCode {
             ErrorCode::InvalidBearerFormat => StatusCode::UNAUTHORIZED,
             ErrorCode::InsufficientPermissions => StatusCode::FORBIDDEN,
 
-            // Input validation -> 400
+            // Input validation -> 400 (404 for unknown runs, 409 for conflicts)
             ErrorCode::InvalidInput => StatusCode::BAD_REQUEST,
             ErrorCode::MissingRequiredField => StatusCode::BAD_REQUEST,
             ErrorCode::InvalidJsonFormat => StatusCode::BAD_REQUEST,
@@ -85,6 +92,9 @@ impl ErrorCode {
             ErrorCode::InvalidLogFormat => StatusCode::BAD_REQUEST,
             ErrorCode::InvalidTimestamp => StatusCode::BAD_REQUEST,
             ErrorCode::InvalidStepValue => StatusCode::BAD_REQUEST,
+            ErrorCode::DuplicateRequest => StatusCode::CONFLICT,
+            ErrorCode::InvalidStatusTransition => StatusCode::CONFLICT,
+            ErrorCode::RunNotFound => StatusCode::NOT_FOUND,
 
             // Processing errors -> 422
             ErrorCode::ProcessingFailed => StatusCode::UNPROCESSABLE_ENTITY,
@@ -132,11 +142,12 @@ impl fmt::Display for ErrorCode {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct AppError {
     pub code: ErrorCode,
     pub message: String,
     pub details: Option<serde_json::Value>,
+    pub retry_after: Option<Duration>, // Sent as the Retry-After header
 }
 
 impl fmt::Display for AppError {
@@ -158,10 +169,10 @@ impl AppError {
             code,
             message: message.into(),
             details: None,
+            retry_after: None,
         }
     }
 
-    #[allow(dead_code)]
     pub fn with_details(
         code: ErrorCode,
         message: impl Into<String>,
@@ -171,8 +182,15 @@ impl AppError {
             code,
             message: message.into(),
             details: Some(details),
+            retry_after: None,
         }
     }
+
+    // Tells the client when to retry, e.g. after a rate limit
+    pub fn with_retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = Some(retry_after);
+        self
+    }
 }
 
 impl IntoResponse for AppError {
@@ -184,7 +202,19 @@ impl IntoResponse for AppError {
             details: self.details,
         };
 
-        (status, Json(body)).into_response()
+        match self.retry_after {
+            // Round up so clients never retry too early
+            Some(retry_after) => {
+                let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                (
+                    status,
+                    [(header::RETRY_AFTER, seconds.max(1).to_string())],
+                    Json(body),
+                )
+                    .into_response()
+            }
+            None => (status, Json(body)).into_response(),
+        }
     }
 }
 
@@ -213,6 +243,7 @@ impl From<StatusCode> for AppError {
             StatusCode::UNAUTHORIZED => ErrorCode::AuthenticationFailed,
             StatusCode::FORBIDDEN => ErrorCode::InsufficientPermissions,
             StatusCode::BAD_REQUEST => ErrorCode::InvalidInput,
+            StatusCode::CONFLICT => ErrorCode::DuplicateRequest,
             StatusCode::UNPROCESSABLE_ENTITY => ErrorCode::ProcessingFailed,
             StatusCode::TOO_MANY_REQUESTS => ErrorCode::RateLimitExceeded,
             StatusCode::SERVICE_UNAVAILABLE => ErrorCode::ServiceUnavailable,
@@ -224,6 +255,21 @@ impl From<StatusCode> for AppError {
     }
 }
 
+// A saturated background processor channel sheds the request with 503 and Retry-After
+impl<T> From<SendError<T>> for AppError {
+    fn from(err: SendError<T>) -> Self {
+        if err.is_overloaded() {
+            AppError::new(
+                ErrorCode::ServiceOverloaded,
+                format!("Ingest pipeline is overloaded: {}", err),
+            )
+            .with_retry_after(OVERLOADED_RETRY_AFTER)
+        } else {
+            AppError::new(ErrorCode::InternalError, err.to_string())
+        }
+    }
+}
+
 pub fn missing_header_error(header_name: &str) -> AppError {
     AppError::new(
         ErrorCode::InvalidHeaderFormat,
@@ -0,0 +1,252 @@
+use clickhouse::sql::Identifier;
+use clickhouse::Client;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+use crate::config::RUNS_TABLE_NAME;
+use crate::error::AppError;
+use crate::models::status::{RunRow, StatusCode};
+use crate::runs::now_millis;
+
+// How often last-seen times are persisted and stale runs are looked for
+const SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+
+// Identifies a run across tenants and projects
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RunKey {
+    pub tenant_id: String,
+    pub project_name: String,
+    pub run_id: u64,
+}
+
+// Last-seen times of runs that sent data or heartbeats since the previous sweep
+// Kept in memory and persisted to `mlop_runs` by the sweeper, so every ingest call stays cheap
+#[derive(Default)]
+pub struct HeartbeatTracker {
+    seen: Mutex<HashMap<RunKey, u64>>, // Milliseconds since the Unix epoch
+}
+
+impl HeartbeatTracker {
+    // Records that the run is alive, returns the recorded time
+    // Called for heartbeats and every ingest request, as any data sent for a run shows it is alive
+    pub fn record(&self, key: RunKey) -> u64 {
+        let now = now_millis();
+        self.seen.lock().unwrap().insert(key, now);
+        now
+    }
+
+    // Time the run was seen by this instance since the last sweep, if at all
+    pub fn last_seen(&self, key: &RunKey) -> Option<u64> {
+        self.seen.lock().unwrap().get(key).copied()
+    }
+
+    fn take_seen(&self) -> HashMap<RunKey, u64> {
+        std::mem::take(&mut *self.seen.lock().unwrap())
+    }
+}
+
+// Periodically persists last-seen times and marks runs that went silent as CRASHED
+// Runs are only marked once no instance has persisted a last-seen time within `crash_timeout`
+pub async fn start_crash_sweeper(
+    client: Client,
+    tracker: Arc<HeartbeatTracker>,
+    crash_timeout: Duration,
+) {
+    info!(
+        crash_timeout_secs = crash_timeout.as_secs(),
+        "Crash sweeper started"
+    );
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let seen = tracker.take_seen();
+        if let Err(e) = persist_last_seen(&client, &seen).await {
+            warn!(runs = seen.len(), error = %e, "Failed to persist run heartbeats");
+            // Keep the heartbeats for the next sweep unless newer ones arrived meanwhile
+            let mut pending = tracker.seen.lock().unwrap();
+            for (key, last_seen) in seen {
+                pending.entry(key).or_insert(last_seen);
+            }
+        }
+
+        let cutoff = now_millis().saturating_sub(crash_timeout.as_millis() as u64);
+        if let Err(e) = mark_crashed_runs(&client, cutoff).await {
+            error!(error = %e, "Failed to mark silent runs as crashed");
+        }
+    }
+}
+
+// Writes newer last-seen times for active runs, with one query and one insert for all of them
+// A run previously marked as CRASHED that reports again is moved back to RUNNING
+async fn persist_last_seen(client: &Client, seen: &HashMap<RunKey, u64>) -> Result<(), AppError> {
+    if seen.is_empty() {
+        return Ok(());
+    }
+    let keys: Vec<(&str, &str, u64)> = seen
+        .keys()
+        .map(|key| {
+            (
+                key.tenant_id.as_str(),
+                key.project_name.as_str(),
+                key.run_id,
+            )
+        })
+        .collect();
+    // Runs that were never created through /status are not tracked
+    let runs = client
+        .query("SELECT ?fields FROM ? FINAL WHERE has(?, (tenantId, projectName, runId))")
+        .bind(Identifier(RUNS_TABLE_NAME))
+        .bind(keys)
+        .fetch_all::<RunRow>()
+        .await?;
+
+    let mut updated = Vec::new();
+    for mut run in runs {
+        let key = RunKey {
+            tenant_id: run.tenant_id.clone(),
+            project_name: run.project_name.clone(),
+            run_id: run.run_id,
+        };
+        let Some(&last_seen) = seen.get(&key) else {
+            continue;
+        };
+        if last_seen <= run.last_seen_at {
+            continue;
+        }
+
+        match StatusCode::parse(&run.status) {
+            Some(StatusCode::Init | StatusCode::Running) => {}
+            Some(StatusCode::Crashed) => {
+                info!(
+                    run_id = run.run_id,
+                    "Crashed run reported again, marking as running"
+                );
+                run.status = StatusCode::Running.as_str().to_string();
+                run.ended_at = None;
+            }
+            // Finished runs keep their final state
+            _ => continue,
+        }
+        run.last_seen_at = last_seen;
+        updated.push(run);
+    }
+    write_sweeper_versions(client, updated).await
+}
+
+// Marks every INIT/RUNNING run not seen since `cutoff` as CRASHED
+async fn mark_crashed_runs(client: &Client, cutoff: u64) -> Result<(), AppError> {
+    let stale = client
+        .query(
+            "SELECT ?fields FROM ? FINAL \
+             WHERE status IN ('INIT', 'RUNNING') AND lastSeenAt < fromUnixTimestamp64Milli(toInt64(?))",
+        )
+        .bind(Identifier(RUNS_TABLE_NAME))
+        .bind(cutoff)
+        .fetch_all::<RunRow>()
+        .await?;
+
+    let crashed = stale
+        .into_iter()
+        .map(|mut run| {
+            warn!(
+                run_id = run.run_id,
+                project = %run.project_name,
+                last_seen_at = run.last_seen_at,
+                "Run went silent, marking as crashed"
+            );
+            run.status = StatusCode::Crashed.as_str().to_string();
+            run.ended_at = Some(run.last_seen_at);
+            run
+        })
+        .collect();
+    write_sweeper_versions(client, crashed).await
+}
+
+// Writes versions that lose against any concurrent change made through /status
+// (those use the current time as version, which is always ahead of the previous version)
+// All runs go into one insert, so a sweep costs the same however many runs it touches
+async fn write_sweeper_versions(client: &Client, runs: Vec<RunRow>) -> Result<(), AppError> {
+    if runs.is_empty() {
+        return Ok(());
+    }
+    let mut insert = client.insert(RUNS_TABLE_NAME)?;
+    for mut run in runs {
+        run.updated_at += 1;
+        insert.write(&run).await?;
+    }
+    insert.end().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clickhouse::test::{handlers, Mock};
+    use clickhouse::Compression;
+
+    fn key(run_id: u64) -> RunKey {
+        RunKey {
+            tenant_id: "tenant".to_string(),
+            project_name: "project".to_string(),
+            run_id,
+        }
+    }
+
+    fn run(run_id: u64, status: StatusCode, last_seen_at: u64) -> RunRow {
+        RunRow {
+            tenant_id: "tenant".to_string(),
+            project_name: "project".to_string(),
+            run_id,
+            run_name: format!("run-{}", run_id),
+            status: status.as_str().to_string(),
+            exit_code: None,
+            config: "{}".to_string(),
+            metadata: "{}".to_string(),
+            created_at: 0,
+            ended_at: (status == StatusCode::Crashed).then_some(last_seen_at),
+            last_seen_at,
+            updated_at: 10,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_last_seen_times_are_persisted_in_one_insert() {
+        let mock = Mock::new();
+        mock.add(handlers::provide(vec![
+            run(1, StatusCode::Running, 100),
+            run(2, StatusCode::Crashed, 100),
+            run(3, StatusCode::Completed, 100),
+            run(4, StatusCode::Running, 900), // Already persisted by another instance
+        ]));
+        let recording = mock.add(handlers::record::<RunRow>());
+        let client = Client::default()
+            .with_url(mock.url())
+            .with_compression(Compression::None);
+
+        // Run 5 was never created through /status
+        let seen = (1..=5).map(|run_id| (key(run_id), 500)).collect();
+        persist_last_seen(&client, &seen).await.unwrap();
+
+        let mut written: Vec<RunRow> = recording.collect().await;
+        written.sort_by_key(|run| run.run_id);
+        let written: Vec<_> = written
+            .iter()
+            .map(|run| {
+                (
+                    run.run_id,
+                    run.status.as_str(),
+                    run.last_seen_at,
+                    run.ended_at,
+                    run.updated_at,
+                )
+            })
+            .collect();
+        assert_eq!(
+            written,
+            [(1, "RUNNING", 500, None, 11), (2, "RUNNING", 500, None, 11)]
+        );
+    }
+}
@@ -0,0 +1,309 @@
+use axum::http::HeaderMap;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+use crate::error::{AppError, ErrorCode};
+use crate::processors::stream::{StreamCheckpoint, StreamResponse};
+
+// Headers carrying a client-supplied request identity, in order of precedence
+pub const IDEMPOTENCY_HEADERS: [&str; 2] = ["Idempotency-Key", "X-Batch-Id"];
+// Maximum accepted length of an idempotency key
+const MAX_KEY_LENGTH: usize = 255;
+
+// Extracts the client-supplied idempotency key from the request headers, if any
+pub fn key_from_headers(headers: &HeaderMap) -> Result<Option<String>, AppError> {
+    for header_name in IDEMPOTENCY_HEADERS {
+        if let Some(value) = headers.get(header_name) {
+            let key = value
+                .to_str()
+                .ok()
+                .map(str::trim)
+                .filter(|k| !k.is_empty() && k.len() <= MAX_KEY_LENGTH)
+                .ok_or_else(|| {
+                    AppError::new(
+                        ErrorCode::InvalidHeaderFormat,
+                        format!(
+                            "{} must be a non-empty ASCII string of at most {} characters",
+                            header_name, MAX_KEY_LENGTH
+                        ),
+                    )
+                })?;
+            return Ok(Some(key.to_string()));
+        }
+    }
+    Ok(None)
+}
+
+// Identifies a request within the scope it applies to
+// The same key sent to different tables, runs or tenants is a different request
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct IdempotencyKey {
+    pub tenant_id: String,
+    pub table_name: &'static str,
+    pub project_name: String,
+    pub run_id: String,
+    pub key: String,
+}
+
+// Result of claiming a key before processing a request
+pub enum Claim<T = StreamResponse> {
+    // First time the key is seen, the request must be processed
+    // The guard has to be completed, otherwise the key is released when it is dropped
+    New(IdempotencyGuard<T>),
+    // A request with the same key stopped at a rate limit or overload, the request
+    // continues from the checkpoint instead of ingesting the lines before it again
+    Resume(IdempotencyGuard<T>, StreamCheckpoint),
+    // A request with the same key is still being processed
+    InFlight,
+    // A request with the same key already finished, its outcome is returned instead
+    Completed(Result<T, AppError>),
+}
+
+enum Entry<T> {
+    InFlight,
+    Completed(Result<T, AppError>),
+    Interrupted(StreamCheckpoint), // Stopped by an error that invites a retry (429, 503)
+}
+
+struct State<T> {
+    entries: HashMap<IdempotencyKey, (Instant, Entry<T>)>,
+    order: VecDeque<(Instant, IdempotencyKey)>, // Keys in the order they were claimed, for expiry and eviction
+}
+
+// Bounded in-memory window of recently seen idempotency keys
+// Retried requests within the window get the original outcome instead of being ingested twice
+// The outcome is the response of an ingest request, or e.g. the run created by a retried INIT
+pub struct IdempotencyCache<T = StreamResponse> {
+    window: Duration,
+    max_keys: usize,
+    state: Mutex<State<T>>,
+}
+
+impl<T: Clone> IdempotencyCache<T> {
+    pub fn new(window: Duration, max_keys: usize) -> Self {
+        Self {
+            window,
+            max_keys,
+            state: Mutex::new(State {
+                entries: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+        }
+    }
+
+    // Claims a key for processing, or returns the state of an earlier request with the same key
+    pub fn claim(self: &Arc<Self>, key: IdempotencyKey) -> Claim<T> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        self.evict(&mut state, now);
+
+        if let Some((_, entry)) = state.entries.get_mut(&key) {
+            return match entry {
+                Entry::InFlight => Claim::InFlight,
+                Entry::Completed(outcome) => Claim::Completed(outcome.clone()),
+                Entry::Interrupted(checkpoint) => {
+                    let checkpoint = checkpoint.clone();
+                    *entry = Entry::InFlight;
+                    let guard = IdempotencyGuard {
+                        cache: Arc::clone(self),
+                        key: Some(key),
+                        checkpoint: Some(checkpoint.clone()),
+                    };
+                    Claim::Resume(guard, checkpoint)
+                }
+            };
+        }
+
+        state.entries.insert(key.clone(), (now, Entry::InFlight));
+        state.order.push_back((now, key.clone()));
+        Claim::New(IdempotencyGuard {
+            cache: Arc::clone(self),
+            key: Some(key),
+            checkpoint: None,
+        })
+    }
+
+    // Drops expired keys and, if the window is over capacity, the oldest ones
+    // Keys of requests still in flight are kept, evicting them would let a retry ingest the
+    // request a second time while it is being processed
+    fn evict(&self, state: &mut State<T>, now: Instant) {
+        let mut in_flight = Vec::new();
+        while let Some((claimed_at, _)) = state.order.front() {
+            let expired = now.duration_since(*claimed_at) >= self.window;
+            if !expired && state.entries.len() < self.max_keys {
+                break;
+            }
+            let (claimed_at, key) = state.order.pop_front().unwrap();
+            // Only remove the entry if it was not released and claimed again since
+            match state.entries.get(&key) {
+                Some((at, Entry::InFlight)) if *at == claimed_at => {
+                    in_flight.push((claimed_at, key))
+                }
+                Some((at, _)) if *at == claimed_at => {
+                    debug!(key = %key.key, "Evicting idempotency key");
+                    state.entries.remove(&key);
+                }
+                _ => {}
+            }
+        }
+        // In-flight keys go back to the front, in the order they were claimed
+        for claimed in in_flight.into_iter().rev() {
+            state.order.push_front(claimed);
+        }
+    }
+}
+
+// Holds a claimed key while its request is processed
+pub struct IdempotencyGuard<T = StreamResponse> {
+    cache: Arc<IdempotencyCache<T>>,
+    key: Option<IdempotencyKey>,
+    checkpoint: Option<StreamCheckpoint>, // Restored if a resumed request is cancelled
+}
+
+impl<T> IdempotencyGuard<T> {
+    // Records the outcome so retries with the same key receive it instead of being processed
+    // Errors are replayed without Retry-After, as retrying them would get the same answer
+    pub fn complete(mut self, outcome: Result<T, AppError>) {
+        let outcome = outcome.map_err(|e| AppError {
+            retry_after: None,
+            ..e
+        });
+        self.set_entry(Entry::Completed(outcome));
+    }
+
+    // Records where the request stopped, so a retry with the same key continues from there
+    pub fn interrupt(mut self, checkpoint: StreamCheckpoint) {
+        self.set_entry(Entry::Interrupted(checkpoint));
+    }
+
+    // Forgets the key so the request can be retried as if it was never seen
+    pub fn release(mut self) {
+        self.checkpoint = None;
+        self.release_key();
+    }
+
+    fn set_entry(&mut self, new_entry: Entry<T>) {
+        if let Some(key) = self.key.take() {
+            let mut state = self.cache.state.lock().unwrap();
+            if let Some((_, entry)) = state.entries.get_mut(&key) {
+                *entry = new_entry;
+            }
+        }
+    }
+
+    fn release_key(&mut self) {
+        match self.checkpoint.take() {
+            // Lines before the checkpoint were ingested, a later retry must still skip them
+            Some(checkpoint) => self.set_entry(Entry::Interrupted(checkpoint)),
+            None => {
+                if let Some(key) = self.key.take() {
+                    self.cache.state.lock().unwrap().entries.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+impl<T> Drop for IdempotencyGuard<T> {
+    // Requests that were cancelled (e.g. client disconnected) release their key
+    fn drop(&mut self) {
+        self.release_key();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(key: &str) -> IdempotencyKey {
+        IdempotencyKey {
+            tenant_id: "tenant".to_string(),
+            table_name: "mlop_metrics",
+            project_name: "project".to_string(),
+            run_id: "1".to_string(),
+            key: key.to_string(),
+        }
+    }
+
+    fn summary(claim: Claim) -> Option<String> {
+        match claim {
+            Claim::Completed(Ok(StreamResponse::Summary(message))) => Some(message),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_completed_key_is_replayed() {
+        let cache: Arc<IdempotencyCache> =
+            Arc::new(IdempotencyCache::new(Duration::from_secs(60), 10));
+        let Claim::New(guard) = cache.claim(key("a")) else {
+            panic!("expected a new claim");
+        };
+        assert!(matches!(cache.claim(key("a")), Claim::InFlight));
+
+        guard.complete(Ok(StreamResponse::Summary("done".to_string())));
+        assert_eq!(summary(cache.claim(key("a"))).as_deref(), Some("done"));
+        assert!(matches!(cache.claim(key("b")), Claim::New(_)));
+    }
+
+    #[test]
+    fn test_released_key_can_be_retried() {
+        let cache: Arc<IdempotencyCache> =
+            Arc::new(IdempotencyCache::new(Duration::from_secs(60), 10));
+        let Claim::New(guard) = cache.claim(key("a")) else {
+            panic!("expected a new claim");
+        };
+        guard.release();
+        assert!(matches!(cache.claim(key("a")), Claim::New(_)));
+    }
+
+    #[test]
+    fn test_interrupted_key_is_resumed() {
+        let cache: Arc<IdempotencyCache> =
+            Arc::new(IdempotencyCache::new(Duration::from_secs(60), 10));
+        let Claim::New(guard) = cache.claim(key("a")) else {
+            panic!("expected a new claim");
+        };
+        guard.interrupt(StreamCheckpoint::default());
+
+        let Claim::Resume(guard, _) = cache.claim(key("a")) else {
+            panic!("expected the request to be resumed");
+        };
+        assert!(matches!(cache.claim(key("a")), Claim::InFlight));
+        // A cancelled retry keeps the checkpoint for the next one
+        drop(guard);
+        assert!(matches!(cache.claim(key("a")), Claim::Resume(..)));
+    }
+
+    #[test]
+    fn test_window_is_bounded() {
+        let cache: Arc<IdempotencyCache> =
+            Arc::new(IdempotencyCache::new(Duration::from_secs(60), 2));
+        for k in ["a", "b", "c"] {
+            if let Claim::New(guard) = cache.claim(key(k)) {
+                guard.complete(Ok(StreamResponse::Summary(k.to_string())));
+            }
+        }
+        assert!(matches!(cache.claim(key("a")), Claim::New(_)));
+        assert_eq!(summary(cache.claim(key("c"))).as_deref(), Some("c"));
+    }
+
+    #[test]
+    fn test_in_flight_keys_are_not_evicted() {
+        let cache: Arc<IdempotencyCache> =
+            Arc::new(IdempotencyCache::new(Duration::from_secs(60), 3));
+        let Claim::New(_in_flight) = cache.claim(key("a")) else {
+            panic!("expected a new claim");
+        };
+        for k in ["b", "c", "d"] {
+            if let Claim::New(guard) = cache.claim(key(k)) {
+                guard.complete(Ok(StreamResponse::Summary(k.to_string())));
+            }
+        }
+        assert!(matches!(cache.claim(key("a")), Claim::InFlight));
+        assert_eq!(summary(cache.claim(key("d"))).as_deref(), Some("d"));
+        assert!(matches!(cache.claim(key("b")), Claim::New(_)));
+    }
+}
@@ -0,0 +1,495 @@
+use arrow_array::{
+    cast::AsArray,
+    types::{Float64Type, UInt64Type},
+    Array, ArrayRef, PrimitiveArray, RecordBatch, StringArray,
+};
+use arrow_cast::cast;
+use arrow_ipc::reader::{FileReader, StreamReader};
+use arrow_schema::{ArrowError, DataType, TimeUnit};
+use bytes::Bytes;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::io::Cursor;
+
+use crate::{
+    auth::Scope,
+    error::{AppError, ErrorCode},
+    import::{flush, ImportBatch, ImportSummary},
+    models::{
+        data::{DataEnrichment, DataInput, DataRow},
+        log::{LogEnrichment, LogInput, LogRow},
+        metrics::{MetricEnrichment, MetricInput, MetricRow},
+    },
+    traits::{DatabaseRow, ImportSink},
+};
+
+// Rows decoded at a time from Parquet files
+const PARQUET_BATCH_ROWS: usize = 8192;
+
+/// Table the rows of a Parquet or Arrow import are written to
+///
+/// Columns are named like the fields of the table's JSON records:
+/// - metrics: `time`, `step`, `logName`, `value`
+/// - logs: `time`, `message`, `lineNumber`, `logType`
+/// - data: `time`, `data`, `step`, `dataType`, `logName`
+///
+/// Optional `projectName` and `runId` columns name the run of each row
+#[derive(Debug, Clone, Copy, Deserialize, clap::ArgEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportTable {
+    Metrics,
+    Logs,
+    Data,
+}
+
+impl ImportTable {
+    // Scope of the ingest route of the same table
+    pub fn scope(self) -> Scope {
+        match self {
+            ImportTable::Metrics => Scope::IngestMetrics,
+            ImportTable::Logs => Scope::IngestLogs,
+            ImportTable::Data => Scope::IngestData,
+        }
+    }
+
+    // Required columns and the types they are read as
+    fn columns(self) -> &'static [(&'static str, DataType)] {
+        match self {
+            ImportTable::Metrics => &[
+                ("time", DataType::UInt64),
+                ("step", DataType::UInt64),
+                ("logName", DataType::Utf8),
+                ("value", DataType::Float64),
+            ],
+            ImportTable::Logs => &[
+                ("time", DataType::UInt64),
+                ("message", DataType::Utf8),
+                ("lineNumber", DataType::UInt64),
+                ("logType", DataType::Utf8),
+            ],
+            ImportTable::Data => &[
+                ("time", DataType::UInt64),
+                ("data", DataType::Utf8),
+                ("step", DataType::UInt64),
+                ("dataType", DataType::Utf8),
+                ("logName", DataType::Utf8),
+            ],
+        }
+    }
+}
+
+// Container of the record batches, detected from the first bytes of the file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ColumnarFormat {
+    Parquet,
+    ArrowFile,   // Arrow IPC file format (also known as Feather v2)
+    ArrowStream, // Arrow IPC streaming format
+}
+
+impl ColumnarFormat {
+    fn detect(bytes: &[u8]) -> Self {
+        if bytes.starts_with(b"PAR1") {
+            ColumnarFormat::Parquet
+        } else if bytes.starts_with(b"ARROW1") {
+            ColumnarFormat::ArrowFile
+        } else {
+            ColumnarFormat::ArrowStream
+        }
+    }
+}
+
+pub type RecordBatches = Box<dyn Iterator<Item = Result<RecordBatch, ArrowError>> + Send>;
+
+// Opens the record batches of a Parquet file or an Arrow IPC file or stream
+pub fn record_batches(bytes: Bytes) -> Result<RecordBatches, AppError> {
+    Ok(match ColumnarFormat::detect(&bytes) {
+        ColumnarFormat::Parquet => Box::new(
+            ParquetRecordBatchReaderBuilder::try_new(bytes)
+                .map_err(invalid_file)?
+                .with_batch_size(PARQUET_BATCH_ROWS)
+                .build()
+                .map_err(invalid_file)?,
+        ),
+        ColumnarFormat::ArrowFile => {
+            Box::new(FileReader::try_new(Cursor::new(bytes), None).map_err(invalid_file)?)
+        }
+        ColumnarFormat::ArrowStream => {
+            Box::new(StreamReader::try_new(Cursor::new(bytes), None).map_err(invalid_file)?)
+        }
+    })
+}
+
+fn invalid_file(e: impl Display) -> AppError {
+    AppError::new(
+        ErrorCode::StreamDecodingError,
+        format!("Failed to read Parquet or Arrow file: {}", e),
+    )
+}
+
+// Maps the rows of record batches to rows of a table, validating them like the ingest routes
+// `tenantId` and `logGroup` columns are ignored, the tenant is the importer's and the group
+// follows from the name
+pub struct Converter<A> {
+    table: ImportTable,
+    tenant_id: String,
+    project_name: Option<String>, // Run of rows without `projectName` and `runId` columns
+    run_id: Option<u64>,
+    authorize: A, // Checks each project and run the first time it appears
+    authorized: HashSet<(String, u64)>,
+    rows: u64, // Rows read so far, to name rows in errors
+}
+
+impl<A> Converter<A>
+where
+    A: FnMut(&str, u64) -> Result<(), AppError>,
+{
+    pub fn new(
+        table: ImportTable,
+        tenant_id: String,
+        project_name: Option<String>,
+        run_id: Option<u64>,
+        authorize: A,
+    ) -> Self {
+        Self {
+            table,
+            tenant_id,
+            project_name,
+            run_id,
+            authorize,
+            authorized: HashSet::new(),
+            rows: 0,
+        }
+    }
+
+    // Adds the rows of a record batch to the batch, stopping at the first invalid row
+    pub fn convert(
+        &mut self,
+        record_batch: &RecordBatch,
+        batch: &mut ImportBatch,
+    ) -> Result<(), AppError> {
+        let columns = Columns::read(record_batch, self.table)?;
+        let projects = column(record_batch, "projectName", &DataType::Utf8)?;
+        let projects = projects.as_ref().map(|c| c.as_string::<i32>());
+        let runs = column(record_batch, "runId", &DataType::UInt64)?;
+        let runs = runs.as_ref().map(|c| c.as_primitive::<UInt64Type>());
+
+        for row in 0..record_batch.num_rows() {
+            let result = self
+                .target(projects, runs, row)
+                .and_then(|(project_name, run_id)| {
+                    self.convert_row(&columns, row, project_name, run_id, batch)
+                });
+            if let Err(e) = result {
+                // Rows are numbered from 1 across all record batches, as lines are
+                return Err(AppError {
+                    message: format!("Row {}: {}", self.rows + row as u64 + 1, e.message),
+                    ..e
+                });
+            }
+        }
+        self.rows += record_batch.num_rows() as u64;
+        batch.bytes += record_batch.get_array_memory_size() as u64;
+        Ok(())
+    }
+
+    fn target(
+        &mut self,
+        projects: Option<&StringArray>,
+        runs: Option<&PrimitiveArray<UInt64Type>>,
+        row: usize,
+    ) -> Result<(String, u64), AppError> {
+        let project_name = match projects.filter(|c| c.is_valid(row)) {
+            Some(column) => column.value(row).to_string(),
+            None => self.project_name.clone().ok_or_else(|| {
+                AppError::new(
+                    ErrorCode::MissingRequiredField,
+                    "no 'projectName' column and no default project",
+                )
+            })?,
+        };
+        let run_id = match runs.filter(|c| c.is_valid(row)) {
+            Some(column) => column.value(row),
+            None => self.run_id.ok_or_else(|| {
+                AppError::new(
+                    ErrorCode::MissingRequiredField,
+                    "no 'runId' column and no default run",
+                )
+            })?,
+        };
+        if !self.authorized.contains(&(project_name.clone(), run_id)) {
+            (self.authorize)(&project_name, run_id)?;
+            self.authorized.insert((project_name.clone(), run_id));
+        }
+        Ok((project_name, run_id))
+    }
+
+    fn convert_row(
+        &self,
+        columns: &Columns,
+        row: usize,
+        project_name: String,
+        run_id: u64,
+        batch: &mut ImportBatch,
+    ) -> Result<(), AppError> {
+        let tenant_id = self.tenant_id.clone();
+        match self.table {
+            ImportTable::Metrics => {
+                let input = MetricInput {
+                    time: columns.u64("time", row)?,
+                    step: columns.u64("step", row)?,
+                    data: HashMap::from([(
+                        columns.string("logName", row)?,
+                        columns.f64("value", row)?,
+                    )]),
+                };
+                let enrichment = MetricEnrichment {
+                    tenant_id,
+                    run_id,
+                    project_name,
+                };
+                batch.metrics.push(
+                    <MetricRow as DatabaseRow<MetricInput, MetricEnrichment>>::from(
+                        input, enrichment,
+                    )?,
+                );
+            }
+            ImportTable::Logs => {
+                let input = LogInput {
+                    time: columns.u64("time", row)?,
+                    message: columns.string("message", row)?,
+                    line_number: columns.u64("lineNumber", row)?,
+                    log_type: columns.string("logType", row)?,
+                };
+                let enrichment = LogEnrichment {
+                    tenant_id,
+                    run_id,
+                    project_name,
+                };
+                batch
+                    .logs
+                    .push(<LogRow as DatabaseRow<LogInput, LogEnrichment>>::from(
+                        input, enrichment,
+                    )?);
+            }
+            ImportTable::Data => {
+                let input = DataInput {
+                    time: columns.u64("time", row)?,
+                    data: columns.string("data", row)?,
+                    step: columns.u64("step", row)?,
+                    data_type: columns.string("dataType", row)?,
+                    log_name: columns.string("logName", row)?,
+                };
+                let enrichment = DataEnrichment {
+                    tenant_id,
+                    run_id,
+                    project_name,
+                };
+                batch
+                    .data
+                    .push(<DataRow as DatabaseRow<DataInput, DataEnrichment>>::from(
+                        input, enrichment,
+                    )?);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Reads a column of a record batch as the type of its row field, None if it is missing
+// Integer, float and string columns are cast when their type differs (e.g. Int32 steps)
+fn column(
+    record_batch: &RecordBatch,
+    name: &str,
+    data_type: &DataType,
+) -> Result<Option<ArrayRef>, AppError> {
+    let Some(array) = record_batch.column_by_name(name) else {
+        return Ok(None);
+    };
+    let mut array = array.clone();
+    // Timestamps of any unit become milliseconds since the epoch
+    if let DataType::Timestamp(unit, tz) = array.data_type() {
+        if *unit != TimeUnit::Millisecond {
+            let millis = DataType::Timestamp(TimeUnit::Millisecond, tz.clone());
+            array = cast(&array, &millis).map_err(|e| cast_error(name, e))?;
+        }
+        array = cast(&array, &DataType::Int64).map_err(|e| cast_error(name, e))?;
+    }
+    cast(&array, data_type)
+        .map(Some)
+        .map_err(|e| cast_error(name, e))
+}
+
+// The required columns of a table in a record batch, cast once per batch
+struct Columns {
+    arrays: HashMap<&'static str, ArrayRef>,
+}
+
+impl Columns {
+    fn read(record_batch: &RecordBatch, table: ImportTable) -> Result<Self, AppError> {
+        let mut arrays = HashMap::new();
+        for (name, data_type) in table.columns() {
+            let array = column(record_batch, name, data_type)?.ok_or_else(|| {
+                AppError::new(
+                    ErrorCode::MissingRequiredField,
+                    format!("missing column '{}'", name),
+                )
+            })?;
+            arrays.insert(*name, array);
+        }
+        Ok(Self { arrays })
+    }
+
+    fn u64(&self, name: &str, row: usize) -> Result<u64, AppError> {
+        let array = self.arrays[name].as_primitive::<UInt64Type>();
+        non_null(array, name, row).map(|_| array.value(row))
+    }
+
+    fn f64(&self, name: &str, row: usize) -> Result<f64, AppError> {
+        let array = self.arrays[name].as_primitive::<Float64Type>();
+        non_null(array, name, row).map(|_| array.value(row))
+    }
+
+    fn string(&self, name: &str, row: usize) -> Result<String, AppError> {
+        let array = self.arrays[name].as_string::<i32>();
+        non_null(array, name, row).map(|_| array.value(row).to_string())
+    }
+}
+
+// Casts that do not fit (e.g. negative steps) leave nulls, so they are reported here as well
+fn non_null(array: &dyn Array, name: &str, row: usize) -> Result<(), AppError> {
+    if array.is_null(row) {
+        return Err(AppError::new(
+            ErrorCode::InvalidInput,
+            format!("column '{}' is null or out of range", name),
+        ));
+    }
+    Ok(())
+}
+
+fn cast_error(name: &str, e: ArrowError) -> AppError {
+    AppError::new(
+        ErrorCode::InvalidInput,
+        format!("column '{}' has an unsupported type: {}", name, e),
+    )
+}
+
+// Reads the record batches of a file and writes their rows to the sink in batches,
+// calling `on_progress` after each batch that was written
+// Rows before an invalid row are kept, the summary counts them
+pub async fn import<S, A>(
+    record_batches: RecordBatches,
+    converter: &mut Converter<A>,
+    sink: &mut S,
+    summary: &mut ImportSummary,
+    mut on_progress: impl FnMut(&ImportSummary),
+) -> Result<(), AppError>
+where
+    S: ImportSink,
+    A: FnMut(&str, u64) -> Result<(), AppError>,
+{
+    let mut batch = ImportBatch::default();
+    for record_batch in record_batches {
+        let record_batch = record_batch.map_err(invalid_file)?;
+        summary.records += record_batch.num_rows() as u64;
+        if let Err(e) = converter.convert(&record_batch, &mut batch) {
+            flush(sink, &mut batch, summary, true).await?;
+            return Err(e);
+        }
+        if flush(sink, &mut batch, summary, false).await? {
+            on_progress(summary);
+        }
+    }
+    flush(sink, &mut batch, summary, true).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::{Float32Array, Int32Array, TimestampSecondArray};
+    use arrow_ipc::writer::FileWriter;
+    use parquet::arrow::ArrowWriter;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_convert_metrics() {
+        let record_batch = RecordBatch::try_from_iter([
+            (
+                "time",
+                Arc::new(TimestampSecondArray::from(vec![
+                    1_700_000_000,
+                    1_700_000_001,
+                ])) as ArrayRef,
+            ),
+            ("step", Arc::new(Int32Array::from(vec![1, 2])) as ArrayRef),
+            (
+                "logName",
+                Arc::new(StringArray::from(vec!["train/loss", "train/loss"])) as ArrayRef,
+            ),
+            (
+                "value",
+                Arc::new(Float32Array::from(vec![0.5, f32::NAN])) as ArrayRef,
+            ),
+            (
+                "runId",
+                Arc::new(PrimitiveArray::<UInt64Type>::from(vec![Some(8), None])) as ArrayRef,
+            ),
+        ])
+        .unwrap();
+
+        // Round trip through Parquet and Arrow IPC files
+        let mut parquet = Vec::new();
+        let mut writer = ArrowWriter::try_new(&mut parquet, record_batch.schema(), None).unwrap();
+        writer.write(&record_batch).unwrap();
+        writer.close().unwrap();
+        let rows: usize = record_batches(Bytes::from(parquet))
+            .unwrap()
+            .map(|b| b.unwrap().num_rows())
+            .sum();
+        assert_eq!(rows, 2);
+
+        let mut file = Vec::new();
+        let mut writer = FileWriter::try_new(&mut file, &record_batch.schema()).unwrap();
+        writer.write(&record_batch).unwrap();
+        writer.finish().unwrap();
+        drop(writer);
+        let record_batch = record_batches(Bytes::from(file))
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+
+        let mut targets = Vec::new();
+        let authorize = |project: &str, run_id: u64| {
+            targets.push((project.to_string(), run_id));
+            Ok(())
+        };
+        let mut converter = Converter::new(
+            ImportTable::Metrics,
+            "org".to_string(),
+            Some("p".to_string()),
+            Some(7),
+            authorize,
+        );
+        let mut batch = ImportBatch::default();
+        let err = converter.convert(&record_batch, &mut batch).unwrap_err();
+
+        // The first row is converted, the second fails validation like a JSON record would
+        let row = &batch.metrics[0];
+        assert_eq!((row.time, row.step, row.value), (1_700_000_000_000, 1, 0.5));
+        assert_eq!((row.log_group.as_str(), row.run_id), ("train", 8));
+        assert!(err.message.starts_with("Row 2: "), "{}", err.message);
+        assert!(matches!(err.code, ErrorCode::InvalidMetricFormat));
+        drop(converter);
+        assert_eq!(targets, [("p".to_string(), 8), ("p".to_string(), 7)]);
+
+        let mut converter =
+            Converter::new(ImportTable::Logs, "org".into(), None, None, |_: &str, _| {
+                Ok(())
+            });
+        let err = converter.convert(&record_batch, &mut batch).unwrap_err();
+        assert!(matches!(err.code, ErrorCode::MissingRequiredField));
+    }
+}
@@ -0,0 +1,284 @@
+use aws_sdk_s3::Client as StorageClient;
+use bytes::Bytes;
+use clickhouse::Client;
+use futures::{StreamExt, TryStreamExt};
+use serde::Serialize;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio_util::io::ReaderStream;
+use tracing::info;
+
+use crate::{
+    config::{
+        Config, DATA_TABLE_NAME, FILES_TABLE_NAME, IMPORT_BATCH_BYTES, IMPORT_BATCH_ROWS,
+        LOGS_TABLE_NAME, METRICS_TABLE_NAME,
+    },
+    error::{AppError, ErrorCode},
+    models::{data::DataRow, files::FilesRow, log::LogRow, metrics::MetricRow},
+    processors::background::insert_batch,
+    rate_limit::RateLimiter,
+    routes::files::{put_file, storage_client, storage_key},
+    traits::ImportSink,
+};
+
+pub mod columnar;
+pub mod tfevents;
+
+// Rows read from an import, grouped by table
+#[derive(Debug, Default)]
+pub struct ImportBatch {
+    pub metrics: Vec<MetricRow>,
+    pub logs: Vec<LogRow>,
+    pub data: Vec<DataRow>,
+    pub files: Vec<ImportedFile>,
+    pub bytes: u64, // Size of the source records the rows were read from
+}
+
+impl ImportBatch {
+    pub fn len(&self) -> usize {
+        self.metrics.len() + self.logs.len() + self.data.len() + self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn is_full(&self) -> bool {
+        self.len() >= IMPORT_BATCH_ROWS || self.bytes >= IMPORT_BATCH_BYTES
+    }
+}
+
+// A file found in an import, uploaded to storage before its row is written
+#[derive(Debug)]
+pub struct ImportedFile {
+    pub row: FilesRow,
+    pub content_type: String,
+    pub bytes: Vec<u8>,
+}
+
+impl ImportedFile {
+    pub fn storage_key(&self) -> String {
+        storage_key(
+            &self.row.tenant_id,
+            &self.row.project_name,
+            self.row.run_id,
+            &self.row.log_name,
+            &self.row.file_name,
+        )
+    }
+}
+
+/// Counts of an import, returned by the import routes and commands
+///
+/// # Example
+/// ```json
+/// { "records": 1200, "metrics": 1000, "logs": 10, "data": 100, "files": 20, "skipped": 5 }
+/// ```
+#[derive(Debug, Default, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportSummary {
+    pub records: u64, // Records read from the source
+    // Rows written, only counting batches that were written completely
+    pub metrics: u64,
+    pub logs: u64,
+    pub data: u64,
+    pub files: u64,
+    pub skipped: u64, // Values without an equivalent in mlop (e.g. audio)
+}
+
+impl ImportSummary {
+    fn add(&mut self, batch: &ImportBatch) {
+        self.metrics += batch.metrics.len() as u64;
+        self.logs += batch.logs.len() as u64;
+        self.data += batch.data.len() as u64;
+        self.files += batch.files.len() as u64;
+    }
+}
+
+// Writes the batch to the sink once it is full, or whatever it holds if `force` is set
+// Returns whether the batch was written
+pub async fn flush<S: ImportSink>(
+    sink: &mut S,
+    batch: &mut ImportBatch,
+    summary: &mut ImportSummary,
+    force: bool,
+) -> Result<bool, AppError> {
+    if batch.is_empty() || !(force || batch.is_full()) {
+        return Ok(false);
+    }
+    let batch = mem::take(batch);
+    let mut written = summary.clone();
+    written.add(&batch);
+    sink.write(batch).await?;
+    *summary = written;
+    Ok(true)
+}
+
+// Writes imports straight to ClickHouse and storage with synchronous inserts,
+// for backfills and the import commands
+pub struct ClickHouseSink {
+    client: Client,
+    config: Arc<Config>,
+    storage: Option<StorageClient>, // Created for the first batch with files
+    rate_limiter: Option<(Arc<RateLimiter>, String)>, // Limiter and tenant charged for each batch
+}
+
+impl ClickHouseSink {
+    pub fn new(client: Client, config: Arc<Config>) -> Self {
+        Self {
+            client,
+            config,
+            storage: None,
+            rate_limiter: None,
+        }
+    }
+
+    // Charges every batch to the tenant's rate limits, for imports through the API
+    pub fn with_rate_limiter(mut self, rate_limiter: Arc<RateLimiter>, tenant_id: String) -> Self {
+        if rate_limiter.is_enabled() {
+            self.rate_limiter = Some((rate_limiter, tenant_id));
+        }
+        self
+    }
+}
+
+impl ImportSink for ClickHouseSink {
+    async fn write(&mut self, batch: ImportBatch) -> Result<(), AppError> {
+        if let Some((rate_limiter, tenant_id)) = &self.rate_limiter {
+            rate_limiter.acquire(tenant_id, batch.len() as u64, batch.bytes)?;
+        }
+        if !batch.metrics.is_empty() {
+            insert_batch(&self.client, METRICS_TABLE_NAME, &batch.metrics).await?;
+        }
+        if !batch.logs.is_empty() {
+            insert_batch(&self.client, LOGS_TABLE_NAME, &batch.logs).await?;
+        }
+        if !batch.data.is_empty() {
+            insert_batch(&self.client, DATA_TABLE_NAME, &batch.data).await?;
+        }
+        if batch.files.is_empty() {
+            return Ok(());
+        }
+
+        // Rows of files are only written once the files are in storage
+        let storage = match &self.storage {
+            Some(storage) => storage,
+            None => self.storage.insert(storage_client(&self.config).await),
+        };
+        let mut files = Vec::with_capacity(batch.files.len());
+        for file in batch.files {
+            let key = file.storage_key();
+            put_file(storage, &self.config, key, file.content_type, file.bytes).await?;
+            files.push(file.row);
+        }
+        insert_batch(&self.client, FILES_TABLE_NAME, &files).await?;
+        Ok(())
+    }
+}
+
+// ClickHouse client of the import commands, which run without the server
+fn clickhouse_client(config: &Config) -> Client {
+    Client::default()
+        .with_url(config.clickhouse_url.clone())
+        .with_user(config.clickhouse_user.clone())
+        .with_password(config.clickhouse_password.clone())
+}
+
+/// Run that the files of an import command are written to
+#[derive(clap::Args, Debug)]
+pub struct ImportArgs {
+    /// Tenant (organization) ID of the run
+    #[clap(long)]
+    pub tenant_id: String,
+    /// Project of the run
+    #[clap(long)]
+    pub project: String,
+    /// Numeric ID of the run
+    #[clap(long)]
+    pub run_id: u64,
+    /// Files to import, in order
+    #[clap(required = true)]
+    pub files: Vec<PathBuf>,
+}
+
+// Imports TensorBoard event files into a run, for the `import-tensorboard` command
+pub async fn import_tensorboard(
+    config: Arc<Config>,
+    args: ImportArgs,
+) -> Result<ImportSummary, AppError> {
+    let mut sink = ClickHouseSink::new(clickhouse_client(&config), config);
+    let mut converter = tfevents::Converter::new(args.tenant_id, args.project, args.run_id);
+    let mut summary = ImportSummary::default();
+    for path in &args.files {
+        let file = tokio::fs::File::open(path)
+            .await
+            .map_err(|e| read_error(path, e))?;
+        let stream = ReaderStream::new(file)
+            .map_err(|e| AppError::new(ErrorCode::StreamDecodingError, e.to_string()))
+            .boxed();
+        tfevents::import(stream, &mut converter, &mut sink, &mut summary).await?;
+        info!(path = %path.display(), ?summary, "Imported event file");
+    }
+    Ok(summary)
+}
+
+/// Table and runs that the files of a Parquet or Arrow import command are written to
+#[derive(clap::Args, Debug)]
+pub struct ColumnarImportArgs {
+    /// Table the rows are written to
+    #[clap(long, arg_enum)]
+    pub table: columnar::ImportTable,
+    /// Tenant (organization) ID of the runs
+    #[clap(long)]
+    pub tenant_id: String,
+    /// Project of rows without a `projectName` column
+    #[clap(long)]
+    pub project: Option<String>,
+    /// Run of rows without a `runId` column
+    #[clap(long)]
+    pub run_id: Option<u64>,
+    /// Parquet or Arrow IPC files to import, in order
+    #[clap(required = true)]
+    pub files: Vec<PathBuf>,
+}
+
+// Imports Parquet or Arrow IPC files into a table, for the `import` command
+pub async fn import_columnar(
+    config: Arc<Config>,
+    args: ColumnarImportArgs,
+) -> Result<ImportSummary, AppError> {
+    let mut sink = ClickHouseSink::new(clickhouse_client(&config), config);
+    let mut converter = columnar::Converter::new(
+        args.table,
+        args.tenant_id,
+        args.project,
+        args.run_id,
+        |_: &str, _| Ok(()),
+    );
+    let mut summary = ImportSummary::default();
+    for path in &args.files {
+        let bytes = tokio::fs::read(path)
+            .await
+            .map_err(|e| read_error(path, e))?;
+        let record_batches = columnar::record_batches(Bytes::from(bytes))?;
+        let on_progress = |summary: &ImportSummary| info!(?summary, "Import progress");
+        columnar::import(
+            record_batches,
+            &mut converter,
+            &mut sink,
+            &mut summary,
+            on_progress,
+        )
+        .await?;
+        info!(path = %path.display(), ?summary, "Imported file");
+    }
+    Ok(summary)
+}
+
+fn read_error(path: &Path, e: std::io::Error) -> AppError {
+    AppError::new(
+        ErrorCode::InvalidInput,
+        format!("Failed to read {}: {}", path.display(), e),
+    )
+}
@@ -0,0 +1,593 @@
+use bytes::BytesMut;
+use futures::{stream::BoxStream, StreamExt};
+use prost::Message;
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::{
+    error::{AppError, ErrorCode},
+    import::{flush, ImportBatch, ImportSummary, ImportedFile},
+    models::{data::DataRow, files::FilesRow, log::LogRow, metrics::MetricRow},
+    routes::files::FileType,
+    traits::ImportSink,
+    utils::log_group_from_log_name,
+};
+
+// A TFRecord is the length (u64), its masked CRC (u32), the data and its masked CRC (u32)
+const RECORD_HEADER_BYTES: usize = 12;
+const RECORD_FOOTER_BYTES: usize = 4;
+// Largest record accepted, event files hold one summary (e.g. a batch of images) per record
+const MAX_RECORD_BYTES: u64 = 64 * 1024 * 1024;
+
+// Values of TensorFlow's `DataType` enum found in summary tensors
+const DT_FLOAT: i32 = 1;
+const DT_DOUBLE: i32 = 2;
+const DT_INT32: i32 = 3;
+const DT_INT64: i32 = 9;
+
+// Messages of TensorFlow's event.proto and summary.proto, only the fields that are imported
+#[derive(Clone, PartialEq, Message)]
+pub struct Event {
+    #[prost(double, tag = "1")]
+    pub wall_time: f64, // Seconds since the epoch
+    #[prost(int64, tag = "2")]
+    pub step: i64,
+    #[prost(message, optional, tag = "5")]
+    pub summary: Option<Summary>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Summary {
+    #[prost(message, repeated, tag = "1")]
+    pub value: Vec<SummaryValue>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct SummaryValue {
+    #[prost(string, tag = "1")]
+    pub tag: String,
+    // TF1 summaries set one of these
+    #[prost(float, optional, tag = "2")]
+    pub simple_value: Option<f32>,
+    #[prost(message, optional, tag = "4")]
+    pub image: Option<SummaryImage>,
+    #[prost(message, optional, tag = "5")]
+    pub histo: Option<HistogramProto>,
+    // TF2 summaries are tensors interpreted by the plugin named in the metadata
+    #[prost(message, optional, tag = "8")]
+    pub tensor: Option<TensorProto>,
+    #[prost(message, optional, tag = "9")]
+    pub metadata: Option<SummaryMetadata>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct SummaryImage {
+    #[prost(bytes = "vec", tag = "4")]
+    pub encoded_image_string: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct HistogramProto {
+    #[prost(double, tag = "1")]
+    pub min: f64,
+    #[prost(double, tag = "2")]
+    pub max: f64,
+    #[prost(double, repeated, tag = "6")]
+    pub bucket_limit: Vec<f64>, // Right edge of each bucket
+    #[prost(double, repeated, tag = "7")]
+    pub bucket: Vec<f64>, // Count of each bucket
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct SummaryMetadata {
+    #[prost(message, optional, tag = "1")]
+    pub plugin_data: Option<PluginData>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct PluginData {
+    #[prost(string, tag = "1")]
+    pub plugin_name: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct TensorProto {
+    #[prost(int32, tag = "1")]
+    pub dtype: i32,
+    #[prost(bytes = "vec", tag = "4")]
+    pub tensor_content: Vec<u8>, // Little-endian values, used instead of the typed fields
+    #[prost(float, repeated, tag = "5")]
+    pub float_val: Vec<f32>,
+    #[prost(double, repeated, tag = "6")]
+    pub double_val: Vec<f64>,
+    #[prost(int32, repeated, tag = "7")]
+    pub int_val: Vec<i32>,
+    #[prost(bytes = "vec", repeated, tag = "8")]
+    pub string_val: Vec<Vec<u8>>,
+    #[prost(int64, repeated, tag = "10")]
+    pub int64_val: Vec<i64>,
+}
+
+impl TensorProto {
+    // Numeric values of the tensor in row-major order, empty for other types
+    fn values(&self) -> Vec<f64> {
+        fn le<const N: usize>(content: &[u8], f: impl Fn([u8; N]) -> f64) -> Vec<f64> {
+            content
+                .chunks_exact(N)
+                .map(|c| f(c.try_into().unwrap()))
+                .collect()
+        }
+        let content = &self.tensor_content;
+        if !content.is_empty() {
+            return match self.dtype {
+                DT_FLOAT => le(content, |b| f32::from_le_bytes(b) as f64),
+                DT_DOUBLE => le(content, f64::from_le_bytes),
+                DT_INT32 => le(content, |b| i32::from_le_bytes(b) as f64),
+                DT_INT64 => le(content, |b| i64::from_le_bytes(b) as f64),
+                _ => Vec::new(),
+            };
+        }
+        match self.dtype {
+            DT_FLOAT => self.float_val.iter().map(|&v| v as f64).collect(),
+            DT_DOUBLE => self.double_val.clone(),
+            DT_INT32 => self.int_val.iter().map(|&v| v as f64).collect(),
+            DT_INT64 => self.int64_val.iter().map(|&v| v as f64).collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Histogram stored as the `data` of a `DataRow` with `dataType` "histogram"
+///
+/// # Example
+/// ```json
+/// { "bins": [0.0, 0.5, 1.0], "counts": [3.0, 7.0] }
+/// ```
+/// `bins` holds the edges of the buckets, one more than `counts`
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Histogram {
+    pub bins: Vec<f64>,
+    pub counts: Vec<f64>,
+}
+
+impl Histogram {
+    // TF1 histograms store the right edge of every bucket, the first one starts at the minimum
+    fn from_proto(histo: &HistogramProto) -> Option<Self> {
+        let last = histo.bucket_limit.len().checked_sub(1)?;
+        let mut bins = Vec::with_capacity(histo.bucket_limit.len() + 1);
+        bins.push(histo.min);
+        bins.extend_from_slice(&histo.bucket_limit[..last]);
+        // The last limit is usually f64::MAX
+        bins.push(histo.bucket_limit[last].min(histo.max));
+        Some(Self {
+            bins,
+            counts: histo.bucket.iter().take(last + 1).copied().collect(),
+        })
+    }
+
+    // TF2 histograms are a k x 3 tensor of (left edge, right edge, count)
+    fn from_tensor(tensor: &TensorProto) -> Option<Self> {
+        let values = tensor.values();
+        if values.is_empty() || !values.len().is_multiple_of(3) {
+            return None;
+        }
+        let mut bins: Vec<f64> = values.chunks_exact(3).map(|b| b[0]).collect();
+        bins.push(values[values.len() - 2]);
+        Some(Self {
+            bins,
+            counts: values.chunks_exact(3).map(|b| b[2]).collect(),
+        })
+    }
+}
+
+// What a summary value holds, after interpreting TF1 fields and TF2 plugin tensors
+enum Summarized {
+    Scalar(f64),
+    Text(String),
+    Histogram(Histogram),
+    Images(Vec<Vec<u8>>), // Encoded images (PNG, or GIF for animations)
+}
+
+// Finds the next TFRecord at the start of `buffer`
+// Returns the length of its data, or None if more bytes are needed
+pub fn next_record(buffer: &[u8]) -> Result<Option<usize>, AppError> {
+    if buffer.len() < RECORD_HEADER_BYTES {
+        return Ok(None);
+    }
+    let length_bytes = &buffer[..8];
+    let length_crc = u32::from_le_bytes(buffer[8..12].try_into().unwrap());
+    if masked_crc(length_bytes) != length_crc {
+        return Err(AppError::new(
+            ErrorCode::StreamDecodingError,
+            "Corrupt record length, this is not a TensorBoard event file",
+        ));
+    }
+    let length = u64::from_le_bytes(length_bytes.try_into().unwrap());
+    if length > MAX_RECORD_BYTES {
+        return Err(AppError::new(
+            ErrorCode::BufferOverflowError,
+            format!(
+                "Record of {} bytes exceeds the limit of {} bytes",
+                length, MAX_RECORD_BYTES
+            ),
+        ));
+    }
+    let length = length as usize;
+    Ok((buffer.len() >= RECORD_HEADER_BYTES + length + RECORD_FOOTER_BYTES).then_some(length))
+}
+
+// Returns the data of a complete record, checking its CRC
+fn record_data(record: &[u8]) -> Result<&[u8], AppError> {
+    let (data, crc) =
+        record[RECORD_HEADER_BYTES..].split_at(record.len() - RECORD_HEADER_BYTES - 4);
+    if masked_crc(data) != u32::from_le_bytes(crc.try_into().unwrap()) {
+        return Err(AppError::new(
+            ErrorCode::StreamDecodingError,
+            "Record failed its CRC check",
+        ));
+    }
+    Ok(data)
+}
+
+// CRC32-C, masked as in TFRecord files
+fn masked_crc(bytes: &[u8]) -> u32 {
+    let crc = crc32c::crc32c(bytes);
+    crc.rotate_right(15).wrapping_add(0xa282_ead8)
+}
+
+// Frames data as a TFRecord
+#[cfg(test)]
+pub fn record(data: &[u8]) -> Vec<u8> {
+    let length = (data.len() as u64).to_le_bytes();
+    let mut record = length.to_vec();
+    record.extend_from_slice(&masked_crc(&length).to_le_bytes());
+    record.extend_from_slice(data);
+    record.extend_from_slice(&masked_crc(data).to_le_bytes());
+    record
+}
+
+// Maps the summaries of events to the rows of a run
+// Scalars become metrics, text logs, histograms data and images files
+pub struct Converter {
+    tenant_id: String,
+    project_name: String,
+    run_id: u64,
+    plugins: HashMap<String, String>, // Tag -> plugin, only the first value of a tag names it
+    line_number: u64,                 // Of the next text summary
+}
+
+impl Converter {
+    pub fn new(tenant_id: String, project_name: String, run_id: u64) -> Self {
+        Self {
+            tenant_id,
+            project_name,
+            run_id,
+            plugins: HashMap::new(),
+            line_number: 0,
+        }
+    }
+
+    // Adds the rows of an event to the batch, returns the number of values that were skipped
+    // Events without summaries (file version, graphs, session logs) are ignored
+    pub fn convert(&mut self, event: Event, batch: &mut ImportBatch) -> u64 {
+        let Some(summary) = event.summary else {
+            return 0;
+        };
+        let time = (event.wall_time * 1000.0) as u64;
+        let step = u64::try_from(event.step).unwrap_or(0);
+
+        let mut skipped = 0;
+        for value in summary.value {
+            match self.summarized(&value) {
+                // Diverged runs log NaN losses, metrics only hold finite values
+                Some(Summarized::Scalar(value)) if !value.is_finite() => skipped += 1,
+                Some(summarized) => self.add(value.tag, time, step, summarized, batch),
+                None => skipped += 1,
+            }
+        }
+        skipped
+    }
+
+    fn summarized(&mut self, value: &SummaryValue) -> Option<Summarized> {
+        if let Some(plugin_data) = value.metadata.as_ref().and_then(|m| m.plugin_data.as_ref()) {
+            self.plugins
+                .insert(value.tag.clone(), plugin_data.plugin_name.clone());
+        }
+
+        if let Some(value) = value.simple_value {
+            return Some(Summarized::Scalar(value as f64));
+        }
+        if let Some(histo) = &value.histo {
+            return Histogram::from_proto(histo).map(Summarized::Histogram);
+        }
+        if let Some(image) = &value.image {
+            return Some(Summarized::Images(vec![image.encoded_image_string.clone()]));
+        }
+        let tensor = value.tensor.as_ref()?;
+        match self.plugins.get(&value.tag)?.as_str() {
+            "scalars" => tensor.values().first().copied().map(Summarized::Scalar),
+            "histograms" => Histogram::from_tensor(tensor).map(Summarized::Histogram),
+            // The first two strings are the width and height
+            "images" => Some(Summarized::Images(
+                tensor.string_val.iter().skip(2).cloned().collect(),
+            )),
+            "text" => Some(Summarized::Text(
+                tensor
+                    .string_val
+                    .iter()
+                    .map(|s| String::from_utf8_lossy(s))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            )),
+            _ => None,
+        }
+    }
+
+    fn add(
+        &mut self,
+        tag: String,
+        time: u64,
+        step: u64,
+        value: Summarized,
+        batch: &mut ImportBatch,
+    ) {
+        let log_group = log_group_from_log_name(&tag);
+        match value {
+            Summarized::Scalar(value) => batch.metrics.push(MetricRow {
+                time,
+                step,
+                log_group,
+                log_name: tag,
+                value,
+                tenant_id: self.tenant_id.clone(),
+                run_id: self.run_id,
+                project_name: self.project_name.clone(),
+            }),
+            Summarized::Text(message) => {
+                batch.logs.push(LogRow {
+                    time,
+                    message,
+                    line_number: self.line_number,
+                    log_type: "INFO".to_string(),
+                    tenant_id: self.tenant_id.clone(),
+                    run_id: self.run_id,
+                    project_name: self.project_name.clone(),
+                });
+                self.line_number += 1;
+            }
+            Summarized::Histogram(histogram) => batch.data.push(DataRow {
+                time,
+                data: serde_json::to_string(&histogram).expect("Histograms are serializable"),
+                step,
+                data_type: "histogram".to_string(),
+                log_group,
+                log_name: tag,
+                tenant_id: self.tenant_id.clone(),
+                run_id: self.run_id,
+                project_name: self.project_name.clone(),
+            }),
+            Summarized::Images(images) => {
+                for (index, bytes) in images.into_iter().enumerate() {
+                    let file_type = image_type(&bytes);
+                    let extension = file_type.extension();
+                    let file_name = match extension.as_str() {
+                        "" => format!("{}-{}", step, index),
+                        extension => format!("{}-{}.{}", step, index, extension),
+                    };
+                    batch.files.push(ImportedFile {
+                        row: FilesRow {
+                            tenant_id: self.tenant_id.clone(),
+                            project_name: self.project_name.clone(),
+                            run_id: self.run_id,
+                            time,
+                            step,
+                            log_group: log_group.clone(),
+                            log_name: tag.clone(),
+                            file_name,
+                            file_type: extension,
+                            file_size: bytes.len() as u64,
+                        },
+                        content_type: file_type.mime_type(),
+                        bytes,
+                    });
+                }
+            }
+        }
+    }
+}
+
+// Detects the format of an encoded image from its signature
+fn image_type(bytes: &[u8]) -> FileType {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        FileType::Png
+    } else if bytes.starts_with(&[0xff, 0xd8, 0xff]) {
+        FileType::Jpg
+    } else if bytes.starts_with(b"GIF8") {
+        FileType::Gif
+    } else if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        FileType::Webp
+    } else {
+        FileType::Custom("application/octet-stream".to_string())
+    }
+}
+
+// Reads an event file and writes its rows to the sink in batches
+// Rows of records before an error are kept, the summary counts them
+pub async fn import<S: ImportSink>(
+    mut stream: BoxStream<'static, Result<bytes::Bytes, AppError>>,
+    converter: &mut Converter,
+    sink: &mut S,
+    summary: &mut ImportSummary,
+) -> Result<(), AppError> {
+    let mut buffer = BytesMut::new();
+    let mut batch = ImportBatch::default();
+    while let Some(chunk) = stream.next().await {
+        buffer.extend_from_slice(&chunk?);
+        while let Some(length) = next_record(&buffer)? {
+            let record = buffer.split_to(RECORD_HEADER_BYTES + length + RECORD_FOOTER_BYTES);
+            let event = Event::decode(record_data(&record)?).map_err(|e| {
+                AppError::new(
+                    ErrorCode::StreamDecodingError,
+                    format!("Failed to parse event: {}", e),
+                )
+            })?;
+            summary.records += 1;
+            summary.skipped += converter.convert(event, &mut batch);
+            batch.bytes += record.len() as u64;
+            flush(sink, &mut batch, summary, false).await?;
+        }
+    }
+    if !buffer.is_empty() {
+        // Keep what was read, as TensorBoard does for files of runs that are still writing
+        flush(sink, &mut batch, summary, true).await?;
+        return Err(AppError::new(
+            ErrorCode::StreamDecodingError,
+            format!(
+                "Event file ends in the middle of a record ({} bytes)",
+                buffer.len()
+            ),
+        ));
+    }
+    flush(sink, &mut batch, summary, true).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_record() {
+        let data = Event {
+            wall_time: 1.0,
+            step: 1,
+            summary: None,
+        }
+        .encode_to_vec();
+        let record = record(&data);
+        assert_eq!(next_record(&record[..11]).unwrap(), None);
+        assert_eq!(next_record(&record[..record.len() - 1]).unwrap(), None);
+        assert_eq!(next_record(&record).unwrap(), Some(data.len()));
+        assert_eq!(record_data(&record).unwrap(), &data[..]);
+
+        let mut corrupt = record.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert!(record_data(&corrupt).is_err());
+        assert!(next_record(b"not an event file").is_err());
+    }
+
+    #[test]
+    fn test_convert_summaries() {
+        let value = |tag: &str| SummaryValue {
+            tag: tag.to_string(),
+            ..Default::default()
+        };
+        let plugin = |name: &str| {
+            Some(SummaryMetadata {
+                plugin_data: Some(PluginData {
+                    plugin_name: name.to_string(),
+                }),
+            })
+        };
+        let text = |s: &str| TensorProto {
+            dtype: 7,
+            string_val: vec![s.as_bytes().to_vec()],
+            ..Default::default()
+        };
+        let event = |step: i64, value: Vec<SummaryValue>| Event {
+            wall_time: 1700000000.5,
+            step,
+            summary: Some(Summary { value }),
+        };
+
+        let mut converter = Converter::new("org".into(), "p".into(), 7);
+        let mut batch = ImportBatch::default();
+        let skipped = converter.convert(
+            event(
+                3,
+                vec![
+                    SummaryValue {
+                        simple_value: Some(0.5),
+                        ..value("train/loss")
+                    },
+                    SummaryValue {
+                        tensor: Some(TensorProto {
+                            dtype: DT_FLOAT,
+                            tensor_content: 2.0f32.to_le_bytes().to_vec(),
+                            ..Default::default()
+                        }),
+                        metadata: plugin("scalars"),
+                        ..value("lr")
+                    },
+                    SummaryValue {
+                        tensor: Some(text("hello")),
+                        metadata: plugin("text"),
+                        ..value("notes")
+                    },
+                    SummaryValue {
+                        histo: Some(HistogramProto {
+                            min: -1.0,
+                            max: 1.0,
+                            bucket_limit: vec![0.0, f64::MAX],
+                            bucket: vec![2.0, 3.0],
+                        }),
+                        ..value("weights")
+                    },
+                    SummaryValue {
+                        image: Some(SummaryImage {
+                            encoded_image_string: b"\x89PNG\r\n\x1a\n...".to_vec(),
+                        }),
+                        ..value("samples/input")
+                    },
+                    SummaryValue {
+                        tensor: Some(text("")),
+                        metadata: plugin("pr_curves"),
+                        ..value("pr")
+                    },
+                ],
+            ),
+            &mut batch,
+        );
+        assert_eq!(skipped, 1);
+
+        let metrics: Vec<_> = batch
+            .metrics
+            .iter()
+            .map(|m| (m.log_name.as_str(), m.value))
+            .collect();
+        assert_eq!(metrics, [("train/loss", 0.5), ("lr", 2.0)]);
+        assert_eq!(
+            (batch.metrics[0].time, batch.metrics[0].step),
+            (1_700_000_000_500, 3)
+        );
+        assert_eq!(batch.logs[0].message, "hello");
+        assert_eq!(batch.data[0].data_type, "histogram");
+        assert_eq!(
+            batch.data[0].data,
+            r#"{"bins":[-1.0,0.0,1.0],"counts":[2.0,3.0]}"#
+        );
+        let file = &batch.files[0];
+        assert_eq!(
+            (file.row.file_name.as_str(), file.content_type.as_str()),
+            ("3-0.png", "image/png")
+        );
+        assert_eq!(file.storage_key(), "org/p/7/samples/input/3-0.png");
+
+        // Later values of a tag carry no metadata, the plugin is remembered
+        let mut batch = ImportBatch::default();
+        converter.convert(
+            event(
+                4,
+                vec![SummaryValue {
+                    tensor: Some(text("world")),
+                    ..value("notes")
+                }],
+            ),
+            &mut batch,
+        );
+        assert_eq!(
+            (batch.logs[0].message.as_str(), batch.logs[0].line_number),
+            ("world", 1)
+        );
+    }
+}
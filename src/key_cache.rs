@@ -0,0 +1,177 @@
+use hashlink::LruCache;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+use crate::auth::ApiKeyGrant;
+
+// What is known about a hashed API key
+#[derive(Debug, Clone, PartialEq)]
+pub enum CachedKey {
+    // The key exists, with what it grants access to
+    Found(ApiKeyGrant),
+    // The key does not exist (cached briefly to blunt brute force attempts)
+    NotFound,
+}
+
+// Cached lookups by hashed key, found and unknown keys each in least recently used order
+struct Entries {
+    found: LruCache<String, (Instant, CachedKey)>,
+    not_found: LruCache<String, (Instant, CachedKey)>,
+}
+
+impl Entries {
+    fn len(&self) -> usize {
+        self.found.len() + self.not_found.len()
+    }
+
+    fn remove(&mut self, hashed_key: &str) -> bool {
+        self.found.remove(hashed_key).is_some() || self.not_found.remove(hashed_key).is_some()
+    }
+}
+
+// In-memory cache of API key lookups, keyed by the hashed key
+// Found keys are kept for `ttl`, unknown keys for `negative_ttl`
+pub struct ApiKeyCache {
+    ttl: Duration,
+    negative_ttl: Duration,
+    max_entries: usize,
+    entries: Mutex<Entries>,
+    invalidations: watch::Sender<u64>, // Number of invalidations so far
+}
+
+impl ApiKeyCache {
+    pub fn new(ttl: Duration, negative_ttl: Duration, max_entries: usize) -> Self {
+        Self {
+            ttl,
+            negative_ttl,
+            max_entries,
+            entries: Mutex::new(Entries {
+                found: LruCache::new_unbounded(),
+                not_found: LruCache::new_unbounded(),
+            }),
+            invalidations: watch::Sender::new(0),
+        }
+    }
+
+    // Returns the cached lookup result, if it is still fresh
+    pub fn get(&self, hashed_key: &str) -> Option<CachedKey> {
+        let mut entries = self.entries.lock().unwrap();
+        let entries = &mut *entries;
+        let (cached_at, key) = match entries.found.get(hashed_key) {
+            Some(entry) => entry,
+            None => entries.not_found.get(hashed_key)?,
+        };
+        if cached_at.elapsed() < self.ttl_for(key) {
+            Some(key.clone())
+        } else {
+            None
+        }
+    }
+
+    pub fn insert(&self, hashed_key: String, key: CachedKey) {
+        if self.ttl_for(&key).is_zero() {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.remove(&hashed_key) && entries.len() >= self.max_entries {
+            // Make room by dropping the least recently used unknown key, or if there is none
+            // the least recently used found key, so a flood of unknown keys cannot push valid
+            // keys out of the cache
+            if entries.not_found.remove_lru().is_none() {
+                entries.found.remove_lru();
+            }
+        }
+        let lru = match key {
+            CachedKey::Found(_) => &mut entries.found,
+            CachedKey::NotFound => &mut entries.not_found,
+        };
+        lru.insert(hashed_key, (Instant::now(), key));
+    }
+
+    // Forgets a single key, e.g. after it was revoked
+    pub fn invalidate(&self, hashed_key: &str) -> bool {
+        let removed = self.entries.lock().unwrap().remove(hashed_key);
+        // Notified even if the key was not cached, connections may have authenticated with it earlier
+        self.invalidations.send_modify(|count| *count += 1);
+        removed
+    }
+
+    // Forgets all keys, returns how many were cached
+    pub fn invalidate_all(&self) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let count = entries.len();
+        entries.found.clear();
+        entries.not_found.clear();
+        drop(entries);
+        self.invalidations.send_modify(|count| *count += 1);
+        count
+    }
+
+    // Receiver that changes whenever keys are invalidated
+    pub fn invalidations(&self) -> watch::Receiver<u64> {
+        self.invalidations.subscribe()
+    }
+
+    fn ttl_for(&self, key: &CachedKey) -> Duration {
+        match key {
+            CachedKey::Found(_) => self.ttl,
+            CachedKey::NotFound => self.negative_ttl,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn found(tenant_id: &str) -> CachedKey {
+        CachedKey::Found(ApiKeyGrant {
+            key_id: format!("key-{}", tenant_id),
+            tenant_id: tenant_id.to_string(),
+            expires_at: None,
+            scopes: None,
+            allowed_projects: None,
+            project_name: None,
+            run_id: None,
+        })
+    }
+
+    #[test]
+    fn test_entries_expire_by_kind() {
+        let cache = ApiKeyCache::new(Duration::from_secs(60), Duration::ZERO, 10);
+        cache.insert("a".to_string(), found("tenant"));
+        cache.insert("b".to_string(), CachedKey::NotFound);
+
+        assert_eq!(cache.get("a"), Some(found("tenant")));
+        // A zero negative TTL disables negative caching
+        assert_eq!(cache.get("b"), None);
+
+        assert!(cache.invalidate("a"));
+        assert_eq!(cache.get("a"), None);
+    }
+
+    #[test]
+    fn test_cache_is_bounded() {
+        let cache = ApiKeyCache::new(Duration::from_secs(60), Duration::from_secs(60), 3);
+        cache.insert("a".to_string(), found("1"));
+        cache.insert("b".to_string(), found("2"));
+
+        // Unknown keys are evicted before found ones, however many are inserted
+        for i in 0..10 {
+            cache.insert(format!("unknown-{}", i), CachedKey::NotFound);
+        }
+        assert_eq!(cache.entries.lock().unwrap().len(), 3);
+        assert_eq!(cache.get("a"), Some(found("1")));
+        assert_eq!(cache.get("b"), Some(found("2")));
+        assert_eq!(cache.get("unknown-9"), Some(CachedKey::NotFound));
+
+        // Then the least recently used found key
+        cache.insert("c".to_string(), found("3"));
+        cache.insert("d".to_string(), found("4"));
+        assert_eq!(cache.entries.lock().unwrap().len(), 3);
+        assert_eq!(cache.get("a"), None);
+        assert_eq!(cache.get("b"), Some(found("2")));
+        assert_eq!(cache.get("d"), Some(found("4")));
+    }
+}
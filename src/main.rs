@@ -1,32 +1,57 @@
 mod auth;
 mod config;
+mod console;
 mod db;
 mod error;
+mod heartbeat;
+mod idempotency;
+mod import;
+mod key_cache;
 mod models;
+mod otlp;
 mod processors;
+mod rate_limit;
 mod routes;
+mod runs;
+mod shutdown;
+mod telemetry;
 mod traits;
 mod utils;
 
 use axum::Router;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use clickhouse::Client;
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
 use config::{
-    Config, DATA_FLUSH_CONFIG, FILES_FLUSH_CONFIG, LOGS_FLUSH_CONFIG, METRICS_FLUSH_CONFIG,
+    AuthBackend, Config, DATA_FLUSH_CONFIG, DATA_TABLE_NAME, FILES_FLUSH_CONFIG, FILES_TABLE_NAME,
+    LOGS_FLUSH_CONFIG, LOGS_TABLE_NAME, METRICS_FLUSH_CONFIG, METRICS_TABLE_NAME,
+    TENANT_QUEUE_CAPACITY, TENANT_QUEUE_QUANTUM,
 };
 use models::{data::DataRow, files::FilesRow, log::LogRow};
 use routes::step;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::net::TcpListener;
-use tokio::sync::mpsc;
+use tokio::sync::Semaphore;
 
+use crate::auth::jwt::JwtAuthProvider;
+use crate::auth::keys_file::KeysFileProvider;
+use crate::auth::single_tenant::SingleTenantProvider;
+use crate::auth::AuthProvider;
+use crate::console::ConsoleLineNumbers;
 use crate::db::Database;
+use crate::heartbeat::{start_crash_sweeper, HeartbeatTracker};
+use crate::idempotency::IdempotencyCache;
+use crate::import::{ColumnarImportArgs, ImportArgs};
 use crate::models::metrics::MetricRow;
-use crate::processors::background::start_background_processor;
-use crate::routes::{files, health, ingest, AppState};
+use crate::processors::background::{start_background_processor, ProcessorStatus};
+use crate::processors::fair_queue::fair_channel;
+use crate::processors::spool::Spool;
+use crate::rate_limit::RateLimiter;
+use crate::routes::{admin, files, health, ingest, query, status, websocket, AppState};
+use crate::runs::RunIdGenerator;
+use crate::shutdown::Shutdown;
 
 // Define command-line arguments
 #[derive(Parser, Debug)]
@@ -35,6 +60,17 @@ struct Cli {
     /// Optional: Specify environment to load (.env.<ENV> file)
     #[clap(long)]
     env: Option<String>,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+// Commands that run instead of the server
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Import TensorBoard event files into a run, writing straight to ClickHouse and storage
+    ImportTensorboard(ImportArgs),
+    /// Import Parquet or Arrow IPC files into a table, writing straight to ClickHouse
+    Import(ColumnarImportArgs),
 }
 
 #[tokio::main]
@@ -99,21 +135,89 @@ async fn main() {
 
     // Load application configuration
     let config = Config::new();
+    // Tenant IDs only appear in metric labels if enabled explicitly
+    telemetry::set_tenant_labels(config.metrics_tenant_labels);
+
+    if let Some(command) = cli.command {
+        let config = Arc::new(config);
+        let result = match command {
+            Command::ImportTensorboard(args) => import::import_tensorboard(config, args).await,
+            Command::Import(args) => import::import_columnar(config, args).await,
+        };
+        match result {
+            Ok(summary) => tracing::info!(?summary, "Import completed"),
+            Err(e) => {
+                tracing::error!(error = %e, "Import failed");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
     // tracing::info!(database_url = %config.database_url, clickhouse_url = %config.clickhouse_url, "Configuration loaded");
 
-    // Connect to the primary database (e.g., PostgreSQL)
-    let db = Database::connect(&config.database_url)
-        .await
-        .expect("Failed to connect to database");
+    // Set up the source of API keys, only the Postgres backend needs a database connection
+    let mut console_lines = ConsoleLineNumbers::default();
+    let mut database = None;
+    let auth_provider: Arc<dyn AuthProvider> = match &config.auth_backend {
+        AuthBackend::Postgres { database_url } => {
+            // Connect to the primary database (e.g., PostgreSQL)
+            let db = Database::connect(database_url, &config)
+                .await
+                .expect("Failed to connect to database");
+
+            // Wrap database connection in an Arc for shared access
+            let db = Arc::new(db);
+
+            // Record when API keys were last used without adding a write to every request
+            // Uses since the last write are written on shutdown
+            tokio::spawn(
+                db.clone()
+                    .write_last_used(config.api_key_last_used_interval),
+            );
+            database = Some(db.clone());
 
-    // Wrap database connection in an Arc for shared access
-    let db = Arc::new(db);
+            // Drop revoked API keys from the cache as soon as Postgres reports them
+            if let Some(channel) = config.api_key_notify_channel.clone() {
+                tokio::spawn(db.clone().listen_for_key_changes(channel));
+            }
 
-    // Create MPSC channels for different data types to be processed in the background
-    let (metrics_record_sender, metrics_record_receiver) = mpsc::channel::<MetricRow>(1_000);
-    let (log_record_sender, log_record_receiver) = mpsc::channel::<LogRow>(1_000);
-    let (data_record_sender, data_record_receiver) = mpsc::channel::<DataRow>(1_000);
-    let (files_record_sender, files_record_receiver) = mpsc::channel::<FilesRow>(1_000);
+            // Number console lines in Postgres, so any instance can take any run's output
+            match ConsoleLineNumbers::shared(db.pool().clone()).await {
+                Ok(shared) => console_lines = shared,
+                Err(e) => tracing::warn!(
+                    error = %e,
+                    "Numbering console lines per instance, console output must be routed by run"
+                ),
+            }
+            db
+        }
+        AuthBackend::KeysFile { path } => {
+            Arc::new(KeysFileProvider::load(path).expect("Failed to load API keys file"))
+        }
+        AuthBackend::SingleTenant { tenant_id } => {
+            tracing::warn!(tenant_id = %tenant_id, "Authentication is disabled, every request is accepted as this tenant");
+            Arc::new(SingleTenantProvider::new(tenant_id.clone()))
+        }
+    };
+
+    // Also accept signed JWTs if verification keys are configured
+    let auth_provider = match JwtAuthProvider::from_config(auth_provider.clone(), &config)
+        .expect("Failed to load JWT verification keys")
+    {
+        Some(jwt_provider) => Arc::new(jwt_provider),
+        None => auth_provider,
+    };
+
+    // Create channels for different data types to be processed in the background
+    // Each tenant gets its own sub-queue so one tenant's backlog does not stall the others
+    let (metrics_record_sender, metrics_record_receiver) =
+        fair_channel::<MetricRow>(TENANT_QUEUE_CAPACITY, TENANT_QUEUE_QUANTUM);
+    let (log_record_sender, log_record_receiver) =
+        fair_channel::<LogRow>(TENANT_QUEUE_CAPACITY, TENANT_QUEUE_QUANTUM);
+    let (data_record_sender, data_record_receiver) =
+        fair_channel::<DataRow>(TENANT_QUEUE_CAPACITY, TENANT_QUEUE_QUANTUM);
+    let (files_record_sender, files_record_receiver) =
+        fair_channel::<FilesRow>(TENANT_QUEUE_CAPACITY, TENANT_QUEUE_QUANTUM);
 
     // Configure the ClickHouse client
     let clickhouse_client = Client::default()
@@ -121,65 +225,184 @@ async fn main() {
         .with_user(config.clickhouse_user.clone())
         .with_password(config.clickhouse_password.clone());
 
+    // Open the write-ahead spools for each table
+    // Batches left behind by a previous run are replayed by the background processors
+    let metrics_spool = Spool::open(&config.spool_dir, METRICS_TABLE_NAME)
+        .await
+        .expect("Failed to open metrics spool");
+    let logs_spool = Spool::open(&config.spool_dir, LOGS_TABLE_NAME)
+        .await
+        .expect("Failed to open logs spool");
+    let data_spool = Spool::open(&config.spool_dir, DATA_TABLE_NAME)
+        .await
+        .expect("Failed to open data spool");
+    let files_spool = Spool::open(&config.spool_dir, FILES_TABLE_NAME)
+        .await
+        .expect("Failed to open files spool");
+
     // Wrap config in an Arc for shared access
     let config = Arc::new(config);
 
+    // Progress of each background processor, shared with the request handlers
+    let metrics_status = Arc::new(ProcessorStatus::default());
+    let logs_status = Arc::new(ProcessorStatus::default());
+    let data_status = Arc::new(ProcessorStatus::default());
+    let files_status = Arc::new(ProcessorStatus::default());
+
     // Spawn background processors for each data type
     // These processors receive data through channels and upload it
-    tokio::spawn(start_background_processor(
-        metrics_record_receiver,
-        METRICS_FLUSH_CONFIG,
-        skip_upload,
-        config.clone(),
-    ));
+    // Keep the task handles so shutdown can wait for each processor's final flush
+    let processors = vec![
+        (
+            METRICS_TABLE_NAME,
+            tokio::spawn(start_background_processor(
+                metrics_record_receiver,
+                METRICS_FLUSH_CONFIG,
+                skip_upload,
+                config.clone(),
+                metrics_spool,
+                metrics_status.clone(),
+            )),
+        ),
+        (
+            LOGS_TABLE_NAME,
+            tokio::spawn(start_background_processor(
+                log_record_receiver,
+                LOGS_FLUSH_CONFIG,
+                skip_upload,
+                config.clone(),
+                logs_spool,
+                logs_status.clone(),
+            )),
+        ),
+        (
+            DATA_TABLE_NAME,
+            tokio::spawn(start_background_processor(
+                data_record_receiver,
+                DATA_FLUSH_CONFIG,
+                skip_upload,
+                config.clone(),
+                data_spool,
+                data_status.clone(),
+            )),
+        ),
+        (
+            FILES_TABLE_NAME,
+            tokio::spawn(start_background_processor(
+                files_record_receiver,
+                FILES_FLUSH_CONFIG,
+                skip_upload,
+                config.clone(),
+                files_spool,
+                files_status.clone(),
+            )),
+        ),
+    ];
 
-    tokio::spawn(start_background_processor(
-        log_record_receiver,
-        LOGS_FLUSH_CONFIG,
-        skip_upload,
-        config.clone(),
+    // Track when runs were last seen and mark runs that went silent as crashed
+    let heartbeats = Arc::new(HeartbeatTracker::default());
+    tokio::spawn(start_crash_sweeper(
+        clickhouse_client.clone(),
+        heartbeats.clone(),
+        config.run_crash_timeout,
     ));
 
-    tokio::spawn(start_background_processor(
-        data_record_receiver,
-        DATA_FLUSH_CONFIG,
-        skip_upload,
-        config.clone(),
-    ));
+    // Per-tenant ingest limits, with overrides from the tenant limits file
+    let rate_limiter = Arc::new(
+        RateLimiter::load(config.tenant_limits, config.tenant_limits_file.as_deref())
+            .expect("Failed to load tenant limits"),
+    );
 
-    tokio::spawn(start_background_processor(
-        files_record_receiver,
-        FILES_FLUSH_CONFIG,
-        skip_upload,
-        config.clone(),
-    ));
+    // Listen for SIGTERM/SIGINT to drain the ingest pipeline before exiting
+    let shutdown = Shutdown::listen(config.shutdown_drain_timeout);
 
     // Create the application state, wrapping shared resources in Arc
+    // Continue after the run IDs this node issued before it was restarted
+    let run_ids = RunIdGenerator::new(config.node_id);
+    if let Err(e) = run_ids.resume(&clickhouse_client).await {
+        tracing::warn!(
+            error = %e,
+            "Failed to read the last issued run ID, IDs issued shortly before the restart may repeat"
+        );
+    }
+
     let state = Arc::new(AppState {
-        metrics_record_sender,
-        log_record_sender,
-        data_record_sender,
-        files_record_sender,
+        metrics_record_sender: metrics_record_sender.with_limits(config.ingest_send_limits),
+        log_record_sender: log_record_sender.with_limits(config.ingest_send_limits),
+        data_record_sender: data_record_sender.with_limits(config.ingest_send_limits),
+        files_record_sender: files_record_sender.with_limits(config.ingest_send_limits),
         clickhouse_client,
-        db: db.clone(),
+        storage_client: files::storage_client(&config).await,
+        auth_provider,
         config: config.clone(),
+        metrics_status,
+        logs_status,
+        data_status,
+        files_status,
+        idempotency: Arc::new(IdempotencyCache::new(
+            config.idempotency_window,
+            config.idempotency_max_keys,
+        )),
+        rate_limiter,
+        run_ids: Arc::new(run_ids),
+        run_inits: Arc::new(IdempotencyCache::new(
+            config.idempotency_window,
+            config.idempotency_max_keys,
+        )),
+        heartbeats,
+        console_lines: Arc::new(console_lines),
+        import_permits: Arc::new(Semaphore::new(config.import_max_concurrent)),
+        shutdown: shutdown.clone(),
     });
 
     // Define the Axum application router, merging routes from different modules
     let app = Router::new()
         .merge(health::router())
+        .merge(admin::router())
         .merge(ingest::router())
+        .merge(websocket::router())
+        .merge(routes::otlp::router())
         .merge(step::router())
         .merge(files::router())
-        .with_state(state); // Provide the application state to the routes
+        .merge(routes::import::router())
+        .merge(query::router())
+        .merge(status::router())
+        .merge(routes::telemetry::router())
+        .with_state(state.clone()); // Provide the application state to the routes
 
     // Define the server address (IPv6)
     let ipv6 = SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 0], 3003));
     tracing::info!(address = %ipv6, "Server starting to listen");
 
     // Bind the TCP listener and start the Axum server
+    // On shutdown the server stops accepting connections and waits for in-flight requests
     let ipv6_listener = TcpListener::bind(ipv6).await.unwrap();
-    axum::serve(ipv6_listener, app.into_make_service())
-        .await
-        .unwrap();
+    let server = axum::serve(ipv6_listener, app.into_make_service()).with_graceful_shutdown({
+        let shutdown = shutdown.clone();
+        async move { shutdown.requested().await }
+    });
+
+    tokio::select! {
+        result = server => result.unwrap(),
+        _ = shutdown.deadline_elapsed() => {
+            tracing::warn!("Drain deadline reached before in-flight requests completed");
+        }
+    }
+
+    // WebSocket connections and imports may still hold the record senders, wait for them to
+    // finish, then close the channels so each background processor performs its final flush
+    shutdown.wait_for_connections().await;
+    if let Some(database) = &database {
+        database.flush_last_used().await;
+    }
+    state.close_record_senders();
+    drop(state);
+    let persisted = shutdown.drain_processors(processors).await;
+    if persisted {
+        tracing::info!("All buffered records persisted, exiting");
+        std::process::exit(0);
+    } else {
+        tracing::error!("Exiting with records that were not persisted to ClickHouse");
+        std::process::exit(1);
+    }
 }
@@ -5,8 +5,9 @@ use serde::{Deserialize, Serialize};
 use crate::{
     config::DATA_TABLE_NAME,
     error::{missing_header_error, AppError, ErrorCode},
+    heartbeat::RunKey,
     processors::stream::SingleRowInput,
-    traits::{DatabaseRow, EnrichmentData, InputData},
+    traits::{DatabaseRow, EnrichmentData, InputData, ProtobufInput},
     utils::log_group_from_log_name,
 };
 
@@ -54,6 +55,35 @@ impl DataInput {
     }
 }
 
+/// Protobuf encoding of `DataInput`, mirrors `Data` in proto/ingest.proto
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DataMessage {
+    #[prost(uint64, tag = "1")]
+    pub time: u64,
+    #[prost(string, tag = "2")]
+    pub data: String,
+    #[prost(uint64, tag = "3")]
+    pub step: u64,
+    #[prost(string, tag = "4")]
+    pub data_type: String,
+    #[prost(string, tag = "5")]
+    pub log_name: String,
+}
+
+impl ProtobufInput for DataInput {
+    type Message = DataMessage;
+
+    fn from_message(message: DataMessage) -> Self {
+        Self {
+            time: message.time,
+            data: message.data,
+            step: message.step,
+            data_type: message.data_type,
+            log_name: message.log_name,
+        }
+    }
+}
+
 impl InputData for DataInput {
     fn validate(&self) -> Result<(), AppError> {
         self.validate()
@@ -90,6 +120,14 @@ impl EnrichmentData for DataEnrichment {
             project_name,
         })
     }
+
+    fn run_key(&self) -> Option<RunKey> {
+        Some(RunKey {
+            tenant_id: self.tenant_id.clone(),
+            project_name: self.project_name.clone(),
+            run_id: self.run_id,
+        })
+    }
 }
 
 // Final database row combining input and enrichment
@@ -5,6 +5,7 @@ use serde::{Deserialize, Serialize};
 use crate::{
     config::FILES_TABLE_NAME,
     error::{missing_header_error, AppError},
+    heartbeat::RunKey,
     processors::stream::SingleRowInput,
     traits::{DatabaseRow, EnrichmentData, InputData},
     utils::log_group_from_log_name,
@@ -62,6 +63,14 @@ impl EnrichmentData for FilesEnrichment {
             project_name,
         })
     }
+
+    fn run_key(&self) -> Option<RunKey> {
+        Some(RunKey {
+            tenant_id: self.tenant_id.clone(),
+            project_name: self.project_name.clone(),
+            run_id: self.run_id,
+        })
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -5,8 +5,9 @@ use serde::{Deserialize, Serialize};
 use crate::{
     config::LOGS_TABLE_NAME,
     error::{missing_header_error, AppError, ErrorCode},
+    heartbeat::RunKey,
     processors::stream::SingleRowInput,
-    traits::{DatabaseRow, EnrichmentData, InputData},
+    traits::{DatabaseRow, EnrichmentData, InputData, ProtobufInput},
 };
 
 /// Raw input data for logs
@@ -51,6 +52,32 @@ impl LogInput {
     }
 }
 
+/// Protobuf encoding of `LogInput`, mirrors `Log` in proto/ingest.proto
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct LogMessage {
+    #[prost(uint64, tag = "1")]
+    pub time: u64,
+    #[prost(string, tag = "2")]
+    pub message: String,
+    #[prost(uint64, tag = "3")]
+    pub line_number: u64,
+    #[prost(string, tag = "4")]
+    pub log_type: String,
+}
+
+impl ProtobufInput for LogInput {
+    type Message = LogMessage;
+
+    fn from_message(message: LogMessage) -> Self {
+        Self {
+            time: message.time,
+            message: message.message,
+            line_number: message.line_number,
+            log_type: message.log_type,
+        }
+    }
+}
+
 impl InputData for LogInput {
     fn validate(&self) -> Result<(), AppError> {
         self.validate()
@@ -87,6 +114,14 @@ impl EnrichmentData for LogEnrichment {
             project_name,
         })
     }
+
+    fn run_key(&self) -> Option<RunKey> {
+        Some(RunKey {
+            tenant_id: self.tenant_id.clone(),
+            project_name: self.project_name.clone(),
+            run_id: self.run_id,
+        })
+    }
 }
 
 // Final database row combining input and enrichment
@@ -6,8 +6,9 @@ use std::collections::HashMap;
 use crate::{
     config::METRICS_TABLE_NAME,
     error::{missing_header_error, AppError, ErrorCode},
+    heartbeat::RunKey,
     processors::stream::IntoRows,
-    traits::{DatabaseRow, EnrichmentData, InputData},
+    traits::{DatabaseRow, EnrichmentData, InputData, ProtobufInput},
     utils::log_group_from_log_name,
 };
 
@@ -64,6 +65,29 @@ impl MetricInput {
     }
 }
 
+/// Protobuf encoding of `MetricInput`, mirrors `Metric` in proto/ingest.proto
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct MetricMessage {
+    #[prost(uint64, tag = "1")]
+    pub time: u64,
+    #[prost(uint64, tag = "2")]
+    pub step: u64,
+    #[prost(map = "string, double", tag = "3")]
+    pub data: HashMap<LogName, f64>,
+}
+
+impl ProtobufInput for MetricInput {
+    type Message = MetricMessage;
+
+    fn from_message(message: MetricMessage) -> Self {
+        Self {
+            time: message.time,
+            step: message.step,
+            data: message.data,
+        }
+    }
+}
+
 impl InputData for MetricInput {
     fn validate(&self) -> Result<(), AppError> {
         self.validate()
@@ -75,8 +99,11 @@ impl IntoRows<MetricEnrichment, MetricRow> for MetricInput {
     fn into_rows(self, enrichment: MetricEnrichment) -> Result<Vec<MetricRow>, AppError> {
         self.validate()?;
 
-        Ok(self
-            .data
+        // Ordered by name rather than hash order, so a stream resumed part-way through a line
+        // skips exactly the rows it sent before
+        let mut data: Vec<_> = self.data.into_iter().collect();
+        data.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(data
             .into_iter()
             .map(|(log_name, value)| MetricRow {
                 time: self.time,
@@ -85,7 +112,7 @@ impl IntoRows<MetricEnrichment, MetricRow> for MetricInput {
                 log_name,
                 value,
                 tenant_id: enrichment.tenant_id.clone(),
-                run_id: enrichment.run_id.clone(),
+                run_id: enrichment.run_id,
                 project_name: enrichment.project_name.clone(),
             })
             .collect())
@@ -120,6 +147,14 @@ impl EnrichmentData for MetricEnrichment {
             project_name,
         })
     }
+
+    fn run_key(&self) -> Option<RunKey> {
+        Some(RunKey {
+            tenant_id: self.tenant_id.clone(),
+            project_name: self.project_name.clone(),
+            run_id: self.run_id,
+        })
+    }
 }
 
 // Final database row combining input and enrichment
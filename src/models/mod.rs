@@ -1,5 +1,5 @@
+pub mod data;
 pub mod files;
 pub mod log;
 pub mod metrics;
 pub mod status;
-pub mod data;
@@ -1,27 +1,276 @@
+use axum::http::HeaderMap;
+use clickhouse::Row;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-#[derive(Debug, Serialize, Deserialize)]
+use crate::{
+    error::{missing_header_error, AppError, ErrorCode},
+    heartbeat::RunKey,
+    traits::EnrichmentData,
+};
+
+// Lifecycle status of a run
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 #[serde(rename_all = "UPPERCASE")]
 pub enum StatusCode {
     Init,
+    Running,
+    Completed,
+    Failed,
+    Crashed,
 }
 
+impl StatusCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StatusCode::Init => "INIT",
+            StatusCode::Running => "RUNNING",
+            StatusCode::Completed => "COMPLETED",
+            StatusCode::Failed => "FAILED",
+            StatusCode::Crashed => "CRASHED",
+        }
+    }
+
+    pub fn parse(status: &str) -> Option<Self> {
+        match status {
+            "INIT" => Some(StatusCode::Init),
+            "RUNNING" => Some(StatusCode::Running),
+            "COMPLETED" => Some(StatusCode::Completed),
+            "FAILED" => Some(StatusCode::Failed),
+            "CRASHED" => Some(StatusCode::Crashed),
+            _ => None,
+        }
+    }
+
+    // Whether the run has ended
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            StatusCode::Completed | StatusCode::Failed | StatusCode::Crashed
+        )
+    }
+
+    // Whether a run in this status may move to `next`
+    // CRASHED is inferred by the server, so a run that reports again can still move on
+    pub fn can_transition_to(&self, next: StatusCode) -> bool {
+        next != StatusCode::Init && !matches!(self, StatusCode::Completed | StatusCode::Failed)
+    }
+}
+
+/// Data sent when creating a run, the run ID is allocated by the server
+///
+/// # Example
+/// ```json
+/// {
+///     "status": "INIT",
+///     "data": {
+///         "runName": "resnet-baseline",
+///         "config": { "lr": 0.001 },
+///         "metadata": { "host": "gpu-01" }
+///     }
+/// }
+/// ```
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
 pub struct InitData {
-    pub run_id: String,
     pub run_name: String,
-    pub project_id: String,
+    #[serde(default)]
+    pub config: Value,
+    #[serde(default)]
+    pub metadata: Value,
+}
+
+/// Data sent when a run moves to another status
+///
+/// # Example
+/// ```json
+/// {
+///     "status": "FAILED",
+///     "data": { "exitCode": 1, "endTime": 1700000000000 }
+/// }
+/// ```
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct TransitionData {
+    pub exit_code: Option<i32>,
+    pub end_time: Option<u64>, // Milliseconds since the Unix epoch, defaults to now for final statuses
+    pub metadata: Option<Value>, // Replaces the run's metadata if set
+}
+
+// Body of a POST /status request, the `data` shape depends on the status
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "status", content = "data", rename_all = "UPPERCASE")]
+pub enum StatusRequest {
+    Init(InitData),
+    Running(#[serde(default)] TransitionData),
+    Completed(#[serde(default)] TransitionData),
+    Failed(#[serde(default)] TransitionData),
+    Crashed(#[serde(default)] TransitionData),
+}
+
+impl StatusRequest {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            StatusRequest::Init(_) => StatusCode::Init,
+            StatusRequest::Running(_) => StatusCode::Running,
+            StatusRequest::Completed(_) => StatusCode::Completed,
+            StatusRequest::Failed(_) => StatusCode::Failed,
+            StatusRequest::Crashed(_) => StatusCode::Crashed,
+        }
+    }
+}
+
+// Identifies the run a status request applies to
+// The run ID is absent when a run is being created
+#[derive(Debug, Clone)]
+pub struct RunEnrichment {
+    pub tenant_id: String,
+    pub project_name: String,
+    pub run_id: Option<u64>,
+}
+
+impl RunEnrichment {
+    // Returns the run ID, for requests that address an existing run
+    pub fn require_run_id(&self) -> Result<u64, AppError> {
+        self.run_id.ok_or_else(|| missing_header_error("X-Run-Id"))
+    }
+}
+
+impl EnrichmentData for RunEnrichment {
+    fn from_headers(tenant_id: String, headers: &HeaderMap) -> Result<Self, AppError> {
+        let run_id = headers
+            .get("X-Run-Id")
+            .map(|h| {
+                h.to_str()
+                    .ok()
+                    .and_then(|v| v.parse::<u64>().ok())
+                    .ok_or_else(|| {
+                        AppError::new(
+                            ErrorCode::InvalidHeaderFormat,
+                            "X-Run-Id must be a numeric run ID",
+                        )
+                    })
+            })
+            .transpose()?;
+
+        let project_name = headers
+            .get("X-Project-Name")
+            .and_then(|h| h.to_str().ok())
+            .ok_or_else(|| missing_header_error("X-Project-Name"))?
+            .to_string();
+
+        Ok(Self {
+            tenant_id,
+            project_name,
+            run_id,
+        })
+    }
+
+    fn run_key(&self) -> Option<RunKey> {
+        self.run_id.map(|run_id| RunKey {
+            tenant_id: self.tenant_id.clone(),
+            project_name: self.project_name.clone(),
+            run_id,
+        })
+    }
+}
+
+// Latest state of a run as stored in ClickHouse
+// Every change inserts a new version, ReplacingMergeTree keeps the one with the latest `updatedAt`
+#[derive(Debug, Serialize, Deserialize, Row, Clone)]
+pub struct RunRow {
+    #[serde(rename = "tenantId")]
+    pub tenant_id: String,
+    #[serde(rename = "projectName")]
+    pub project_name: String,
+    #[serde(rename = "runId")]
+    pub run_id: u64,
+    #[serde(rename = "runName")]
+    pub run_name: String,
+    pub status: String,
+    #[serde(rename = "exitCode")]
+    pub exit_code: Option<i32>,
+    pub config: String,   // JSON
+    pub metadata: String, // JSON
+    #[serde(rename = "createdAt")]
+    pub created_at: u64,
+    #[serde(rename = "endedAt")]
+    pub ended_at: Option<u64>,
+    #[serde(rename = "lastSeenAt")]
+    pub last_seen_at: u64, // Last ingest call or heartbeat, persisted periodically
+    #[serde(rename = "updatedAt")]
+    pub updated_at: u64, // Version of the row
+}
+
+/// A run as returned by the API
+///
+/// # Example
+/// ```json
+/// {
+///     "runId": 123456789,
+///     "projectName": "vision",
+///     "runName": "resnet-baseline",
+///     "status": "COMPLETED",
+///     "exitCode": 0,
+///     "config": { "lr": 0.001 },
+///     "metadata": {},
+///     "createdAt": 1700000000000,
+///     "endedAt": 1700000360000,
+///     "lastSeenAt": 1700000359000,
+///     "updatedAt": 1700000360000
+/// }
+/// ```
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Run {
+    pub run_id: u64,
+    pub project_name: String,
+    pub run_name: String,
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    pub config: Value,
     pub metadata: Value,
+    pub created_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ended_at: Option<u64>,
+    pub last_seen_at: u64,
+    pub updated_at: u64,
+}
+
+impl From<RunRow> for Run {
+    fn from(row: RunRow) -> Self {
+        Self {
+            run_id: row.run_id,
+            project_name: row.project_name,
+            run_name: row.run_name,
+            status: row.status,
+            exit_code: row.exit_code,
+            config: serde_json::from_str(&row.config).unwrap_or(Value::Null),
+            metadata: serde_json::from_str(&row.metadata).unwrap_or(Value::Null),
+            created_at: row.created_at,
+            ended_at: row.ended_at,
+            last_seen_at: row.last_seen_at,
+            updated_at: row.updated_at,
+        }
+    }
 }
 
+/// Response to a heartbeat
+///
+/// # Example
+/// ```json
+/// { "message": "Heartbeat recorded", "lastSeenAt": 1700000359000 }
+/// ```
 #[derive(Debug, Serialize, Deserialize)]
-pub struct StatusRequest {
-    pub status: StatusCode,
-    pub data: InitData,
+#[serde(rename_all = "camelCase")]
+pub struct HeartbeatResponse {
+    pub message: String,
+    pub last_seen_at: u64,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct StatusResponse {
     pub message: String,
+    pub run: Run,
 }
@@ -0,0 +1,325 @@
+use axum::http::HeaderMap;
+use opentelemetry_proto::tonic::{
+    collector::{logs::v1::ExportLogsServiceRequest, metrics::v1::ExportMetricsServiceRequest},
+    common::v1::{any_value::Value, AnyValue, KeyValue},
+    logs::v1::LogRecord,
+    metrics::v1::{metric::Data, number_data_point, NumberDataPoint},
+    resource::v1::Resource,
+};
+
+use crate::error::{missing_header_error, AppError, ErrorCode};
+use crate::models::{log::LogRow, metrics::MetricRow};
+use crate::runs::now_millis;
+use crate::utils::log_group_from_log_name;
+
+// Resource attributes naming the project and run of the resource's records
+// The X-Project-Name and X-Run-Id headers are used for resources without them
+pub const PROJECT_ATTRIBUTE: &str = "mlop.project_name";
+pub const RUN_ID_ATTRIBUTE: &str = "mlop.run_id";
+// Data point attribute holding the step of a metric value, 0 if missing
+pub const STEP_ATTRIBUTE: &str = "step";
+
+// Project and run the records of a resource are written to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    pub project_name: String,
+    pub run_id: u64,
+}
+
+impl Target {
+    fn resolve(resource: Option<&Resource>, headers: &HeaderMap) -> Result<Self, AppError> {
+        let attributes = resource.map_or(&[][..], |r| &r.attributes[..]);
+        let header = |name: &str| headers.get(name).and_then(|h| h.to_str().ok());
+
+        let project_name = match attribute(attributes, PROJECT_ATTRIBUTE) {
+            Some(Value::StringValue(name)) if !name.is_empty() => name.clone(),
+            _ => header("X-Project-Name")
+                .ok_or_else(|| missing_header_error("X-Project-Name"))?
+                .to_string(),
+        };
+        let run_id = match attribute(attributes, RUN_ID_ATTRIBUTE) {
+            Some(Value::IntValue(id)) => u64::try_from(*id).ok(),
+            Some(Value::StringValue(id)) => id.parse().ok(),
+            Some(_) => None,
+            None => header("X-Run-Id")
+                .ok_or_else(|| missing_header_error("X-Run-Id"))?
+                .parse()
+                .ok(),
+        }
+        .ok_or_else(|| {
+            AppError::new(
+                ErrorCode::InvalidInput,
+                format!("'{}' must be a non-negative integer", RUN_ID_ATTRIBUTE),
+            )
+        })?;
+
+        Ok(Self {
+            project_name,
+            run_id,
+        })
+    }
+}
+
+// Rows converted from an export request
+pub struct Converted<T> {
+    pub targets: Vec<Target>, // Distinct project/run pairs of the rows
+    pub rows: Vec<T>,
+    pub rejected: u64, // Data points or log records that could not be converted
+    pub error_message: Option<String>, // Why the first rejected item was rejected
+}
+
+impl<T> Converted<T> {
+    fn new() -> Self {
+        Self {
+            targets: Vec::new(),
+            rows: Vec::new(),
+            rejected: 0,
+            error_message: None,
+        }
+    }
+
+    fn add_target(&mut self, target: &Target) {
+        if !self.targets.contains(target) {
+            self.targets.push(target.clone());
+        }
+    }
+
+    pub fn reject(&mut self, count: u64, message: impl FnOnce() -> String) {
+        if count > 0 {
+            self.rejected += count;
+            self.error_message.get_or_insert_with(message);
+        }
+    }
+}
+
+// Maps the gauge and sum data points of an export request to metric rows
+// The metric name becomes the log name, other metric types are rejected
+pub fn metric_rows(
+    request: ExportMetricsServiceRequest,
+    tenant_id: &str,
+    headers: &HeaderMap,
+) -> Result<Converted<MetricRow>, AppError> {
+    let mut converted = Converted::new();
+    for resource_metrics in request.resource_metrics {
+        let target = Target::resolve(resource_metrics.resource.as_ref(), headers)?;
+        converted.add_target(&target);
+
+        for metric in resource_metrics
+            .scope_metrics
+            .into_iter()
+            .flat_map(|scope| scope.metrics)
+        {
+            let data_points = match metric.data {
+                Some(Data::Gauge(gauge)) => gauge.data_points,
+                Some(Data::Sum(sum)) => sum.data_points,
+                Some(Data::Histogram(histogram)) => {
+                    let count = histogram.data_points.len() as u64;
+                    converted.reject(count, || unsupported(&metric.name, "histogram"));
+                    continue;
+                }
+                Some(Data::ExponentialHistogram(histogram)) => {
+                    let count = histogram.data_points.len() as u64;
+                    converted.reject(count, || unsupported(&metric.name, "exponential histogram"));
+                    continue;
+                }
+                Some(Data::Summary(summary)) => {
+                    let count = summary.data_points.len() as u64;
+                    converted.reject(count, || unsupported(&metric.name, "summary"));
+                    continue;
+                }
+                None => continue,
+            };
+            if metric.name.trim().is_empty() {
+                let count = data_points.len() as u64;
+                converted.reject(count, || "metric name cannot be empty".to_string());
+                continue;
+            }
+
+            for point in data_points {
+                match metric_row(&metric.name, &point, tenant_id, &target) {
+                    Some(row) => converted.rows.push(row),
+                    None => converted.reject(1, || {
+                        format!(
+                            "metric '{}' has a data point without a finite value",
+                            metric.name
+                        )
+                    }),
+                }
+            }
+        }
+    }
+    Ok(converted)
+}
+
+fn metric_row(
+    name: &str,
+    point: &NumberDataPoint,
+    tenant_id: &str,
+    target: &Target,
+) -> Option<MetricRow> {
+    let value = match point.value? {
+        number_data_point::Value::AsDouble(value) => value,
+        number_data_point::Value::AsInt(value) => value as f64,
+    };
+    if !value.is_finite() {
+        return None;
+    }
+    let step = match attribute(&point.attributes, STEP_ATTRIBUTE) {
+        Some(Value::IntValue(step)) => u64::try_from(*step).unwrap_or(0),
+        Some(Value::DoubleValue(step)) if *step >= 0.0 => *step as u64,
+        _ => 0,
+    };
+
+    Some(MetricRow {
+        time: millis(point.time_unix_nano),
+        step,
+        log_group: log_group_from_log_name(name),
+        log_name: name.to_string(),
+        value,
+        tenant_id: tenant_id.to_string(),
+        run_id: target.run_id,
+        project_name: target.project_name.clone(),
+    })
+}
+
+// Maps the log records of an export request to log rows
+// The severity becomes the log type, line numbers are assigned per run when the rows are ingested
+pub fn log_rows(
+    request: ExportLogsServiceRequest,
+    tenant_id: &str,
+    headers: &HeaderMap,
+) -> Result<Converted<LogRow>, AppError> {
+    let mut converted = Converted::new();
+    for resource_logs in request.resource_logs {
+        let target = Target::resolve(resource_logs.resource.as_ref(), headers)?;
+        converted.add_target(&target);
+
+        for record in resource_logs
+            .scope_logs
+            .into_iter()
+            .flat_map(|scope| scope.log_records)
+        {
+            converted.rows.push(LogRow {
+                time: millis(match record.time_unix_nano {
+                    0 => record.observed_time_unix_nano,
+                    time => time,
+                }),
+                message: record
+                    .body
+                    .as_ref()
+                    .map(any_value_to_string)
+                    .unwrap_or_default(),
+                line_number: 0,
+                log_type: log_type(&record),
+                tenant_id: tenant_id.to_string(),
+                run_id: target.run_id,
+                project_name: target.project_name.clone(),
+            });
+        }
+    }
+    Ok(converted)
+}
+
+// The severity text, or the name of the severity number's range
+fn log_type(record: &LogRecord) -> String {
+    if !record.severity_text.trim().is_empty() {
+        return record.severity_text.trim().to_ascii_uppercase();
+    }
+    match record.severity_number {
+        1..=4 => "TRACE",
+        5..=8 => "DEBUG",
+        13..=16 => "WARN",
+        17..=20 => "ERROR",
+        21..=24 => "FATAL",
+        _ => "INFO",
+    }
+    .to_string()
+}
+
+fn attribute<'a>(attributes: &'a [KeyValue], key: &str) -> Option<&'a Value> {
+    attributes
+        .iter()
+        .find(|kv| kv.key == key)
+        .and_then(|kv| kv.value.as_ref())
+        .and_then(|v| v.value.as_ref())
+}
+
+fn any_value_to_string(value: &AnyValue) -> String {
+    match &value.value {
+        Some(Value::StringValue(s)) => s.clone(),
+        Some(Value::BoolValue(b)) => b.to_string(),
+        Some(Value::IntValue(i)) => i.to_string(),
+        Some(Value::DoubleValue(d)) => d.to_string(),
+        Some(Value::BytesValue(bytes)) => String::from_utf8_lossy(bytes).into_owned(),
+        // Arrays and maps are kept as their OTLP JSON encoding
+        Some(_) => serde_json::to_string(value).unwrap_or_default(),
+        None => String::new(),
+    }
+}
+
+// OTLP timestamps are nanoseconds, rows use milliseconds (the receive time if unset)
+fn millis(unix_nano: u64) -> u64 {
+    match unix_nano {
+        0 => now_millis(),
+        nanos => nanos / 1_000_000,
+    }
+}
+
+fn unsupported(name: &str, kind: &str) -> String {
+    format!(
+        "metric '{}' is a {}, only gauges and sums are supported",
+        name, kind
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metric_rows_from_json() {
+        let request: ExportMetricsServiceRequest = serde_json::from_value(serde_json::json!({
+            "resourceMetrics": [{
+                "resource": { "attributes": [
+                    { "key": "mlop.project_name", "value": { "stringValue": "p" } },
+                    { "key": "mlop.run_id", "value": { "intValue": "7" } }
+                ] },
+                "scopeMetrics": [{ "metrics": [
+                    { "name": "train/loss", "gauge": { "dataPoints": [
+                        { "timeUnixNano": "1700000000000000000", "asDouble": 0.5,
+                          "attributes": [{ "key": "step", "value": { "intValue": "3" } }] }
+                    ] } },
+                    { "name": "latency", "histogram": { "dataPoints": [{}] } }
+                ] }]
+            }]
+        }))
+        .unwrap();
+
+        let converted = metric_rows(request, "org", &HeaderMap::new()).unwrap();
+        assert_eq!(
+            converted.targets,
+            [Target {
+                project_name: "p".to_string(),
+                run_id: 7
+            }]
+        );
+        let row = &converted.rows[0];
+        assert_eq!((row.time, row.step, row.value), (1_700_000_000_000, 3, 0.5));
+        assert_eq!(
+            (row.log_group.as_str(), row.log_name.as_str()),
+            ("train", "train/loss")
+        );
+        assert_eq!(converted.rejected, 1);
+
+        // Without resource attributes the headers name the run
+        let request = ExportMetricsServiceRequest {
+            resource_metrics: vec![Default::default()],
+        };
+        assert!(metric_rows(request.clone(), "org", &HeaderMap::new()).is_err());
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Project-Name", "q".parse().unwrap());
+        headers.insert("X-Run-Id", "8".parse().unwrap());
+        let converted = metric_rows(request, "org", &headers).unwrap();
+        assert_eq!(converted.targets[0].run_id, 8);
+    }
+}
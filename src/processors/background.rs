@@ -1,24 +1,75 @@
 use clickhouse::Client;
 use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
 use std::time::Instant;
 use tokio::select;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
 use tokio::time::{sleep, Duration};
 use tracing::{debug, error, info, instrument, warn, Instrument};
 
 use crate::config::{Config, FlushConfig};
+use crate::processors::fair_queue::FairReceiver;
+use crate::processors::spool::Spool;
+use crate::telemetry::{
+    DROPPED_BATCHES, FAILED_BATCHES, FLUSH_BATCH_SIZE, FLUSH_DURATION, FLUSH_RETRIES,
+};
 use crate::traits::{DatabaseRow, EnrichmentData, InputData};
 
+// How often pending spool segments are retried while ClickHouse is unhealthy
+const SPOOL_REPLAY_INTERVAL: Duration = Duration::from_secs(30);
+
+// Shared view of a background processor's progress, readable from request handlers
+pub struct ProcessorStatus {
+    flushes: watch::Sender<u64>, // Number of confirmed inserts, bumped after each one
+    consecutive_errors: AtomicU32, // Flushes that failed every attempt since the last success
+}
+
+impl Default for ProcessorStatus {
+    fn default() -> Self {
+        Self {
+            flushes: watch::channel(0).0,
+            consecutive_errors: AtomicU32::new(0),
+        }
+    }
+}
+
+impl ProcessorStatus {
+    // Returns a receiver that is notified whenever new rows were confirmed by ClickHouse
+    pub fn subscribe_flushes(&self) -> watch::Receiver<u64> {
+        self.flushes.subscribe()
+    }
+
+    // Number of failed flushes in a row, zero while ClickHouse accepts inserts
+    pub fn consecutive_errors(&self) -> u32 {
+        self.consecutive_errors.load(Ordering::Relaxed)
+    }
+
+    // Publishes the number of failed flushes in a row
+    pub fn record_errors(&self, consecutive_errors: u32) {
+        self.consecutive_errors
+            .store(consecutive_errors, Ordering::Relaxed);
+    }
+
+    fn record_flush(&self) {
+        self.flushes.send_modify(|flushes| *flushes += 1);
+    }
+}
+
 // Starts a generic background processor task
-// This task receives records of type `F` through an MPSC channel,
+// This task receives records of type `F` through a fair per-tenant channel,
 // buffers them, and periodically flushes them to a ClickHouse table
+// Runs until all senders are dropped and returns whether every record
+// was persisted to ClickHouse (false if batches remain in the spool)
 pub async fn start_background_processor<F, R, E>(
-    mut receiver: mpsc::Receiver<F>, // The channel receiver for incoming records
-    flush_config: FlushConfig,       // Configuration for batch size and flush interval
-    skip_upload: bool,               // Flag to skip actual database uploads (for testing)
-    config: Arc<Config>,             // Shared application configuration (for DB credentials etc)
-) where
+    mut receiver: FairReceiver<F>, // The channel receiver for incoming records
+    flush_config: FlushConfig,     // Configuration for batch size and flush interval
+    skip_upload: bool,             // Flag to skip actual database uploads (for testing)
+    config: Arc<Config>,           // Shared application configuration (for DB credentials etc)
+    mut spool: Spool,              // Write-ahead spool for batches not yet confirmed
+    status: Arc<ProcessorStatus>,  // Progress shared with request handlers
+) -> bool
+where
     F: DatabaseRow<R, E> + Send + 'static, // `F` must be a DatabaseRow, Send, and static lifetime
     R: InputData,
     E: EnrichmentData,
@@ -42,6 +93,14 @@ pub async fn start_background_processor<F, R, E>(
         let mut last_flush = Instant::now();
         // Count consecutive errors during flushing
         let mut consecutive_errors = 0;
+        // Track the last attempt at replaying spooled batches
+        let mut last_replay = Instant::now();
+
+        // Replay batches left behind by a previous run before accepting new ones
+        // A failed replay counts as a flush error so it is retried periodically
+        if !skip_upload && !replay_spool::<F>(&client, &mut spool, &status, &table_name).await {
+            consecutive_errors += 1;
+        }
 
         // Spawn a simple timer task to trigger inactivity checks periodically
         let (inactivity_tx, mut inactivity_rx) = mpsc::channel::<()>(1);
@@ -61,6 +120,9 @@ pub async fn start_background_processor<F, R, E>(
 
         // Main processing loop
         loop {
+            // Publish the error count for health checks
+            status.record_errors(consecutive_errors);
+
             // Pre-check: If buffer is completely full, force a flush immediately
             // This prevents the select! from potentially adding another record and exceeding capacity
             if buffer.len() >= flush_config.batch_size {
@@ -75,6 +137,8 @@ pub async fn start_background_processor<F, R, E>(
                         &mut buffer,
                         &mut consecutive_errors,
                         &mut last_flush,
+                        &mut spool,
+                        &status,
                         table_name.clone(),
                     )
                     .await;
@@ -95,12 +159,18 @@ pub async fn start_background_processor<F, R, E>(
                         // Branch 1.1: The input channel was closed
                         None => {
                             info!("Input channel closed. Performing final flush.");
+                            if skip_upload {
+                                info!("Exiting background processor.");
+                                break true; // Exit the loop
+                            }
                             // If there are remaining records in the buffer, perform a final flush
-                            if !buffer.is_empty() && !skip_upload {
-                                final_flush(&client, &mut buffer, table_name.clone()).await;
+                            if !buffer.is_empty() {
+                                final_flush(&client, &mut buffer, &mut spool, &status, table_name.clone()).await;
                             }
-                            info!("Exiting background processor.");
-                            break; // Exit the loop
+                            // Give batches spooled by earlier failures a last chance before exiting
+                            let persisted = replay_spool::<F>(&client, &mut spool, &status, &table_name).await;
+                            info!(persisted, "Exiting background processor.");
+                            break persisted; // Exit the loop
                         }
                     }
                 }
@@ -117,9 +187,19 @@ pub async fn start_background_processor<F, R, E>(
                             &mut buffer,
                             &mut consecutive_errors,
                             &mut last_flush,
+                            &mut spool,
+                            &status,
                             table_name.clone(),
                         ).await;
                     }
+                    // Periodically retry spooled batches so they are replayed once ClickHouse recovers,
+                    // even when no new records arrive
+                    if consecutive_errors > 0 && last_replay.elapsed() >= SPOOL_REPLAY_INTERVAL && !skip_upload {
+                        last_replay = Instant::now();
+                        if replay_spool::<F>(&client, &mut spool, &status, &table_name).await {
+                            consecutive_errors = 0;
+                        }
+                    }
                 }
             }
         }
@@ -128,13 +208,36 @@ pub async fn start_background_processor<F, R, E>(
     .await // Apply the tracing span to the entire async block
 }
 
+// Inserts a batch of records into a ClickHouse table in a single insert
+// `insert.end()` returning successfully is the confirmation that the batch was persisted
+pub async fn insert_batch<F>(
+    client: &Client,
+    table_name: &str,
+    records: &[F],
+) -> Result<(), clickhouse::error::Error>
+where
+    F: clickhouse::Row + serde::Serialize,
+{
+    let mut insert = client.insert(table_name)?;
+    // Write each record by reference from the original Vec
+    for record in records {
+        insert.write(record).await?;
+    }
+    // Finalize the insert operation
+    insert.end().await?;
+    Ok(())
+}
+
 // Function to flush a batch of records to ClickHouse with retry logic
-#[instrument(skip(client, buffer, consecutive_errors, last_flush, table_name), fields(batch_size = buffer.len()))]
+// The batch is written to the spool first and only truncated from it once the insert is confirmed
+#[instrument(skip(client, buffer, consecutive_errors, last_flush, spool, status, table_name), fields(batch_size = buffer.len()))]
 async fn flush_records<F, R, E>(
     client: &Client,              // ClickHouse client instance
     buffer: &mut VecDeque<F>,     // Buffer containing records to flush
     consecutive_errors: &mut u32, // Mutable counter for consecutive errors
     last_flush: &mut Instant,     // Mutable timestamp of the last successful flush
+    spool: &mut Spool,            // Write-ahead spool for this table
+    status: &ProcessorStatus,     // Progress shared with request handlers
     table_name: String,           // Name of the target ClickHouse table
 ) where
     F: DatabaseRow<R, E> + Send + 'static + std::fmt::Debug + Clone, // Added Clone requirement
@@ -149,7 +252,16 @@ async fn flush_records<F, R, E>(
     let records_to_flush: Vec<_> = buffer.drain(..).collect();
     let num_records = records_to_flush.len();
     // Update the span with the actual number of records being flushed
-    tracing::Span::current().record("batch_size", &num_records);
+    tracing::Span::current().record("batch_size", num_records);
+
+    // Persist the batch locally before attempting the insert
+    let segment = match spool.append(&records_to_flush).await {
+        Ok(segment) => Some(segment),
+        Err(e) => {
+            error!(error = %e, "Failed to write batch to spool, uploading without durability");
+            None
+        }
+    };
 
     let max_retries = 3; // Maximum number of retries for flushing
     let mut retry_count = 0;
@@ -158,52 +270,51 @@ async fn flush_records<F, R, E>(
 
     // Retry loop
     loop {
-        let client = client.clone();
         // Conditionally enable async insert based on batch size (heuristic)
-        let client = if num_records > 1000 {
-            client // Use synchronous insert for very large batches
+        let insert_client = if num_records > 1000 {
+            client.clone() // Use synchronous insert for very large batches
         } else {
             // Use async insert for smaller batches
+            // Wait for the async insert to be committed so the spool is only truncated for persisted rows
             client
+                .clone()
                 .with_option("async_insert", "1")
-                .with_option("wait_for_async_insert", "0")
+                .with_option("wait_for_async_insert", "1")
         };
 
         let start = Instant::now();
-        let table_name_clone = table_name.clone();
-        // Clone client and table_name for the inner async block
-        let current_client = client.clone();
-        let current_table_name = table_name_clone.clone();
-        // Execute the insertion in a separate async block for better tracing
-        let result = async {
-            // Outer block is not move
-            let insert_span = tracing::debug_span!("clickhouse_insert", count = num_records);
-            // Inner block is not move, captures what it needs
-            async {
-                let mut insert = current_client.insert(&current_table_name)?;
-                // Write each record by reference from the original Vec
-                for record in &records_to_flush {
-                    // Borrow records_to_flush
-                    insert.write(record).await?;
-                }
-                // Finalize the insert operation
-                insert.end().await?;
-                Ok::<_, clickhouse::error::Error>(())
-            }
+        // Execute the insertion in a separate span for better tracing
+        let insert_span = tracing::debug_span!("clickhouse_insert", count = num_records);
+        let result = insert_batch(&insert_client, &table_name, &records_to_flush)
             .instrument(insert_span)
-            .await
-        }
-        .await;
+            .await;
 
         match result {
             Ok(_) => {
                 // Success!
-                let elapsed_ms = start.elapsed().as_millis();
+                let elapsed = start.elapsed();
+                FLUSH_BATCH_SIZE
+                    .with_label_values(&[&table_name])
+                    .observe(num_records as f64);
+                FLUSH_DURATION
+                    .with_label_values(&[&table_name])
+                    .observe(elapsed.as_secs_f64());
+                let elapsed_ms = elapsed.as_millis();
                 info!(
                     elapsed_ms,
                     attempt = retry_count + 1,
                     "Successfully uploaded batch"
                 );
+                status.record_flush();
+                if let Some(segment) = segment {
+                    if let Err(e) = spool.remove(segment).await {
+                        error!(segment, error = %e, "Failed to truncate spool segment after upload");
+                    }
+                }
+                // ClickHouse is reachable again, replay anything left behind by earlier failures
+                if *consecutive_errors > 0 {
+                    replay_spool::<F>(client, spool, status, &table_name).await;
+                }
                 // Reset consecutive error count and update last flush time
                 *consecutive_errors = 0;
                 *last_flush = Instant::now();
@@ -217,17 +328,30 @@ async fn flush_records<F, R, E>(
 
                 // Check if max retries reached
                 if retry_count >= max_retries {
-                    error!(
-                        attempts = max_retries,
-                        "Failed to upload batch after multiple attempts. Dropping batch."
-                    );
+                    match segment {
+                        Some(segment) => {
+                            FAILED_BATCHES.with_label_values(&[&table_name]).inc();
+                            error!(
+                                attempts = max_retries,
+                                segment,
+                                "Failed to upload batch after multiple attempts. Batch retained in spool for replay."
+                            )
+                        }
+                        None => {
+                            DROPPED_BATCHES.with_label_values(&[&table_name]).inc();
+                            error!(
+                                attempts = max_retries,
+                                "Failed to upload batch after multiple attempts. Dropping batch."
+                            )
+                        }
+                    }
                     // Increment consecutive errors, update last flush (attempt) time, and return
                     *consecutive_errors += 1;
                     *last_flush = Instant::now();
-                    // Note: The records are dropped here as `records_to_flush` goes out of scope
                     return;
                 }
 
+                FLUSH_RETRIES.with_label_values(&[&table_name]).inc();
                 // Calculate exponential backoff duration
                 let backoff_duration = Duration::from_secs(2u64.pow(retry_count));
                 warn!(duration = ?backoff_duration, "Backing off before retry.");
@@ -239,10 +363,15 @@ async fn flush_records<F, R, E>(
 }
 
 // Function to perform a final flush attempt when the processor is shutting down
-// This uses a higher retry count and panics if flushing ultimately fails
-#[instrument(skip(client, buffer), fields(table = %table_name, batch_size = buffer.len()))]
-async fn final_flush<F, R, E>(client: &Client, buffer: &mut VecDeque<F>, table_name: String)
-where
+// Records that cannot be uploaded stay in the spool and are replayed on the next start
+#[instrument(skip(client, buffer, spool, status), fields(table = %table_name, batch_size = buffer.len()))]
+async fn final_flush<F, R, E>(
+    client: &Client,
+    buffer: &mut VecDeque<F>,
+    spool: &mut Spool,
+    status: &ProcessorStatus,
+    table_name: String,
+) where
     F: DatabaseRow<R, E> + Send + 'static + Clone, // Added Clone requirement
     R: InputData,
     E: EnrichmentData,
@@ -251,46 +380,46 @@ where
     let records_to_flush: Vec<_> = buffer.drain(..).collect();
     let num_records = records_to_flush.len();
     // Update span field
-    tracing::Span::current().record("batch_size", &num_records);
+    tracing::Span::current().record("batch_size", num_records);
 
     if num_records == 0 {
         info!("Final flush called with empty buffer, skipping.");
         return;
     }
 
+    // Persist the batch locally before attempting the insert
+    let segment = match spool.append(&records_to_flush).await {
+        Ok(segment) => Some(segment),
+        Err(e) => {
+            error!(error = %e, "Failed to write final batch to spool");
+            None
+        }
+    };
+
     let mut retry_count = 0;
     let max_retries = 5; // Higher retry limit for final flush
 
     info!(num_records, "Starting final flush");
 
-    // Retry loop (similar to flush_records, but panics on persistent failure)
+    // Retry loop (similar to flush_records)
     loop {
-        let table_name_clone = table_name.clone();
-        // Clone client and table_name for the inner async block
-        let current_client = client.clone();
-        let current_table_name = table_name_clone.clone();
-        match async {
-            // Outer block is not move
-            let insert_span = tracing::debug_span!("clickhouse_final_insert", count = num_records);
-            // Inner block is not move, captures what it needs
-            async {
-                let mut insert = current_client.insert(&current_table_name)?;
-                // Write records by reference from the original Vec
-                for record in &records_to_flush {
-                    // Borrow records_to_flush
-                    insert.write(record).await?;
-                }
-                insert.end().await?;
-                Ok::<_, clickhouse::error::Error>(())
-            }
+        let insert_span = tracing::debug_span!("clickhouse_final_insert", count = num_records);
+        match insert_batch(client, &table_name, &records_to_flush)
             .instrument(insert_span)
             .await
-        }
-        .await
         {
             Ok(_) => {
                 // Success!
+                FLUSH_BATCH_SIZE
+                    .with_label_values(&[&table_name])
+                    .observe(num_records as f64);
                 info!(num_records, "Successfully completed final flush");
+                status.record_flush();
+                if let Some(segment) = segment {
+                    if let Err(e) = spool.remove(segment).await {
+                        error!(segment, error = %e, "Failed to truncate spool segment after final flush");
+                    }
+                }
                 break; // Exit the loop and function
             }
             Err(e) => {
@@ -300,13 +429,26 @@ where
                 error!(attempt = retry_count, max_attempts = max_retries, error = %error_message, "Error in final flush");
                 // Check if max retries reached
                 if retry_count >= max_retries {
-                    // Panic if the final flush fails after all retries
-                    // This is considered a critical failure as data would be lost
-                    panic!(
-                        "Failed to flush final batch of {} records to table '{}' after {} attempts. Error: {}",
-                        num_records, table_name, max_retries, error_message
-                    );
+                    match segment {
+                        Some(segment) => {
+                            FAILED_BATCHES.with_label_values(&[&table_name]).inc();
+                            error!(
+                                num_records,
+                                segment,
+                                "Failed to flush final batch. Records retained in spool and will be replayed on next start."
+                            )
+                        }
+                        None => {
+                            DROPPED_BATCHES.with_label_values(&[&table_name]).inc();
+                            error!(
+                                num_records,
+                                "Failed to flush final batch and it could not be spooled. Records lost."
+                            )
+                        }
+                    }
+                    break;
                 }
+                FLUSH_RETRIES.with_label_values(&[&table_name]).inc();
                 // Calculate backoff and wait
                 let backoff_duration = Duration::from_secs(2u64.pow(retry_count));
                 warn!(duration = ?backoff_duration, "Backing off before final flush retry.");
@@ -315,3 +457,60 @@ where
         }
     }
 }
+
+// Inserts all pending spool segments, oldest first, truncating each once confirmed
+// Stops at the first failure so remaining segments are retried later
+// Returns true if the spool was fully drained
+#[instrument(skip(client, spool, status))]
+async fn replay_spool<F>(
+    client: &Client,
+    spool: &mut Spool,
+    status: &ProcessorStatus,
+    table_name: &str,
+) -> bool
+where
+    F: clickhouse::Row + serde::Serialize + serde::de::DeserializeOwned,
+{
+    let pending = match spool.pending().await {
+        Ok(pending) => pending,
+        Err(e) => {
+            error!(error = %e, "Failed to list spool segments");
+            return false;
+        }
+    };
+    if pending.is_empty() {
+        return true;
+    }
+
+    info!(segments = pending.len(), "Replaying spooled batches");
+    for segment in pending {
+        let records = match spool.read::<F>(segment).await {
+            Ok(records) => records,
+            // Corrupt segments are moved aside by the spool, keep going with the rest
+            Err(e) => {
+                error!(segment, error = %e, "Skipping unreadable spool segment");
+                continue;
+            }
+        };
+
+        match insert_batch(client, table_name, &records).await {
+            Ok(_) => {
+                info!(
+                    segment,
+                    num_records = records.len(),
+                    "Replayed spooled batch"
+                );
+                status.record_flush();
+                if let Err(e) = spool.remove(segment).await {
+                    error!(segment, error = %e, "Failed to truncate replayed spool segment");
+                    return false;
+                }
+            }
+            Err(e) => {
+                warn!(segment, error = %e, "Failed to replay spooled batch, will retry later");
+                return false;
+            }
+        }
+    }
+    true
+}
@@ -0,0 +1,205 @@
+use async_compression::tokio::bufread::{GzipDecoder, ZlibDecoder, ZstdDecoder};
+use axum::{
+    body::Body,
+    http::{header, HeaderMap},
+};
+use bytes::Bytes;
+use futures::{stream::BoxStream, StreamExt, TryStreamExt};
+use tokio::io::AsyncBufRead;
+use tokio_util::io::{ReaderStream, StreamReader};
+use tracing::{error, warn};
+
+use crate::error::{AppError, ErrorCode};
+
+// Size of the chunks read from a decompressor
+const DECOMPRESSED_CHUNK_SIZE: usize = 64 * 1024;
+
+// Content codings accepted for request bodies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentEncoding {
+    Identity,
+    Gzip,
+    Zstd,
+    Deflate, // zlib-wrapped deflate, as defined for HTTP
+}
+
+impl ContentEncoding {
+    // Resolves the coding from the `Content-Encoding` header, a missing header means identity
+    pub fn from_headers(headers: &HeaderMap) -> Result<Self, AppError> {
+        let Some(value) = headers.get(header::CONTENT_ENCODING) else {
+            return Ok(ContentEncoding::Identity);
+        };
+        let value = value.to_str().map_err(|_| {
+            AppError::new(
+                ErrorCode::InvalidHeaderFormat,
+                "Content-Encoding header contains invalid characters",
+            )
+        })?;
+
+        match value.trim().to_ascii_lowercase().as_str() {
+            "" | "identity" => Ok(ContentEncoding::Identity),
+            "gzip" | "x-gzip" => Ok(ContentEncoding::Gzip),
+            "zstd" => Ok(ContentEncoding::Zstd),
+            "deflate" => Ok(ContentEncoding::Deflate),
+            other => Err(AppError::new(
+                ErrorCode::InvalidHeaderFormat,
+                format!(
+                    "Unsupported Content-Encoding '{}', expected gzip, zstd or deflate",
+                    other
+                ),
+            )),
+        }
+    }
+}
+
+// Turns the request body into a stream of chunks, decompressed according to `Content-Encoding`
+// Decompression stops with a BufferOverflowError once more than `max_decompressed_bytes`
+// come out of a compressed body, so a small zip bomb cannot occupy the server
+pub fn decode_body(
+    headers: &HeaderMap,
+    body: Body,
+    max_decompressed_bytes: u64,
+) -> Result<BoxStream<'static, Result<Bytes, AppError>>, AppError> {
+    let encoding = ContentEncoding::from_headers(headers)?;
+    let stream = body.into_data_stream();
+    if encoding == ContentEncoding::Identity {
+        return Ok(stream
+            .map_err(|e| {
+                error!(error = %e, "Failed to read stream chunk");
+                AppError::new(
+                    ErrorCode::StreamProcessingError,
+                    format!("Failed to read stream chunk: {}", e),
+                )
+            })
+            .boxed());
+    }
+
+    let reader = StreamReader::new(stream.map_err(std::io::Error::other));
+    Ok(decompress(encoding, reader, max_decompressed_bytes))
+}
+
+// Reads the whole (decompressed) request body, for formats that cannot be processed as a stream
+// Uncompressed bodies are held in memory as well, so they get the same limit
+pub async fn read_body(
+    headers: &HeaderMap,
+    body: Body,
+    max_decompressed_bytes: u64,
+) -> Result<Vec<u8>, AppError> {
+    let mut stream = decode_body(headers, body, max_decompressed_bytes)?;
+    let mut bytes = Vec::new();
+    while let Some(chunk) = stream.next().await {
+        bytes.extend_from_slice(&chunk?);
+        if bytes.len() as u64 > max_decompressed_bytes {
+            return Err(AppError::new(
+                ErrorCode::BufferOverflowError,
+                format!(
+                    "Request body exceeds the limit of {} bytes",
+                    max_decompressed_bytes
+                ),
+            ));
+        }
+    }
+    Ok(bytes)
+}
+
+fn decompress<B>(
+    encoding: ContentEncoding,
+    reader: B,
+    max_decompressed_bytes: u64,
+) -> BoxStream<'static, Result<Bytes, AppError>>
+where
+    B: AsyncBufRead + Send + Unpin + 'static,
+{
+    let chunks = match encoding {
+        ContentEncoding::Gzip => {
+            let mut decoder = GzipDecoder::new(reader);
+            // Concatenated gzip members form a single body, like `cat a.gz b.gz`
+            decoder.multiple_members(true);
+            ReaderStream::with_capacity(decoder, DECOMPRESSED_CHUNK_SIZE).boxed()
+        }
+        ContentEncoding::Zstd => {
+            let mut decoder = ZstdDecoder::new(reader);
+            decoder.multiple_members(true);
+            ReaderStream::with_capacity(decoder, DECOMPRESSED_CHUNK_SIZE).boxed()
+        }
+        ContentEncoding::Deflate => {
+            ReaderStream::with_capacity(ZlibDecoder::new(reader), DECOMPRESSED_CHUNK_SIZE).boxed()
+        }
+        ContentEncoding::Identity => unreachable!("Identity bodies are not decompressed"),
+    };
+
+    let mut decompressed_bytes: u64 = 0;
+    chunks
+        .map(move |chunk| {
+            let chunk = chunk.map_err(|e| {
+                error!(error = %e, ?encoding, "Failed to decompress request body");
+                AppError::new(
+                    ErrorCode::StreamDecodingError,
+                    format!("Failed to decompress {:?} request body: {}", encoding, e),
+                )
+            })?;
+            decompressed_bytes += chunk.len() as u64;
+            if decompressed_bytes > max_decompressed_bytes {
+                warn!(
+                    limit = max_decompressed_bytes,
+                    ?encoding,
+                    "Decompressed request body exceeds the size limit"
+                );
+                return Err(AppError::new(
+                    ErrorCode::BufferOverflowError,
+                    format!(
+                        "Decompressed request body exceeds the limit of {} bytes",
+                        max_decompressed_bytes
+                    ),
+                ));
+            }
+            Ok(chunk)
+        })
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_compression::tokio::bufread::GzipEncoder;
+    use tokio::io::AsyncReadExt;
+
+    async fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        GzipEncoder::new(data)
+            .read_to_end(&mut compressed)
+            .await
+            .unwrap();
+        compressed
+    }
+
+    async fn collect(
+        mut stream: BoxStream<'static, Result<Bytes, AppError>>,
+    ) -> Result<Vec<u8>, AppError> {
+        let mut data = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            data.extend_from_slice(&chunk?);
+        }
+        Ok(data)
+    }
+
+    #[tokio::test]
+    async fn test_gzip_body_and_size_limit() {
+        let lines = b"{\"a\":1}\n".repeat(10_000);
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_ENCODING, "gzip".parse().unwrap());
+
+        let body = Body::from(gzip(&lines).await);
+        let decoded = collect(decode_body(&headers, body, 1 << 20).unwrap()).await;
+        assert_eq!(decoded.unwrap(), lines);
+
+        let body = Body::from(gzip(&lines).await);
+        let err = collect(decode_body(&headers, body, 1_000).unwrap())
+            .await
+            .unwrap_err();
+        assert!(matches!(err.code, ErrorCode::BufferOverflowError));
+
+        headers.insert(header::CONTENT_ENCODING, "br".parse().unwrap());
+        assert!(decode_body(&headers, Body::empty(), 1_000).is_err());
+    }
+}
@@ -0,0 +1,405 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{Notify, Semaphore};
+
+// Creates a channel with a bounded sub-queue per tenant, drained by deficit round robin
+// Each tenant waits only for space in its own sub-queue, and the receiver takes up to
+// `quantum` records from a tenant before moving on to the next tenant with queued records,
+// so a bulk upload from one tenant does not delay the records of the others
+// All tenants get the same quantum, there are no per-tenant weights
+pub fn fair_channel<T>(tenant_capacity: usize, quantum: usize) -> (FairSender<T>, FairReceiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            queues: HashMap::new(),
+            active: VecDeque::new(),
+            len: 0,
+            senders: 1,
+            closed: false,
+            sending_closed: false,
+        }),
+        records_available: Notify::new(),
+        tenant_capacity,
+        quantum: quantum.max(1),
+    });
+    (
+        FairSender {
+            shared: shared.clone(),
+            limits: SendLimits::default(),
+        },
+        FairReceiver { shared },
+    )
+}
+
+// Bounds on how long and into how full a channel `send` waits, unset means no bound
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SendLimits {
+    pub timeout: Option<Duration>, // Maximum wait for space in the tenant's sub-queue
+    pub high_water_mark: Option<usize>, // Records queued over all tenants at which sends fail
+}
+
+// Returned by `send` with the record that could not be sent
+#[derive(Debug)]
+pub enum SendError<T> {
+    Closed(T),  // The receiver is gone
+    Full(T),    // The channel is at its high-water mark
+    Timeout(T), // The tenant's sub-queue stayed full for the whole send timeout
+}
+
+impl<T> SendError<T> {
+    // Whether the channel is saturated, as opposed to closed
+    pub fn is_overloaded(&self) -> bool {
+        !matches!(self, SendError::Closed(_))
+    }
+
+    // Metric label of the error
+    pub fn reason(&self) -> &'static str {
+        match self {
+            SendError::Closed(_) => "closed",
+            SendError::Full(_) => "high_water_mark",
+            SendError::Timeout(_) => "timeout",
+        }
+    }
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Closed(_) => write!(f, "channel closed"),
+            SendError::Full(_) => write!(f, "channel is at its high-water mark"),
+            SendError::Timeout(_) => write!(f, "timed out waiting for space in the channel"),
+        }
+    }
+}
+
+struct TenantQueue<T> {
+    records: VecDeque<T>,
+    space: Arc<Semaphore>, // One permit per free slot
+    deficit: usize,        // Records the tenant may still take in its current turn
+}
+
+// Tenant IDs are shared between `queues` and `active`, so queuing a record does not allocate
+// Queues are removed once they are empty and no sender is waiting on them
+struct State<T> {
+    queues: HashMap<Arc<str>, TenantQueue<T>>,
+    active: VecDeque<Arc<str>>, // Tenants with queued records, in round robin order
+    len: usize,                 // Records queued over all tenants
+    senders: usize,             // Live senders, the channel closes when the last one is dropped
+    closed: bool,               // The receiver was dropped
+    sending_closed: bool,       // `close` was called, as if every sender was dropped
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    records_available: Notify,
+    tenant_capacity: usize,
+    quantum: usize,
+}
+
+pub struct FairSender<T> {
+    shared: Arc<Shared<T>>,
+    limits: SendLimits,
+}
+
+impl<T> Clone for FairSender<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().senders += 1;
+        Self {
+            shared: self.shared.clone(),
+            limits: self.limits,
+        }
+    }
+}
+
+impl<T> Drop for FairSender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.senders -= 1;
+        if state.senders == 0 {
+            // Wake the receiver so it sees that the channel is closed
+            self.shared.records_available.notify_one();
+        }
+    }
+}
+
+impl<T> FairSender<T> {
+    // Makes `send` fail instead of waiting beyond the given limits
+    pub fn with_limits(mut self, limits: SendLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    // Queues a record of `tenant_id`, waiting while the tenant's sub-queue is full
+    pub async fn send(&self, tenant_id: &str, record: T) -> Result<(), SendError<T>> {
+        let space = {
+            let mut state = self.shared.state.lock().unwrap();
+            if state.closed || state.sending_closed {
+                return Err(SendError::Closed(record));
+            }
+            if matches!(self.limits.high_water_mark, Some(mark) if state.len >= mark) {
+                return Err(SendError::Full(record));
+            }
+            match state.queues.get(tenant_id) {
+                Some(queue) => queue.space.clone(),
+                None => {
+                    let space = Arc::new(Semaphore::new(self.shared.tenant_capacity));
+                    let queue = TenantQueue {
+                        records: VecDeque::new(),
+                        space: space.clone(),
+                        deficit: 0,
+                    };
+                    state.queues.insert(tenant_id.into(), queue);
+                    space
+                }
+            }
+        };
+
+        let acquired = match self.limits.timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, space.acquire()).await {
+                Ok(acquired) => acquired,
+                Err(_) => return Err(SendError::Timeout(record)),
+            },
+            None => space.acquire().await,
+        };
+        // The semaphore is closed when the receiver is dropped or the channel is closed
+        let Ok(permit) = acquired else {
+            return Err(SendError::Closed(record));
+        };
+        // The permit is returned by the receiver once the record leaves the queue
+        permit.forget();
+
+        let mut state = self.shared.state.lock().unwrap();
+        let state = &mut *state;
+        // The channel may have been closed while the permit was acquired
+        if state.sending_closed {
+            space.add_permits(1);
+            return Err(SendError::Closed(record));
+        }
+        let (key, queue) = state
+            .queues
+            .get_key_value(tenant_id)
+            .expect("Queues are not removed while a sender holds their semaphore");
+        if queue.records.is_empty() {
+            state.active.push_back(key.clone());
+        }
+        let queue = state.queues.get_mut(tenant_id).unwrap();
+        queue.records.push_back(record);
+        state.len += 1;
+        self.shared.records_available.notify_one();
+        Ok(())
+    }
+
+    // Closes the channel for every sender, including clones held elsewhere
+    // The receiver still gets the records queued so far, then ends as if every sender was dropped
+    pub fn close(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.sending_closed = true;
+        // Fail waiting sends
+        for queue in state.queues.values() {
+            queue.space.close();
+        }
+        self.shared.records_available.notify_one();
+    }
+
+    // Records queued over all tenants
+    pub fn len(&self) -> usize {
+        self.shared.state.lock().unwrap().len
+    }
+
+    // Records queued over all tenants at which sends fail, if any
+    pub fn high_water_mark(&self) -> Option<usize> {
+        self.limits.high_water_mark
+    }
+
+    // Space of the sub-queues of all tenants with queued records (at least one sub-queue)
+    pub fn capacity(&self) -> usize {
+        let active = self.shared.state.lock().unwrap().active.len();
+        self.shared.tenant_capacity * active.max(1)
+    }
+}
+
+pub struct FairReceiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Drop for FairReceiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.closed = true;
+        // Fail waiting and future sends
+        for queue in state.queues.values() {
+            queue.space.close();
+        }
+    }
+}
+
+impl<T> FairReceiver<T> {
+    // Returns the next record, or None once every sender was dropped (or the channel was
+    // closed) and the queues are empty
+    pub async fn recv(&mut self) -> Option<T> {
+        loop {
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if let Some(record) = self.next_record(&mut state) {
+                    return Some(record);
+                }
+                if state.senders == 0 || state.sending_closed {
+                    return None;
+                }
+            }
+            // Notify keeps a permit if records arrive before this point, so none are missed
+            self.shared.records_available.notified().await;
+        }
+    }
+
+    // Deficit round robin: the tenant at the front of `active` takes up to `quantum` records,
+    // then moves to the back if it still has records queued
+    fn next_record(&self, state: &mut State<T>) -> Option<T> {
+        let tenant_id = state.active.front()?.clone();
+        let queue = state
+            .queues
+            .get_mut(&tenant_id)
+            .expect("Active tenants have a queue");
+
+        if queue.deficit == 0 {
+            queue.deficit = self.shared.quantum;
+        }
+        let record = queue.records.pop_front()?;
+        queue.space.add_permits(1);
+        queue.deficit -= 1;
+
+        if queue.records.is_empty() {
+            // Idle tenants do not keep their remaining deficit, nor their queue unless a
+            // sender is waiting on it
+            queue.deficit = 0;
+            if Arc::strong_count(&queue.space) == 1 {
+                state.queues.remove(&tenant_id);
+            }
+            state.active.pop_front();
+        } else if queue.deficit == 0 {
+            state.active.rotate_left(1);
+        }
+        state.len -= 1;
+        Some(record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_tenants_are_served_in_turns() {
+        let (sender, mut receiver) = fair_channel(10, 2);
+        for i in 0..6 {
+            sender.send("bulk", format!("bulk-{}", i)).await.unwrap();
+        }
+        sender.send("small", "small-0".to_string()).await.unwrap();
+        assert_eq!(sender.len(), 7);
+        drop(sender);
+
+        let mut order = Vec::new();
+        while let Some(record) = receiver.recv().await {
+            order.push(record);
+        }
+        assert_eq!(
+            order,
+            ["bulk-0", "bulk-1", "small-0", "bulk-2", "bulk-3", "bulk-4", "bulk-5"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_full_tenant_does_not_block_others() {
+        let (sender, mut receiver) = fair_channel(1, 1);
+        sender.send("bulk", 1).await.unwrap();
+
+        // The bulk tenant's sub-queue is full, but another tenant can still send
+        let blocked =
+            tokio::time::timeout(std::time::Duration::from_millis(20), sender.send("bulk", 2));
+        assert!(blocked.await.is_err());
+        sender.send("small", 3).await.unwrap();
+
+        assert_eq!(receiver.recv().await, Some(1));
+        assert_eq!(receiver.recv().await, Some(3));
+    }
+
+    #[tokio::test]
+    async fn test_send_limits() {
+        let (sender, mut receiver) = fair_channel(2, 1);
+        let sender = sender.with_limits(SendLimits {
+            timeout: Some(std::time::Duration::from_millis(20)),
+            high_water_mark: Some(3),
+        });
+        sender.send("bulk", 1).await.unwrap();
+        sender.send("bulk", 2).await.unwrap();
+
+        // The bulk tenant's sub-queue is full, so the send gives up after the timeout
+        assert!(matches!(
+            sender.send("bulk", 3).await,
+            Err(SendError::Timeout(3))
+        ));
+        sender.send("small", 4).await.unwrap();
+
+        // Three records are queued in total, which is the high-water mark
+        assert!(matches!(
+            sender.send("other", 5).await,
+            Err(SendError::Full(5))
+        ));
+        assert_eq!(receiver.recv().await, Some(1));
+        sender.send("other", 5).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_idle_tenant_queues_are_removed() {
+        let (sender, mut receiver) = fair_channel(1, 1);
+        let queues = |sender: &FairSender<u32>| sender.shared.state.lock().unwrap().queues.len();
+        sender.send("bulk", 1).await.unwrap();
+        sender.send("small", 2).await.unwrap();
+        assert_eq!(queues(&sender), 2);
+
+        // A sender waiting for space keeps the bulk tenant's queue after it is drained
+        let waiting = sender.send("bulk", 3);
+        tokio::pin!(waiting);
+        assert!(futures::FutureExt::now_or_never(&mut waiting).is_none());
+        assert_eq!(receiver.recv().await, Some(1));
+        assert_eq!(receiver.recv().await, Some(2));
+        assert_eq!(queues(&sender), 1);
+
+        waiting.await.unwrap();
+        assert_eq!(receiver.recv().await, Some(3));
+        assert_eq!(queues(&sender), 0);
+    }
+
+    #[tokio::test]
+    async fn test_default_ingest_high_water_mark_is_reachable() {
+        use crate::config::{
+            DEFAULT_INGEST_HIGH_WATER_MARK, DEFAULT_INGEST_SEND_TIMEOUT, TENANT_QUEUE_CAPACITY,
+            TENANT_QUEUE_QUANTUM,
+        };
+
+        let (sender, _receiver) = fair_channel(TENANT_QUEUE_CAPACITY, TENANT_QUEUE_QUANTUM);
+        let sender = sender.with_limits(SendLimits {
+            timeout: Some(DEFAULT_INGEST_SEND_TIMEOUT),
+            high_water_mark: Some(DEFAULT_INGEST_HIGH_WATER_MARK),
+        });
+
+        // Tenants fill their queues until the mark is reached, a send that had to wait
+        // for space would fail after the timeout
+        let mut tenants = 0;
+        while sender.len() < DEFAULT_INGEST_HIGH_WATER_MARK {
+            for record in 0..TENANT_QUEUE_CAPACITY {
+                sender.send(&tenants.to_string(), record).await.unwrap();
+            }
+            tenants += 1;
+        }
+        assert_eq!(sender.len(), DEFAULT_INGEST_HIGH_WATER_MARK);
+        assert_eq!(tenants, 10);
+
+        // Then every tenant is shed right away
+        assert!(matches!(
+            sender.send("another", 0).await,
+            Err(SendError::Full(0))
+        ));
+    }
+}
@@ -0,0 +1,145 @@
+use axum::http::{header, HeaderMap};
+
+use crate::error::{AppError, ErrorCode};
+use crate::traits::ProtobufInput;
+
+// Largest length-delimited record accepted, larger prefixes are treated as corrupt input
+const MAX_RECORD_BYTES: u64 = 16 * 1024 * 1024;
+
+// Encoding of the records in an ingest request body, negotiated on `Content-Type`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BodyFormat {
+    // Newline-delimited JSON (default, also used for unknown or missing content types)
+    #[default]
+    NdJson,
+    // MessagePack maps with the same field names as the JSON records, each prefixed by its length
+    MessagePack,
+    // Messages of proto/ingest.proto, each prefixed by its length
+    Protobuf,
+}
+
+impl BodyFormat {
+    pub fn from_headers(headers: &HeaderMap) -> Result<Self, AppError> {
+        let Some(value) = headers.get(header::CONTENT_TYPE) else {
+            return Ok(BodyFormat::NdJson);
+        };
+        let value = value.to_str().map_err(|_| {
+            AppError::new(
+                ErrorCode::InvalidHeaderFormat,
+                "Content-Type header contains invalid characters",
+            )
+        })?;
+
+        // Ignore parameters such as `; charset=utf-8`
+        let media_type = value.split(';').next().unwrap_or("").trim();
+        match media_type.to_ascii_lowercase().as_str() {
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                Ok(BodyFormat::MessagePack)
+            }
+            "application/protobuf"
+            | "application/x-protobuf"
+            | "application/vnd.google.protobuf" => Ok(BodyFormat::Protobuf),
+            _ => Ok(BodyFormat::NdJson),
+        }
+    }
+
+    // Whether records are length-delimited instead of newline-delimited
+    pub fn is_length_delimited(&self) -> bool {
+        !matches!(self, BodyFormat::NdJson)
+    }
+}
+
+// Finds the next length-delimited record at the start of `buffer`
+// Returns the length of the varint prefix and of the record, or None if more bytes are needed
+// The prefix is the base 128 varint used by Protobuf's `writeDelimitedTo`
+pub fn next_delimited_record(buffer: &[u8]) -> Result<Option<(usize, usize)>, AppError> {
+    let mut length: u64 = 0;
+    for (i, &byte) in buffer.iter().enumerate().take(10) {
+        length |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            if length > MAX_RECORD_BYTES {
+                return Err(AppError::new(
+                    ErrorCode::BufferOverflowError,
+                    format!(
+                        "Record of {} bytes exceeds the limit of {} bytes",
+                        length, MAX_RECORD_BYTES
+                    ),
+                ));
+            }
+            let prefix = i + 1;
+            let length = length as usize;
+            return Ok((buffer.len() >= prefix + length).then_some((prefix, length)));
+        }
+    }
+    if buffer.len() >= 10 {
+        return Err(AppError::new(
+            ErrorCode::StreamDecodingError,
+            "Invalid record length prefix",
+        ));
+    }
+    Ok(None)
+}
+
+// Decodes a MessagePack record into the input type
+pub fn decode_msgpack<R: serde::de::DeserializeOwned>(record: &[u8]) -> Result<R, AppError> {
+    rmp_serde::from_slice(record).map_err(|e| {
+        AppError::new(
+            ErrorCode::StreamDecodingError,
+            format!("Failed to parse MessagePack record: {}", e),
+        )
+    })
+}
+
+// Decodes a Protobuf record into the input type
+pub fn decode_protobuf<R: ProtobufInput>(record: &[u8]) -> Result<R, AppError> {
+    let message = <R::Message as prost::Message>::decode(record).map_err(|e| {
+        AppError::new(
+            ErrorCode::StreamDecodingError,
+            format!("Failed to parse Protobuf record: {}", e),
+        )
+    })?;
+    Ok(R::from_message(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_delimited_record() {
+        // 300 is encoded as 0xac 0x02
+        let mut buffer = vec![0xac, 0x02];
+        assert_eq!(next_delimited_record(&buffer).unwrap(), None);
+        buffer.resize(2 + 300, 0);
+        assert_eq!(next_delimited_record(&buffer).unwrap(), Some((2, 300)));
+        assert_eq!(next_delimited_record(&[0x00]).unwrap(), Some((1, 0)));
+        assert_eq!(next_delimited_record(&[]).unwrap(), None);
+
+        let err = next_delimited_record(&[0xff, 0xff, 0xff, 0x7f]).unwrap_err();
+        assert!(matches!(err.code, ErrorCode::BufferOverflowError));
+    }
+
+    #[test]
+    fn test_decode_binary_records() {
+        use crate::models::metrics::{MetricInput, MetricMessage};
+        use prost::Message;
+        use std::collections::HashMap;
+
+        let message = MetricMessage {
+            time: 1,
+            step: 2,
+            data: HashMap::from([("loss".to_string(), 0.5)]),
+        };
+        let input: MetricInput = decode_protobuf(&message.encode_to_vec()).unwrap();
+        assert_eq!((input.time, input.step, input.data["loss"]), (1, 2, 0.5));
+
+        // MessagePack records use the JSON field names, integers are accepted for floats
+        let record = rmp_serde::to_vec_named(&serde_json::json!({
+            "time": 1, "step": 2, "data": { "loss": 1 }
+        }))
+        .unwrap();
+        let input: MetricInput = decode_msgpack(&record).unwrap();
+        assert_eq!(input.data["loss"], 1.0);
+        assert!(decode_msgpack::<MetricInput>(&[0xc1]).is_err());
+    }
+}
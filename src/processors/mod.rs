@@ -1,2 +1,6 @@
 pub mod background;
+pub mod decompress;
+pub mod fair_queue;
+pub mod format;
+pub mod spool;
 pub mod stream;
@@ -0,0 +1,241 @@
+use serde::{de::DeserializeOwned, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, error, info, warn};
+
+// File extension for completed spool segments
+const SEGMENT_EXTENSION: &str = "seg";
+// File extension for segments that are still being written
+const TMP_EXTENSION: &str = "tmp";
+// File extension for segments that could not be decoded during replay
+const CORRUPT_EXTENSION: &str = "corrupt";
+
+// Identifier of a single spool segment (monotonically increasing per table)
+pub type SegmentId = u64;
+
+// Durable on-disk write-ahead spool for a single ClickHouse table
+//
+// Every batch handed to the background processor is written to its own
+// append-only segment file before it is inserted. A segment is removed only
+// once ClickHouse has confirmed the insert, so batches that could not be
+// uploaded survive restarts and are replayed later.
+//
+// Layout: `<root>/<table>/<segment id>.seg`, one JSON row per line
+pub struct Spool {
+    dir: PathBuf,            // Directory holding this table's segments
+    next_segment: SegmentId, // Id assigned to the next appended segment
+}
+
+impl Spool {
+    // Opens (and creates if needed) the spool directory for the given table
+    // Leftover temporary files from an interrupted write are discarded
+    pub async fn open(root: impl AsRef<Path>, table_name: &str) -> io::Result<Self> {
+        let dir = root.as_ref().join(table_name);
+        fs::create_dir_all(&dir).await?;
+
+        let mut max_segment = 0;
+        let mut entries = fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            match path.extension().and_then(|e| e.to_str()) {
+                Some(SEGMENT_EXTENSION) => {
+                    if let Some(id) = segment_id_from_path(&path) {
+                        max_segment = max_segment.max(id);
+                    }
+                }
+                Some(TMP_EXTENSION) => {
+                    // A crash happened before the segment was completed and the
+                    // batch was never handed to ClickHouse, so it is safe to drop
+                    warn!(path = %path.display(), "Removing incomplete spool segment");
+                    fs::remove_file(&path).await?;
+                }
+                _ => {}
+            }
+        }
+
+        let spool = Self {
+            dir,
+            next_segment: max_segment + 1,
+        };
+        let pending = spool.pending().await?.len();
+        info!(dir = %spool.dir.display(), pending, "Spool opened");
+        Ok(spool)
+    }
+
+    // Durably writes a batch of records to a new segment and returns its id
+    // The segment only becomes visible (via rename) once fully written and synced
+    pub async fn append<F: Serialize>(&mut self, records: &[F]) -> io::Result<SegmentId> {
+        let id = self.next_segment;
+        self.next_segment += 1;
+
+        let mut contents = Vec::new();
+        for record in records {
+            serde_json::to_writer(&mut contents, record)?;
+            contents.push(b'\n');
+        }
+
+        let tmp_path = self.segment_path(id).with_extension(TMP_EXTENSION);
+        let mut file = fs::File::create(&tmp_path).await?;
+        file.write_all(&contents).await?;
+        file.sync_all().await?;
+        drop(file);
+        fs::rename(&tmp_path, self.segment_path(id)).await?;
+        self.sync_dir().await?;
+
+        debug!(
+            segment = id,
+            records = records.len(),
+            bytes = contents.len(),
+            "Batch written to spool"
+        );
+        Ok(id)
+    }
+
+    // Reads all records stored in a segment
+    // Segments that cannot be decoded are moved aside so they do not block replay
+    pub async fn read<F: DeserializeOwned>(&self, id: SegmentId) -> io::Result<Vec<F>> {
+        let path = self.segment_path(id);
+        let contents = fs::read(&path).await?;
+
+        let records = contents
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(serde_json::from_slice::<F>)
+            .collect::<Result<Vec<_>, _>>();
+
+        match records {
+            Ok(records) => Ok(records),
+            Err(e) => {
+                error!(segment = id, error = %e, "Spool segment is corrupt, moving it aside");
+                fs::rename(&path, path.with_extension(CORRUPT_EXTENSION)).await?;
+                self.sync_dir().await?;
+                Err(io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+        }
+    }
+
+    // Removes a segment after its records were confirmed by ClickHouse
+    pub async fn remove(&self, id: SegmentId) -> io::Result<()> {
+        fs::remove_file(self.segment_path(id)).await?;
+        debug!(segment = id, "Spool segment truncated");
+        Ok(())
+    }
+
+    // Lists the ids of all segments still waiting to be inserted, oldest first
+    pub async fn pending(&self) -> io::Result<Vec<SegmentId>> {
+        let mut ids = Vec::new();
+        let mut entries = fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) == Some(SEGMENT_EXTENSION) {
+                if let Some(id) = segment_id_from_path(&path) {
+                    ids.push(id);
+                }
+            }
+        }
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    // Makes renames within the directory durable, so a crash cannot undo them
+    // (e.g. losing a segment whose batch was already handed to ClickHouse)
+    async fn sync_dir(&self) -> io::Result<()> {
+        fs::File::open(&self.dir).await?.sync_all().await
+    }
+
+    fn segment_path(&self, id: SegmentId) -> PathBuf {
+        self.dir.join(format!("{:020}.{}", id, SEGMENT_EXTENSION))
+    }
+}
+
+fn segment_id_from_path(path: &Path) -> Option<SegmentId> {
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct TestRow {
+        step: u64,
+        value: f64,
+    }
+
+    fn test_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("spool-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        root
+    }
+
+    #[tokio::test]
+    async fn test_append_read_remove() {
+        let root = test_root("append");
+        let mut spool = Spool::open(&root, "mlop_metrics").await.unwrap();
+
+        let rows = vec![
+            TestRow {
+                step: 1,
+                value: 0.5,
+            },
+            TestRow {
+                step: 2,
+                value: 0.25,
+            },
+        ];
+        let id = spool.append(&rows).await.unwrap();
+        assert_eq!(spool.pending().await.unwrap(), vec![id]);
+        assert_eq!(spool.read::<TestRow>(id).await.unwrap(), rows);
+
+        spool.remove(id).await.unwrap();
+        assert!(spool.pending().await.unwrap().is_empty());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reopen_keeps_pending_segments() {
+        let root = test_root("reopen");
+        let mut spool = Spool::open(&root, "mlop_logs").await.unwrap();
+        let first = spool
+            .append(&[TestRow {
+                step: 1,
+                value: 1.0,
+            }])
+            .await
+            .unwrap();
+        let second = spool
+            .append(&[TestRow {
+                step: 2,
+                value: 2.0,
+            }])
+            .await
+            .unwrap();
+        std::fs::write(
+            root.join("mlop_logs").join("00000000000000000099.tmp"),
+            b"{",
+        )
+        .unwrap();
+        drop(spool);
+
+        let mut spool = Spool::open(&root, "mlop_logs").await.unwrap();
+        assert_eq!(spool.pending().await.unwrap(), vec![first, second]);
+        assert!(!root
+            .join("mlop_logs")
+            .join("00000000000000000099.tmp")
+            .exists());
+        let third = spool
+            .append(&[TestRow {
+                step: 3,
+                value: 3.0,
+            }])
+            .await
+            .unwrap();
+        assert!(third > second);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
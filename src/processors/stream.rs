@@ -1,19 +1,32 @@
-use axum::http::HeaderMap;
+use axum::{
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+    Json,
+};
 use bytes::Bytes;
-use futures::StreamExt;
+use futures::{stream::BoxStream, StreamExt};
+use serde::Serialize;
 use simd_json;
 use std::{
     sync::Arc,
     time::{Duration, Instant},
 };
-use tokio::sync::mpsc;
 use tracing::{debug, error, info, info_span, trace, warn, Instrument};
 
 use crate::{
-    auth::auth,
-    db::Database,
+    auth::{auth, AuthProvider, Scope},
+    config::DEFAULT_MAX_DECOMPRESSED_BYTES,
     error::{AppError, ErrorCode},
-    traits::{DatabaseRow, EnrichmentData, InputData, StreamProcessor},
+    heartbeat::HeartbeatTracker,
+    idempotency::{self, Claim, IdempotencyCache, IdempotencyGuard, IdempotencyKey},
+    processors::{
+        decompress,
+        fair_queue::{FairSender, SendError},
+        format::{self, BodyFormat},
+    },
+    rate_limit::RateLimiter,
+    telemetry::{tenant_label, LOAD_SHED, ROWS_RECEIVED},
+    traits::{DatabaseRow, EnrichmentData, InputData, ProtobufInput, StreamProcessor},
 };
 
 /// Trait for input data types that can be converted into one or more database rows,
@@ -46,6 +59,118 @@ where
     }
 }
 
+// Header used to opt into per-line error reporting
+pub const ERROR_MODE_HEADER: &str = "X-Error-Mode";
+// Rejected lines listed in a report, further ones are only counted, so a body full of invalid
+// lines cannot grow the report (or its idempotency cache entry) without bound
+pub const MAX_REPORTED_LINES: usize = 1_000;
+
+// How a stream processor reacts to lines that fail to parse or validate
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorMode {
+    // Stop at the first invalid line and return its error (rows from earlier lines are kept)
+    #[default]
+    Abort,
+    // Skip invalid lines and return a report listing the rejected lines
+    Report,
+}
+
+impl ErrorMode {
+    // Resolves the error mode from the `X-Error-Mode` header or the `errors` query parameter
+    // The header takes precedence when both are present
+    pub fn from_request(headers: &HeaderMap, query: Option<&str>) -> Result<Self, AppError> {
+        let value = match headers.get(ERROR_MODE_HEADER) {
+            Some(header) => Some(header.to_str().map_err(|_| {
+                AppError::new(
+                    ErrorCode::InvalidHeaderFormat,
+                    format!("{} header contains invalid characters", ERROR_MODE_HEADER),
+                )
+            })?),
+            None => query,
+        };
+
+        match value.map(|v| v.trim().to_ascii_lowercase()).as_deref() {
+            None | Some("") | Some("abort") => Ok(ErrorMode::Abort),
+            Some("report") => Ok(ErrorMode::Report),
+            Some(other) => Err(AppError::new(
+                ErrorCode::InvalidInput,
+                format!(
+                    "Invalid error mode '{}', expected 'abort' or 'report'",
+                    other
+                ),
+            )),
+        }
+    }
+}
+
+// A line that was skipped in `ErrorMode::Report`
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RejectedLine {
+    pub line: u64,   // 1-based line number within the request body
+    pub offset: u64, // Byte offset of the start of the line within the (decompressed) request body
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+/// Structured result of a stream processed in `ErrorMode::Report`
+///
+/// # Example
+/// ```json
+/// {
+///     "accepted": 12,
+///     "acceptedLines": 6,
+///     "rejectedLines": 1,
+///     "rejected": [
+///         { "line": 3, "offset": 118, "code": "INVALID_METRIC_FORMAT", "message": "..." }
+///     ]
+/// }
+/// ```
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamReport {
+    pub accepted: usize,     // Number of rows sent to the background processor
+    pub accepted_lines: u64, // Number of lines that produced rows
+    pub rejected_lines: u64, // Number of invalid lines, `rejected` lists the first MAX_REPORTED_LINES
+    pub rejected: Vec<RejectedLine>,
+}
+
+// Where a stream stopped by a rate limit or overload (errors with Retry-After) continues
+// when it is retried with the same idempotency key and body
+#[derive(Debug, Clone, Default)]
+pub struct StreamCheckpoint {
+    line: u64,        // Line (or binary record) the stream stopped at
+    line_rows: usize, // Rows of that line that were sent before the stream stopped
+    total_processed: usize,
+    accepted_lines: u64,
+    rejected_lines: u64,
+    rejected: Vec<RejectedLine>,
+}
+
+// Header marking a response that was replayed for a retried idempotent request
+pub const IDEMPOTENT_REPLAYED_HEADER: &str = "Idempotent-Replayed";
+
+// Response returned by a stream processor, depending on the requested error mode
+#[derive(Debug, Clone)]
+pub enum StreamResponse {
+    Summary(String),
+    Report(StreamReport),
+    // Outcome of an earlier request with the same idempotency key
+    Replayed(Box<StreamResponse>),
+}
+
+impl IntoResponse for StreamResponse {
+    fn into_response(self) -> Response {
+        match self {
+            StreamResponse::Summary(message) => message.into_response(),
+            StreamResponse::Report(report) => Json(report).into_response(),
+            StreamResponse::Replayed(response) => {
+                ([(IDEMPOTENT_REPLAYED_HEADER, "true")], *response).into_response()
+            }
+        }
+    }
+}
+
 // Processor implementation for handling newline-delimited JSON streams
 pub struct JsonLineProcessor<R, E, D>
 where
@@ -53,10 +178,18 @@ where
     E: EnrichmentData,             // E: Enrichment data type (e.g., from headers)
     D: DatabaseRow<R, E>,          // D: Target database row type
 {
-    record_sender: mpsc::Sender<D>, // Channel sender to the background processor for type D
+    record_sender: FairSender<D>, // Channel sender to the background processor for type D
     _raw_type: std::marker::PhantomData<R>, // Phantom data to hold the type R
     _enrichment_type: std::marker::PhantomData<E>, // Phantom data to hold the type E
-    db: Arc<Database>,              // Shared database connection (for auth)
+    auth_provider: Arc<dyn AuthProvider>, // Authenticates the request
+    scope: Scope,                 // Scope the API key must grant
+    error_mode: ErrorMode,        // How invalid lines are handled
+    idempotency: Option<Arc<IdempotencyCache>>, // Window of seen idempotency keys, if enabled
+    heartbeats: Option<Arc<HeartbeatTracker>>, // Last-seen times of runs, if tracked
+    rate_limiter: Option<Arc<RateLimiter>>, // Per-tenant rate limits and daily quotas, if any
+    max_decompressed_bytes: u64,  // Size limit of compressed bodies after decompression
+    format: BodyFormat,           // Encoding of the records, from the request's Content-Type
+    resume_at: Option<(u64, usize)>, // Line and rows of it already ingested by an earlier attempt
 }
 
 impl<R, E, D> JsonLineProcessor<R, E, D>
@@ -66,239 +199,849 @@ where
     D: DatabaseRow<R, E>,
 {
     // Constructor for the JsonLineProcessor
-    pub fn new(record_sender: mpsc::Sender<D>, db: Arc<Database>) -> Self {
+    pub fn new(
+        record_sender: FairSender<D>,
+        auth_provider: Arc<dyn AuthProvider>,
+        scope: Scope,
+    ) -> Self {
         Self {
             record_sender,
-            db,
+            auth_provider,
+            scope,
+            error_mode: ErrorMode::default(),
+            idempotency: None,
+            heartbeats: None,
+            rate_limiter: None,
+            max_decompressed_bytes: DEFAULT_MAX_DECOMPRESSED_BYTES,
+            format: BodyFormat::default(),
+            resume_at: None,
             _raw_type: std::marker::PhantomData,
             _enrichment_type: std::marker::PhantomData,
         }
     }
+
+    // Sets how lines that fail to parse or validate are handled
+    pub fn with_error_mode(mut self, error_mode: ErrorMode) -> Self {
+        self.error_mode = error_mode;
+        self
+    }
+
+    // Enables deduplication of retried requests carrying an `Idempotency-Key`/`X-Batch-Id` header
+    pub fn with_idempotency(mut self, idempotency: Arc<IdempotencyCache>) -> Self {
+        self.idempotency = Some(idempotency);
+        self
+    }
+
+    // Records every stream as a heartbeat of the run it belongs to
+    pub fn with_heartbeats(mut self, heartbeats: Arc<HeartbeatTracker>) -> Self {
+        self.heartbeats = Some(heartbeats);
+        self
+    }
+
+    // Charges every accepted line to the tenant's rate limits and daily quotas
+    pub fn with_rate_limiter(mut self, rate_limiter: Arc<RateLimiter>) -> Self {
+        // Skip the per-line bookkeeping if no tenant has any limit
+        if rate_limiter.is_enabled() {
+            self.rate_limiter = Some(rate_limiter);
+        }
+        self
+    }
+
+    // Sets how large a gzip, zstd or deflate body may grow when decompressed
+    pub fn with_max_decompressed_bytes(mut self, max_decompressed_bytes: u64) -> Self {
+        self.max_decompressed_bytes = max_decompressed_bytes;
+        self
+    }
+}
+
+// Running totals for a single stream
+#[derive(Default)]
+struct StreamProgress {
+    lines: u64,             // Lines read so far, including empty ones
+    line_rows: usize,       // Rows of the current line sent so far
+    total_processed: usize, // Rows sent to the background processor
+    accepted_lines: u64,    // Lines that produced rows
+    rejected_lines: u64,    // Invalid lines, the first MAX_REPORTED_LINES are kept in `rejected`
+    rejected: Vec<RejectedLine>,
+}
+
+impl StreamProgress {
+    // Totals of the earlier attempts of a resumed stream, lines are counted again from the start
+    fn resume(checkpoint: StreamCheckpoint) -> Self {
+        Self {
+            total_processed: checkpoint.total_processed,
+            accepted_lines: checkpoint.accepted_lines,
+            rejected_lines: checkpoint.rejected_lines,
+            rejected: checkpoint.rejected,
+            ..Self::default()
+        }
+    }
+
+    fn checkpoint(&self) -> StreamCheckpoint {
+        StreamCheckpoint {
+            line: self.lines,
+            line_rows: self.line_rows,
+            total_processed: self.total_processed,
+            accepted_lines: self.accepted_lines,
+            rejected_lines: self.rejected_lines,
+            rejected: self.rejected.clone(),
+        }
+    }
+}
+
+// Progress of a stream together with its claimed idempotency key, if any
+// The key is settled from the progress when the stream ends, or when the request is cancelled
+struct TrackedProgress {
+    progress: StreamProgress,
+    guard: Option<IdempotencyGuard>,
+    resumed_rows: usize, // Rows sent by the earlier attempts of a resumed request
+}
+
+impl TrackedProgress {
+    fn new(guard: Option<IdempotencyGuard>, progress: StreamProgress) -> Self {
+        Self {
+            resumed_rows: progress.total_processed,
+            progress,
+            guard,
+        }
+    }
+
+    // Records the outcome against the idempotency key, so retries with the same key are handled
+    fn settle(&mut self, result: &Result<StreamResponse, AppError>) {
+        let Some(guard) = self.guard.take() else {
+            return;
+        };
+        match result {
+            // Rate limits and overload invite a retry, which continues where this one stopped
+            Err(e) if e.retry_after.is_some() => guard.interrupt(self.progress.checkpoint()),
+            // Nothing was ingested, so the client may safely retry with the same key
+            Err(_) if self.progress.total_processed == 0 => guard.release(),
+            // Remember the outcome, including partial failures, so retries are not double counted
+            _ => guard.complete(result.clone()),
+        }
+    }
+}
+
+impl Drop for TrackedProgress {
+    // A request cancelled after sending rows (e.g. the client disconnected) leaves a checkpoint,
+    // so a retry with the same key continues after the rows that were already ingested
+    // Otherwise the guard releases the key, or restores the checkpoint it resumed from
+    fn drop(&mut self) {
+        if let Some(guard) = self.guard.take() {
+            if self.progress.total_processed > self.resumed_rows {
+                guard.interrupt(self.progress.checkpoint());
+            }
+        }
+    }
+}
+
+impl<R, E, D> JsonLineProcessor<R, E, D>
+where
+    R: InputData
+        + IntoRows<E, D>
+        + ProtobufInput
+        + Send
+        + 'static
+        + for<'de> serde::Deserialize<'de>,
+    E: EnrichmentData + Send + 'static + Clone,
+    D: DatabaseRow<R, E> + Send + 'static,
+{
+    // Parses, validates and sends a single line
+    // Invalid lines either abort the stream or are recorded, depending on the error mode
+    // Failing to hand rows to the background processor always aborts
+    async fn process_line(
+        &self,
+        line_bytes: &mut [u8],
+        line_number: u64,
+        line_offset: u64,
+        tenant_id: &str,
+        enrichment: &E,
+        progress: &mut StreamProgress,
+    ) -> Result<(), AppError> {
+        // Skip what an earlier attempt with the same idempotency key already ingested
+        let skip_rows = match self.resume_at {
+            Some((line, _)) if line_number < line => return Ok(()),
+            Some((line, rows)) if line_number == line => rows,
+            _ => 0,
+        };
+        progress.line_rows = skip_rows;
+
+        // Trim whitespace and newline characters from JSON lines, binary records are used as is
+        let (start, end) = if self.format.is_length_delimited() {
+            (0, line_bytes.len())
+        } else {
+            let start = line_bytes
+                .iter()
+                .position(|&b| !matches!(b, b' ' | b'\t'))
+                .unwrap_or(0);
+            let end = line_bytes
+                .iter()
+                .rposition(|&b| !matches!(b, b'\r' | b'\n' | b' ' | b'\t'))
+                .map_or(0, |p| p + 1);
+            (start, end)
+        };
+
+        // Check if the effective line is empty after trimming
+        if start >= end {
+            trace!(line = line_number, "Skipping empty line");
+            return Ok(());
+        }
+
+        // Use the trimmed slice view for processing, avoid extra allocation if possible
+        // This slice is mutable which simd_json needs
+        let trimmed_line_slice = &mut line_bytes[start..end];
+
+        let task_span = info_span!("process_line", line_num = line_number);
+        async {
+            let rows = match self.parse_line(trimmed_line_slice, enrichment) {
+                Ok(rows) => rows,
+                Err(app_err) => match self.error_mode {
+                    ErrorMode::Abort => {
+                        error!(error = %app_err, "Line processing failed");
+                        return Err(app_err); // Propagate error immediately
+                    }
+                    ErrorMode::Report => {
+                        warn!(error = %app_err, "Line rejected");
+                        progress.rejected_lines += 1;
+                        if progress.rejected.len() < MAX_REPORTED_LINES {
+                            progress.rejected.push(RejectedLine {
+                                line: line_number,
+                                offset: line_offset,
+                                code: app_err.code,
+                                message: app_err.message,
+                            });
+                        }
+                        return Ok(());
+                    }
+                },
+            };
+
+            let num_rows = rows.len();
+            // Rate limits always abort, the details tell the client where to resume
+            if let Some(rate_limiter) = &self.rate_limiter {
+                rate_limiter
+                    .acquire(
+                        tenant_id,
+                        (num_rows - skip_rows) as u64,
+                        (end - start) as u64,
+                    )
+                    .map_err(|e| AppError {
+                        details: Some(serde_json::json!({
+                            "line": line_number,
+                            "acceptedLines": progress.accepted_lines,
+                        })),
+                        ..e
+                    })?;
+            }
+            trace!(count = num_rows, "Converted to rows, sending to channel...");
+            let send_start = Instant::now();
+            // Queue the rows in the tenant's sub-queue of the background processor
+            // Fails fast with 503 if the channel is saturated, the lines before stay accepted
+            let mut sent = 0;
+            let result = send_rows(
+                &self.record_sender,
+                tenant_id,
+                D::table_name(),
+                rows.into_iter().skip(skip_rows),
+                &mut sent,
+            )
+            .await;
+            progress.line_rows += sent as usize;
+            progress.total_processed += sent as usize;
+            result.map_err(|e| {
+                if e.is_overloaded() {
+                    return AppError {
+                        details: Some(serde_json::json!({
+                            "line": line_number,
+                            "acceptedLines": progress.accepted_lines,
+                        })),
+                        ..AppError::from(e)
+                    };
+                }
+                error!(error = %e, "Failed to send record to background processor channel");
+                AppError::new(
+                    ErrorCode::StreamProcessingError,
+                    format!("Failed to send record to processor: {}", e),
+                )
+            })?;
+            let send_duration = send_start.elapsed();
+            if send_duration > Duration::from_millis(10) {
+                warn!(
+                    duration_ms = send_duration.as_millis(),
+                    "Sending rows to channel took longer than expected"
+                );
+            }
+            debug!(rows_processed = num_rows, "Line processed successfully");
+            progress.accepted_lines += 1;
+            Ok(())
+        }
+        .instrument(task_span)
+        .await
+    }
+
+    // Reads the (decompressed) body stream, splitting it into lines and processing them sequentially
+    // Records of the binary formats are split on their length prefix and counted as lines
+    async fn read_lines(
+        &self,
+        mut stream: BoxStream<'static, Result<Bytes, AppError>>,
+        tenant_id: &str,
+        enrichment: &E,
+        progress: &mut StreamProgress,
+    ) -> Result<(), AppError> {
+        let mut line_buffer = Vec::new(); // Buffer to accumulate bytes and find newlines
+        let mut buffer_offset: u64 = 0; // Byte offset of the start of `line_buffer` within the body
+
+        // Read chunks from the stream
+        while let Some(chunk_result) = stream.next().await {
+            let chunk: Bytes = chunk_result?;
+            trace!(bytes = chunk.len(), "Read chunk from stream");
+
+            // Append the chunk to the line buffer
+            line_buffer.extend_from_slice(&chunk);
+
+            // Process complete length-delimited records found in the buffer
+            if self.format.is_length_delimited() {
+                while let Some((prefix, length)) = format::next_delimited_record(&line_buffer)? {
+                    progress.lines += 1;
+                    let mut record = line_buffer
+                        .drain(..prefix + length)
+                        .skip(prefix)
+                        .collect::<Vec<u8>>();
+                    let record_offset = buffer_offset;
+                    buffer_offset += (prefix + length) as u64;
+
+                    self.process_line(
+                        &mut record,
+                        progress.lines,
+                        record_offset,
+                        tenant_id,
+                        enrichment,
+                        progress,
+                    )
+                    .await?;
+                }
+                continue;
+            }
+
+            // Process complete lines found in the buffer
+            while let Some(pos) = line_buffer.iter().position(|&b| b == b'\n') {
+                progress.lines += 1;
+                // Extract the line (including the newline character) and remove it from the buffer
+                let mut line_bytes = line_buffer.drain(..=pos).collect::<Vec<u8>>();
+                let line_offset = buffer_offset;
+                buffer_offset += line_bytes.len() as u64;
+
+                self.process_line(
+                    &mut line_bytes,
+                    progress.lines,
+                    line_offset,
+                    tenant_id,
+                    enrichment,
+                    progress,
+                )
+                .await?;
+            }
+        }
+
+        // A binary body must end with a complete record
+        if self.format.is_length_delimited() {
+            if !line_buffer.is_empty() {
+                warn!(
+                    bytes = line_buffer.len(),
+                    "Body ends with a truncated record"
+                );
+                return Err(AppError::with_details(
+                    ErrorCode::StreamDecodingError,
+                    "Body ends with a truncated record",
+                    serde_json::json!({
+                        "line": progress.lines + 1,
+                        "acceptedLines": progress.accepted_lines,
+                    }),
+                ));
+            }
+            return Ok(());
+        }
+
+        // After the stream ends, process any remaining data without a trailing newline
+        if line_buffer
+            .iter()
+            .any(|&b| !matches!(b, b'\r' | b'\n' | b' ' | b'\t'))
+        {
+            progress.lines += 1;
+            let mut remaining_bytes = std::mem::take(&mut line_buffer);
+            info!(
+                line = progress.lines,
+                bytes = remaining_bytes.len(),
+                "Processing remaining data in buffer"
+            );
+            self.process_line(
+                &mut remaining_bytes,
+                progress.lines,
+                buffer_offset,
+                tenant_id,
+                enrichment,
+                progress,
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    // Logs summary statistics and builds the response for a fully processed stream
+    fn finish(&self, progress: StreamProgress, start_time: Instant) -> StreamResponse {
+        // All lines processed sequentially
+        let final_count = progress.total_processed;
+        let total_duration_sec = start_time.elapsed().as_secs_f64();
+        let rate = if total_duration_sec > 0.0 {
+            final_count as f64 / total_duration_sec
+        } else {
+            0.0 // Avoid division by zero if processing was instantaneous
+        };
+
+        // Log final summary statistics
+        info!(
+            processed_records = final_count,
+            rejected_lines = progress.rejected_lines,
+            total_duration_sec = format!("{:.2}", total_duration_sec),
+            records_per_sec = format!("{:.2}", rate),
+            lines_processed = progress.lines, // Log total lines encountered
+            "Stream processing finished successfully"
+        );
+
+        match self.error_mode {
+            // Return a success message with the total count
+            ErrorMode::Abort => StreamResponse::Summary(format!(
+                "Stream processed successfully: {} records",
+                final_count
+            )),
+            // Return the structured report so the client can resend only the rejected lines
+            ErrorMode::Report => StreamResponse::Report(StreamReport {
+                accepted: final_count,
+                accepted_lines: progress.accepted_lines,
+                rejected_lines: progress.rejected_lines,
+                rejected: progress.rejected,
+            }),
+        }
+    }
+
+    // Parses and validates a single trimmed line (or binary record) into database rows
+    fn parse_line(&self, line: &mut [u8], enrichment: &E) -> Result<Vec<D>, AppError> {
+        let raw_data = match self.format {
+            BodyFormat::NdJson => self.parse_json(line)?,
+            BodyFormat::MessagePack => format::decode_msgpack::<R>(line)?,
+            BodyFormat::Protobuf => format::decode_protobuf::<R>(line)?,
+        };
+        trace!("Record parsed successfully, validating...");
+        raw_data.validate()?;
+        trace!("Validation successful, converting to rows...");
+        raw_data.into_rows(enrichment.clone()) // Clone enrichment per line
+    }
+
+    fn parse_json(&self, line: &mut [u8]) -> Result<R, AppError> {
+        let line_preview = String::from_utf8_lossy(line)
+            .chars()
+            .take(100)
+            .collect::<String>();
+
+        trace!("Attempting to parse JSON line");
+        simd_json::from_slice::<R>(line).map_err(|e| {
+            error!(error = %e, line = %line_preview, "Failed to parse JSON line");
+            AppError::new(
+                ErrorCode::StreamDecodingError,
+                format!(
+                    "Failed to parse JSON line (bytes): '{}': {}",
+                    line_preview, e
+                ),
+            )
+        })
+    }
 }
 
 // Implementation of the StreamProcessor trait for JsonLineProcessor
 impl<R, E, D> StreamProcessor<R, E, D> for JsonLineProcessor<R, E, D>
 where
-    R: InputData + IntoRows<E, D> + Send + 'static + for<'de> serde::Deserialize<'de>,
+    R: InputData
+        + IntoRows<E, D>
+        + ProtobufInput
+        + Send
+        + 'static
+        + for<'de> serde::Deserialize<'de>,
     E: EnrichmentData + Send + 'static + Clone,
     D: DatabaseRow<R, E> + Send + 'static,
 {
     // Processes an incoming request body stream containing newline-delimited JSON
     async fn process_stream(
-        self,
-        headers: HeaderMap,
+        mut self,
+        mut headers: HeaderMap,
         body: axum::body::Body,
-    ) -> Result<String, AppError> {
+    ) -> Result<StreamResponse, AppError> {
         // Authenticate the request using headers
-        let auth_details = auth(&headers, &self.db).await?;
+        let auth_details = auth(&mut headers, self.auth_provider.as_ref(), self.scope).await?;
         let tenant_id = auth_details.tenant_id;
 
         // Create a tracing span for this stream processing operation
-        let span = info_span!("process_stream", tenant_id = %tenant_id);
+        let span =
+            info_span!("process_stream", tenant_id = %tenant_id, error_mode = ?self.error_mode);
         async move {
             info!("Starting stream processing");
             // Extract enrichment data from headers specific to this data type
-            let enrichment = E::from_headers(tenant_id, &headers)?;
-
-            // Convert the request body into an asynchronous stream of Bytes chunks
-            let mut stream = body.into_data_stream();
-            let mut total_processed: usize = 0;
-            let start_time = Instant::now(); // Track overall processing time
-            let mut line_buffer = Vec::new(); // Buffer to accumulate bytes and find newlines
-            let mut line_counter: u64 = 0; // Counter for lines processed (for logging)
-
-            // Read chunks from the stream
-            while let Some(chunk_result) = stream.next().await {
-                let chunk: Bytes = chunk_result.map_err(|e| {
-                    error!(error = %e, "Failed to read stream chunk");
-                    AppError::new(
-                        ErrorCode::StreamProcessingError,
-                        format!("Failed to read stream chunk: {}", e),
-                    )
-                })?;
-                trace!(bytes = chunk.len(), "Read chunk from stream");
-
-                // Append the chunk to the line buffer
-                line_buffer.extend_from_slice(&chunk);
-
-                // Process complete lines found in the buffer
-                while let Some(pos) = line_buffer.iter().position(|&b| b == b'\n') {
-                    line_counter += 1;
-                    // Extract the line (including the newline character) and remove it from the buffer
-                    let mut line_bytes_mut = line_buffer.drain(..=pos).collect::<Vec<u8>>();
-                    // Trim whitespace and newline characters from the extracted line
-                    let start = line_bytes_mut.iter().position(|&b| !matches!(b, b' ' | b'\t')).unwrap_or(0);
-                    let end = line_bytes_mut.iter().rposition(|&b| !matches!(b, b'\r' | b'\n' | b' ' | b'\t')).map_or(0, |p| p + 1);
-                    
-                    // Check if the effective line is empty after trimming conceptually
-                    if start >= end {
-                        trace!(line = line_counter, "Skipping empty line");
-                        continue;
-                    }
-
-                    // Use the trimmed slice view for processing, avoid extra allocation if possible
-                    // This slice is mutable which simd_json needs
-                    let trimmed_line_slice = &mut line_bytes_mut[start..end];
+            let enrichment = E::from_headers(tenant_id.clone(), &headers)?;
+            if let (Some(heartbeats), Some(run_key)) = (&self.heartbeats, enrichment.run_key()) {
+                heartbeats.record(run_key);
+            }
 
-                    // Skip empty lines (double check after slice)
-                    if trimmed_line_slice.is_empty() {
-                        trace!(line = line_counter, "Skipping empty line (post-slice)");
-                        continue;
-                    }
+            // Undo any Content-Encoding (gzip, zstd or deflate) before the lines are split
+            let body = decompress::decode_body(&headers, body, self.max_decompressed_bytes)?;
+            // NDJSON unless Content-Type names MessagePack or Protobuf
+            self.format = BodyFormat::from_headers(&headers)?;
 
-                    // ---- Start Sequential Line Processing ----
-                    let line_data_for_error = trimmed_line_slice.to_vec(); // Clone only for potential error message
-                    let task_span = info_span!("process_line", line_num = line_counter);
-                    let process_result = async {
-                        trace!("Attempting to parse JSON line");
-                        match simd_json::from_slice::<R>(trimmed_line_slice) {
-                            Ok(raw_data) => {
-                                trace!("JSON parsed successfully, validating...");
-                                raw_data.validate()?;
-                                trace!("Validation successful, converting to rows...");
-                                let rows = raw_data.into_rows(enrichment.clone())?; // Clone enrichment per line
-                                let num_rows = rows.len();
-                                trace!(count = num_rows, "Converted to rows, sending to channel...");
-                                let mut processed_in_task = 0;
-                                for row in rows {
-                                    let send_start = Instant::now();
-                                    trace!("Sending record to background channel...");
-                                    // Send directly using self.record_sender
-                                    self.record_sender.send(row).await.map_err(|e| { 
-                                        error!(error = %e, "Failed to send record to background processor channel");
-                                        AppError::new(
-                                            ErrorCode::StreamProcessingError,
-                                            format!("Failed to send record to processor: {}", e),
-                                        )
-                                    })?;
-                                    let send_duration = send_start.elapsed();
-                                    trace!(duration_ms = send_duration.as_millis(), "Record sent to channel");
-                                    processed_in_task += 1;
-                                }
-                                debug!(rows_processed = processed_in_task, "Line processed successfully");
-                                Ok(processed_in_task) // Return count for this line
-                            }
-                            Err(e) => {
-                                let line_preview = String::from_utf8_lossy(&line_data_for_error);
-                                error!(error = %e, line = %line_preview.chars().take(100).collect::<String>(), "Failed to parse JSON line");
-                                Err(AppError::new(
-                                    ErrorCode::StreamDecodingError,
-                                    format!(
-                                        "Failed to parse JSON line (bytes): '{}': {}",
-                                        line_preview.chars().take(100).collect::<String>(),
-                                        e
-                                    ),
-                                ))
-                            }
+            // Honor a client-supplied idempotency key so retried requests are not ingested twice
+            let idempotency_key = idempotency::key_from_headers(&headers)?;
+            let (idempotency_guard, checkpoint) = match (&self.idempotency, idempotency_key) {
+                (Some(cache), Some(key)) => {
+                    let key = IdempotencyKey {
+                        tenant_id: tenant_id.clone(),
+                        table_name: D::table_name(),
+                        project_name: header_value(&headers, "X-Project-Name"),
+                        run_id: header_value(&headers, "X-Run-Id"),
+                        key,
+                    };
+                    match cache.claim(key) {
+                        Claim::New(guard) => (Some(guard), None),
+                        Claim::Resume(guard, checkpoint) => {
+                            info!(
+                                line = checkpoint.line,
+                                "Resuming request with the same idempotency key"
+                            );
+                            (Some(guard), Some(checkpoint))
                         }
-                    }.instrument(task_span).await; // Await the processing immediately
-
-                    match process_result {
-                        Ok(count) => total_processed += count, // Add to total
-                        Err(app_err) => {
-                            error!(error = %app_err, "Line processing failed");
-                            return Err(app_err); // Propagate error immediately
+                        Claim::InFlight => {
+                            warn!("Request with the same idempotency key is still in flight");
+                            return Err(AppError::new(
+                                ErrorCode::DuplicateRequest,
+                                "A request with this idempotency key is still being processed",
+                            ));
+                        }
+                        Claim::Completed(outcome) => {
+                            info!("Replaying outcome of request with the same idempotency key");
+                            return outcome
+                                .map(|response| StreamResponse::Replayed(Box::new(response)));
                         }
                     }
-                    // ---- End Sequential Line Processing ----
                 }
-            }
+                _ => (None, None),
+            };
 
-            // After the stream ends, check if there's any remaining data in the buffer
-            // Drain the remaining buffer
-            let mut remaining_bytes = line_buffer.drain(..).collect::<Vec<u8>>();
-            let start = remaining_bytes.iter().position(|&b| !matches!(b, b' ' | b'\t')).unwrap_or(0);
-            let end = remaining_bytes.iter().rposition(|&b| !matches!(b, b'\r' | b'\n' | b' ' | b'\t')).map_or(0, |p| p + 1);
-
-            // Process the remaining data if it's not empty
-            if start < end {
-                line_counter += 1;
-                let trimmed_line_slice = &mut remaining_bytes[start..end];
-                
-                if !trimmed_line_slice.is_empty() {
-                    info!(
-                        line = line_counter,
-                        bytes = trimmed_line_slice.len(),
-                        "Processing remaining data in buffer"
-                    );
-
-                    let line_data_for_error = trimmed_line_slice.to_vec(); // Clone only for error message
-                    let task_span = info_span!("process_line", line_num = line_counter);
-                    let process_result = async {
-                        match simd_json::from_slice::<R>(trimmed_line_slice) {
-                            Ok(raw_data) => {
-                                raw_data.validate()?;
-                                let rows = raw_data.into_rows(enrichment.clone())?; // Clone enrichment
-                                let mut processed_in_task = 0;
-                                for row in rows {
-                                    let send_start = Instant::now();
-                                    trace!("Sending remaining record to background channel...");
-                                    // Send directly
-                                    self.record_sender.send(row).await.map_err(|e| {
-                                        error!(error = %e, "Failed to send remaining record to background processor channel");
-                                        AppError::new(
-                                            ErrorCode::StreamProcessingError,
-                                            format!("Failed to send record to processor: {}", e),
-                                        )
-                                    })?;
-                                    let send_duration = send_start.elapsed();
-                                    if send_duration > Duration::from_millis(10) {
-                                        warn!(duration_ms = send_duration.as_millis(), "Sending remaining record to channel took longer than expected");
-                                    }
-                                    trace!(duration_ms = send_duration.as_millis(), "Remaining record sent to channel");
-                                    processed_in_task += 1;
-                                }
-                                debug!(rows_processed = processed_in_task, "Remaining line processed successfully");
-                                Ok(processed_in_task)
-                            }
-                            Err(e) => {
-                                let line_preview = String::from_utf8_lossy(&line_data_for_error);
-                                error!(error = %e, line = %line_preview.chars().take(100).collect::<String>(), "Failed to parse final JSON line");
-                                Err(AppError::new(
-                                    ErrorCode::StreamDecodingError,
-                                    format!(
-                                        "Failed to parse final JSON line (bytes): '{}': {}",
-                                        line_preview.chars().take(100).collect::<String>(),
-                                        e
-                                    ),
-                                ))
-                            }
-                        }
-                    }.instrument(task_span).await; // Await immediately
+            // Reject tenants that are over their limits before reading the body
+            // Completed requests were replayed above, a rejected claim is released (or keeps
+            // its checkpoint) as the guard is dropped
+            if let Some(rate_limiter) = &self.rate_limiter {
+                rate_limiter.acquire(&tenant_id, 0, 0)?;
+            }
 
-                    match process_result {
-                        Ok(count) => total_processed += count, // Add to total
-                        Err(app_err) => {
-                            error!(error = %app_err, "Final line processing failed");
-                            return Err(app_err); // Propagate error immediately
-                        }
-                    }
+            let start_time = Instant::now(); // Track overall processing time
+            let progress = match checkpoint {
+                Some(checkpoint) => {
+                    self.resume_at = Some((checkpoint.line, checkpoint.line_rows));
+                    StreamProgress::resume(checkpoint)
                 }
+                None => StreamProgress::default(),
+            };
+            let mut tracked = TrackedProgress::new(idempotency_guard, progress);
+            let result = self
+                .read_lines(body, &tenant_id, &enrichment, &mut tracked.progress)
+                .await;
+            let result = match result {
+                Ok(()) => Ok(self.finish(std::mem::take(&mut tracked.progress), start_time)),
+                Err(app_err) => Err(app_err),
+            };
+            tracked.settle(&result);
+
+            result
+        }
+        .instrument(span)
+        .await // Instrument the main async block
+    }
+}
+
+// Sends rows to a background processor in order, counting them as received
+// Stops at the first row that cannot be sent, the rows before it stay queued and are counted in
+// `sent`, so callers can tell how far they got
+pub async fn send_rows<T>(
+    sender: &FairSender<T>,
+    tenant_id: &str,
+    table_name: &'static str,
+    rows: impl IntoIterator<Item = T>,
+    sent: &mut u64,
+) -> Result<(), SendError<T>> {
+    let mut count = 0;
+    let mut result = Ok(());
+    for row in rows {
+        if let Err(e) = sender.send(tenant_id, row).await {
+            if e.is_overloaded() {
+                warn!(error = %e, "Shedding load, background processor channel is saturated");
+                LOAD_SHED.with_label_values(&[table_name, e.reason()]).inc();
             }
+            result = Err(e);
+            break;
+        }
+        count += 1;
+    }
+    ROWS_RECEIVED
+        .with_label_values(&[table_name, tenant_label(tenant_id)])
+        .inc_by(count);
+    *sent += count;
+    result
+}
 
-            // All lines processed sequentially (or an error was returned)
-            let final_count = total_processed; // Use the direct counter
-            let total_duration_sec = start_time.elapsed().as_secs_f64();
-            let rate = if total_duration_sec > 0.0 {
-                final_count as f64 / total_duration_sec
-            } else {
-                0.0 // Avoid division by zero if processing was instantaneous
-            };
+// Returns the value of a header as a string, or an empty string if it is missing or invalid
+fn header_value(headers: &HeaderMap, name: &str) -> String {
+    headers
+        .get(name)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or_default()
+        .to_string()
+}
 
-            // Log final summary statistics
-            info!(
-                processed_records = final_count,
-                total_duration_sec = format!("{:.2}", total_duration_sec),
-                records_per_sec = format!("{:.2}", rate),
-                lines_processed = line_counter, // Log total lines encountered
-                "Stream processing finished successfully"
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::single_tenant::SingleTenantProvider;
+    use crate::models::metrics::{MetricEnrichment, MetricInput, MetricRow};
+    use crate::processors::fair_queue::{fair_channel, FairReceiver, SendLimits};
+
+    type MetricProcessor = JsonLineProcessor<MetricInput, MetricEnrichment, MetricRow>;
+
+    fn metric_processor(sender: &FairSender<MetricRow>) -> MetricProcessor {
+        let auth_provider = Arc::new(SingleTenantProvider::new("tenant".to_string()));
+        JsonLineProcessor::new(sender.clone(), auth_provider, Scope::IngestMetrics)
+    }
+
+    fn headers(idempotency_key: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Project-Name", "project".parse().unwrap());
+        headers.insert("X-Run-Id", "1".parse().unwrap());
+        headers.insert("Idempotency-Key", idempotency_key.parse().unwrap());
+        headers
+    }
+
+    // Steps and names of the rows queued so far
+    fn queued(receiver: &mut FairReceiver<MetricRow>) -> Vec<(u64, String)> {
+        let mut rows = Vec::new();
+        while let Some(Some(row)) = futures::FutureExt::now_or_never(receiver.recv()) {
+            rows.push((row.step, row.log_name));
+        }
+        rows
+    }
+
+    #[tokio::test]
+    async fn test_retry_after_overload_resumes_where_it_stopped() {
+        let (sender, mut receiver) = fair_channel(100, 100);
+        // Two rows per line, the third row hits the high-water mark part-way through line 2
+        let sender = sender.with_limits(SendLimits {
+            timeout: None,
+            high_water_mark: Some(3),
+        });
+        let cache = Arc::new(IdempotencyCache::new(Duration::from_secs(60), 10));
+        let body: String = (1..=3)
+            .map(|step| format!(r#"{{"time":1,"step":{step},"data":{{"b":1,"a":2}}}}"#) + "\n")
+            .collect();
+
+        let error = metric_processor(&sender)
+            .with_idempotency(cache.clone())
+            .process_stream(headers("batch-1"), body.clone().into())
+            .await
+            .unwrap_err();
+        assert!(matches!(error.code, ErrorCode::ServiceOverloaded));
+        assert!(error.retry_after.is_some());
+        let mut rows = queued(&mut receiver);
+        assert_eq!(rows.len(), 3);
+
+        // The retry with the same key ingests the rest instead of replaying the 503
+        let response = metric_processor(&sender)
+            .with_idempotency(cache.clone())
+            .process_stream(headers("batch-1"), body.clone().into())
+            .await
+            .unwrap();
+        match response {
+            StreamResponse::Summary(message) => {
+                assert_eq!(message, "Stream processed successfully: 6 records")
+            }
+            other => panic!("Expected a summary, got {:?}", other),
+        }
+        rows.extend(queued(&mut receiver));
+        let expected: Vec<(u64, String)> = (1..=3)
+            .flat_map(|step| [(step, "a".to_string()), (step, "b".to_string())])
+            .collect();
+        assert_eq!(rows, expected);
+
+        // Once completed, further retries are replayed
+        let response = metric_processor(&sender)
+            .with_idempotency(cache)
+            .process_stream(headers("batch-1"), body.into())
+            .await
+            .unwrap();
+        assert!(matches!(response, StreamResponse::Replayed(_)));
+        assert!(queued(&mut receiver).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_request_resumes_where_it_stopped() {
+        let (sender, mut receiver) = fair_channel(100, 100);
+        let cache = Arc::new(IdempotencyCache::new(Duration::from_secs(60), 10));
+        let lines: Vec<String> = (1..=3)
+            .map(|step| format!(r#"{{"time":1,"step":{step},"data":{{"b":1,"a":2}}}}"#) + "\n")
+            .collect();
+
+        // The client sends the first line and then disconnects
+        let (body_sender, body_receiver) = futures::channel::mpsc::unbounded();
+        body_sender
+            .unbounded_send(Ok::<_, std::io::Error>(lines[0].clone()))
+            .unwrap();
+        let request = metric_processor(&sender)
+            .with_idempotency(cache.clone())
+            .process_stream(
+                headers("batch-2"),
+                axum::body::Body::from_stream(body_receiver),
             );
+        let cancelled = tokio::time::timeout(Duration::from_millis(100), request).await;
+        assert!(cancelled.is_err());
+        let mut rows = queued(&mut receiver);
+        assert_eq!(rows.len(), 2);
 
-            // Return a success message with the total count
-            Ok(format!(
-                "Stream processed successfully: {} records",
-                final_count
-            ))
-        }.instrument(span).await // Instrument the main async block
+        // The retry with the same key skips the rows that were already ingested
+        let response = metric_processor(&sender)
+            .with_idempotency(cache)
+            .process_stream(headers("batch-2"), lines.concat().into())
+            .await
+            .unwrap();
+        match response {
+            StreamResponse::Summary(message) => {
+                assert_eq!(message, "Stream processed successfully: 6 records")
+            }
+            other => panic!("Expected a summary, got {:?}", other),
+        }
+        rows.extend(queued(&mut receiver));
+        let expected: Vec<(u64, String)> = (1..=3)
+            .flat_map(|step| [(step, "a".to_string()), (step, "b".to_string())])
+            .collect();
+        assert_eq!(rows, expected);
+    }
+
+    #[tokio::test]
+    async fn test_completed_request_is_replayed_over_the_rate_limit() {
+        let (sender, mut receiver) = fair_channel(100, 100);
+        let cache = Arc::new(IdempotencyCache::new(Duration::from_secs(60), 10));
+        let rate_limiter = Arc::new(RateLimiter::new(
+            crate::rate_limit::TenantLimits {
+                daily_rows: Some(2),
+                ..Default::default()
+            },
+            Default::default(),
+        ));
+        let body = r#"{"time":1,"step":1,"data":{"b":1,"a":2}}"#;
+        let request = |key: &str| {
+            metric_processor(&sender)
+                .with_idempotency(cache.clone())
+                .with_rate_limiter(rate_limiter.clone())
+                .process_stream(headers(key), body.into())
+        };
+
+        request("batch-3").await.unwrap();
+        assert_eq!(queued(&mut receiver).len(), 2);
+        // The quota is used up, but the retry gets the original outcome instead of a 429
+        let response = request("batch-3").await.unwrap();
+        assert!(matches!(response, StreamResponse::Replayed(_)));
+        let error = request("batch-4").await.unwrap_err();
+        assert!(matches!(error.code, ErrorCode::ResourceExhausted));
+        assert!(queued(&mut receiver).is_empty());
+    }
+
+    // Body that arrives in the given chunks, so lines can span chunks
+    fn chunked(chunks: Vec<String>) -> axum::body::Body {
+        let chunks = chunks.into_iter().map(Ok::<_, std::io::Error>);
+        axum::body::Body::from_stream(futures::stream::iter(chunks))
+    }
+
+    fn report(response: StreamResponse) -> StreamReport {
+        match response {
+            StreamResponse::Report(report) => report,
+            other => panic!("Expected a report, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_report_lists_rejected_lines() {
+        let (sender, mut receiver) = fair_channel(2_000, 100);
+        let body = [
+            "{\"time\":1,\"step\":1,\"data\":{\"a\":1}}\n", // Offset 0
+            "\n",                                           // 35, empty lines are skipped
+            "not json\n",                                   // 36
+            "{\"time\":1,\"step\":2,\"data\":{\"a\":1,\"b\":2}}\r\n", // 45
+            "{\"time\":1,\"step\":3,\"data\":{}}\n",        // 87
+            "{\"time\":1,\"step\":4,\"data\":{\"a\":1}}",   // 117, without a newline
+        ]
+        .concat();
+        // Split inside lines 3 and 4
+        let chunks = vec![
+            body[..40].to_string(),
+            body[40..60].to_string(),
+            body[60..].to_string(),
+        ];
+
+        let response = metric_processor(&sender)
+            .with_error_mode(ErrorMode::Report)
+            .process_stream(headers("report-1"), chunked(chunks))
+            .await
+            .unwrap();
+        let report = report(response);
+        assert_eq!(
+            (
+                report.accepted,
+                report.accepted_lines,
+                report.rejected_lines
+            ),
+            (4, 3, 2)
+        );
+        let rejected: Vec<_> = report
+            .rejected
+            .iter()
+            .map(|r| (r.line, r.offset, r.code))
+            .collect();
+        assert!(matches!(
+            rejected.as_slice(),
+            [
+                (3, 36, ErrorCode::StreamDecodingError),
+                (5, 87, ErrorCode::InvalidMetricFormat)
+            ]
+        ));
+        let steps: Vec<u64> = queued(&mut receiver)
+            .iter()
+            .map(|(step, _)| *step)
+            .collect();
+        assert_eq!(steps, [1, 2, 2, 4]);
+    }
+
+    #[tokio::test]
+    async fn test_report_is_capped() {
+        let (sender, mut receiver) = fair_channel(2_000, 100);
+        let invalid = MAX_REPORTED_LINES + 2;
+        let mut body = "not json\n".repeat(invalid);
+        body.push_str("{\"time\":1,\"step\":1,\"data\":{\"a\":1}}\n");
+
+        let response = metric_processor(&sender)
+            .with_error_mode(ErrorMode::Report)
+            .process_stream(headers("report-2"), body.into())
+            .await
+            .unwrap();
+        let report = report(response);
+        // Rejected lines past the cap are counted but not listed, later valid lines are still ingested
+        assert_eq!(report.rejected_lines, invalid as u64);
+        assert_eq!(report.rejected.len(), MAX_REPORTED_LINES);
+        let last = report.rejected.last().unwrap();
+        assert_eq!((last.line, last.offset), (1_000, 999 * 9));
+        assert_eq!((report.accepted, report.accepted_lines), (1, 1));
+        assert_eq!(queued(&mut receiver).len(), 1);
     }
 }
@@ -0,0 +1,324 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::error::{AppError, ErrorCode};
+use crate::telemetry::{tenant_label, RATE_LIMITED};
+
+// Seconds of unused rate a tenant can accumulate and spend in a burst
+const BURST_SECS: f64 = 10.0;
+
+/// Ingest limits of a tenant, unset or 0 means unlimited
+///
+/// # Example
+/// ```toml
+/// [tenants.my-org]
+/// rowsPerSec = 50000
+/// dailyBytes = 10000000000
+/// ```
+/// Tenants in the limits file inherit every limit they do not set from the defaults
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct TenantLimits {
+    pub rows_per_sec: Option<u64>,
+    pub bytes_per_sec: Option<u64>,
+    pub daily_rows: Option<u64>,  // Rows per UTC day
+    pub daily_bytes: Option<u64>, // Bytes per UTC day
+}
+
+impl TenantLimits {
+    // Fills the limits this tenant does not set from `defaults`
+    fn or(self, defaults: TenantLimits) -> Self {
+        Self {
+            rows_per_sec: self.rows_per_sec.or(defaults.rows_per_sec),
+            bytes_per_sec: self.bytes_per_sec.or(defaults.bytes_per_sec),
+            daily_rows: self.daily_rows.or(defaults.daily_rows),
+            daily_bytes: self.daily_bytes.or(defaults.daily_bytes),
+        }
+    }
+
+    fn is_unlimited(&self) -> bool {
+        [
+            self.rows_per_sec,
+            self.bytes_per_sec,
+            self.daily_rows,
+            self.daily_bytes,
+        ]
+        .iter()
+        .all(|limit| limit.unwrap_or(0) == 0)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LimitsFile {
+    tenants: HashMap<String, TenantLimits>,
+}
+
+// Refills at `rate` tokens per second, up to BURST_SECS worth of tokens
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, now: Instant) -> Self {
+        Self {
+            rate: rate as f64,
+            tokens: rate as f64 * BURST_SECS,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate * BURST_SECS);
+        self.updated = now;
+    }
+
+    // Time until `amount` tokens can be taken, zero if they can be taken now
+    // Amounts above the burst size only wait for a full bucket and leave it in debt
+    fn wait_for(&self, amount: f64) -> Duration {
+        let needed = amount.min(self.rate * BURST_SECS);
+        if self.tokens >= needed {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((needed - self.tokens) / self.rate)
+        }
+    }
+}
+
+// Buckets and usage of the current day of a single tenant
+struct TenantState {
+    limits: TenantLimits,
+    rows: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+    day: NaiveDate,
+    rows_today: u64,
+    bytes_today: u64,
+}
+
+// Per-tenant token buckets (rows/sec, bytes/sec) and daily quotas for the ingest routes
+// Usage is tracked in memory, so each server instance enforces the limits on its own
+pub struct RateLimiter {
+    defaults: TenantLimits,
+    overrides: HashMap<String, TenantLimits>, // Tenant ID -> limits, merged with the defaults
+    tenants: Mutex<HashMap<String, TenantState>>,
+}
+
+impl RateLimiter {
+    pub fn new(defaults: TenantLimits, overrides: HashMap<String, TenantLimits>) -> Self {
+        Self {
+            defaults,
+            overrides,
+            tenants: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Creates the limiter with per-tenant limits from a TOML (`.toml`) or JSON file
+    pub fn load(defaults: TenantLimits, path: Option<&str>) -> Result<Self, AppError> {
+        let Some(path) = path else {
+            return Ok(Self::new(defaults, HashMap::new()));
+        };
+        let contents = std::fs::read_to_string(path).map_err(|e| {
+            AppError::new(
+                ErrorCode::ConfigurationError,
+                format!("Failed to read tenant limits file {}: {}", path, e),
+            )
+        })?;
+        let file: LimitsFile = if path.ends_with(".toml") {
+            toml::from_str(&contents).map_err(|e| e.to_string())
+        } else {
+            serde_json::from_str(&contents).map_err(|e| e.to_string())
+        }
+        .map_err(|e| {
+            AppError::new(
+                ErrorCode::ConfigurationError,
+                format!("Invalid tenant limits file: {}", e),
+            )
+        })?;
+        info!(
+            path,
+            tenants = file.tenants.len(),
+            "Loaded tenant limits file"
+        );
+        Ok(Self::new(defaults, file.tenants))
+    }
+
+    // Whether any tenant has a limit, the ingest routes skip the limiter otherwise
+    pub fn is_enabled(&self) -> bool {
+        !self.defaults.is_unlimited() || self.overrides.values().any(|l| !l.is_unlimited())
+    }
+
+    // Charges `rows` and `bytes` to the tenant, or rejects them with 429 and Retry-After
+    // Nothing is charged if any limit would be exceeded
+    // Charging nothing checks whether the tenant may send at all
+    pub fn acquire(&self, tenant_id: &str, rows: u64, bytes: u64) -> Result<(), AppError> {
+        self.acquire_at(tenant_id, rows, bytes, Instant::now(), Utc::now())
+    }
+
+    fn acquire_at(
+        &self,
+        tenant_id: &str,
+        rows: u64,
+        bytes: u64,
+        now: Instant,
+        wall_clock: DateTime<Utc>,
+    ) -> Result<(), AppError> {
+        let today = wall_clock.date_naive();
+        let mut tenants = self.tenants.lock().unwrap();
+        let state = tenants.entry(tenant_id.to_string()).or_insert_with(|| {
+            let limits = match self.overrides.get(tenant_id) {
+                Some(limits) => limits.or(self.defaults),
+                None => self.defaults,
+            };
+            let bucket =
+                |rate: Option<u64>| rate.filter(|&r| r > 0).map(|r| TokenBucket::new(r, now));
+            TenantState {
+                rows: bucket(limits.rows_per_sec),
+                bytes: bucket(limits.bytes_per_sec),
+                limits,
+                day: today,
+                rows_today: 0,
+                bytes_today: 0,
+            }
+        });
+
+        // Daily quotas reset at midnight UTC
+        if state.day != today {
+            state.day = today;
+            state.rows_today = 0;
+            state.bytes_today = 0;
+        }
+        let quotas = [
+            (
+                "daily_rows",
+                state.limits.daily_rows,
+                state.rows_today,
+                rows,
+            ),
+            (
+                "daily_bytes",
+                state.limits.daily_bytes,
+                state.bytes_today,
+                bytes,
+            ),
+        ];
+        for (name, quota, used, amount) in quotas {
+            let Some(quota) = quota.filter(|&q| q > 0) else {
+                continue;
+            };
+            if used >= quota || used + amount > quota {
+                let midnight = (today + chrono::Days::new(1))
+                    .and_hms_opt(0, 0, 0)
+                    .unwrap()
+                    .and_utc();
+                let retry_after = (midnight - wall_clock).to_std().unwrap_or_default();
+                RATE_LIMITED
+                    .with_label_values(&[tenant_label(tenant_id), name])
+                    .inc();
+                warn!(
+                    tenant_id,
+                    quota = name,
+                    limit = quota,
+                    "Daily quota exhausted"
+                );
+                return Err(AppError::new(
+                    ErrorCode::ResourceExhausted,
+                    format!("Daily quota of {} {} exhausted", quota, &name[6..]),
+                )
+                .with_retry_after(retry_after));
+            }
+        }
+
+        let buckets = [
+            ("rows_per_sec", state.rows.as_mut(), rows),
+            ("bytes_per_sec", state.bytes.as_mut(), bytes),
+        ];
+        let mut wait = Duration::ZERO;
+        let mut exceeded = None;
+        for (name, bucket, amount) in buckets {
+            if let Some(bucket) = bucket {
+                bucket.refill(now);
+                let bucket_wait = bucket.wait_for(amount as f64);
+                if bucket_wait > wait {
+                    wait = bucket_wait;
+                    exceeded = Some(name);
+                }
+            }
+        }
+        if let Some(name) = exceeded {
+            RATE_LIMITED
+                .with_label_values(&[tenant_label(tenant_id), name])
+                .inc();
+            return Err(AppError::new(
+                ErrorCode::RateLimitExceeded,
+                format!("Rate limit exceeded ({})", name),
+            )
+            .with_retry_after(wait));
+        }
+
+        // Every limit allows the request, charge it
+        if let Some(bucket) = state.rows.as_mut() {
+            bucket.tokens -= rows as f64;
+        }
+        if let Some(bucket) = state.bytes.as_mut() {
+            bucket.tokens -= bytes as f64;
+        }
+        state.rows_today += rows;
+        state.bytes_today += bytes;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_token_bucket_and_daily_quota() {
+        let defaults = TenantLimits {
+            rows_per_sec: Some(10),
+            daily_rows: Some(150),
+            ..Default::default()
+        };
+        let overrides = HashMap::from([(
+            "unlimited".to_string(),
+            TenantLimits {
+                rows_per_sec: Some(0),
+                daily_rows: Some(0),
+                ..Default::default()
+            },
+        )]);
+        let limiter = RateLimiter::new(defaults, overrides);
+        let start = Instant::now();
+        let day = Utc.with_ymd_and_hms(2025, 1, 1, 12, 0, 0).unwrap();
+
+        // The burst allows 100 rows, then the tenant has to wait for the bucket to refill
+        limiter.acquire_at("org", 100, 0, start, day).unwrap();
+        let err = limiter.acquire_at("org", 5, 0, start, day).unwrap_err();
+        assert!(matches!(err.code, ErrorCode::RateLimitExceeded));
+        assert_eq!(err.retry_after, Some(Duration::from_millis(500)));
+        limiter
+            .acquire_at("org", 50, 0, start + Duration::from_secs(5), day)
+            .unwrap();
+
+        // The daily quota is used up until midnight UTC
+        let later = start + Duration::from_secs(60);
+        let err = limiter.acquire_at("org", 1, 0, later, day).unwrap_err();
+        assert!(matches!(err.code, ErrorCode::ResourceExhausted));
+        assert_eq!(err.retry_after, Some(Duration::from_secs(12 * 3600)));
+        let next_day = day + chrono::Duration::days(1);
+        limiter.acquire_at("org", 1, 0, later, next_day).unwrap();
+
+        // Overrides of 0 lift the default limits
+        limiter
+            .acquire_at("unlimited", 1_000_000, 0, start, day)
+            .unwrap();
+    }
+}
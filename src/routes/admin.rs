@@ -0,0 +1,80 @@
+use axum::{extract::State, http::HeaderMap, response::Json, routing::post, Router};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use tracing::{info, warn};
+
+use crate::{
+    error::{AppError, ErrorCode},
+    routes::AppState,
+};
+
+// Defines the router for the /admin endpoints
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new().route("/admin/api-keys/invalidate", post(invalidate_api_keys))
+}
+
+/// Request body for dropping API keys from the cache
+///
+/// # Example
+/// ```json
+/// { "hashedKeys": ["9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"] }
+/// ```
+/// Omitting `hashedKeys` invalidates every cached key
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct InvalidateRequest {
+    pub hashed_keys: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InvalidateResponse {
+    pub invalidated: usize, // Number of keys that were cached
+}
+
+// Checks the admin bearer token, admin routes and /metrics are disabled if no ADMIN_TOKEN is configured
+pub fn admin_auth(headers: &HeaderMap, state: &AppState) -> Result<(), AppError> {
+    let Some(admin_token) = &state.config.admin_token else {
+        return Err(AppError::new(
+            ErrorCode::InsufficientPermissions,
+            "Admin routes are disabled",
+        ));
+    };
+
+    let token = headers
+        .get("Authorization")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .ok_or_else(|| AppError::new(ErrorCode::MissingToken, "Missing admin token"))?;
+
+    // Compare digests so the comparison time does not depend on the token contents
+    if Sha256::digest(token.trim().as_bytes()) != Sha256::digest(admin_token.as_bytes()) {
+        warn!("Invalid admin token");
+        return Err(AppError::new(
+            ErrorCode::InvalidToken,
+            "Invalid admin token",
+        ));
+    }
+    Ok(())
+}
+
+// Handler for the POST /admin/api-keys/invalidate endpoint
+// Drops revoked or changed API keys from the auth cache so they take effect immediately
+async fn invalidate_api_keys(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(request): Json<InvalidateRequest>,
+) -> Result<Json<InvalidateResponse>, AppError> {
+    admin_auth(&headers, &state)?;
+
+    let invalidated = match request.hashed_keys {
+        Some(hashed_keys) => hashed_keys
+            .iter()
+            .filter(|hashed_key| state.auth_provider.invalidate_api_key(hashed_key))
+            .count(),
+        None => state.auth_provider.invalidate_all_api_keys(),
+    };
+    info!(invalidated, "API keys invalidated by admin request");
+
+    Ok(Json(InvalidateResponse { invalidated }))
+}
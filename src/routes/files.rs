@@ -13,10 +13,13 @@ use std::sync::Arc;
 use std::time::{Duration, Instant, SystemTime};
 
 use crate::{
-    auth::auth,
+    auth::{auth, Scope},
+    config::Config,
     error::{AppError, ErrorCode},
     models::files::{FileInput, FilesEnrichment, FilesRow},
+    processors::stream::send_rows,
     routes::AppState,
+    telemetry::PRESIGN_DURATION,
     traits::{DatabaseRow, EnrichmentData},
 };
 
@@ -289,23 +292,89 @@ pub struct PresignedUrlResponse {
     pub log_files: HashMap<String, Vec<HashMap<String, String>>>,
 }
 
+// Creates an S3/R2 client using the storage credentials from the app config
+pub async fn storage_client(config: &Config) -> Client {
+    let region_provider = RegionProviderChain::first_try(Region::new("auto"));
+    let shared_config = aws_config::from_env()
+        .region(region_provider)
+        .credentials_provider(Credentials::new(
+            config.storage_access_key_id.as_str(),
+            config.storage_secret_access_key.as_str(),
+            None,
+            None,
+            "storage_config",
+        ))
+        .endpoint_url(config.storage_endpoint.as_str())
+        .load()
+        .await;
+
+    Client::new(&shared_config)
+}
+
+// Object key of a file of a run, the same for presigned uploads and imports
+pub fn storage_key(
+    tenant_id: &str,
+    project_name: &str,
+    run_id: u64,
+    log_name: &str,
+    file_name: &str,
+) -> String {
+    format!(
+        "{}/{}/{}/{}/{}",
+        tenant_id, project_name, run_id, log_name, file_name
+    )
+}
+
+// Uploads a file to storage from the server itself, for files that arrive inside imports
+pub async fn put_file(
+    client: &Client,
+    config: &Config,
+    key: String,
+    content_type: String,
+    bytes: Vec<u8>,
+) -> Result<(), AppError> {
+    client
+        .put_object()
+        .bucket(config.storage_bucket.as_str())
+        .key(key)
+        .content_type(content_type)
+        .body(bytes.into())
+        .send()
+        .await
+        .map_err(|e| {
+            AppError::new(
+                ErrorCode::InternalError,
+                format!("Failed to upload file to storage: {}", e),
+            )
+        })?;
+    Ok(())
+}
+
 // Handler for the POST /files endpoint
 // Generates presigned URLs for S3/R2 uploads
 pub async fn generate_presigned_urls(
     State(state): State<Arc<AppState>>,
-    headers: axum::http::HeaderMap,
+    mut headers: axum::http::HeaderMap,
     Json(payload): Json<FileUploadRequest>,
 ) -> Result<Json<PresignedUrlResponse>, AppError> {
     let req_start = Instant::now();
     // Authenticate the request using headers and database
-    let auth = auth(&headers, &state.db).await?;
+    let auth = auth(
+        &mut headers,
+        state.auth_provider.as_ref(),
+        Scope::UploadFiles,
+    )
+    .await?;
     let tenant_id = auth.tenant_id;
 
     let enrichment_start = Instant::now();
 
     // Extract enrichment data (like run_id, project_name) from headers
     let enrichment_data = FilesEnrichment::from_headers(tenant_id.clone(), &headers)?;
-    let run_id = enrichment_data.run_id.clone();
+    if let Some(run_key) = enrichment_data.run_key() {
+        state.heartbeats.record(run_key);
+    }
+    let run_id = enrichment_data.run_id;
     let project_name = enrichment_data.project_name.clone();
 
     println!("[FILES] Payload \n {:?}", payload);
@@ -313,7 +382,8 @@ pub async fn generate_presigned_urls(
 
     let send_start = Instant::now();
 
-    // Send metadata about each file to the background processor via channel
+    // Create the database rows of the files
+    let mut files_rows = Vec::with_capacity(payload.files.len());
     for file in payload.files.iter() {
         let file_input = FileInput {
             log_name: file.log_name.clone(),
@@ -329,32 +399,20 @@ pub async fn generate_presigned_urls(
             file_input,
             enrichment_data.clone(),
         )?;
-        // Send the row to the files background processor
-        state
-            .files_record_sender
-            .send(files_row)
-            .await
-            .map_err(|e| AppError::new(ErrorCode::InternalError, e.to_string()))?;
+        files_rows.push(files_row);
     }
-
+    // Send metadata about each file to the background processor via channel
+    send_rows(
+        &state.files_record_sender,
+        &tenant_id,
+        FilesRow::table_name(),
+        files_rows,
+        &mut 0,
+    )
+    .await?;
     println!("[FILES] Send time: {:?}", send_start.elapsed());
 
-    // Setup S3/R2 client configuration using credentials from app config
-    let region_provider = RegionProviderChain::first_try(Region::new("auto"));
-    let shared_config = aws_config::from_env()
-        .region(region_provider)
-        .credentials_provider(Credentials::new(
-            state.config.storage_access_key_id.as_str(),
-            state.config.storage_secret_access_key.as_str(),
-            None,
-            None,
-            "storage_config",
-        ))
-        .endpoint_url(state.config.storage_endpoint.as_str())
-        .load()
-        .await;
-
-    let s3_client: Arc<Client> = Arc::new(Client::new(&shared_config));
+    let s3_client: Arc<Client> = Arc::new(state.storage_client.clone());
 
     // Build a presigning config with a defined expiry and explicit start time
     // Using explicit start_time helps mitigate potential clock skew issues
@@ -374,16 +432,13 @@ pub async fn generate_presigned_urls(
         let cfg: Arc<PresigningConfig> = Arc::clone(&presigning_config);
         let tenant = tenant_id.clone();
         let project = project_name.clone();
-        let run = run_id.clone();
+        let run = run_id;
 
         let storage_bucket = state.config.storage_bucket.clone();
 
         async move {
             // Construct the S3 object key using tenant, project, run, log, and file names
-            let key = format!(
-                "{}/{}/{}/{}/{}",
-                tenant, project, run, file.log_name, file.file_name
-            );
+            let key = storage_key(&tenant, &project, run, &file.log_name, &file.file_name);
 
             // Build the PutObject request, setting bucket, key, content type, and length
             let req = s3
@@ -407,6 +462,7 @@ pub async fn generate_presigned_urls(
     // Wait for all presigned URL generation tasks to complete
     let results = join_all(url_futures).await;
     let duration = start.elapsed();
+    PRESIGN_DURATION.observe(duration.as_secs_f64());
     println!("[FILES] Generated presigned URLs in {:?}", duration);
 
     // Process the results, grouping URLs by log_name
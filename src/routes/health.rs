@@ -1,14 +1,333 @@
-use axum::{routing::get, Router};
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+    routing::get,
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::future::Future;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::warn;
 
-use crate::routes::AppState;
+use crate::{
+    config::{DATA_TABLE_NAME, FILES_TABLE_NAME, LOGS_TABLE_NAME, METRICS_TABLE_NAME},
+    error::{AppError, ErrorCode},
+    processors::{background::ProcessorStatus, fair_queue::FairSender},
+    routes::AppState,
+};
 
-// Defines the router for the /health endpoint
+// Maximum time each dependency check may take before it counts as failed
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+// Fraction of a channel's high-water mark above which its processor counts as unhealthy
+const MAX_CHANNEL_FILL: f64 = 0.9;
+
+// Defines the router for the /health and /ready endpoints
 pub fn router() -> Router<Arc<AppState>> {
-    Router::new().route("/health", get(health_check))
+    Router::new()
+        .route("/health", get(health_check))
+        .route("/ready", get(readiness_check))
+}
+
+#[derive(Debug, Deserialize)]
+struct HealthParams {
+    // Set to 1 (or true) to check dependencies instead of only reporting that the server is up
+    verbose: Option<String>,
+}
+
+// Result of checking a single dependency
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CheckResult {
+    healthy: bool,
+    latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+// State of a single background processor
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ProcessorReport {
+    healthy: bool,
+    channel_depth: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    high_water_mark: Option<usize>,
+    consecutive_errors: u32,
+}
+
+/// Breakdown of the server's dependencies and background processors
+///
+/// # Example
+/// ```json
+/// {
+///     "status": "unhealthy",
+///     "checks": {
+///         "clickhouse": { "healthy": false, "latencyMs": 2000, "error": "Timed out after 2s" },
+///         "auth": { "healthy": true, "latencyMs": 3 },
+///         "storage": { "healthy": true, "latencyMs": 41 }
+///     },
+///     "processors": {
+///         "mlop_metrics": { "healthy": false, "channelDepth": 9500, "highWaterMark": 10000, "consecutiveErrors": 2 }
+///     }
+/// }
+/// ```
+#[derive(Debug, Serialize)]
+struct ReadinessReport {
+    status: &'static str,
+    checks: BTreeMap<&'static str, CheckResult>,
+    processors: BTreeMap<&'static str, ProcessorReport>,
+}
+
+impl ReadinessReport {
+    fn is_healthy(&self) -> bool {
+        self.checks.values().all(|c| c.healthy) && self.processors.values().all(|p| p.healthy)
+    }
+}
+
+impl IntoResponse for ReadinessReport {
+    fn into_response(self) -> Response {
+        let status = if self.is_healthy() {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        (status, Json(self)).into_response()
+    }
+}
+
+// Liveness check handler, returns "OK" as long as the server is running
+// With `?verbose=1` it returns the same dependency breakdown as /ready
+async fn health_check(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<HealthParams>,
+) -> Response {
+    match params.verbose.as_deref() {
+        Some("1") | Some("true") => readiness_report(&state).await.into_response(),
+        _ => "OK".into_response(),
+    }
+}
+
+// Readiness check handler
+// Returns 503 with a JSON breakdown if any dependency or background processor is unhealthy
+async fn readiness_check(State(state): State<Arc<AppState>>) -> ReadinessReport {
+    readiness_report(&state).await
+}
+
+async fn readiness_report(state: &AppState) -> ReadinessReport {
+    // Check all dependencies concurrently
+    let (clickhouse, auth, storage) = tokio::join!(
+        check(async {
+            state.clickhouse_client.query("SELECT 1").execute().await?;
+            Ok(())
+        }),
+        check(state.auth_provider.ping()),
+        check(async {
+            state
+                .storage_client
+                .head_bucket()
+                .bucket(state.config.storage_bucket.as_str())
+                .send()
+                .await
+                .map_err(|e| {
+                    AppError::new(
+                        ErrorCode::ServiceUnavailable,
+                        format!("Bucket check failed: {}", e),
+                    )
+                })?;
+            Ok(())
+        }),
+    );
+
+    let checks = BTreeMap::from([
+        ("clickhouse", clickhouse),
+        ("auth", auth),
+        ("storage", storage),
+    ]);
+    let processors = BTreeMap::from([
+        (
+            METRICS_TABLE_NAME,
+            processor_report(&state.metrics_record_sender, &state.metrics_status),
+        ),
+        (
+            LOGS_TABLE_NAME,
+            processor_report(&state.log_record_sender, &state.logs_status),
+        ),
+        (
+            DATA_TABLE_NAME,
+            processor_report(&state.data_record_sender, &state.data_status),
+        ),
+        (
+            FILES_TABLE_NAME,
+            processor_report(&state.files_record_sender, &state.files_status),
+        ),
+    ]);
+
+    let mut report = ReadinessReport {
+        status: "ok",
+        checks,
+        processors,
+    };
+    if !report.is_healthy() {
+        warn!(report = ?report, "Readiness check failed");
+        report.status = "unhealthy";
+    }
+    report
+}
+
+// Runs a dependency check with a timeout and measures its latency
+async fn check(future: impl Future<Output = Result<(), AppError>>) -> CheckResult {
+    let start = Instant::now();
+    let result = tokio::time::timeout(CHECK_TIMEOUT, future).await;
+    let latency_ms = start.elapsed().as_millis() as u64;
+
+    let error = match result {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(e.message),
+        Err(_) => Some(format!("Timed out after {}s", CHECK_TIMEOUT.as_secs())),
+    };
+    CheckResult {
+        healthy: error.is_none(),
+        latency_ms,
+        error,
+    }
+}
+
+// A processor is unhealthy while its flushes fail or its channel nears the high-water mark,
+// at which point the requests of every tenant are shed
+// A tenant that fills its own sub-queue only slows down itself, so it does not count
+fn processor_report<T>(sender: &FairSender<T>, status: &ProcessorStatus) -> ProcessorReport {
+    let high_water_mark = sender.high_water_mark();
+    let channel_depth = sender.len();
+    let consecutive_errors = status.consecutive_errors();
+
+    ProcessorReport {
+        healthy: consecutive_errors == 0
+            && high_water_mark
+                .is_none_or(|mark| (channel_depth as f64) < mark as f64 * MAX_CHANNEL_FILL),
+        channel_depth,
+        high_water_mark,
+        consecutive_errors,
+    }
 }
 
-// Simple health check handler that returns "OK"
-async fn health_check() -> &'static str {
-    "OK"
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TENANT_QUEUE_CAPACITY;
+    use crate::processors::fair_queue::{fair_channel, SendLimits};
+    use crate::routes::testing::{test_state, test_storage_client};
+    use crate::shutdown::Shutdown;
+    use clickhouse::test::{handlers, Mock};
+    use tokio::net::TcpListener;
+
+    // Object storage that answers every request with 200, so the bucket check passes
+    async fn storage_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let app = Router::new().fallback(|| async { StatusCode::OK });
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        url
+    }
+
+    // State whose ClickHouse is `mock` and whose storage is up
+    async fn state_with(mock: &Mock) -> Arc<AppState> {
+        let (shutdown, _trigger) = Shutdown::new(Duration::from_secs(5));
+        let (state, _channels) = test_state(mock.url(), shutdown);
+        let mut state = (*state).clone();
+        state.storage_client = test_storage_client(&storage_server().await);
+        Arc::new(state)
+    }
+
+    async fn json_body(response: Response) -> (StatusCode, serde_json::Value) {
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_ready_when_dependencies_and_processors_are_healthy() {
+        let mock = Mock::new();
+        mock.add(handlers::record_ddl()); // SELECT 1
+        let state = state_with(&mock).await;
+
+        let response = readiness_check(State(state)).await.into_response();
+        let (status, report) = json_body(response).await;
+        assert_eq!(status, StatusCode::OK, "{}", report);
+        assert_eq!(report["status"], "ok");
+        for check in ["clickhouse", "auth", "storage"] {
+            assert_eq!(report["checks"][check]["healthy"], true, "{}", check);
+        }
+        assert_eq!(report["processors"][METRICS_TABLE_NAME]["healthy"], true);
+    }
+
+    #[tokio::test]
+    async fn test_failing_flushes_make_the_server_unready() {
+        let mock = Mock::new();
+        mock.add(handlers::record_ddl());
+        let state = state_with(&mock).await;
+        // ClickHouse answers queries, but the inserts of a processor keep failing
+        state.logs_status.record_errors(3);
+
+        let response = readiness_check(State(state)).await.into_response();
+        let (status, report) = json_body(response).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(report["status"], "unhealthy");
+        assert_eq!(report["checks"]["clickhouse"]["healthy"], true);
+        let logs = &report["processors"][LOGS_TABLE_NAME];
+        assert_eq!(logs["healthy"], false);
+        assert_eq!(logs["consecutiveErrors"], 3);
+        assert_eq!(report["processors"][METRICS_TABLE_NAME]["healthy"], true);
+    }
+
+    #[tokio::test]
+    async fn test_verbose_health_reports_clickhouse_failures() {
+        let mock = Mock::new();
+        mock.add(handlers::failure(StatusCode::INTERNAL_SERVER_ERROR));
+        let state = state_with(&mock).await;
+
+        // Without `verbose` nothing is checked, so the server is alive
+        let params = HealthParams { verbose: None };
+        let response = health_check(State(state.clone()), Query(params)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let params = HealthParams {
+            verbose: Some("1".to_string()),
+        };
+        let response = health_check(State(state), Query(params)).await;
+        let (status, report) = json_body(response).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(report["status"], "unhealthy");
+        assert_eq!(report["checks"]["clickhouse"]["healthy"], false);
+        assert!(report["checks"]["clickhouse"]["error"].is_string());
+        assert_eq!(report["checks"]["storage"]["healthy"], true);
+    }
+
+    #[tokio::test]
+    async fn test_saturated_tenant_keeps_processor_ready() {
+        let (sender, _receiver) = fair_channel(TENANT_QUEUE_CAPACITY, 100);
+        let sender = sender.with_limits(SendLimits {
+            timeout: None,
+            high_water_mark: Some(TENANT_QUEUE_CAPACITY * 2),
+        });
+        let status = ProcessorStatus::default();
+
+        // One tenant fills its whole sub-queue
+        for record in 0..TENANT_QUEUE_CAPACITY {
+            sender.send("bulk", record).await.unwrap();
+        }
+        let report = processor_report(&sender, &status);
+        assert!(report.healthy);
+        assert_eq!(report.channel_depth, TENANT_QUEUE_CAPACITY);
+
+        // Close to the high-water mark every tenant is about to be shed
+        for record in 0..TENANT_QUEUE_CAPACITY * 8 / 10 {
+            sender.send("other", record).await.unwrap();
+        }
+        assert!(!processor_report(&sender, &status).healthy);
+    }
 }
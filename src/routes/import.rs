@@ -0,0 +1,469 @@
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::HeaderMap,
+    response::sse::{Event, KeepAlive, Sse},
+    routing::post,
+    Json, Router,
+};
+use bytes::Bytes;
+use futures::stream::{self, Stream};
+use serde::Deserialize;
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tracing::{info, instrument, warn, Instrument};
+
+use crate::{
+    auth::{auth, authenticate, Auth, Scope},
+    config::{
+        DATA_TABLE_NAME, FILES_TABLE_NAME, LOGS_TABLE_NAME, METRICS_TABLE_NAME,
+        OVERLOADED_RETRY_AFTER,
+    },
+    error::{missing_header_error, AppError, ErrorCode, ErrorResponse},
+    heartbeat::RunKey,
+    import::{
+        columnar::{self, ImportTable},
+        tfevents, ClickHouseSink, ImportBatch, ImportSummary,
+    },
+    processors::{decompress, stream::send_rows},
+    routes::{files::put_file, AppState},
+    traits::ImportSink,
+};
+
+// Progress events buffered for a client that reads them slowly
+const IMPORT_EVENT_BUFFER: usize = 16;
+
+// Defines the routes for importing runs recorded with other tools
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/import", post(import_columnar)) // Parquet or Arrow IPC files
+        .route("/import/tensorboard", post(import_tensorboard)) // Event files (tfevents)
+}
+
+// Query parameters of the /import endpoint
+#[derive(Debug, Deserialize)]
+struct ColumnarImportParams {
+    table: ImportTable, // metrics, logs or data
+}
+
+// Handler for the /import endpoint
+// Backfills a table from a Parquet or Arrow IPC file with large synchronous inserts straight
+// to ClickHouse, bypassing the background processors
+// Progress is streamed as server-sent events: a `progress` event after every insert and a
+// final `done` or `error` event, each carrying the counts so far
+#[instrument(skip(state, params, headers, body))]
+async fn import_columnar(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<ColumnarImportParams>,
+    mut headers: HeaderMap,
+    body: Body,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, AppError> {
+    let auth = auth(
+        &mut headers,
+        state.auth_provider.as_ref(),
+        params.table.scope(),
+    )
+    .await?;
+    // Run of rows without `projectName` and `runId` columns
+    let project_name = header_value(&headers, "X-Project-Name")?.map(str::to_string);
+    let run_id = match header_value(&headers, "X-Run-Id")? {
+        Some(run_id) => Some(parse_run_id(run_id)?),
+        None => None,
+    };
+    // Held until the import ends, as the file is in memory until then
+    let permit = state
+        .import_permits
+        .clone()
+        .try_acquire_owned()
+        .map_err(|_| {
+            AppError::new(
+                ErrorCode::ServiceOverloaded,
+                format!(
+                    "Too many imports are running, at most {} run at once",
+                    state.config.import_max_concurrent
+                ),
+            )
+            .with_retry_after(OVERLOADED_RETRY_AFTER)
+        })?;
+
+    // Parquet and Arrow files keep their metadata at the end, so the whole body is read first
+    let body =
+        decompress::read_body(&headers, body, state.config.ingest_max_decompressed_bytes).await?;
+    let record_batches = columnar::record_batches(Bytes::from(body))?;
+
+    let tenant_id = auth.tenant_id.clone();
+    let mut converter = columnar::Converter::new(
+        params.table,
+        tenant_id.clone(),
+        project_name,
+        run_id,
+        move |project_name: &str, run_id| auth.authorize_target(project_name, run_id),
+    );
+    let mut sink = ClickHouseSink::new(state.clickhouse_client.clone(), state.config.clone())
+        .with_rate_limiter(state.rate_limiter.clone(), tenant_id);
+
+    // The import runs to completion even if the client disconnects, and shutdown waits for it
+    let (events, receiver) = mpsc::channel(IMPORT_EVENT_BUFFER);
+    let import = async move {
+        let _permit = permit;
+        let mut summary = ImportSummary::default();
+        // Progress events are dropped rather than stalling the import for a slow client
+        let on_progress = |summary: &ImportSummary| {
+            let _ = events.try_send(summary_event("progress", summary));
+        };
+        let result = columnar::import(
+            record_batches,
+            &mut converter,
+            &mut sink,
+            &mut summary,
+            on_progress,
+        )
+        .await;
+        let event = match result {
+            Ok(()) => {
+                info!(?summary, "Import completed");
+                summary_event("done", &summary)
+            }
+            Err(e) => {
+                warn!(error = %e, ?summary, "Import failed");
+                let error = ErrorResponse {
+                    code: e.code,
+                    message: e.message,
+                    details: serde_json::to_value(&summary).ok(),
+                };
+                Event::default()
+                    .event("error")
+                    .json_data(&error)
+                    .unwrap_or_else(|_| Event::default().event("error"))
+            }
+        };
+        let _ = events.send(event).await;
+    };
+    tokio::spawn(state.shutdown.track_connection(import.in_current_span()));
+
+    let stream = stream::unfold(receiver, |mut receiver| async move {
+        let event = receiver.recv().await?;
+        Some((Ok(event), receiver))
+    });
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+fn summary_event(name: &'static str, summary: &ImportSummary) -> Event {
+    Event::default()
+        .event(name)
+        .json_data(summary)
+        .unwrap_or_else(|_| Event::default().event(name))
+}
+
+// Handler for the /import/tensorboard endpoint
+// The body is a single event file, written to the run named in the headers
+#[instrument(skip(state, headers, body))]
+async fn import_tensorboard(
+    State(state): State<Arc<AppState>>,
+    mut headers: HeaderMap,
+    body: Body,
+) -> Result<Json<ImportSummary>, AppError> {
+    let auth = authenticate(&mut headers, state.auth_provider.as_ref()).await?;
+    let (project_name, run_id) = import_target(&headers)?;
+    auth.authorize_target(&project_name, run_id)?;
+    state.heartbeats.record(RunKey {
+        tenant_id: auth.tenant_id.clone(),
+        project_name: project_name.clone(),
+        run_id,
+    });
+
+    let stream =
+        decompress::decode_body(&headers, body, state.config.ingest_max_decompressed_bytes)?;
+    let mut converter = tfevents::Converter::new(auth.tenant_id.clone(), project_name, run_id);
+    let mut sink = ChannelSink {
+        state: &state,
+        auth: &auth,
+        headers: &headers,
+    };
+    let mut summary = ImportSummary::default();
+    match tfevents::import(stream, &mut converter, &mut sink, &mut summary).await {
+        Ok(()) => {
+            info!(?summary, "TensorBoard import completed");
+            Ok(Json(summary))
+        }
+        // Rows written before the error are kept, the details say how far the import got
+        Err(e) => {
+            warn!(error = %e, ?summary, "TensorBoard import failed");
+            Err(AppError {
+                details: serde_json::to_value(&summary).ok(),
+                ..e
+            })
+        }
+    }
+}
+
+// Project and run an import is written to
+fn import_target(headers: &HeaderMap) -> Result<(String, u64), AppError> {
+    let project_name = header_value(headers, "X-Project-Name")?
+        .ok_or_else(|| missing_header_error("X-Project-Name"))?
+        .to_string();
+    let run_id =
+        header_value(headers, "X-Run-Id")?.ok_or_else(|| missing_header_error("X-Run-Id"))?;
+    Ok((project_name, parse_run_id(run_id)?))
+}
+
+fn header_value<'a>(headers: &'a HeaderMap, name: &str) -> Result<Option<&'a str>, AppError> {
+    headers
+        .get(name)
+        .map(|h| {
+            h.to_str().map_err(|_| {
+                AppError::new(
+                    ErrorCode::InvalidHeaderFormat,
+                    format!("{} header contains invalid characters", name),
+                )
+            })
+        })
+        .transpose()
+}
+
+fn parse_run_id(run_id: &str) -> Result<u64, AppError> {
+    run_id.parse().map_err(|_| {
+        AppError::new(
+            ErrorCode::InvalidHeaderFormat,
+            "X-Run-Id must be a non-negative integer",
+        )
+    })
+}
+
+// Hands imported rows to the background processors, as the ingest routes do
+struct ChannelSink<'a> {
+    state: &'a AppState,
+    auth: &'a Auth,
+    headers: &'a HeaderMap,
+}
+
+impl ImportSink for ChannelSink<'_> {
+    async fn write(&mut self, batch: ImportBatch) -> Result<(), AppError> {
+        // Each kind of row needs the scope of the route that ingests it
+        let scopes = [
+            (batch.metrics.len(), Scope::IngestMetrics),
+            (batch.logs.len(), Scope::IngestLogs),
+            (batch.data.len(), Scope::IngestData),
+            (batch.files.len(), Scope::UploadFiles),
+        ];
+        for (rows, scope) in scopes {
+            if rows > 0 {
+                self.auth.authorize(scope, self.headers)?;
+            }
+        }
+        let state = self.state;
+        let tenant_id = &self.auth.tenant_id;
+        if state.rate_limiter.is_enabled() {
+            state
+                .rate_limiter
+                .acquire(tenant_id, batch.len() as u64, batch.bytes)?;
+        }
+
+        // The summary only counts batches that were sent completely
+        let mut sent = 0;
+        send_rows(
+            &state.metrics_record_sender,
+            tenant_id,
+            METRICS_TABLE_NAME,
+            batch.metrics,
+            &mut sent,
+        )
+        .await?;
+        send_rows(
+            &state.log_record_sender,
+            tenant_id,
+            LOGS_TABLE_NAME,
+            batch.logs,
+            &mut sent,
+        )
+        .await?;
+        send_rows(
+            &state.data_record_sender,
+            tenant_id,
+            DATA_TABLE_NAME,
+            batch.data,
+            &mut sent,
+        )
+        .await?;
+        if batch.files.is_empty() {
+            return Ok(());
+        }
+
+        // Rows of files are only sent once the files are in storage
+        let mut rows = Vec::with_capacity(batch.files.len());
+        for file in batch.files {
+            let key = file.storage_key();
+            put_file(
+                &state.storage_client,
+                &state.config,
+                key,
+                file.content_type,
+                file.bytes,
+            )
+            .await?;
+            rows.push(file.row);
+        }
+        send_rows(
+            &state.files_record_sender,
+            tenant_id,
+            FILES_TABLE_NAME,
+            rows,
+            &mut sent,
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::import::tfevents::{Event as TfEvent, Summary, SummaryValue};
+    use crate::models::metrics::MetricRow;
+    use crate::routes::testing::test_state;
+    use crate::shutdown::Shutdown;
+    use arrow_array::{ArrayRef, Float64Array, Int64Array, RecordBatch, StringArray};
+    use axum::response::IntoResponse;
+    use clickhouse::test::{handlers, Mock};
+    use parquet::arrow::ArrowWriter;
+    use prost::Message;
+    use std::time::Duration;
+    use tokio::sync::Semaphore;
+
+    fn import_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Project-Name", "project".parse().unwrap());
+        headers.insert("X-Run-Id", "7".parse().unwrap());
+        headers
+    }
+
+    fn metrics_parquet() -> Vec<u8> {
+        let record_batch = RecordBatch::try_from_iter([
+            (
+                "time",
+                Arc::new(Int64Array::from(vec![1_700_000_000_000, 1_700_000_001_000])) as ArrayRef,
+            ),
+            ("step", Arc::new(Int64Array::from(vec![1, 2])) as ArrayRef),
+            (
+                "logName",
+                Arc::new(StringArray::from(vec!["train/loss", "train/loss"])) as ArrayRef,
+            ),
+            (
+                "value",
+                Arc::new(Float64Array::from(vec![0.5, 0.25])) as ArrayRef,
+            ),
+        ])
+        .unwrap();
+        let mut parquet = Vec::new();
+        let mut writer = ArrowWriter::try_new(&mut parquet, record_batch.schema(), None).unwrap();
+        writer.write(&record_batch).unwrap();
+        writer.close().unwrap();
+        parquet
+    }
+
+    #[tokio::test]
+    async fn test_columnar_import_inserts_rows_and_reports_them() {
+        let mock = Mock::new();
+        let recording = mock.add(handlers::record::<MetricRow>());
+        let (shutdown, _trigger) = Shutdown::new(Duration::from_secs(5));
+        let (state, _channels) = test_state(mock.url(), shutdown);
+
+        let params = ColumnarImportParams {
+            table: ImportTable::Metrics,
+        };
+        let response = import_columnar(
+            State(state.clone()),
+            Query(params),
+            import_headers(),
+            Body::from(metrics_parquet()),
+        )
+        .await
+        .unwrap()
+        .into_response();
+        // The stream ends once the import has sent its final event
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("event: done"), "{}", body);
+        assert!(body.contains(r#""metrics":2"#), "{}", body);
+
+        let rows: Vec<MetricRow> = recording.collect().await;
+        let rows: Vec<_> = rows
+            .iter()
+            .map(|row| (row.time, row.step, row.value, row.run_id))
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                (1_700_000_000_000, 1, 0.5, 7),
+                (1_700_000_001_000, 2, 0.25, 7)
+            ]
+        );
+        // The permit of the import was returned
+        assert_eq!(
+            state.import_permits.available_permits(),
+            state.config.import_max_concurrent
+        );
+    }
+
+    #[tokio::test]
+    async fn test_columnar_imports_over_the_limit_are_rejected() {
+        let (shutdown, _trigger) = Shutdown::new(Duration::from_secs(5));
+        let (state, _channels) = test_state("http://localhost:8123", shutdown);
+        let mut state = (*state).clone();
+        state.import_permits = Arc::new(Semaphore::new(0));
+
+        let params = ColumnarImportParams {
+            table: ImportTable::Metrics,
+        };
+        let error = import_columnar(
+            State(Arc::new(state)),
+            Query(params),
+            import_headers(),
+            Body::from(metrics_parquet()),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert!(matches!(error.code, ErrorCode::ServiceOverloaded));
+        assert!(error.retry_after.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_tensorboard_import_sends_scalars_to_the_processors() {
+        let (shutdown, _trigger) = Shutdown::new(Duration::from_secs(5));
+        let (state, mut channels) = test_state("http://localhost:8123", shutdown);
+
+        let scalar = |step: i64, value: f32| TfEvent {
+            wall_time: 1_700_000_000.5 + step as f64,
+            step,
+            summary: Some(Summary {
+                value: vec![SummaryValue {
+                    tag: "train/loss".to_string(),
+                    simple_value: Some(value),
+                    ..Default::default()
+                }],
+            }),
+        };
+        let mut file = Vec::new();
+        for event in [scalar(1, 0.5), scalar(2, 0.25)] {
+            file.extend(tfevents::record(&event.encode_to_vec()));
+        }
+
+        let Json(summary) =
+            import_tensorboard(State(state.clone()), import_headers(), Body::from(file))
+                .await
+                .unwrap();
+        assert_eq!((summary.records, summary.metrics), (2, 2));
+
+        let first = channels.metrics.recv().await.unwrap();
+        let second = channels.metrics.recv().await.unwrap();
+        assert_eq!(
+            [(first.step, first.value), (second.step, second.value)],
+            [(1, 0.5), (2, 0.25)]
+        );
+        assert_eq!((first.time, first.run_id), (1_700_000_001_500, 7));
+    }
+}
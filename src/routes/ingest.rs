@@ -1,87 +1,335 @@
-use axum::{extract::State, routing::post, Router};
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::HeaderMap,
+    routing::post,
+    Router,
+};
+use futures::StreamExt;
+use serde::Deserialize;
 use std::sync::Arc;
-use tracing::instrument;
+use tracing::{info, instrument};
 
 use crate::{
+    auth::{auth, Scope},
+    config::LOGS_TABLE_NAME,
+    console::ConsoleDecoder,
     error::{AppError, ErrorCode},
+    heartbeat::RunKey,
     models::{
         data::{DataEnrichment, DataInput, DataRow},
         log::{LogEnrichment, LogInput, LogRow},
         metrics::{MetricEnrichment, MetricInput, MetricRow},
     },
-    processors::stream::JsonLineProcessor,
+    processors::{
+        decompress,
+        stream::{send_rows, ErrorMode, JsonLineProcessor, StreamResponse},
+    },
     routes::AppState,
-    traits::StreamProcessor,
+    runs::now_millis,
+    traits::{EnrichmentData, StreamProcessor},
 };
 
+// Header naming the console stream sent to /ingest/console, `stdout` (the default) or `stderr`
+const STREAM_NAME_HEADER: &str = "X-Stream-Name";
+
 // Defines the routes for the /ingest path
 pub fn router() -> Router<Arc<AppState>> {
     Router::new()
         .route("/ingest/metrics", post(ingest_metrics)) // Route for ingesting metrics
         .route("/ingest/logs", post(ingest_logs)) // Route for ingesting logs
         .route("/ingest/data", post(ingest_data)) // Route for ingesting generic data
+        .route("/ingest/console", post(ingest_console)) // Route for raw console output
+}
+
+// Query parameters accepted by all /ingest endpoints
+#[derive(Debug, Deserialize)]
+struct IngestParams {
+    // Set to "report" to skip invalid lines and receive a per-line error report
+    errors: Option<String>,
+}
+
+// Maps processor errors to AppError, keeping the original error code so clients see
+// the right status (e.g. 401 for auth failures, 409 for in-flight idempotent retries)
+fn stream_error(error: AppError, stream_name: &str) -> AppError {
+    AppError {
+        message: format!("Failed to process {} stream: {}", stream_name, error),
+        ..error
+    }
 }
 
 // Handler for the /ingest/metrics endpoint
-#[instrument(skip(state, headers, body))]
+#[instrument(skip(state, params, headers, body))]
 async fn ingest_metrics(
     State(state): State<Arc<AppState>>, // Access shared application state
+    Query(params): Query<IngestParams>, // Query parameters (error mode)
     headers: axum::http::HeaderMap,     // Request headers
     body: axum::body::Body,             // Request body stream
-) -> Result<String, AppError> {
+) -> Result<StreamResponse, AppError> {
+    let error_mode = ErrorMode::from_request(&headers, params.errors.as_deref())?;
     // Create a processor for JSON lines specific to Metric data
     let processor = JsonLineProcessor::<MetricInput, MetricEnrichment, MetricRow>::new(
         state.metrics_record_sender.clone(), // Sender channel for metrics
-        state.db.clone(),                    // Database connection
-    );
+        state.auth_provider.clone(),         // Authenticates the request
+        Scope::IngestMetrics,                // Scope the API key must grant
+    )
+    // Abort on the first invalid line or report per-line errors
+    .with_error_mode(error_mode)
+    .with_idempotency(state.idempotency.clone())
+    .with_heartbeats(state.heartbeats.clone())
+    .with_rate_limiter(state.rate_limiter.clone())
+    .with_max_decompressed_bytes(state.config.ingest_max_decompressed_bytes);
     // Process the incoming stream using the processor
-    processor.process_stream(headers, body).await.map_err(|e| {
-        // Map processor errors to AppError
-        AppError::new(
-            ErrorCode::ProcessingFailed,
-            format!("Failed to process metrics stream: {}", e),
-        )
-    })
+    processor
+        .process_stream(headers, body)
+        .await
+        .map_err(|e| stream_error(e, "metrics"))
 }
 
 // Handler for the /ingest/logs endpoint
-#[instrument(skip(state, headers, body))]
+#[instrument(skip(state, params, headers, body))]
 async fn ingest_logs(
     State(state): State<Arc<AppState>>,
+    Query(params): Query<IngestParams>,
     headers: axum::http::HeaderMap,
     body: axum::body::Body,
-) -> Result<String, AppError> {
+) -> Result<StreamResponse, AppError> {
+    let error_mode = ErrorMode::from_request(&headers, params.errors.as_deref())?;
     // Create a processor for JSON lines specific to Log data
     let processor = JsonLineProcessor::<LogInput, LogEnrichment, LogRow>::new(
         state.log_record_sender.clone(), // Sender channel for logs
-        state.db.clone(),
-    );
+        state.auth_provider.clone(),
+        Scope::IngestLogs,
+    )
+    .with_error_mode(error_mode)
+    .with_idempotency(state.idempotency.clone())
+    .with_heartbeats(state.heartbeats.clone())
+    .with_rate_limiter(state.rate_limiter.clone())
+    .with_max_decompressed_bytes(state.config.ingest_max_decompressed_bytes);
     // Process the incoming stream
-    processor.process_stream(headers, body).await.map_err(|e| {
-        AppError::new(
-            ErrorCode::ProcessingFailed,
-            format!("Failed to process logs stream: {}", e),
-        )
-    })
+    processor
+        .process_stream(headers, body)
+        .await
+        .map_err(|e| stream_error(e, "logs"))
 }
 
 // Handler for the /ingest/data endpoint
-#[instrument(skip(state, headers, body))]
+#[instrument(skip(state, params, headers, body))]
 async fn ingest_data(
     State(state): State<Arc<AppState>>,
+    Query(params): Query<IngestParams>,
     headers: axum::http::HeaderMap,
     body: axum::body::Body,
-) -> Result<String, AppError> {
+) -> Result<StreamResponse, AppError> {
+    let error_mode = ErrorMode::from_request(&headers, params.errors.as_deref())?;
     // Create a processor for JSON lines specific to generic Data
     let processor = JsonLineProcessor::<DataInput, DataEnrichment, DataRow>::new(
         state.data_record_sender.clone(), // Sender channel for data
-        state.db.clone(),
-    );
+        state.auth_provider.clone(),
+        Scope::IngestData,
+    )
+    .with_error_mode(error_mode)
+    .with_idempotency(state.idempotency.clone())
+    .with_heartbeats(state.heartbeats.clone())
+    .with_rate_limiter(state.rate_limiter.clone())
+    .with_max_decompressed_bytes(state.config.ingest_max_decompressed_bytes);
     // Process the incoming stream
-    processor.process_stream(headers, body).await.map_err(|e| {
-        AppError::new(
-            ErrorCode::ProcessingFailed,
-            format!("Failed to process data stream: {}", e),
+    processor
+        .process_stream(headers, body)
+        .await
+        .map_err(|e| stream_error(e, "data"))
+}
+
+// Handler for the /ingest/console endpoint
+// Takes the raw console output of a run as text/plain, one log row per line: the server
+// numbers the lines within the run and timestamps them on receipt
+#[instrument(skip(state, headers, body))]
+async fn ingest_console(
+    State(state): State<Arc<AppState>>,
+    mut headers: HeaderMap,
+    body: Body,
+) -> Result<StreamResponse, AppError> {
+    let auth = auth(
+        &mut headers,
+        state.auth_provider.as_ref(),
+        Scope::IngestLogs,
+    )
+    .await?;
+    let enrichment = LogEnrichment::from_headers(auth.tenant_id, &headers)?;
+    let log_type = console_log_type(&headers)?;
+    if let Some(run_key) = enrichment.run_key() {
+        state.heartbeats.record(run_key);
+    }
+
+    let mut body =
+        decompress::decode_body(&headers, body, state.config.ingest_max_decompressed_bytes)?;
+    // Reject tenants that are over their limits before reading the body
+    if state.rate_limiter.is_enabled() {
+        state.rate_limiter.acquire(&enrichment.tenant_id, 0, 0)?;
+    }
+
+    let mut decoder = ConsoleDecoder::default();
+    let mut sent = 0;
+    let mut result = Ok(());
+    while let Some(chunk) = body.next().await {
+        result = match chunk {
+            Ok(chunk) => {
+                let lines = decoder.push(&chunk);
+                let bytes = chunk.len() as u64;
+                send_console_lines(&state, &enrichment, log_type, lines, bytes, &mut sent).await
+            }
+            Err(e) => Err(e),
+        };
+        if result.is_err() {
+            break;
+        }
+    }
+    // A last line without a trailing newline is only complete once the body ends
+    if result.is_ok() {
+        let lines = decoder.finish().into_iter().collect();
+        result = send_console_lines(&state, &enrichment, log_type, lines, 0, &mut sent).await;
+    }
+    result.map_err(|e| stream_error(e, "console"))?;
+
+    info!(lines = sent, "Console stream processed");
+    Ok(StreamResponse::Summary(format!(
+        "Stream processed successfully: {} records",
+        sent
+    )))
+}
+
+// Log type of the lines of a console stream, from the stream name header
+fn console_log_type(headers: &HeaderMap) -> Result<&'static str, AppError> {
+    let Some(value) = headers.get(STREAM_NAME_HEADER) else {
+        return Ok("INFO");
+    };
+    match value.to_str().map(|name| name.trim().to_ascii_lowercase()) {
+        Ok(name) if name == "stdout" => Ok("INFO"),
+        Ok(name) if name == "stderr" => Ok("ERROR"),
+        _ => Err(AppError::new(
+            ErrorCode::InvalidHeaderFormat,
+            format!("{} must be 'stdout' or 'stderr'", STREAM_NAME_HEADER),
+        )),
+    }
+}
+
+// Numbers and timestamps console lines and sends them to the logs background processor
+// Line numbers are reserved before sending, so lines lost to an error leave a gap
+async fn send_console_lines(
+    state: &AppState,
+    enrichment: &LogEnrichment,
+    log_type: &str,
+    lines: Vec<String>,
+    bytes: u64, // Size of the chunk the lines were read from
+    sent: &mut u64,
+) -> Result<(), AppError> {
+    let tenant_id = &enrichment.tenant_id;
+    if state.rate_limiter.is_enabled() {
+        state
+            .rate_limiter
+            .acquire(tenant_id, lines.len() as u64, bytes)?;
+    }
+    if lines.is_empty() {
+        return Ok(());
+    }
+
+    let run_key = RunKey {
+        tenant_id: tenant_id.clone(),
+        project_name: enrichment.project_name.clone(),
+        run_id: enrichment.run_id,
+    };
+    let first = state
+        .console_lines
+        .reserve(&state.clickhouse_client, &run_key, lines.len() as u64)
+        .await?;
+    let time = now_millis();
+    let rows = (first..).zip(lines).map(|(line_number, message)| LogRow {
+        time,
+        message,
+        line_number,
+        log_type: log_type.to_string(),
+        tenant_id: tenant_id.clone(),
+        run_id: enrichment.run_id,
+        project_name: enrichment.project_name.clone(),
+    });
+    send_rows(
+        &state.log_record_sender,
+        tenant_id,
+        LOGS_TABLE_NAME,
+        rows,
+        sent,
+    )
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::StoredLines;
+    use crate::routes::testing::{test_state, TEST_TENANT_ID};
+    use crate::shutdown::Shutdown;
+    use clickhouse::test::{handlers, Mock};
+    use std::time::Duration;
+
+    fn console_headers(stream_name: Option<&str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Project-Name", "project".parse().unwrap());
+        headers.insert("X-Run-Id", "7".parse().unwrap());
+        if let Some(stream_name) = stream_name {
+            headers.insert(STREAM_NAME_HEADER, stream_name.parse().unwrap());
+        }
+        headers
+    }
+
+    #[tokio::test]
+    async fn test_console_lines_are_numbered_within_the_run() {
+        let mock = Mock::new();
+        mock.add(handlers::provide(vec![StoredLines {
+            lines: 10,
+            max_line_number: 9,
+        }]));
+        let (shutdown, _trigger) = Shutdown::new(Duration::from_secs(5));
+        let (state, mut channels) = test_state(mock.url(), shutdown);
+
+        let stdout = Body::from("epoch 1\r\n\x1b[32m 50%\r100%\x1b[0m\nno newline");
+        let response = ingest_console(State(state.clone()), console_headers(None), stdout)
+            .await
+            .unwrap();
+        assert!(
+            matches!(response, StreamResponse::Summary(summary) if summary.ends_with(": 3 records"))
+        );
+        let stderr = Body::from("Traceback\n");
+        ingest_console(
+            State(state.clone()),
+            console_headers(Some("stderr")),
+            stderr,
         )
-    })
+        .await
+        .unwrap();
+
+        let mut rows = Vec::new();
+        for _ in 0..4 {
+            let row = channels.logs.recv().await.unwrap();
+            assert_eq!(row.tenant_id, TEST_TENANT_ID);
+            assert_eq!((row.project_name.as_str(), row.run_id), ("project", 7));
+            rows.push((row.line_number, row.log_type, row.message));
+        }
+        let expected = [
+            (10, "INFO", "epoch 1"),
+            (11, "INFO", "100%"),
+            (12, "INFO", "no newline"),
+            (13, "ERROR", "Traceback"),
+        ];
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|&(line, log_type, message)| (line, log_type.to_string(), message.to_string()))
+            .collect();
+        assert_eq!(rows, expected);
+
+        let error = ingest_console(State(state), console_headers(Some("stdin")), Body::empty())
+            .await
+            .unwrap_err();
+        assert!(matches!(error.code, ErrorCode::InvalidHeaderFormat));
+    }
 }
@@ -1,28 +1,83 @@
+use aws_sdk_s3::Client as StorageClient;
 use clickhouse::Client;
 use std::sync::Arc;
-use tokio::sync::mpsc;
+use tokio::sync::Semaphore;
 
+use crate::auth::AuthProvider;
 use crate::config::Config;
-use crate::db::Database;
-use crate::models::{data::DataRow, files::FilesRow, log::LogRow, metrics::MetricRow};
+use crate::console::ConsoleLineNumbers;
+use crate::heartbeat::HeartbeatTracker;
+use crate::idempotency::IdempotencyCache;
+use crate::models::{
+    data::DataRow, files::FilesRow, log::LogRow, metrics::MetricRow, status::RunRow,
+};
+use crate::processors::background::ProcessorStatus;
+use crate::processors::fair_queue::FairSender;
+use crate::rate_limit::RateLimiter;
+use crate::runs::RunIdGenerator;
+use crate::shutdown::Shutdown;
 
+pub mod admin;
 pub mod files;
 pub mod health;
+pub mod import;
 pub mod ingest;
+pub mod otlp;
+pub mod query;
+pub mod status;
 pub mod step;
+pub mod telemetry;
+pub mod websocket;
+
+#[cfg(test)]
+pub mod testing;
 
 // Holds the shared state for the Axum application
 #[derive(Clone)]
 pub struct AppState {
-    // Sender channels for various data types to background processors
-    pub metrics_record_sender: mpsc::Sender<MetricRow>,
-    pub log_record_sender: mpsc::Sender<LogRow>,
-    pub data_record_sender: mpsc::Sender<DataRow>,
-    pub files_record_sender: mpsc::Sender<FilesRow>,
+    // Sender channels for various data types to background processors, with a sub-queue per tenant
+    pub metrics_record_sender: FairSender<MetricRow>,
+    pub log_record_sender: FairSender<LogRow>,
+    pub data_record_sender: FairSender<DataRow>,
+    pub files_record_sender: FairSender<FilesRow>,
     // ClickHouse client for direct interaction if needed
     pub clickhouse_client: Client,
-    // Arc-wrapped primary database connection pool
-    pub db: Arc<Database>,
+    // Object storage client for presigned uploads, imported files and health checks
+    pub storage_client: StorageClient,
+    // Looks up the tenant and permissions of API keys (Postgres, keys file or single tenant)
+    pub auth_provider: Arc<dyn AuthProvider>,
     // Arc-wrapped application configuration
     pub config: Arc<Config>,
+    // Progress of each background processor (used to follow new log lines and for health checks)
+    pub metrics_status: Arc<ProcessorStatus>,
+    pub logs_status: Arc<ProcessorStatus>,
+    pub data_status: Arc<ProcessorStatus>,
+    pub files_status: Arc<ProcessorStatus>,
+    // Window of recently seen idempotency keys used to deduplicate retried ingest requests
+    pub idempotency: Arc<IdempotencyCache>,
+    // Per-tenant rate limits and daily quotas of the ingest routes
+    pub rate_limiter: Arc<RateLimiter>,
+    // Allocates the numeric run IDs for newly created runs
+    pub run_ids: Arc<RunIdGenerator>,
+    // Runs created by INIT requests with an idempotency key, so a retry returns the same run
+    pub run_inits: Arc<IdempotencyCache<RunRow>>,
+    // Last-seen times of runs, recorded on every ingest call and heartbeat
+    pub heartbeats: Arc<HeartbeatTracker>,
+    // Next line number of each run that sends raw console output
+    pub console_lines: Arc<ConsoleLineNumbers>,
+    // Permits of the columnar imports, which may run at once up to IMPORT_MAX_CONCURRENT
+    pub import_permits: Arc<Semaphore>,
+    // Signals long-lived connections to close, so the record senders they hold are dropped
+    pub shutdown: Shutdown,
+}
+
+impl AppState {
+    // Closes the channels to the background processors, even if senders are still held
+    // (e.g. by connections that did not close in time), so each processor does its final flush
+    pub fn close_record_senders(&self) {
+        self.metrics_record_sender.close();
+        self.log_record_sender.close();
+        self.data_record_sender.close();
+        self.files_record_sender.close();
+    }
 }
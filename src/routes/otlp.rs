@@ -0,0 +1,360 @@
+use axum::{
+    body::Body,
+    extract::State,
+    http::{header, HeaderMap},
+    response::{IntoResponse, Response},
+    routing::post,
+    Router,
+};
+use opentelemetry_proto::tonic::collector::{
+    logs::v1::{ExportLogsPartialSuccess, ExportLogsServiceRequest, ExportLogsServiceResponse},
+    metrics::v1::{
+        ExportMetricsPartialSuccess, ExportMetricsServiceRequest, ExportMetricsServiceResponse,
+    },
+};
+use prost::Message;
+use serde::{de::DeserializeOwned, Serialize};
+use std::mem;
+use std::sync::Arc;
+use tracing::{info, instrument, warn};
+
+use crate::{
+    auth::{auth, Auth, Scope},
+    config::{LOGS_TABLE_NAME, METRICS_TABLE_NAME},
+    error::{AppError, ErrorCode},
+    heartbeat::RunKey,
+    models::log::LogRow,
+    otlp::{self, Converted},
+    processors::{decompress, fair_queue::FairSender, stream},
+    routes::AppState,
+};
+
+// Defines the OTLP/HTTP receiver routes, at the paths OTLP exporters post to by default
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/v1/metrics", post(export_metrics)) // Gauges and sums become metrics
+        .route("/v1/logs", post(export_logs)) // Log records become logs
+}
+
+// Encoding of an OTLP/HTTP request, the response uses the same encoding
+#[derive(Debug, Clone, Copy)]
+enum OtlpEncoding {
+    Protobuf,
+    Json,
+}
+
+impl OtlpEncoding {
+    fn from_headers(headers: &HeaderMap) -> Result<Self, AppError> {
+        let content_type = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or_default();
+        match content_type.split(';').next().unwrap_or("").trim() {
+            "application/x-protobuf" | "application/protobuf" => Ok(OtlpEncoding::Protobuf),
+            "application/json" => Ok(OtlpEncoding::Json),
+            other => Err(AppError::new(
+                ErrorCode::InvalidHeaderFormat,
+                format!(
+                    "Unsupported Content-Type '{}', expected application/x-protobuf or application/json",
+                    other
+                ),
+            )),
+        }
+    }
+
+    fn decode<M: Message + Default + DeserializeOwned>(self, body: &[u8]) -> Result<M, AppError> {
+        match self {
+            OtlpEncoding::Protobuf => M::decode(body).map_err(|e| {
+                AppError::new(
+                    ErrorCode::StreamDecodingError,
+                    format!("Failed to parse OTLP protobuf request: {}", e),
+                )
+            }),
+            OtlpEncoding::Json => serde_json::from_slice(body).map_err(|e| {
+                AppError::new(
+                    ErrorCode::InvalidJsonFormat,
+                    format!("Failed to parse OTLP JSON request: {}", e),
+                )
+            }),
+        }
+    }
+
+    fn respond<M: Message + Serialize>(self, message: M) -> Response {
+        match self {
+            OtlpEncoding::Protobuf => (
+                [(header::CONTENT_TYPE, "application/x-protobuf")],
+                message.encode_to_vec(),
+            )
+                .into_response(),
+            OtlpEncoding::Json => axum::Json(message).into_response(),
+        }
+    }
+}
+
+// Handler for OTLP metric exports
+#[instrument(skip(state, headers, body))]
+async fn export_metrics(
+    State(state): State<Arc<AppState>>,
+    mut headers: HeaderMap,
+    body: Body,
+) -> Result<Response, AppError> {
+    let auth = auth(
+        &mut headers,
+        state.auth_provider.as_ref(),
+        Scope::IngestMetrics,
+    )
+    .await?;
+    let encoding = OtlpEncoding::from_headers(&headers)?;
+    // OTLP requests are single messages
+    let body =
+        decompress::read_body(&headers, body, state.config.ingest_max_decompressed_bytes).await?;
+    let request: ExportMetricsServiceRequest = encoding.decode(&body)?;
+
+    let mut converted = otlp::metric_rows(request, &auth.tenant_id, &headers)?;
+    admit(&state, &auth, &converted, body.len())?;
+    send_rows(
+        &state.metrics_record_sender,
+        METRICS_TABLE_NAME,
+        &auth.tenant_id,
+        &mut converted,
+    )
+    .await?;
+
+    // Data points that were not ingested are reported as a partial success, as OTLP asks for
+    let partial_success = (converted.rejected > 0).then(|| ExportMetricsPartialSuccess {
+        rejected_data_points: converted.rejected as i64,
+        error_message: converted.error_message.unwrap_or_default(),
+    });
+    Ok(encoding.respond(ExportMetricsServiceResponse { partial_success }))
+}
+
+// Handler for OTLP log exports
+#[instrument(skip(state, headers, body))]
+async fn export_logs(
+    State(state): State<Arc<AppState>>,
+    mut headers: HeaderMap,
+    body: Body,
+) -> Result<Response, AppError> {
+    let auth = auth(
+        &mut headers,
+        state.auth_provider.as_ref(),
+        Scope::IngestLogs,
+    )
+    .await?;
+    let encoding = OtlpEncoding::from_headers(&headers)?;
+    let body =
+        decompress::read_body(&headers, body, state.config.ingest_max_decompressed_bytes).await?;
+    let request: ExportLogsServiceRequest = encoding.decode(&body)?;
+
+    let mut converted = otlp::log_rows(request, &auth.tenant_id, &headers)?;
+    admit(&state, &auth, &converted, body.len())?;
+    number_lines(&state, &auth.tenant_id, &mut converted).await?;
+    send_rows(
+        &state.log_record_sender,
+        LOGS_TABLE_NAME,
+        &auth.tenant_id,
+        &mut converted,
+    )
+    .await?;
+
+    let partial_success = (converted.rejected > 0).then(|| ExportLogsPartialSuccess {
+        rejected_log_records: converted.rejected as i64,
+        error_message: converted.error_message.unwrap_or_default(),
+    });
+    Ok(encoding.respond(ExportLogsServiceResponse { partial_success }))
+}
+
+// Checks that the credentials may write to every run of the request and charges the rows
+// to the tenant's limits
+fn admit<T>(
+    state: &AppState,
+    auth: &Auth,
+    converted: &Converted<T>,
+    bytes: usize,
+) -> Result<(), AppError> {
+    for target in &converted.targets {
+        auth.authorize_target(&target.project_name, target.run_id)?;
+    }
+    let tenant_id = &auth.tenant_id;
+    if state.rate_limiter.is_enabled() {
+        state
+            .rate_limiter
+            .acquire(tenant_id, converted.rows.len() as u64, bytes as u64)?;
+    }
+    for target in &converted.targets {
+        state.heartbeats.record(RunKey {
+            tenant_id: tenant_id.clone(),
+            project_name: target.project_name.clone(),
+            run_id: target.run_id,
+        });
+    }
+    Ok(())
+}
+
+// Numbers the log records of each run after the run's earlier lines, which include its console output
+// Numbers are reserved before sending, so records lost to an error leave a gap
+async fn number_lines(
+    state: &AppState,
+    tenant_id: &str,
+    converted: &mut Converted<LogRow>,
+) -> Result<(), AppError> {
+    for target in &converted.targets {
+        let rows: Vec<&mut LogRow> = converted
+            .rows
+            .iter_mut()
+            .filter(|row| row.run_id == target.run_id && row.project_name == target.project_name)
+            .collect();
+        if rows.is_empty() {
+            continue;
+        }
+        let run_key = RunKey {
+            tenant_id: tenant_id.to_string(),
+            project_name: target.project_name.clone(),
+            run_id: target.run_id,
+        };
+        let first = state
+            .console_lines
+            .reserve(&state.clickhouse_client, &run_key, rows.len() as u64)
+            .await?;
+        for (line_number, row) in (first..).zip(rows) {
+            row.line_number = line_number;
+        }
+    }
+    Ok(())
+}
+
+// Hands the rows to the background processor
+// Fails only if no row was sent: exporters retry failed requests as a whole, so once some rows
+// are queued the ones that could not be sent are counted as rejected in `converted` instead
+async fn send_rows<T>(
+    sender: &FairSender<T>,
+    table_name: &'static str,
+    tenant_id: &str,
+    converted: &mut Converted<T>,
+) -> Result<(), AppError> {
+    let rows = mem::take(&mut converted.rows);
+    let total = rows.len() as u64;
+    let mut sent = 0;
+    let result = stream::send_rows(sender, tenant_id, table_name, rows, &mut sent)
+        .await
+        .map_err(AppError::from);
+    info!(rows = total, sent, "OTLP export processed");
+    match result {
+        Err(e) if sent > 0 => {
+            warn!(error = %e, unsent = total - sent, "OTLP export partly ingested, rejecting the rest");
+            converted.reject(total - sent, || e.message);
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::StoredLines;
+    use crate::processors::fair_queue::SendLimits;
+    use crate::routes::testing::test_state;
+    use crate::shutdown::Shutdown;
+    use axum::http::StatusCode;
+    use clickhouse::test::{handlers, Mock};
+    use std::time::Duration;
+
+    // Export of three gauge data points, the first two fit below the high-water mark
+    const GAUGES: &str = r#"{ "resourceMetrics": [{ "scopeMetrics": [{ "metrics": [
+        { "name": "loss", "gauge": { "dataPoints": [
+            { "timeUnixNano": "1700000000000000000", "asDouble": 0.3 },
+            { "timeUnixNano": "1700000001000000000", "asDouble": 0.2 },
+            { "timeUnixNano": "1700000002000000000", "asDouble": 0.1 }
+        ] } }
+    ] }] }] }"#;
+
+    fn export_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, "application/json".parse().unwrap());
+        headers.insert("X-Project-Name", "project".parse().unwrap());
+        headers.insert("X-Run-Id", "7".parse().unwrap());
+        headers
+    }
+
+    #[tokio::test]
+    async fn test_overload_after_some_rows_is_a_partial_success() {
+        let (shutdown, _trigger) = Shutdown::new(Duration::from_secs(5));
+        let (state, mut channels) = test_state("http://localhost:8123", shutdown);
+        let mut state = (*state).clone();
+        state.metrics_record_sender = state.metrics_record_sender.with_limits(SendLimits {
+            timeout: None,
+            high_water_mark: Some(2),
+        });
+        let state = Arc::new(state);
+
+        // Resending the export would duplicate the queued rows, so it succeeds
+        let response = export_metrics(State(state.clone()), export_headers(), Body::from(GAUGES))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let response: ExportMetricsServiceResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(response.partial_success.unwrap().rejected_data_points, 1);
+
+        // Nothing was queued this time, so the exporter may safely retry
+        let error = export_metrics(State(state), export_headers(), Body::from(GAUGES))
+            .await
+            .unwrap_err();
+        assert!(error.retry_after.is_some());
+
+        let first = channels.metrics.recv().await.unwrap();
+        let second = channels.metrics.recv().await.unwrap();
+        assert_eq!((first.value, second.value), (0.3, 0.2));
+    }
+
+    #[tokio::test]
+    async fn test_log_records_are_numbered_within_their_run() {
+        let mock = Mock::new();
+        let stored = |lines, max_line_number| {
+            handlers::provide(vec![StoredLines {
+                lines,
+                max_line_number,
+            }])
+        };
+        mock.add(stored(2, 1)); // Run 7 has two lines
+        mock.add(stored(0, 0)); // Run 8 has none
+        let (shutdown, _trigger) = Shutdown::new(Duration::from_secs(5));
+        let (state, mut channels) = test_state(mock.url(), shutdown);
+
+        let logs = r#"{ "resourceLogs": [
+            { "scopeLogs": [{ "logRecords": [
+                { "body": { "stringValue": "a" } }, { "body": { "stringValue": "b" } }
+            ] }] },
+            { "resource": { "attributes": [
+                { "key": "mlop.project_name", "value": { "stringValue": "project" } },
+                { "key": "mlop.run_id", "value": { "intValue": "8" } }
+              ] },
+              "scopeLogs": [{ "logRecords": [{ "body": { "stringValue": "c" } }] }] }
+        ] }"#;
+        export_logs(State(state.clone()), export_headers(), Body::from(logs))
+            .await
+            .unwrap();
+        // Later exports continue the numbering, without another query
+        let logs = r#"{ "resourceLogs": [{ "scopeLogs": [{ "logRecords": [
+            { "body": { "stringValue": "d" } }
+        ] }] }] }"#;
+        export_logs(State(state), export_headers(), Body::from(logs))
+            .await
+            .unwrap();
+
+        let mut rows = Vec::new();
+        for _ in 0..4 {
+            let row = channels.logs.recv().await.unwrap();
+            rows.push((row.message, row.run_id, row.line_number));
+        }
+        let expected = [("a", 7, 2), ("b", 7, 3), ("c", 8, 0), ("d", 7, 4)];
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|&(message, run_id, line)| (message.to_string(), run_id, line))
+            .collect();
+        assert_eq!(rows, expected);
+    }
+}
@@ -0,0 +1,795 @@
+use axum::{
+    extract::{Query as QueryParams, State},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Json,
+    },
+    routing::{get, post},
+    Router,
+};
+use clickhouse::query::Query;
+use clickhouse::sql::Identifier;
+use clickhouse::{Client, Row};
+use futures::stream::{self, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{debug, warn};
+
+use crate::{
+    auth::{auth, Scope},
+    config::{LOGS_TABLE_NAME, METRICS_TABLE_NAME},
+    error::{AppError, ErrorCode},
+    models::{log::LogEnrichment, metrics::MetricEnrichment},
+    routes::AppState,
+    traits::EnrichmentData,
+};
+
+// Upper bound for the number of points requested per series when downsampling
+const MAX_POINTS: u32 = 10_000;
+// Upper bound for the number of raw points returned per series without downsampling
+const MAX_RAW_POINTS: u64 = 100_000;
+// Default and maximum number of log lines returned per page
+const DEFAULT_LOG_PAGE_SIZE: u64 = 1_000;
+const MAX_LOG_PAGE_SIZE: u64 = 10_000;
+// How often followers poll for new lines when no flush notification arrives
+// (covers lines flushed by other server instances)
+const FOLLOW_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+// Defines the router for the /query endpoints
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/query/metrics", post(query_metrics))
+        .route("/query/logs", post(query_logs))
+        .route("/query/logs/follow", get(follow_logs))
+}
+
+// Downsampling strategies applied in ClickHouse
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Downsample {
+    // Largest-Triangle-Three-Buckets, keeps the visual shape of the series
+    #[default]
+    Lttb,
+    // Minimum and maximum value of each step bucket, keeps spikes
+    MinMax,
+}
+
+/// Request body for reading metric series back
+///
+/// # Example
+/// ```json
+/// {
+///     "logNames": ["train/loss", "train/accuracy"],
+///     "stepMin": 0,
+///     "stepMax": 10000,
+///     "points": 500,
+///     "downsample": "lttb"
+/// }
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct MetricQueryRequest {
+    // Exact metric names to read
+    #[serde(default)]
+    pub log_names: Vec<String>,
+    // Alternatively, read all metrics whose log group starts with this prefix
+    pub log_group: Option<String>,
+    // Optional inclusive step range
+    pub step_min: Option<u64>,
+    pub step_max: Option<u64>,
+    // Downsample each series to about this many points (no downsampling if omitted)
+    pub points: Option<u32>,
+    #[serde(default)]
+    pub downsample: Downsample,
+}
+
+// A single point of a metric series
+#[derive(Debug, Row, Deserialize)]
+struct MetricPointRow {
+    #[serde(rename = "logName")]
+    log_name: String,
+    step: u64,
+    time: u64,
+    value: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MetricPoint {
+    pub step: u64,
+    pub time: u64, // Milliseconds since the Unix epoch
+    pub value: f64,
+}
+
+/// Response containing the requested series, keyed by log name
+/// Example:
+/// {
+///     "series": {
+///         "train/loss": [{ "step": 1, "time": 1700000000000, "value": 0.5 }]
+///     }
+/// }
+#[derive(Debug, Serialize)]
+pub struct MetricQueryResponse {
+    pub series: HashMap<String, Vec<MetricPoint>>,
+}
+
+// Filter shared by every metric query, rendered as a SQL condition with `?` placeholders
+struct MetricFilter {
+    tenant_id: String,
+    project_name: String,
+    run_id: u64,
+    log_names: Vec<String>,
+    log_group: Option<String>,
+    step_min: Option<u64>,
+    step_max: Option<u64>,
+}
+
+impl MetricFilter {
+    fn sql(&self) -> String {
+        let mut sql = String::from("tenantId = ? AND projectName = ? AND runId = ?");
+        if self.log_group.is_some() {
+            sql.push_str(" AND startsWith(logGroup, ?)");
+        } else {
+            sql.push_str(" AND has(?, logName)");
+        }
+        if self.step_min.is_some() {
+            sql.push_str(" AND step >= ?");
+        }
+        if self.step_max.is_some() {
+            sql.push_str(" AND step <= ?");
+        }
+        sql
+    }
+
+    // Binds the filter values in the same order as the placeholders in `sql()`
+    fn bind(&self, query: Query) -> Query {
+        let mut query = query
+            .bind(&self.tenant_id)
+            .bind(&self.project_name)
+            .bind(self.run_id);
+        query = match &self.log_group {
+            Some(log_group) => query.bind(log_group),
+            None => query.bind(&self.log_names),
+        };
+        if let Some(step_min) = self.step_min {
+            query = query.bind(step_min);
+        }
+        if let Some(step_max) = self.step_max {
+            query = query.bind(step_max);
+        }
+        query
+    }
+}
+
+// Handler for the POST /query/metrics endpoint
+// Reads (step, time, value) series for a run, optionally downsampled in ClickHouse
+async fn query_metrics(
+    State(state): State<Arc<AppState>>,
+    mut headers: axum::http::HeaderMap,
+    Json(request): Json<MetricQueryRequest>,
+) -> Result<Json<MetricQueryResponse>, AppError> {
+    // Authenticate the request
+    let auth = auth(&mut headers, state.auth_provider.as_ref(), Scope::Read).await?;
+    // Extract enrichment data (project, run ID) from headers
+    let enrichment = MetricEnrichment::from_headers(auth.tenant_id, &headers)?;
+
+    if request.log_names.is_empty() == request.log_group.is_none() {
+        return Err(AppError::new(
+            ErrorCode::MissingRequiredField,
+            "Exactly one of 'logNames' or 'logGroup' must be provided",
+        ));
+    }
+    if let (Some(step_min), Some(step_max)) = (request.step_min, request.step_max) {
+        if step_min > step_max {
+            return Err(AppError::new(
+                ErrorCode::InvalidStepValue,
+                "'stepMin' must not be greater than 'stepMax'",
+            ));
+        }
+    }
+    if let Some(points) = request.points {
+        if !(2..=MAX_POINTS).contains(&points) {
+            return Err(AppError::new(
+                ErrorCode::InvalidInput,
+                format!("'points' must be between 2 and {}", MAX_POINTS),
+            ));
+        }
+    }
+
+    let filter = MetricFilter {
+        tenant_id: enrichment.tenant_id,
+        project_name: enrichment.project_name,
+        run_id: enrichment.run_id,
+        log_names: request.log_names,
+        log_group: request.log_group,
+        step_min: request.step_min,
+        step_max: request.step_max,
+    };
+
+    let query = match (request.points, request.downsample) {
+        (None, _) => raw_query(&state, &filter),
+        (Some(points), Downsample::Lttb) => lttb_query(&state, &filter, points),
+        (Some(points), Downsample::MinMax) => min_max_query(&state, &filter, points),
+    };
+    let rows = query.fetch_all::<MetricPointRow>().await?;
+
+    // Group the points by log name, rows are already ordered by step
+    let mut series: HashMap<String, Vec<MetricPoint>> = HashMap::new();
+    for row in rows {
+        series.entry(row.log_name).or_default().push(MetricPoint {
+            step: row.step,
+            time: row.time,
+            value: row.value,
+        });
+    }
+
+    Ok(Json(MetricQueryResponse { series }))
+}
+
+// All points of each series, capped at MAX_RAW_POINTS per series
+fn raw_query(state: &AppState, filter: &MetricFilter) -> Query {
+    let sql = format!(
+        "SELECT logName, step, toUInt64(toUnixTimestamp64Milli(time)) AS time_ms, value \
+         FROM ? WHERE {} \
+         ORDER BY logName, step \
+         LIMIT ? BY logName",
+        filter.sql()
+    );
+    let query = state
+        .clickhouse_client
+        .query(&sql)
+        .bind(Identifier(METRICS_TABLE_NAME));
+    filter.bind(query).bind(MAX_RAW_POINTS)
+}
+
+// LTTB downsampling via ClickHouse's largestTriangleThreeBuckets
+// The selected steps are joined back to the table to recover each point's time
+fn lttb_query(state: &AppState, filter: &MetricFilter, points: u32) -> Query {
+    let filter_sql = filter.sql();
+    let sql = format!(
+        "SELECT logName, step, toUInt64(toUnixTimestamp64Milli(time)) AS time_ms, value \
+         FROM ? WHERE {filter_sql} AND (logName, step) IN ( \
+             SELECT logName, toUInt64(tupleElement(p, 1)) FROM ( \
+                 SELECT logName, arrayJoin(largestTriangleThreeBuckets(?)(step, value)) AS p \
+                 FROM ? WHERE {filter_sql} GROUP BY logName)) \
+         ORDER BY logName, step \
+         LIMIT 1 BY logName, step"
+    );
+    let query = state
+        .clickhouse_client
+        .query(&sql)
+        .bind(Identifier(METRICS_TABLE_NAME));
+    let query = filter
+        .bind(query)
+        .bind(points)
+        .bind(Identifier(METRICS_TABLE_NAME));
+    filter.bind(query)
+}
+
+// Min/max downsampling: the step range of each series is split into `points / 2` buckets
+// and the points holding the minimum and maximum value of each bucket are kept
+fn min_max_query(state: &AppState, filter: &MetricFilter, points: u32) -> Query {
+    let sql = format!(
+        "SELECT logName, tupleElement(p, 1) AS step, tupleElement(p, 2) AS time_ms, tupleElement(p, 3) AS value \
+         FROM ( \
+             SELECT logName, arrayJoin(arrayDistinct([ \
+                 argMin(tuple(step, time_ms, value), value), \
+                 argMax(tuple(step, time_ms, value), value)])) AS p \
+             FROM ( \
+                 SELECT logName, step, toUInt64(toUnixTimestamp64Milli(time)) AS time_ms, value, \
+                     intDiv((step - min(step) OVER w) * ?, max(step) OVER w - min(step) OVER w + 1) AS bucket \
+                 FROM ? WHERE {} \
+                 WINDOW w AS (PARTITION BY logName)) \
+             GROUP BY logName, bucket) \
+         ORDER BY logName, step",
+        filter.sql()
+    );
+    let query = state
+        .clickhouse_client
+        .query(&sql)
+        .bind((points / 2).max(1))
+        .bind(Identifier(METRICS_TABLE_NAME));
+    filter.bind(query)
+}
+
+// How the `search` term is matched against log messages
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchMode {
+    // Case-insensitive substring match
+    #[default]
+    Substring,
+    // Case-insensitive whole-token match, the term must be a single token
+    Token,
+}
+
+/// Request body for paging through a run's console output
+///
+/// # Example
+/// ```json
+/// {
+///     "afterLine": 1200,
+///     "limit": 500,
+///     "logTypes": ["ERROR", "WARNING"],
+///     "search": "nan",
+///     "searchMode": "substring"
+/// }
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct LogQueryRequest {
+    // Only return lines with a line number greater than this (paging cursor)
+    pub after_line: Option<u64>,
+    // Optional time range in milliseconds since the Unix epoch (inclusive)
+    pub time_from: Option<u64>,
+    pub time_to: Option<u64>,
+    // Only return lines of these log types
+    #[serde(default)]
+    pub log_types: Vec<String>,
+    // Only return lines whose message matches this term
+    pub search: Option<String>,
+    #[serde(default)]
+    pub search_mode: SearchMode,
+    // Return the last `limit` matching lines instead of the first ones
+    #[serde(default)]
+    pub tail: bool,
+    pub limit: Option<u64>,
+}
+
+// Query parameters for following a run's console output
+// `logTypes` is a comma-separated list
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LogFollowParams {
+    pub after_line: Option<u64>,
+    pub log_types: Option<String>,
+    pub search: Option<String>,
+    #[serde(default)]
+    pub search_mode: SearchMode,
+}
+
+#[derive(Debug, Row, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LogLine {
+    pub line_number: u64,
+    pub time: u64, // Milliseconds since the Unix epoch
+    pub log_type: String,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LogQueryResponse {
+    pub lines: Vec<LogLine>,
+    // Cursor for the next page (pass as `afterLine`), absent if there are no more lines
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_after_line: Option<u64>,
+}
+
+// Filter shared by log queries, rendered as a SQL condition with `?` placeholders
+#[derive(Clone)]
+struct LogFilter {
+    tenant_id: String,
+    project_name: String,
+    run_id: u64,
+    after_line: Option<u64>,
+    time_from: Option<u64>,
+    time_to: Option<u64>,
+    log_types: Vec<String>,
+    search: Option<String>,
+    search_mode: SearchMode,
+}
+
+impl LogFilter {
+    fn new(
+        enrichment: LogEnrichment,
+        log_types: Vec<String>,
+        search: Option<String>,
+        search_mode: SearchMode,
+    ) -> Result<Self, AppError> {
+        let search = search.filter(|s| !s.is_empty());
+        if let (Some(term), SearchMode::Token) = (&search, search_mode) {
+            // hasToken only accepts a single token without separators
+            if !term.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                return Err(AppError::new(
+                    ErrorCode::InvalidInput,
+                    "Token search terms may only contain letters, digits and underscores",
+                ));
+            }
+        }
+
+        Ok(Self {
+            tenant_id: enrichment.tenant_id,
+            project_name: enrichment.project_name,
+            run_id: enrichment.run_id,
+            after_line: None,
+            time_from: None,
+            time_to: None,
+            log_types,
+            search,
+            search_mode,
+        })
+    }
+
+    fn sql(&self) -> String {
+        let mut sql = String::from("tenantId = ? AND projectName = ? AND runId = ?");
+        if self.after_line.is_some() {
+            sql.push_str(" AND lineNumber > ?");
+        }
+        if self.time_from.is_some() {
+            sql.push_str(" AND time >= fromUnixTimestamp64Milli(toInt64(?))");
+        }
+        if self.time_to.is_some() {
+            sql.push_str(" AND time <= fromUnixTimestamp64Milli(toInt64(?))");
+        }
+        if !self.log_types.is_empty() {
+            sql.push_str(" AND has(?, logType)");
+        }
+        match (&self.search, self.search_mode) {
+            (Some(_), SearchMode::Substring) => {
+                sql.push_str(" AND positionCaseInsensitiveUTF8(message, ?) > 0")
+            }
+            (Some(_), SearchMode::Token) => {
+                sql.push_str(" AND hasTokenCaseInsensitive(message, ?)")
+            }
+            (None, _) => {}
+        }
+        sql
+    }
+
+    // Binds the filter values in the same order as the placeholders in `sql()`
+    fn bind(&self, query: Query) -> Query {
+        let mut query = query
+            .bind(&self.tenant_id)
+            .bind(&self.project_name)
+            .bind(self.run_id);
+        if let Some(after_line) = self.after_line {
+            query = query.bind(after_line);
+        }
+        if let Some(time_from) = self.time_from {
+            query = query.bind(time_from);
+        }
+        if let Some(time_to) = self.time_to {
+            query = query.bind(time_to);
+        }
+        if !self.log_types.is_empty() {
+            query = query.bind(&self.log_types);
+        }
+        if let Some(search) = &self.search {
+            query = query.bind(search);
+        }
+        query
+    }
+
+    // Fetches up to `limit` matching lines, ordered by line number
+    async fn fetch(
+        &self,
+        client: &Client,
+        limit: u64,
+        tail: bool,
+    ) -> Result<Vec<LogLine>, AppError> {
+        let order = if tail { "DESC" } else { "ASC" };
+        let sql = format!(
+            "SELECT lineNumber, toUInt64(toUnixTimestamp64Milli(time)) AS time_ms, logType, message \
+             FROM ? WHERE {} \
+             ORDER BY lineNumber {order}, time {order} \
+             LIMIT ?",
+            self.sql()
+        );
+        let query = client.query(&sql).bind(Identifier(LOGS_TABLE_NAME));
+        let mut lines = self.bind(query).bind(limit).fetch_all::<LogLine>().await?;
+        if tail {
+            lines.reverse();
+        }
+        Ok(lines)
+    }
+}
+
+// Handler for the POST /query/logs endpoint
+// Pages through a run's console output by line number, with optional filters and search
+async fn query_logs(
+    State(state): State<Arc<AppState>>,
+    mut headers: axum::http::HeaderMap,
+    Json(request): Json<LogQueryRequest>,
+) -> Result<Json<LogQueryResponse>, AppError> {
+    // Authenticate the request
+    let auth = auth(&mut headers, state.auth_provider.as_ref(), Scope::Read).await?;
+    // Extract enrichment data (project, run ID) from headers
+    let enrichment = LogEnrichment::from_headers(auth.tenant_id, &headers)?;
+
+    let limit = request.limit.unwrap_or(DEFAULT_LOG_PAGE_SIZE);
+    if !(1..=MAX_LOG_PAGE_SIZE).contains(&limit) {
+        return Err(AppError::new(
+            ErrorCode::InvalidInput,
+            format!("'limit' must be between 1 and {}", MAX_LOG_PAGE_SIZE),
+        ));
+    }
+
+    let mut filter = LogFilter::new(
+        enrichment,
+        request.log_types,
+        request.search,
+        request.search_mode,
+    )?;
+    filter.after_line = request.after_line;
+    filter.time_from = request.time_from;
+    filter.time_to = request.time_to;
+
+    let lines = filter
+        .fetch(&state.clickhouse_client, limit, request.tail)
+        .await?;
+    // A full page means there may be more lines after the last one
+    let next_after_line = match lines.last() {
+        Some(last) if !request.tail && lines.len() as u64 == limit => Some(last.line_number),
+        _ => None,
+    };
+
+    Ok(Json(LogQueryResponse {
+        lines,
+        next_after_line,
+    }))
+}
+
+// State carried between events of a follow stream
+// Holds no `AppState`, so open followers do not keep the record senders alive on shutdown
+struct FollowState {
+    client: Client,
+    filter: LogFilter,
+    flushes: watch::Receiver<u64>, // Closed once the logs processor has exited
+    pending: VecDeque<LogLine>,
+    fetch_now: bool, // Fetch immediately instead of waiting for the next flush
+}
+
+// Handler for the GET /query/logs/follow endpoint
+// Streams new console lines as server-sent events as they are flushed to ClickHouse
+// Each event carries one line as JSON, with the line number as the event id so clients
+// can resume with `Last-Event-ID`
+async fn follow_logs(
+    State(state): State<Arc<AppState>>,
+    mut headers: axum::http::HeaderMap,
+    QueryParams(params): QueryParams<LogFollowParams>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, AppError> {
+    // Authenticate the request
+    let auth = auth(&mut headers, state.auth_provider.as_ref(), Scope::Read).await?;
+    // Extract enrichment data (project, run ID) from headers
+    let enrichment = LogEnrichment::from_headers(auth.tenant_id, &headers)?;
+
+    let log_types = params
+        .log_types
+        .map(|types| {
+            types
+                .split(',')
+                .map(|t| t.trim().to_string())
+                .filter(|t| !t.is_empty())
+                .collect()
+        })
+        .unwrap_or_default();
+    let mut filter = LogFilter::new(enrichment, log_types, params.search, params.search_mode)?;
+
+    // Resume after the last event the client received, if it reconnects
+    let last_event_id = headers
+        .get("Last-Event-ID")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|id| id.parse::<u64>().ok());
+    filter.after_line = last_event_id.or(params.after_line);
+
+    let follow_state = FollowState {
+        flushes: state.logs_status.subscribe_flushes(),
+        client: state.clickhouse_client.clone(),
+        filter,
+        pending: VecDeque::new(),
+        fetch_now: true,
+    };
+
+    let events = stream::unfold(follow_state, |mut follow| async move {
+        loop {
+            if let Some(line) = follow.pending.pop_front() {
+                let event = Event::default()
+                    .id(line.line_number.to_string())
+                    .json_data(&line)
+                    .unwrap_or_else(|_| {
+                        Event::default().event("error").data("serialization failed")
+                    });
+                return Some((Ok(event), follow));
+            }
+
+            // Wait for the logs processor to confirm a flush, polling in case lines
+            // were flushed elsewhere
+            if !follow.fetch_now {
+                tokio::select! {
+                    changed = follow.flushes.changed() => {
+                        // The processor is gone, the server is shutting down
+                        if changed.is_err() {
+                            return None;
+                        }
+                    }
+                    _ = tokio::time::sleep(FOLLOW_POLL_INTERVAL) => {}
+                }
+            }
+
+            match follow
+                .filter
+                .fetch(&follow.client, MAX_LOG_PAGE_SIZE, false)
+                .await
+            {
+                Ok(lines) => {
+                    // Keep fetching without waiting while there is a backlog
+                    follow.fetch_now = lines.len() as u64 == MAX_LOG_PAGE_SIZE;
+                    if let Some(last) = lines.last() {
+                        follow.filter.after_line = Some(last.line_number);
+                    }
+                    debug!(count = lines.len(), "Fetched new log lines for follower");
+                    follow.pending.extend(lines);
+                }
+                Err(e) => {
+                    warn!(error = %e, "Failed to fetch log lines for follower");
+                    follow.fetch_now = false;
+                    return Some((Ok(Event::default().event("error").data(e.message)), follow));
+                }
+            }
+        }
+    });
+    // End the stream on shutdown, so graceful shutdown does not wait for the client to leave
+    let shutdown = state.shutdown.clone();
+    let events = events.take_until(async move { shutdown.requested().await });
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes::testing::test_state;
+    use crate::shutdown::Shutdown;
+    use axum::response::IntoResponse;
+    use clickhouse::test::{handlers, Mock};
+
+    fn filter(
+        log_group: Option<&str>,
+        step_min: Option<u64>,
+        step_max: Option<u64>,
+    ) -> MetricFilter {
+        MetricFilter {
+            tenant_id: "tenant".to_string(),
+            project_name: "project".to_string(),
+            run_id: 7,
+            log_names: vec!["train/loss".to_string(), "train/acc".to_string()],
+            log_group: log_group.map(str::to_string),
+            step_min,
+            step_max,
+        }
+    }
+
+    // SQL of a query as sent to ClickHouse, with its values bound
+    async fn bound_sql(mock: &Mock, query: Query) -> String {
+        let recording = mock.add(handlers::record_ddl());
+        query.execute().await.unwrap();
+        recording.query().await
+    }
+
+    const FILTER_SQL: &str = "tenantId = 'tenant' AND projectName = 'project' AND runId = 7";
+
+    #[tokio::test]
+    async fn test_raw_query_limits_each_series() {
+        let mock = Mock::new();
+        let (shutdown, _trigger) = Shutdown::new(Duration::from_secs(5));
+        let (state, _channels) = test_state(mock.url(), shutdown);
+
+        let sql = bound_sql(&mock, raw_query(&state, &filter(None, Some(10), None))).await;
+        let condition = format!(
+            "FROM `mlop_metrics` WHERE {} AND has(['train/loss','train/acc'], logName) AND step >= 10 ORDER BY",
+            FILTER_SQL
+        );
+        assert!(sql.contains(&condition), "{}", sql);
+        assert!(sql.ends_with("LIMIT 100000 BY logName"), "{}", sql);
+    }
+
+    #[tokio::test]
+    async fn test_lttb_query_filters_both_selects() {
+        let mock = Mock::new();
+        let (shutdown, _trigger) = Shutdown::new(Duration::from_secs(5));
+        let (state, _channels) = test_state(mock.url(), shutdown);
+
+        let filter = filter(Some("train"), Some(10), Some(20));
+        let sql = bound_sql(&mock, lttb_query(&state, &filter, 500)).await;
+        let condition = format!(
+            "FROM `mlop_metrics` WHERE {} AND startsWith(logGroup, 'train') AND step >= 10 AND step <= 20",
+            FILTER_SQL
+        );
+        assert_eq!(sql.matches(&condition).count(), 2, "{}", sql);
+        assert!(
+            sql.contains("largestTriangleThreeBuckets(500)(step, value)"),
+            "{}",
+            sql
+        );
+    }
+
+    #[tokio::test]
+    async fn test_min_max_query_keeps_at_least_one_bucket() {
+        let mock = Mock::new();
+        let (shutdown, _trigger) = Shutdown::new(Duration::from_secs(5));
+        let (state, _channels) = test_state(mock.url(), shutdown);
+
+        // Each bucket keeps two points
+        let sql = bound_sql(&mock, min_max_query(&state, &filter(None, None, None), 500)).await;
+        assert!(sql.contains("(step - min(step) OVER w) * 250,"), "{}", sql);
+        let sql = bound_sql(
+            &mock,
+            min_max_query(&state, &filter(None, None, Some(20)), 3),
+        )
+        .await;
+        assert!(sql.contains("(step - min(step) OVER w) * 1,"), "{}", sql);
+        let condition = format!(
+            "FROM `mlop_metrics` WHERE {} AND has(['train/loss','train/acc'], logName) AND step <= 20 WINDOW",
+            FILTER_SQL
+        );
+        assert!(sql.contains(&condition), "{}", sql);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_metric_queries_are_rejected() {
+        let (shutdown, _trigger) = Shutdown::new(Duration::from_secs(5));
+        let (state, _channels) = test_state("http://localhost:8123", shutdown);
+        let query = |request: serde_json::Value| {
+            let mut headers = axum::http::HeaderMap::new();
+            headers.insert("X-Project-Name", "project".parse().unwrap());
+            headers.insert("X-Run-Id", "1".parse().unwrap());
+            let request = serde_json::from_value(request).unwrap();
+            query_metrics(State(state.clone()), headers, Json(request))
+        };
+
+        let error = query(serde_json::json!({})).await.unwrap_err();
+        assert!(matches!(error.code, ErrorCode::MissingRequiredField));
+        let error = query(serde_json::json!({ "logNames": ["a"], "logGroup": "a" }))
+            .await
+            .unwrap_err();
+        assert!(matches!(error.code, ErrorCode::MissingRequiredField));
+        let error = query(serde_json::json!({ "logNames": ["a"], "stepMin": 2, "stepMax": 1 }))
+            .await
+            .unwrap_err();
+        assert!(matches!(error.code, ErrorCode::InvalidStepValue));
+        for points in [0, 1, MAX_POINTS + 1] {
+            let error = query(serde_json::json!({ "logNames": ["a"], "points": points }))
+                .await
+                .unwrap_err();
+            assert!(matches!(error.code, ErrorCode::InvalidInput), "{}", points);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_followers_end_on_shutdown_without_holding_state() {
+        let mock = Mock::new();
+        mock.add(handlers::provide(Vec::<LogLine>::new()));
+        let (shutdown, trigger) = Shutdown::new(Duration::from_secs(5));
+        let (state, _channels) = test_state(mock.url(), shutdown);
+        let state_ref = Arc::downgrade(&state);
+
+        let mut headers = axum::http::HeaderMap::new();
+        headers.insert("X-Project-Name", "project".parse().unwrap());
+        headers.insert("X-Run-Id", "1".parse().unwrap());
+        let params = LogFollowParams {
+            after_line: None,
+            log_types: None,
+            search: None,
+            search_mode: SearchMode::default(),
+        };
+        let sse = follow_logs(State(state), headers, QueryParams(params))
+            .await
+            .unwrap();
+        // The follower only keeps the ClickHouse client and the flush notifications
+        assert!(state_ref.upgrade().is_none());
+
+        let body = sse.into_response().into_body();
+        let read = tokio::spawn(axum::body::to_bytes(body, usize::MAX));
+        tokio::time::sleep(Duration::from_millis(100)).await; // Let the first fetch finish
+        trigger.fire();
+        tokio::time::timeout(Duration::from_secs(1), read)
+            .await
+            .expect("Follow stream did not end on shutdown")
+            .unwrap()
+            .unwrap();
+    }
+}
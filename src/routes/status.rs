@@ -0,0 +1,298 @@
+use axum::{extract::State, response::Json, routing::post, Router};
+use std::sync::Arc;
+use tracing::info;
+
+use crate::{
+    auth::{auth, Scope},
+    config::RUNS_TABLE_NAME,
+    error::{missing_header_error, AppError, ErrorCode},
+    idempotency::{self, Claim, IdempotencyKey},
+    models::status::{
+        HeartbeatResponse, Run, RunEnrichment, RunRow, StatusCode, StatusRequest, StatusResponse,
+        TransitionData,
+    },
+    routes::AppState,
+    runs::{fetch_run, now_millis, write_run},
+    traits::EnrichmentData,
+};
+
+// Defines the router for the /status endpoints
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/status", post(update_status).get(get_status))
+        .route("/heartbeat", post(heartbeat))
+}
+
+// Handler for the POST /status endpoint
+// INIT creates a run and allocates its ID (no X-Run-Id header), every other status
+// moves the run given by X-Run-Id to that status
+async fn update_status(
+    State(state): State<Arc<AppState>>,
+    mut headers: axum::http::HeaderMap,
+    Json(request): Json<StatusRequest>,
+) -> Result<Json<StatusResponse>, AppError> {
+    // Authenticate the request
+    let auth = auth(&mut headers, state.auth_provider.as_ref(), Scope::RunsWrite).await?;
+    // Extract enrichment data (project, optional run ID) from headers
+    let enrichment = RunEnrichment::from_headers(auth.tenant_id, &headers)?;
+
+    let status = request.status();
+    let (message, run) = match request {
+        StatusRequest::Init(data) => {
+            if enrichment.run_id.is_some() {
+                return Err(AppError::new(
+                    ErrorCode::InvalidInput,
+                    "X-Run-Id must not be set when creating a run, the run ID is allocated by the server",
+                ));
+            }
+            if data.run_name.trim().is_empty() {
+                return Err(AppError::new(
+                    ErrorCode::MissingRequiredField,
+                    "'runName' field cannot be empty",
+                ));
+            }
+            // A retried INIT with the same idempotency key returns the run it created
+            let guard = match idempotency::key_from_headers(&headers)? {
+                Some(key) => {
+                    let key = IdempotencyKey {
+                        tenant_id: enrichment.tenant_id.clone(),
+                        table_name: RUNS_TABLE_NAME,
+                        project_name: enrichment.project_name.clone(),
+                        run_id: String::new(), // Allocated by the request
+                        key,
+                    };
+                    match state.run_inits.claim(key) {
+                        Claim::New(guard) | Claim::Resume(guard, _) => Some(guard),
+                        Claim::InFlight => {
+                            return Err(AppError::new(
+                                ErrorCode::DuplicateRequest,
+                                "A request with this idempotency key is still being processed",
+                            ));
+                        }
+                        Claim::Completed(run) => {
+                            let run = run?;
+                            info!(
+                                run_id = run.run_id,
+                                "Replaying run created with the same idempotency key"
+                            );
+                            return Ok(Json(StatusResponse {
+                                message: "Run already created".to_string(),
+                                run: Run::from(run),
+                            }));
+                        }
+                    }
+                }
+                None => None,
+            };
+
+            let now = now_millis();
+            let run = RunRow {
+                tenant_id: enrichment.tenant_id,
+                project_name: enrichment.project_name,
+                run_id: state.run_ids.next_id(),
+                run_name: data.run_name,
+                status: StatusCode::Init.as_str().to_string(),
+                exit_code: None,
+                config: data.config.to_string(),
+                metadata: data.metadata.to_string(),
+                created_at: now,
+                ended_at: None,
+                last_seen_at: now,
+                updated_at: now,
+            };
+            // A failed write releases the key, so a retry creates the run
+            write_run(&state.clickhouse_client, &run).await?;
+            if let Some(guard) = guard {
+                guard.complete(Ok(run.clone()));
+            }
+            info!(run_id = run.run_id, project = %run.project_name, "Run created");
+            ("Run created".to_string(), run)
+        }
+        StatusRequest::Running(data)
+        | StatusRequest::Completed(data)
+        | StatusRequest::Failed(data)
+        | StatusRequest::Crashed(data) => transition(&state, enrichment, status, data).await?,
+    };
+
+    Ok(Json(StatusResponse {
+        message,
+        run: Run::from(run),
+    }))
+}
+
+// Moves an existing run to a new status
+async fn transition(
+    state: &AppState,
+    enrichment: RunEnrichment,
+    next: StatusCode,
+    data: TransitionData,
+) -> Result<(String, RunRow), AppError> {
+    let run_id = enrichment.require_run_id()?;
+    if !next.is_terminal() && (data.exit_code.is_some() || data.end_time.is_some()) {
+        return Err(AppError::new(
+            ErrorCode::InvalidInput,
+            format!(
+                "'exitCode' and 'endTime' are only accepted for final statuses, not {}",
+                next.as_str()
+            ),
+        ));
+    }
+
+    let mut run = fetch_run(
+        &state.clickhouse_client,
+        &enrichment.tenant_id,
+        &enrichment.project_name,
+        run_id,
+    )
+    .await?
+    .ok_or_else(|| AppError::new(ErrorCode::RunNotFound, format!("Run {} not found", run_id)))?;
+
+    let current = StatusCode::parse(&run.status).unwrap_or(StatusCode::Init);
+    // Retried finish calls are accepted without writing a new version
+    if current == next && next.is_terminal() {
+        return Ok((format!("Run already {}", next.as_str()), run));
+    }
+    if !current.can_transition_to(next) {
+        return Err(AppError::new(
+            ErrorCode::InvalidStatusTransition,
+            format!(
+                "Run {} cannot move from {} to {}",
+                run_id,
+                current.as_str(),
+                next.as_str()
+            ),
+        ));
+    }
+
+    let now = now_millis();
+    run.status = next.as_str().to_string();
+    if next.is_terminal() {
+        run.exit_code = data.exit_code;
+        run.ended_at = Some(data.end_time.unwrap_or(now));
+    } else {
+        // A run reported as crashed may come back
+        run.exit_code = None;
+        run.ended_at = None;
+    }
+    if let Some(metadata) = data.metadata {
+        run.metadata = metadata.to_string();
+    }
+    run.last_seen_at = run.last_seen_at.max(now);
+    // The version must increase even if two updates land in the same millisecond
+    run.updated_at = now.max(run.updated_at + 1);
+
+    write_run(&state.clickhouse_client, &run).await?;
+    info!(
+        run_id,
+        from = current.as_str(),
+        to = next.as_str(),
+        "Run status changed"
+    );
+    Ok((format!("Run moved to {}", next.as_str()), run))
+}
+
+// Handler for the GET /status endpoint
+// Returns the current state of the run given by X-Run-Id
+async fn get_status(
+    State(state): State<Arc<AppState>>,
+    mut headers: axum::http::HeaderMap,
+) -> Result<Json<Run>, AppError> {
+    // Authenticate the request
+    let auth = auth(&mut headers, state.auth_provider.as_ref(), Scope::Read).await?;
+    // Extract enrichment data (project, run ID) from headers
+    let enrichment = RunEnrichment::from_headers(auth.tenant_id, &headers)?;
+    let run_id = enrichment.require_run_id()?;
+
+    let run = fetch_run(
+        &state.clickhouse_client,
+        &enrichment.tenant_id,
+        &enrichment.project_name,
+        run_id,
+    )
+    .await?
+    .ok_or_else(|| AppError::new(ErrorCode::RunNotFound, format!("Run {} not found", run_id)))?;
+
+    let mut run = Run::from(run);
+    // Heartbeats seen by this instance are newer than the persisted last-seen time
+    if let Some(last_seen) = enrichment
+        .run_key()
+        .and_then(|key| state.heartbeats.last_seen(&key))
+    {
+        run.last_seen_at = run.last_seen_at.max(last_seen);
+    }
+
+    Ok(Json(run))
+}
+
+// Handler for the POST /heartbeat endpoint
+// Marks the run given by X-Run-Id as alive, for runs that go without sending data for a while
+// Every ingest call also counts as a heartbeat
+async fn heartbeat(
+    State(state): State<Arc<AppState>>,
+    mut headers: axum::http::HeaderMap,
+) -> Result<Json<HeartbeatResponse>, AppError> {
+    // Authenticate the request
+    let auth = auth(&mut headers, state.auth_provider.as_ref(), Scope::RunsWrite).await?;
+    // Extract enrichment data (project, run ID) from headers
+    let enrichment = RunEnrichment::from_headers(auth.tenant_id, &headers)?;
+    let key = enrichment
+        .run_key()
+        .ok_or_else(|| missing_header_error("X-Run-Id"))?;
+    let last_seen_at = state.heartbeats.record(key);
+
+    Ok(Json(HeartbeatResponse {
+        message: "Heartbeat recorded".to_string(),
+        last_seen_at,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes::testing::test_state;
+    use crate::shutdown::Shutdown;
+    use axum::http::HeaderMap;
+    use clickhouse::test::{handlers, Mock};
+    use std::time::Duration;
+
+    fn init_request(key: &str) -> (HeaderMap, Json<StatusRequest>) {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Project-Name", "project".parse().unwrap());
+        headers.insert("Idempotency-Key", key.parse().unwrap());
+        let request = serde_json::from_value(serde_json::json!({
+            "status": "INIT",
+            "data": { "runName": "run" }
+        }))
+        .unwrap();
+        (headers, Json(request))
+    }
+
+    #[tokio::test]
+    async fn test_retried_init_returns_the_same_run() {
+        let mock = Mock::new();
+        let first_write = mock.add(handlers::record::<RunRow>());
+        let second_write = mock.add(handlers::record::<RunRow>());
+        let (shutdown, _trigger) = Shutdown::new(Duration::from_secs(5));
+        let (state, _channels) = test_state(mock.url(), shutdown);
+
+        let (headers, request) = init_request("a");
+        let Json(created) = update_status(State(state.clone()), headers, request)
+            .await
+            .unwrap();
+        let written: Vec<RunRow> = first_write.collect().await;
+        assert_eq!(written[0].run_id, created.run.run_id);
+
+        // The retry does not write a second run
+        let (headers, request) = init_request("a");
+        let Json(replayed) = update_status(State(state.clone()), headers, request)
+            .await
+            .unwrap();
+        assert_eq!(replayed.run.run_id, created.run.run_id);
+
+        let (headers, request) = init_request("b");
+        let Json(other) = update_status(State(state), headers, request).await.unwrap();
+        assert_ne!(other.run.run_id, created.run.run_id);
+        let written: Vec<RunRow> = second_write.collect().await;
+        assert_eq!(written[0].run_id, other.run.run_id);
+    }
+}
@@ -2,8 +2,12 @@ use axum::{extract::State, routing::post, Router};
 use std::sync::Arc;
 
 use crate::{
-    auth::auth, config::METRICS_TABLE_NAME, error::AppError, models::metrics::MetricEnrichment,
-    routes::AppState, traits::EnrichmentData,
+    auth::{auth, Scope},
+    config::METRICS_TABLE_NAME,
+    error::AppError,
+    models::metrics::MetricEnrichment,
+    routes::AppState,
+    traits::EnrichmentData,
 };
 
 use axum::response::Json;
@@ -26,10 +30,10 @@ pub fn router() -> Router<Arc<AppState>> {
 // Retrieves the maximum step number for a specific run from ClickHouse
 async fn step(
     State(state): State<Arc<AppState>>,
-    headers: axum::http::HeaderMap,
+    mut headers: axum::http::HeaderMap,
 ) -> Result<Json<StepRow>, AppError> {
     // Authenticate the request
-    let auth = auth(&headers, &state.db).await?;
+    let auth = auth(&mut headers, state.auth_provider.as_ref(), Scope::Read).await?;
     let tenant_id = auth.tenant_id;
     // Extract enrichment data (project, run ID) from headers
     let enrichment_data = MetricEnrichment::from_headers(tenant_id.clone(), &headers)?;
@@ -0,0 +1,101 @@
+use axum::{
+    extract::State,
+    http::{header, HeaderMap},
+    response::IntoResponse,
+    routing::get,
+    Router,
+};
+use std::sync::Arc;
+
+use crate::{
+    config::{DATA_TABLE_NAME, FILES_TABLE_NAME, LOGS_TABLE_NAME, METRICS_TABLE_NAME},
+    error::AppError,
+    processors::fair_queue::FairSender,
+    routes::{admin::admin_auth, AppState},
+    telemetry::{self, CHANNEL_CAPACITY, CHANNEL_DEPTH},
+};
+
+// Defines the router for the /metrics endpoint
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new().route("/metrics", get(metrics))
+}
+
+// Handler for the GET /metrics endpoint
+// Exposes the ingest pipeline metrics in the Prometheus text format
+// Requires the admin token, as the metrics describe the load and health of every tenant
+async fn metrics(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    admin_auth(&headers, &state)?;
+
+    // Channel depths are sampled at scrape time
+    record_channel_depth(METRICS_TABLE_NAME, &state.metrics_record_sender);
+    record_channel_depth(LOGS_TABLE_NAME, &state.log_record_sender);
+    record_channel_depth(DATA_TABLE_NAME, &state.data_record_sender);
+    record_channel_depth(FILES_TABLE_NAME, &state.files_record_sender);
+
+    Ok((
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        telemetry::render(),
+    ))
+}
+
+fn record_channel_depth<T>(table_name: &str, sender: &FairSender<T>) {
+    let capacity = sender.capacity();
+    CHANNEL_CAPACITY
+        .with_label_values(&[table_name])
+        .set(capacity as i64);
+    CHANNEL_DEPTH
+        .with_label_values(&[table_name])
+        .set(sender.len() as i64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes::testing::test_state;
+    use crate::shutdown::Shutdown;
+    use crate::telemetry::{set_tenant_labels, tenant_label};
+    use axum::http::StatusCode;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_metrics_require_the_admin_token() {
+        let (shutdown, _trigger) = Shutdown::new(Duration::from_secs(5));
+        let (state, _channels) = test_state("http://localhost:8123", shutdown);
+        let mut headers = HeaderMap::new();
+        headers.insert("Authorization", "Bearer secret".parse().unwrap());
+
+        // Disabled without an admin token
+        let error = metrics(State(state.clone()), headers.clone())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.code.status_code(), StatusCode::FORBIDDEN);
+
+        let mut state = (*state).clone();
+        let mut config = (*state.config).clone();
+        config.admin_token = Some("secret".to_string());
+        state.config = Arc::new(config);
+        let state = Arc::new(state);
+        let error = metrics(State(state.clone()), HeaderMap::new())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.code.status_code(), StatusCode::UNAUTHORIZED);
+        let response = metrics(State(state), headers)
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_tenant_labels_are_opt_in() {
+        assert_eq!(tenant_label("tenant"), "");
+        set_tenant_labels(true);
+        assert_eq!(tenant_label("tenant"), "tenant");
+        set_tenant_labels(false);
+    }
+}
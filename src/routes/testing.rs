@@ -0,0 +1,127 @@
+use aws_sdk_s3::config::{Credentials, Region};
+use aws_sdk_s3::Client as StorageClient;
+use clickhouse::{Client, Compression};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+use crate::auth::single_tenant::SingleTenantProvider;
+use crate::config::{
+    AuthBackend, Config, DEFAULT_IMPORT_MAX_CONCURRENT, DEFAULT_MAX_DECOMPRESSED_BYTES,
+    TENANT_QUEUE_CAPACITY, TENANT_QUEUE_QUANTUM,
+};
+use crate::console::ConsoleLineNumbers;
+use crate::heartbeat::HeartbeatTracker;
+use crate::idempotency::IdempotencyCache;
+use crate::models::{data::DataRow, files::FilesRow, log::LogRow, metrics::MetricRow};
+use crate::processors::fair_queue::{fair_channel, FairReceiver, SendLimits};
+use crate::rate_limit::{RateLimiter, TenantLimits};
+use crate::routes::AppState;
+use crate::runs::RunIdGenerator;
+use crate::shutdown::Shutdown;
+
+// Tenant of every request made against a test state
+pub const TEST_TENANT_ID: &str = "test-tenant";
+
+// Receiving ends of the background processor channels of a test state
+// Held even if unused, so sends do not fail because the channel is closed
+#[allow(dead_code)]
+pub struct TestChannels {
+    pub metrics: FairReceiver<MetricRow>,
+    pub logs: FairReceiver<LogRow>,
+    pub data: FairReceiver<DataRow>,
+    pub files: FairReceiver<FilesRow>,
+}
+
+pub fn test_config() -> Config {
+    Config {
+        clickhouse_url: "http://localhost:8123".to_string(),
+        clickhouse_user: "default".to_string(),
+        clickhouse_password: String::new(),
+        storage_access_key_id: String::new(),
+        storage_secret_access_key: String::new(),
+        storage_bucket: "test".to_string(),
+        storage_endpoint: "http://localhost:9000".to_string(),
+        auth_backend: AuthBackend::SingleTenant {
+            tenant_id: TEST_TENANT_ID.to_string(),
+        },
+        database_max_connections: 1,
+        api_key_cache_ttl: Duration::from_secs(60),
+        api_key_negative_cache_ttl: Duration::from_secs(5),
+        api_key_cache_max_entries: 100,
+        api_key_last_used_interval: Duration::from_secs(60),
+        api_key_notify_channel: None,
+        jwt_hs256_secret: None,
+        jwt_public_key_file: None,
+        jwt_jwks_file: None,
+        jwt_issuer: None,
+        admin_token: None,
+        metrics_tenant_labels: false,
+        spool_dir: "spool".to_string(),
+        shutdown_drain_timeout: Duration::from_secs(5),
+        idempotency_window: Duration::from_secs(3600),
+        idempotency_max_keys: 100,
+        tenant_limits: TenantLimits::default(),
+        tenant_limits_file: None,
+        ingest_send_limits: SendLimits::default(),
+        ingest_max_decompressed_bytes: DEFAULT_MAX_DECOMPRESSED_BYTES,
+        import_max_concurrent: DEFAULT_IMPORT_MAX_CONCURRENT,
+        node_id: 0,
+        run_crash_timeout: Duration::from_secs(600),
+    }
+}
+
+// Object storage client for the bucket `test` at `endpoint`, with path-style URLs so the
+// endpoint can be a local server
+pub fn test_storage_client(endpoint: &str) -> StorageClient {
+    let config = aws_sdk_s3::Config::builder()
+        .region(Region::new("auto"))
+        .credentials_provider(Credentials::new("test", "test", None, None, "test"))
+        .endpoint_url(endpoint)
+        .force_path_style(true)
+        .build();
+    StorageClient::from_conf(config)
+}
+
+// Application state of a single tenant without credentials, talking to ClickHouse at `clickhouse_url`
+pub fn test_state(clickhouse_url: &str, shutdown: Shutdown) -> (Arc<AppState>, TestChannels) {
+    let config = test_config();
+    let (metrics_sender, metrics) = fair_channel(TENANT_QUEUE_CAPACITY, TENANT_QUEUE_QUANTUM);
+    let (log_sender, logs) = fair_channel(TENANT_QUEUE_CAPACITY, TENANT_QUEUE_QUANTUM);
+    let (data_sender, data) = fair_channel(TENANT_QUEUE_CAPACITY, TENANT_QUEUE_QUANTUM);
+    let (files_sender, files) = fair_channel(TENANT_QUEUE_CAPACITY, TENANT_QUEUE_QUANTUM);
+    let state = AppState {
+        metrics_record_sender: metrics_sender,
+        log_record_sender: log_sender,
+        data_record_sender: data_sender,
+        files_record_sender: files_sender,
+        clickhouse_client: Client::default()
+            .with_url(clickhouse_url)
+            .with_compression(Compression::None),
+        storage_client: test_storage_client(&config.storage_endpoint),
+        auth_provider: Arc::new(SingleTenantProvider::new(TEST_TENANT_ID.to_string())),
+        config: Arc::new(config),
+        metrics_status: Default::default(),
+        logs_status: Default::default(),
+        data_status: Default::default(),
+        files_status: Default::default(),
+        idempotency: Arc::new(IdempotencyCache::new(Duration::from_secs(3600), 100)),
+        rate_limiter: Arc::new(RateLimiter::new(
+            TenantLimits::default(),
+            Default::default(),
+        )),
+        run_ids: Arc::new(RunIdGenerator::new(0)),
+        run_inits: Arc::new(IdempotencyCache::new(Duration::from_secs(3600), 100)),
+        heartbeats: Arc::new(HeartbeatTracker::default()),
+        console_lines: Arc::new(ConsoleLineNumbers::default()),
+        import_permits: Arc::new(Semaphore::new(DEFAULT_IMPORT_MAX_CONCURRENT)),
+        shutdown,
+    };
+    let channels = TestChannels {
+        metrics,
+        logs,
+        data,
+        files,
+    };
+    (Arc::new(state), channels)
+}
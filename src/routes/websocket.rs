@@ -0,0 +1,589 @@
+use axum::{
+    extract::{
+        ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        State,
+    },
+    http::{HeaderMap, StatusCode},
+    response::Response,
+    routing::get,
+    Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::{Instant, Interval};
+use tracing::{debug, info, info_span, warn, Instrument};
+
+use crate::{
+    auth::{authenticate, Auth, Scope},
+    error::{AppError, ErrorCode},
+    models::{
+        data::{DataEnrichment, DataInput},
+        log::{LogEnrichment, LogInput},
+        metrics::{MetricEnrichment, MetricInput},
+    },
+    processors::{
+        fair_queue::FairSender,
+        stream::{send_rows, IntoRows},
+    },
+    routes::AppState,
+    traits::{DatabaseRow, EnrichmentData, InputData},
+};
+
+// How often open connections check that their credentials are still valid, e.g. not revoked
+const REAUTHENTICATE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Defines the streaming ingest route
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new().route("/ingest/ws", get(ingest_ws))
+}
+
+// Kind of records carried by a frame, each feeds the background processor of the same route
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum FrameType {
+    Metrics,
+    Logs,
+    Data,
+}
+
+impl FrameType {
+    fn scope(self) -> Scope {
+        match self {
+            FrameType::Metrics => Scope::IngestMetrics,
+            FrameType::Logs => Scope::IngestLogs,
+            FrameType::Data => Scope::IngestData,
+        }
+    }
+}
+
+/// Text frame sent by the client
+///
+/// # Example
+/// ```json
+/// {
+///     "id": 42,
+///     "type": "metrics",
+///     "records": [
+///         { "time": 1234567890, "step": 1, "data": { "loss": 0.5 } }
+///     ]
+/// }
+/// ```
+/// Records have the same format as the lines of the matching `/ingest/*` route
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Frame<'a> {
+    id: u64, // Echoed in the ack
+    #[serde(rename = "type")]
+    frame_type: FrameType,
+    #[serde(borrow)]
+    records: Vec<&'a RawValue>,
+}
+
+// A record of a frame that failed to parse or validate, the other records are still ingested
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RejectedRecord {
+    index: usize, // Position of the record in the frame's `records`
+    code: ErrorCode,
+    message: String,
+}
+
+// Error that stopped a frame, records from `resumeAt` on were not (or only partly) ingested
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct FrameError {
+    code: ErrorCode,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resume_at: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after: Option<u64>, // Seconds, for rate limits and overload
+}
+
+/// Text frame sent by the server for every client frame, in order
+///
+/// # Example
+/// ```json
+/// { "id": 42, "accepted": 12, "acceptedRecords": 6, "rejected": [] }
+/// ```
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Ack {
+    id: Option<u64>,       // None if the frame could not be parsed at all
+    accepted: usize,       // Rows sent to the background processor
+    accepted_records: u64, // Records that produced rows
+    rejected: Vec<RejectedRecord>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<FrameError>,
+}
+
+impl Ack {
+    fn fail(&mut self, error: AppError, resume_at: Option<usize>) {
+        self.error = Some(FrameError {
+            code: error.code,
+            message: error.message,
+            resume_at,
+            retry_after: error
+                .retry_after
+                .map(|d| d.as_secs() + u64::from(d.subsec_nanos() > 0)), // Rounded up
+        });
+    }
+}
+
+// Project and run of the connection, resolved once from the upgrade request's headers
+struct Connection {
+    auth: Auth,
+    headers: HeaderMap,
+    metrics: Option<MetricEnrichment>, // None if the headers lack what the type needs
+    logs: Option<LogEnrichment>,
+    data: Option<DataEnrichment>,
+}
+
+// Handler for GET /ingest/ws
+// Authenticates the upgrade request, so the run sends its credentials and
+// X-Project-Name/X-Run-Id headers once for the whole connection
+async fn ingest_ws(
+    State(state): State<Arc<AppState>>,
+    mut headers: HeaderMap,
+    upgrade: WebSocketUpgrade,
+) -> Result<Response, AppError> {
+    let auth = authenticate(&mut headers, state.auth_provider.as_ref()).await?;
+    let tenant_id = auth.tenant_id.clone();
+    let connection = Connection {
+        metrics: MetricEnrichment::from_headers(tenant_id.clone(), &headers).ok(),
+        logs: LogEnrichment::from_headers(tenant_id.clone(), &headers).ok(),
+        data: DataEnrichment::from_headers(tenant_id.clone(), &headers).ok(),
+        auth,
+        headers,
+    };
+
+    let span = info_span!("ingest_ws", tenant_id = %tenant_id);
+    // The connection holds the record senders, so shutdown waits for it to close
+    let shutdown = state.shutdown.clone();
+    Ok(upgrade.on_upgrade(move |socket| {
+        shutdown
+            .track_connection(handle_socket(state, connection, socket))
+            .instrument(span)
+    }))
+}
+
+async fn handle_socket(state: Arc<AppState>, mut connection: Connection, mut socket: WebSocket) {
+    info!("WebSocket ingest connection opened");
+    let mut frames = 0u64;
+    let mut reauthenticate = tokio::time::interval_at(
+        Instant::now() + REAUTHENTICATE_INTERVAL,
+        REAUTHENTICATE_INTERVAL,
+    );
+    let mut invalidations = state.auth_provider.key_invalidations();
+    loop {
+        let message = tokio::select! {
+            message = socket.recv() => message,
+            // Frames already received have been acked, the client reconnects to another instance
+            _ = state.shutdown.requested() => {
+                info!("Server is shutting down, closing WebSocket ingest connection");
+                close(&mut socket, close_code::AWAY, "Server is shutting down").await;
+                break;
+            }
+            _ = expiry(connection.auth.expires_at) => {
+                info!("Credentials have expired, closing WebSocket ingest connection");
+                close(&mut socket, close_code::POLICY, "Credentials have expired").await;
+                break;
+            }
+            _ = recheck_due(&mut reauthenticate, &mut invalidations) => {
+                if let Err(e) = reauthenticate_connection(&state, &mut connection).await {
+                    info!(error = %e, "Credentials are no longer valid, closing WebSocket ingest connection");
+                    close(&mut socket, close_code::POLICY, "Credentials are no longer valid").await;
+                    break;
+                }
+                continue;
+            }
+        };
+        let Some(message) = message else {
+            break;
+        };
+        let ack = match message {
+            Ok(Message::Text(text)) => process_frame(&state, &connection, &text).await,
+            Ok(Message::Binary(_)) => {
+                let mut ack = Ack::default();
+                ack.fail(
+                    AppError::new(ErrorCode::InvalidInput, "Binary frames are not supported"),
+                    None,
+                );
+                ack
+            }
+            // Pings are answered automatically
+            Ok(Message::Ping(_)) | Ok(Message::Pong(_)) => continue,
+            Ok(Message::Close(_)) => break,
+            Err(e) => {
+                warn!(error = %e, "WebSocket ingest connection failed");
+                break;
+            }
+        };
+        frames += 1;
+
+        let ack = serde_json::to_string(&ack).expect("Acks are always serializable");
+        if socket.send(Message::Text(ack)).await.is_err() {
+            warn!("Failed to send ack, closing WebSocket ingest connection");
+            break;
+        }
+    }
+    info!(frames, "WebSocket ingest connection closed");
+}
+
+// Resolves when credentials expiring at `expires_at` have expired, never for credentials without expiry
+async fn expiry(expires_at: Option<DateTime<Utc>>) {
+    match expires_at {
+        Some(expires_at) => {
+            let remaining = (expires_at - Utc::now()).to_std().unwrap_or_default();
+            tokio::time::sleep(remaining).await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+// Resolves when the connection's credentials are due to be checked again: periodically,
+// and right away when the auth provider invalidates cached keys
+async fn recheck_due(interval: &mut Interval, invalidations: &mut Option<watch::Receiver<u64>>) {
+    match invalidations {
+        Some(invalidations) => tokio::select! {
+            _ = interval.tick() => {}
+            Ok(()) = invalidations.changed() => {}
+        },
+        None => {
+            interval.tick().await;
+        }
+    }
+}
+
+// Authenticates the upgrade request's credentials again, so revoked keys and changed
+// grants apply to open connections
+// Only authentication failures are returned, the connection is kept if the check itself
+// fails (e.g. the database is unreachable) and retried at the next interval
+async fn reauthenticate_connection(
+    state: &AppState,
+    connection: &mut Connection,
+) -> Result<(), AppError> {
+    let mut headers = connection.headers.clone();
+    match authenticate(&mut headers, state.auth_provider.as_ref()).await {
+        Ok(auth) if auth.tenant_id == connection.auth.tenant_id => {
+            connection.auth = auth;
+            Ok(())
+        }
+        Ok(_) => Err(AppError::new(
+            ErrorCode::InvalidToken,
+            "Credentials now belong to another tenant",
+        )),
+        Err(e)
+            if matches!(
+                e.code.status_code(),
+                StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN
+            ) =>
+        {
+            Err(e)
+        }
+        Err(e) => {
+            warn!(error = %e, "Failed to re-authenticate WebSocket ingest connection, keeping it open");
+            Ok(())
+        }
+    }
+}
+
+// Sends a close frame, the client may already be gone
+async fn close(socket: &mut WebSocket, code: u16, reason: &'static str) {
+    let frame = CloseFrame {
+        code,
+        reason: reason.into(),
+    };
+    let _ = socket.send(Message::Close(Some(frame))).await;
+}
+
+// Parses a frame, checks that the credentials allow its type and ingests its records
+async fn process_frame(state: &AppState, connection: &Connection, text: &str) -> Ack {
+    let mut ack = Ack::default();
+    let frame: Frame = match serde_json::from_str(text) {
+        Ok(frame) => frame,
+        Err(e) => {
+            ack.fail(
+                AppError::new(
+                    ErrorCode::InvalidJsonFormat,
+                    format!("Invalid frame: {}", e),
+                ),
+                None,
+            );
+            return ack;
+        }
+    };
+    ack.id = Some(frame.id);
+    debug!(id = frame.id, frame_type = ?frame.frame_type, records = frame.records.len(), "Received frame");
+
+    if let Err(e) = connection
+        .auth
+        .authorize(frame.frame_type.scope(), &connection.headers)
+    {
+        ack.fail(e, Some(0));
+        return ack;
+    }
+
+    let bytes = text.len() as u64;
+    let result = match frame.frame_type {
+        FrameType::Metrics => {
+            ingest_records::<MetricInput, _, _>(
+                state,
+                &connection.auth.tenant_id,
+                &state.metrics_record_sender,
+                connection.metrics.as_ref(),
+                &frame.records,
+                bytes,
+                &mut ack,
+            )
+            .await
+        }
+        FrameType::Logs => {
+            ingest_records::<LogInput, _, _>(
+                state,
+                &connection.auth.tenant_id,
+                &state.log_record_sender,
+                connection.logs.as_ref(),
+                &frame.records,
+                bytes,
+                &mut ack,
+            )
+            .await
+        }
+        FrameType::Data => {
+            ingest_records::<DataInput, _, _>(
+                state,
+                &connection.auth.tenant_id,
+                &state.data_record_sender,
+                connection.data.as_ref(),
+                &frame.records,
+                bytes,
+                &mut ack,
+            )
+            .await
+        }
+    };
+    if let Err((error, resume_at)) = result {
+        warn!(error = %error, id = frame.id, "Frame failed");
+        ack.fail(error, Some(resume_at));
+    }
+    ack
+}
+
+// Converts the records of a frame to rows and sends them to the background processor
+// Invalid records are listed in the ack, rate limits and overload stop the frame at
+// the record returned with the error
+async fn ingest_records<R, E, D>(
+    state: &AppState,
+    tenant_id: &str,
+    sender: &FairSender<D>,
+    enrichment: Option<&E>,
+    records: &[&RawValue],
+    bytes: u64,
+    ack: &mut Ack,
+) -> Result<(), (AppError, usize)>
+where
+    R: InputData + IntoRows<E, D>,
+    E: EnrichmentData,
+    D: DatabaseRow<R, E>,
+{
+    // The same headers are required as for the matching /ingest/* route
+    let enrichment = enrichment.ok_or_else(|| {
+        (
+            AppError::new(
+                ErrorCode::MissingRequiredField,
+                "X-Project-Name and X-Run-Id headers are required to send this frame type",
+            ),
+            0,
+        )
+    })?;
+
+    let mut converted = Vec::with_capacity(records.len());
+    for (index, record) in records.iter().enumerate() {
+        let rows = serde_json::from_str::<R>(record.get())
+            .map_err(AppError::from)
+            .and_then(|input| {
+                input.validate()?;
+                input.into_rows(enrichment.clone())
+            });
+        match rows {
+            Ok(rows) => converted.push((index, rows)),
+            Err(e) => ack.rejected.push(RejectedRecord {
+                index,
+                code: e.code,
+                message: e.message,
+            }),
+        }
+    }
+
+    if let Some(run_key) = enrichment.run_key() {
+        state.heartbeats.record(run_key);
+    }
+    if state.rate_limiter.is_enabled() {
+        let rows = converted.iter().map(|(_, rows)| rows.len() as u64).sum();
+        state
+            .rate_limiter
+            .acquire(tenant_id, rows, bytes)
+            .map_err(|e| (e, 0))?;
+    }
+
+    for (index, rows) in converted {
+        let mut sent = 0;
+        let result = send_rows(sender, tenant_id, D::table_name(), rows, &mut sent).await;
+        ack.accepted += sent as usize;
+        result.map_err(|e| (AppError::from(e), index))?;
+        ack.accepted_records += 1;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{ApiKeyGrant, AuthProvider};
+    use crate::routes::testing::{test_state, TEST_TENANT_ID};
+    use crate::shutdown::Shutdown;
+    use futures::future::BoxFuture;
+    use futures::{SinkExt, StreamExt};
+    use std::future::IntoFuture;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+    use tokio_tungstenite::tungstenite::{self, protocol::frame::coding::CloseCode};
+    use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+    type ClientSocket = WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>;
+
+    // Key that can be revoked, notifying open connections like the Postgres provider's cache does
+    struct RevocableProvider {
+        expires_at: Option<DateTime<Utc>>,
+        revoked: AtomicBool,
+        invalidations: watch::Sender<u64>,
+    }
+
+    impl RevocableProvider {
+        fn new(expires_at: Option<DateTime<Utc>>) -> Self {
+            Self {
+                expires_at,
+                revoked: AtomicBool::new(false),
+                invalidations: watch::Sender::new(0),
+            }
+        }
+
+        fn revoke(&self) {
+            self.revoked.store(true, Ordering::SeqCst);
+            self.invalidations.send_modify(|count| *count += 1);
+        }
+    }
+
+    impl AuthProvider for RevocableProvider {
+        fn authenticate<'a>(
+            &'a self,
+            _headers: &'a HeaderMap,
+        ) -> BoxFuture<'a, Result<ApiKeyGrant, AppError>> {
+            Box::pin(async move {
+                if self.revoked.load(Ordering::SeqCst) {
+                    return Err(AppError::new(ErrorCode::InvalidToken, "Invalid API key"));
+                }
+                Ok(ApiKeyGrant {
+                    key_id: "key".to_string(),
+                    tenant_id: TEST_TENANT_ID.to_string(),
+                    expires_at: self.expires_at,
+                    scopes: None,
+                    allowed_projects: None,
+                    project_name: None,
+                    run_id: None,
+                })
+            })
+        }
+
+        fn key_invalidations(&self) -> Option<watch::Receiver<u64>> {
+            Some(self.invalidations.subscribe())
+        }
+    }
+
+    // Serves the route until shutdown and connects a client to it
+    async fn connect(state: Arc<AppState>) -> (ClientSocket, JoinHandle<std::io::Result<()>>) {
+        let shutdown = state.shutdown.clone();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = axum::serve(listener, router().with_state(state).into_make_service())
+            .with_graceful_shutdown(async move { shutdown.requested().await });
+        let server = tokio::spawn(server.into_future());
+
+        let url = format!("ws://{}/ingest/ws", address);
+        let (socket, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        (socket, server)
+    }
+
+    // Waits for the server to close the connection, returns its close code
+    async fn closed(socket: &mut ClientSocket) -> CloseCode {
+        let message = tokio::time::timeout(Duration::from_secs(5), socket.next())
+            .await
+            .expect("Socket was not closed")
+            .unwrap()
+            .unwrap();
+        match message {
+            tungstenite::Message::Close(Some(frame)) => frame.code,
+            other => panic!("Expected a close frame, got {:?}", other),
+        }
+    }
+
+    // Application state authenticating with `provider`
+    fn state_with_provider(provider: Arc<RevocableProvider>) -> Arc<AppState> {
+        let (shutdown, _) = Shutdown::new(Duration::from_secs(5));
+        let (state, _) = test_state("http://localhost:8123", shutdown);
+        let mut state = (*state).clone();
+        state.auth_provider = provider;
+        Arc::new(state)
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_closes_open_sockets() {
+        let (shutdown, trigger) = Shutdown::new(Duration::from_secs(5));
+        let (state, _channels) = test_state("http://localhost:8123", shutdown.clone());
+        let state_ref = Arc::downgrade(&state);
+        let (mut socket, server) = connect(state).await;
+
+        let frame = r#"{ "id": 1, "type": "metrics", "records": [] }"#;
+        socket
+            .send(tungstenite::Message::Text(frame.to_string()))
+            .await
+            .unwrap();
+        let ack = socket.next().await.unwrap().unwrap();
+        assert!(ack.into_text().unwrap().starts_with(r#"{"id":1,"#));
+
+        trigger.fire();
+        assert_eq!(closed(&mut socket).await, CloseCode::Away);
+
+        // The server and the connection let go of the state, and with it the record senders
+        server.await.unwrap().unwrap();
+        tokio::time::timeout(Duration::from_secs(5), shutdown.wait_for_connections())
+            .await
+            .unwrap();
+        assert!(state_ref.upgrade().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_revoked_keys_close_open_sockets() {
+        let provider = Arc::new(RevocableProvider::new(None));
+        let (mut socket, _server) = connect(state_with_provider(provider.clone())).await;
+
+        provider.revoke();
+        assert_eq!(closed(&mut socket).await, CloseCode::Policy);
+    }
+
+    #[tokio::test]
+    async fn test_expired_credentials_close_open_sockets() {
+        let expires_at = Utc::now() + chrono::Duration::milliseconds(500);
+        let provider = Arc::new(RevocableProvider::new(Some(expires_at)));
+        let (mut socket, _server) = connect(state_with_provider(provider)).await;
+
+        assert_eq!(closed(&mut socket).await, CloseCode::Policy);
+        assert!(Utc::now() >= expires_at);
+    }
+}
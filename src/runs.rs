@@ -0,0 +1,184 @@
+use clickhouse::sql::Identifier;
+use clickhouse::Client;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::RUNS_TABLE_NAME;
+use crate::error::AppError;
+use crate::models::status::RunRow;
+
+// Custom epoch for run IDs (2024-01-01T00:00:00Z), in seconds since the Unix epoch
+const RUN_ID_EPOCH_SECS: u64 = 1_704_067_200;
+// Bit layout of a run ID: 32 bits of seconds, 5 bits of node ID, 16 bits of sequence
+// 53 bits in total, so run IDs are exact in JavaScript numbers
+const NODE_ID_BITS: u32 = 5;
+const SEQUENCE_BITS: u32 = 16;
+pub const MAX_NODE_ID: u64 = (1 << NODE_ID_BITS) - 1;
+const MAX_SEQUENCE: u64 = (1 << SEQUENCE_BITS) - 1;
+// How far ahead of the clock a restarted instance trusts the run IDs it issued before
+// IDs only run ahead when a second's sequence is exhausted, far less than this
+const MAX_ISSUED_AHEAD_SECS: u64 = 3600;
+
+// Allocates unique, roughly time-ordered numeric run IDs
+// Each server instance needs its own node ID so IDs never collide across instances
+pub struct RunIdGenerator {
+    node_id: u64,
+    state: Mutex<(u64, u64)>, // (seconds since the run ID epoch, sequence within that second)
+}
+
+impl RunIdGenerator {
+    pub fn new(node_id: u64) -> Self {
+        assert!(
+            node_id <= MAX_NODE_ID,
+            "node ID must be at most {}",
+            MAX_NODE_ID
+        );
+        Self {
+            node_id,
+            state: Mutex::new((0, 0)),
+        }
+    }
+
+    // Continues after the last run ID this node wrote to mlop_runs before it was restarted
+    // Without it, IDs issued ahead of the clock (or before the clock was set back) repeat
+    pub async fn resume(&self, client: &Client) -> Result<(), AppError> {
+        let max_seconds = epoch_seconds() + MAX_ISSUED_AHEAD_SECS;
+        let last_id = client
+            .query(
+                "SELECT max(runId) FROM ? \
+                 WHERE bitAnd(bitShiftRight(runId, ?), ?) = ? AND bitShiftRight(runId, ?) <= ?",
+            )
+            .bind(Identifier(RUNS_TABLE_NAME))
+            .bind(SEQUENCE_BITS)
+            .bind(MAX_NODE_ID)
+            .bind(self.node_id)
+            .bind(NODE_ID_BITS + SEQUENCE_BITS)
+            .bind(max_seconds)
+            .fetch_one::<u64>()
+            .await?;
+        self.resume_after(last_id);
+        Ok(())
+    }
+
+    // Makes the next ID follow `last_id`, unless later IDs were issued already
+    fn resume_after(&self, last_id: u64) {
+        let last = (
+            last_id >> (NODE_ID_BITS + SEQUENCE_BITS),
+            last_id & MAX_SEQUENCE,
+        );
+        let mut state = self.state.lock().unwrap();
+        *state = (*state).max(last);
+    }
+
+    pub fn next_id(&self) -> u64 {
+        let now = epoch_seconds();
+
+        let mut state = self.state.lock().unwrap();
+        let (seconds, sequence) = &mut *state;
+        if now > *seconds {
+            *seconds = now;
+            *sequence = 0;
+        } else if *sequence == MAX_SEQUENCE {
+            // Sequence exhausted (or the clock went backwards), borrow the next second
+            *seconds += 1;
+            *sequence = 0;
+        } else {
+            *sequence += 1;
+        }
+
+        (*seconds << (NODE_ID_BITS + SEQUENCE_BITS)) | (self.node_id << SEQUENCE_BITS) | *sequence
+    }
+}
+
+// Current time in seconds since the run ID epoch
+fn epoch_seconds() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        .saturating_sub(RUN_ID_EPOCH_SECS)
+}
+
+// Current time in milliseconds since the Unix epoch
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+// Reads the latest version of a run, if it exists
+pub async fn fetch_run(
+    client: &Client,
+    tenant_id: &str,
+    project_name: &str,
+    run_id: u64,
+) -> Result<Option<RunRow>, AppError> {
+    let run = client
+        .query("SELECT ?fields FROM ? FINAL WHERE tenantId = ? AND projectName = ? AND runId = ?")
+        .bind(Identifier(RUNS_TABLE_NAME))
+        .bind(tenant_id)
+        .bind(project_name)
+        .bind(run_id)
+        .fetch_optional::<RunRow>()
+        .await?;
+    Ok(run)
+}
+
+// Writes a new version of a run
+// Inserted synchronously so the change is visible to the next read
+pub async fn write_run(client: &Client, run: &RunRow) -> Result<(), AppError> {
+    let mut insert = client.insert(RUNS_TABLE_NAME)?;
+    insert.write(run).await?;
+    insert.end().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clickhouse::test::{handlers, Mock};
+    use clickhouse::Compression;
+
+    #[test]
+    fn test_run_ids_are_unique_and_js_safe() {
+        let generator = RunIdGenerator::new(MAX_NODE_ID);
+        let mut previous = 0;
+        // Enough IDs to exhaust the sequence of at least one second
+        for _ in 0..=MAX_SEQUENCE + 1 {
+            let id = generator.next_id();
+            assert!(id > previous);
+            assert!(id < 1 << 53);
+            assert_eq!((id >> SEQUENCE_BITS) & MAX_NODE_ID, MAX_NODE_ID);
+            previous = id;
+        }
+    }
+
+    #[test]
+    fn test_ids_follow_the_ids_issued_before_a_restart() {
+        let generator = RunIdGenerator::new(3);
+        let first = generator.next_id();
+        // The previous instance borrowed seconds ahead of the clock
+        let issued = first + (10 << (NODE_ID_BITS + SEQUENCE_BITS)) + 5;
+        generator.resume_after(issued);
+        assert_eq!(generator.next_id(), issued + 1);
+
+        // IDs issued since are kept
+        generator.resume_after(first);
+        assert_eq!(generator.next_id(), issued + 2);
+    }
+
+    #[tokio::test]
+    async fn test_resume_reads_the_last_id_of_the_node() {
+        let generator = RunIdGenerator::new(3);
+        let issued = generator.next_id() + (1 << (NODE_ID_BITS + SEQUENCE_BITS));
+        let mock = Mock::new();
+        mock.add(handlers::provide(vec![issued]));
+        let client = Client::default()
+            .with_url(mock.url())
+            .with_compression(Compression::None);
+
+        generator.resume(&client).await.unwrap();
+        assert_eq!(generator.next_id(), issued + 1);
+    }
+}
@@ -0,0 +1,243 @@
+use std::future::Future;
+use std::time::Duration;
+use tokio::signal;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, timeout_at, Instant};
+use tokio_util::task::TaskTracker;
+use tracing::{error, info, warn};
+
+// Time the background processors get for their final flush if connections used up the drain
+// timeout, e.g. because clients kept WebSocket connections open
+const FINAL_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Tracks whether a shutdown was requested and the deadline for draining
+// Cloned into the server, the application state and the drain logic so all share the same deadline
+#[derive(Clone)]
+pub struct Shutdown {
+    deadline: watch::Receiver<Option<Instant>>,
+    // Tasks outliving their request (e.g. WebSocket connections), which hold the record senders
+    connections: TaskTracker,
+}
+
+// Starts the shutdown of the `Shutdown` it was created with
+pub struct ShutdownTrigger {
+    deadline: watch::Sender<Option<Instant>>,
+    drain_timeout: Duration,
+}
+
+impl ShutdownTrigger {
+    pub fn fire(self) {
+        let _ = self
+            .deadline
+            .send(Some(Instant::now() + self.drain_timeout));
+    }
+}
+
+impl Shutdown {
+    // Creates a shutdown that is started by the returned trigger
+    pub fn new(drain_timeout: Duration) -> (Self, ShutdownTrigger) {
+        let (deadline_tx, deadline_rx) = watch::channel(None);
+        let shutdown = Self {
+            deadline: deadline_rx,
+            connections: TaskTracker::new(),
+        };
+        let trigger = ShutdownTrigger {
+            deadline: deadline_tx,
+            drain_timeout,
+        };
+        (shutdown, trigger)
+    }
+
+    // Spawns a task waiting for SIGTERM/SIGINT
+    // The drain deadline starts counting once the signal is received
+    pub fn listen(drain_timeout: Duration) -> Self {
+        let (shutdown, trigger) = Self::new(drain_timeout);
+        tokio::spawn(async move {
+            wait_for_signal().await;
+            info!(
+                drain_timeout_secs = drain_timeout.as_secs(),
+                "Shutdown signal received, draining"
+            );
+            trigger.fire();
+        });
+        shutdown
+    }
+
+    // Resolves once a shutdown signal was received
+    pub async fn requested(&self) {
+        let mut deadline = self.deadline.clone();
+        // An error means the trigger was dropped without firing, so no shutdown will come
+        if deadline.wait_for(Option::is_some).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+
+    // Tracks a connection that must end before the background processors can drain
+    // The connection is expected to close soon after `requested` resolves
+    pub fn track_connection<F: Future>(&self, connection: F) -> impl Future<Output = F::Output> {
+        self.connections.track_future(connection)
+    }
+
+    // Waits until every tracked connection has ended, or the drain deadline has passed
+    pub async fn wait_for_connections(&self) {
+        self.connections.close();
+        let deadline = (*self.deadline.borrow()).unwrap_or_else(Instant::now);
+        if timeout_at(deadline, self.connections.wait()).await.is_err() {
+            error!(
+                connections = self.connections.len(),
+                "Drain deadline reached before connections closed"
+            );
+        }
+    }
+
+    // Resolves once the drain deadline has passed
+    pub async fn deadline_elapsed(&self) {
+        self.requested().await;
+        if let Some(deadline) = *self.deadline.borrow() {
+            sleep_until(deadline).await;
+        }
+    }
+
+    // Waits for the background processors to flush their buffers after their channels closed
+    // Returns true only if every processor finished in time and persisted all of its records
+    pub async fn drain_processors(
+        &self,
+        processors: Vec<(&'static str, JoinHandle<bool>)>,
+    ) -> bool {
+        let deadline = (*self.deadline.borrow())
+            .unwrap_or_else(Instant::now)
+            .max(Instant::now() + FINAL_FLUSH_TIMEOUT);
+        let mut persisted = true;
+
+        for (table_name, handle) in processors {
+            match timeout_at(deadline, handle).await {
+                Ok(Ok(true)) => info!(table = table_name, "Background processor drained"),
+                Ok(Ok(false)) => {
+                    warn!(
+                        table = table_name,
+                        "Background processor exited with batches left in the spool"
+                    );
+                    persisted = false;
+                }
+                Ok(Err(e)) => {
+                    error!(table = table_name, error = %e, "Background processor task failed");
+                    persisted = false;
+                }
+                Err(_) => {
+                    error!(
+                        table = table_name,
+                        "Drain deadline reached before background processor finished"
+                    );
+                    persisted = false;
+                }
+            }
+        }
+
+        persisted
+    }
+}
+
+// Resolves on SIGINT (Ctrl+C) or SIGTERM
+async fn wait_for_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c()
+            .await
+            .expect("Failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, FlushConfig, LOGS_TABLE_NAME};
+    use crate::models::log::LogRow;
+    use crate::processors::background::{start_background_processor, ProcessorStatus};
+    use crate::processors::fair_queue::fair_channel;
+    use crate::processors::spool::Spool;
+    use crate::routes::testing::test_config;
+    use clickhouse::test::{handlers, Mock};
+    use std::sync::Arc;
+
+    fn log_row(line_number: u64) -> LogRow {
+        LogRow {
+            time: 1,
+            message: format!("line {}", line_number),
+            line_number,
+            log_type: "INFO".to_string(),
+            tenant_id: "tenant".to_string(),
+            run_id: 1,
+            project_name: "project".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_queued_rows_are_flushed_when_a_connection_outlives_the_deadline() {
+        let mock = Mock::new();
+        mock.add(handlers::record::<LogRow>());
+        let config = Config {
+            clickhouse_url: mock.url().to_string(),
+            ..test_config()
+        };
+        let root = std::env::temp_dir().join(format!("shutdown-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let spool = Spool::open(&root, LOGS_TABLE_NAME).await.unwrap();
+        let status = Arc::new(ProcessorStatus::default());
+        let (sender, receiver) = fair_channel(10, 10);
+        // Rows are only flushed when the channel closes
+        let flush_config = FlushConfig {
+            batch_size: 100,
+            flush_interval: Duration::from_secs(3600),
+        };
+        let processor = tokio::spawn(start_background_processor(
+            receiver,
+            flush_config,
+            false,
+            Arc::new(config),
+            spool,
+            status.clone(),
+        ));
+
+        // A connection that ignores the shutdown and keeps its sender
+        let (shutdown, trigger) = Shutdown::new(Duration::from_millis(50));
+        let held = sender.clone();
+        tokio::spawn(shutdown.track_connection(async move {
+            let _sender = held;
+            std::future::pending::<()>().await
+        }));
+        for line_number in 0..3 {
+            sender.send("tenant", log_row(line_number)).await.unwrap();
+        }
+
+        trigger.fire();
+        shutdown.wait_for_connections().await;
+        sender.close();
+        assert!(
+            shutdown
+                .drain_processors(vec![(LOGS_TABLE_NAME, processor)])
+                .await
+        );
+        assert_eq!(*status.subscribe_flushes().borrow(), 1);
+        assert!(matches!(
+            sender.send("tenant", log_row(3)).await,
+            Err(crate::processors::fair_queue::SendError::Closed(_))
+        ));
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
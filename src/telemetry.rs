@@ -0,0 +1,135 @@
+use lazy_static::lazy_static;
+use prometheus::{
+    exponential_buckets, register_histogram, register_histogram_vec, register_int_counter_vec,
+    register_int_gauge_vec, Encoder, Histogram, HistogramVec, IntCounterVec, IntGaugeVec,
+    TextEncoder,
+};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Prometheus metrics for the ingest pipeline, registered in the default registry
+// and exposed by the /metrics route
+lazy_static! {
+    // Rows accepted by the ingest endpoints and handed to the background processors
+    pub static ref ROWS_RECEIVED: IntCounterVec = register_int_counter_vec!(
+        "ingest_rows_received_total",
+        "Rows received per table (and tenant, if METRICS_TENANT_LABELS is set)",
+        &["table", "tenant"]
+    )
+    .unwrap();
+
+    // Records waiting in each channel between the handlers and the background processors
+    // Updated when /metrics is scraped
+    pub static ref CHANNEL_DEPTH: IntGaugeVec = register_int_gauge_vec!(
+        "ingest_channel_depth",
+        "Records queued in the channel of each background processor",
+        &["table"]
+    )
+    .unwrap();
+
+    pub static ref CHANNEL_CAPACITY: IntGaugeVec = register_int_gauge_vec!(
+        "ingest_channel_capacity",
+        "Capacity of the channel of each background processor",
+        &["table"]
+    )
+    .unwrap();
+
+    // Number of records per flushed batch
+    pub static ref FLUSH_BATCH_SIZE: HistogramVec = register_histogram_vec!(
+        "flush_batch_size",
+        "Records per batch flushed to ClickHouse",
+        &["table"],
+        exponential_buckets(1.0, 4.0, 11).unwrap() // 1 to ~1M
+    )
+    .unwrap();
+
+    // Duration of a confirmed insert, excluding retries
+    pub static ref FLUSH_DURATION: HistogramVec = register_histogram_vec!(
+        "flush_duration_seconds",
+        "Duration of successful ClickHouse inserts",
+        &["table"],
+        exponential_buckets(0.005, 2.0, 14).unwrap() // 5ms to ~40s
+    )
+    .unwrap();
+
+    // Failed insert attempts that were retried
+    pub static ref FLUSH_RETRIES: IntCounterVec = register_int_counter_vec!(
+        "flush_retries_total",
+        "Failed ClickHouse insert attempts that were retried",
+        &["table"]
+    )
+    .unwrap();
+
+    // Batches that failed every attempt and were kept in the spool for replay
+    pub static ref FAILED_BATCHES: IntCounterVec = register_int_counter_vec!(
+        "flush_failed_batches_total",
+        "Batches that failed every insert attempt and were retained in the spool",
+        &["table"]
+    )
+    .unwrap();
+
+    // Batches that failed every attempt and could not be spooled, their records are lost
+    pub static ref DROPPED_BATCHES: IntCounterVec = register_int_counter_vec!(
+        "flush_dropped_batches_total",
+        "Batches that failed every insert attempt and could not be spooled",
+        &["table"]
+    )
+    .unwrap();
+
+    // Ingest requests rejected by a tenant's rate limit or daily quota
+    pub static ref RATE_LIMITED: IntCounterVec = register_int_counter_vec!(
+        "ingest_rate_limited_total",
+        "Ingest requests rejected by a rate limit or daily quota, per limit (and tenant, if METRICS_TENANT_LABELS is set)",
+        &["tenant", "limit"]
+    )
+    .unwrap();
+
+    // Records rejected because a background processor channel was saturated
+    pub static ref LOAD_SHED: IntCounterVec = register_int_counter_vec!(
+        "ingest_load_shed_total",
+        "Records rejected because a processor channel was saturated, per table and reason",
+        &["table", "reason"]
+    )
+    .unwrap();
+
+    // API key lookups, by whether they were answered from the cache
+    pub static ref AUTH_CACHE_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "auth_cache_requests_total",
+        "API key lookups by cache result (hit or miss)",
+        &["result"]
+    )
+    .unwrap();
+
+    // Time spent generating the presigned URLs of a /files request
+    pub static ref PRESIGN_DURATION: Histogram = register_histogram!(
+        "presign_duration_seconds",
+        "Duration of presigned URL generation per request",
+        exponential_buckets(0.001, 2.0, 14).unwrap() // 1ms to ~8s
+    )
+    .unwrap();
+}
+
+// Whether the `tenant` label carries tenant IDs, set at startup from METRICS_TENANT_LABELS
+static TENANT_LABELS: AtomicBool = AtomicBool::new(false);
+
+pub fn set_tenant_labels(enabled: bool) {
+    TENANT_LABELS.store(enabled, Ordering::Relaxed);
+}
+
+// Value of the `tenant` label: the tenant ID if per-tenant labels are enabled, otherwise empty,
+// so the metrics neither reveal tenant IDs nor grow a series per tenant
+pub fn tenant_label(tenant_id: &str) -> &str {
+    if TENANT_LABELS.load(Ordering::Relaxed) {
+        tenant_id
+    } else {
+        ""
+    }
+}
+
+// Renders all registered metrics in the Prometheus text format
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .expect("Failed to encode metrics");
+    String::from_utf8(buffer).expect("Metrics are not valid UTF-8")
+}
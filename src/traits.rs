@@ -3,10 +3,15 @@ use clickhouse::Row;
 use serde::{de::DeserializeOwned, Serialize};
 
 use crate::error::AppError;
+use crate::heartbeat::RunKey;
+use crate::import::ImportBatch;
+use crate::processors::stream::StreamResponse;
 
 /// Trait for enrichment data that comes from headers
 pub trait EnrichmentData: Clone {
     fn from_headers(tenant_id: String, headers: &HeaderMap) -> Result<Self, AppError>;
+    /// The run the request belongs to, if any (used to track when runs were last seen)
+    fn run_key(&self) -> Option<RunKey>;
 }
 
 /// Trait for input data that can be validated
@@ -15,6 +20,15 @@ pub trait InputData: DeserializeOwned + Send + Sync + 'static + std::fmt::Debug
     fn validate(&self) -> Result<(), AppError>;
 }
 
+/// Trait for input data that can also be sent as a message of the published Protobuf schema
+/// (`proto/ingest.proto`)
+pub trait ProtobufInput: Sized {
+    /// The message type of the input
+    type Message: prost::Message + Default;
+    /// Converts a decoded message into the input, which is validated like a JSON record
+    fn from_message(message: Self::Message) -> Self;
+}
+
 /// Trait for database rows that can be created from input and enrichment data
 pub trait DatabaseRow<R, E>:
     DeserializeOwned + std::fmt::Debug + Serialize + Row + Send + 'static + Clone
@@ -26,6 +40,13 @@ where
     fn table_name() -> &'static str;
 }
 
+/// Trait for destinations of imported runs, the background processors for the import routes
+/// and ClickHouse itself for the import commands
+pub trait ImportSink {
+    /// Writes a batch of rows and uploads its files
+    async fn write(&mut self, batch: ImportBatch) -> Result<(), AppError>;
+}
+
 /// Trait for stream processors
 pub trait StreamProcessor<R, E, F>
 where
@@ -37,5 +58,5 @@ where
         self,
         headers: HeaderMap,
         body: axum::body::Body,
-    ) -> Result<String, AppError>;
+    ) -> Result<StreamResponse, AppError>;
 }